@@ -0,0 +1,203 @@
+use geo::*;
+use obj::*;
+
+const BIN_COUNT : usize = 16;
+const MAX_LEAF_SIZE : usize = 4;
+const TRAVERSAL_COST : f64 = 1.0;
+const INTERSECTION_COST : f64 = 1.0;
+
+#[derive(Copy, Clone)]
+enum NodeKind {
+    // indices[first..first + count]
+    Leaf { first : usize, count : usize },
+    // 左の子は常に直後のノード
+    Interior { right : usize, axis : usize },
+}
+
+#[derive(Copy, Clone)]
+struct Node {
+    bounds : Aabb,
+    kind : NodeKind,
+}
+
+#[derive(Copy, Clone)]
+struct BuildPrim {
+    index : usize,
+    bounds : Aabb,
+    centroid : Vec3,
+}
+
+// Surface Area Heuristic で構築した Bounding Volume Hierarchy
+// 中身は呼び出し側のプリミティブへの添字だけを持つ
+pub(crate) struct Bvh {
+    nodes : Vec<Node>,
+    indices : Vec<usize>,
+}
+
+fn axis_of(v : &Vec3, axis : usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+impl Bvh {
+    pub(crate) fn new(bounds : &[Aabb]) -> Bvh {
+        let mut prims : Vec<_> = bounds.iter().enumerate()
+            .map(|(index, b)| BuildPrim{index, bounds : *b, centroid : b.centroid()})
+            .collect();
+
+        let mut bvh = Bvh {
+            nodes : Vec::with_capacity(2 * prims.len()),
+            indices : Vec::with_capacity(prims.len()),
+        };
+
+        if !prims.is_empty() {
+            bvh.build(&mut prims);
+        }
+
+        bvh
+    }
+
+    fn push_leaf(&mut self, prims : &[BuildPrim], bounds : Aabb) -> usize {
+        let first = self.indices.len();
+        self.indices.extend(prims.iter().map(|p| p.index));
+        self.nodes.push(Node{bounds, kind : NodeKind::Leaf{first, count : prims.len()}});
+        self.nodes.len() - 1
+    }
+
+    fn build(&mut self, prims : &mut [BuildPrim]) -> usize {
+        let bounds = prims.iter().fold(Aabb::empty(), |b, p| b.union(&p.bounds));
+
+        if prims.len() <= 1 {
+            return self.push_leaf(prims, bounds);
+        }
+
+        let cbounds = prims.iter().fold(Aabb::empty(), |b, p| b.grow(&p.centroid));
+
+        // 重心の広がりが最大の軸で分割する
+        let extent = cbounds.max - cbounds.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        let (cmin, cmax) = (axis_of(&cbounds.min, axis), axis_of(&cbounds.max, axis));
+        if cmax <= cmin {
+            // 重心がすべて一致していて分割できない
+            return self.push_leaf(prims, bounds);
+        }
+
+        let bin_of = |p : &BuildPrim| {
+            let b = ((axis_of(&p.centroid, axis) - cmin) / (cmax - cmin) * BIN_COUNT as f64) as usize;
+            b.min(BIN_COUNT - 1)
+        };
+
+        let mut bins = [(Aabb::empty(), 0usize); BIN_COUNT];
+        for p in prims.iter() {
+            let bin = &mut bins[bin_of(p)];
+            bin.0 = bin.0.union(&p.bounds);
+            bin.1 += 1;
+        }
+
+        // 各分割位置 (bin i と i + 1 の間) の SAH コスト
+        let mut best = (f64::INFINITY, 0);
+        for split in 0..BIN_COUNT - 1 {
+            let (lb, ln) = bins[..=split].iter().fold((Aabb::empty(), 0), |(b, n), bin| (b.union(&bin.0), n + bin.1));
+            let (rb, rn) = bins[split + 1..].iter().fold((Aabb::empty(), 0), |(b, n), bin| (b.union(&bin.0), n + bin.1));
+            if ln == 0 || rn == 0 {
+                continue;
+            }
+            let cost = TRAVERSAL_COST + INTERSECTION_COST *
+                (lb.surface_area() * ln as f64 + rb.surface_area() * rn as f64) / bounds.surface_area();
+            if cost < best.0 {
+                best = (cost, split);
+            }
+        }
+
+        let leaf_cost = INTERSECTION_COST * prims.len() as f64;
+        if prims.len() <= MAX_LEAF_SIZE && leaf_cost <= best.0 {
+            return self.push_leaf(prims, bounds);
+        }
+
+        let mid = {
+            let mut mid = 0;
+            for i in 0..prims.len() {
+                if bin_of(&prims[i]) <= best.1 {
+                    prims.swap(i, mid);
+                    mid += 1;
+                }
+            }
+            if mid == 0 || mid == prims.len() {
+                // 面積が 0 のビンなどで SAH が分割できなかったときは中央で切る
+                prims.sort_by(|a, b| axis_of(&a.centroid, axis).partial_cmp(&axis_of(&b.centroid, axis)).unwrap());
+                prims.len() / 2
+            } else {
+                mid
+            }
+        };
+
+        let node = self.nodes.len();
+        self.nodes.push(Node{bounds, kind : NodeKind::Interior{right : 0, axis}});
+
+        let (left, right) = prims.split_at_mut(mid);
+        self.build(left);
+        let right = self.build(right);
+        self.nodes[node].kind = NodeKind::Interior{right, axis};
+
+        node
+    }
+
+    // 最も近い交差を返す
+    // hit_prim は添字で示されるプリミティブとの交差を調べる
+    pub(crate) fn hit<'a, F>(&self, ray : &Ray, (tmin, tmax) : (f64, f64), hit_prim : F) -> Option<HitRecord<'a>>
+        where F : Fn(usize, &Ray, (f64, f64)) -> Option<HitRecord<'a>>
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_direction = Vec3::new(1.0) / ray.direction;
+        let negative = [ray.direction.x < 0.0, ray.direction.y < 0.0, ray.direction.z < 0.0];
+
+        let mut closest : Option<HitRecord<'a>> = None;
+        let mut tmax = tmax;
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            if node.bounds.intersect(&ray.origin, &inv_direction, (tmin, tmax)).is_none() {
+                continue;
+            }
+
+            match node.kind {
+                NodeKind::Leaf{first, count} => {
+                    for &index in &self.indices[first..first + count] {
+                        if let Some(hr) = hit_prim(index, ray, (tmin, tmax)) {
+                            tmax = hr.t;
+                            closest = Some(hr);
+                        }
+                    }
+                },
+                NodeKind::Interior{right, axis} => {
+                    // 近い方の子を先に調べる
+                    if negative[axis] {
+                        stack.push(i + 1);
+                        stack.push(right);
+                    } else {
+                        stack.push(right);
+                        stack.push(i + 1);
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+}
@@ -0,0 +1,472 @@
+// PNG と OpenEXR の ZIP 圧縮で使う zlib (RFC 1950/1951) の圧縮器と展開器
+// 圧縮は LZ77 で一致を探し、固定ハフマン符号のブロックひとつで出力する
+// 展開はすべての種類のブロックに対応する
+
+const WINDOW_SIZE : usize = 32768;
+const MIN_MATCH : usize = 3;
+const MAX_MATCH : usize = 258;
+const MAX_CHAIN : usize = 64;
+const HASH_BITS : usize = 15;
+
+const LENGTH_BASE : [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA : [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE : [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA : [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+struct BitWriter {
+    out : Vec<u8>,
+    bits : u64,
+    count : u32,
+}
+
+impl BitWriter {
+    // 下位ビットから詰める
+    fn write(&mut self, value : u32, n : u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // ハフマン符号は上位ビットから詰める
+    fn write_code(&mut self, code : u32, n : u32) {
+        let reversed = code.reverse_bits() >> (32 - n);
+        self.write(reversed, n);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn write_literal(w : &mut BitWriter, symbol : u32) {
+    match symbol {
+        0..=143 => w.write_code(0x30 + symbol, 8),
+        144..=255 => w.write_code(0x190 + symbol - 144, 9),
+        256..=279 => w.write_code(symbol - 256, 7),
+        _ => w.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(w : &mut BitWriter, length : usize, distance : usize) {
+    let l = LENGTH_BASE.iter().rposition(|&b| b as usize <= length).unwrap();
+    write_literal(w, 257 + l as u32);
+    w.write((length - LENGTH_BASE[l] as usize) as u32, u32::from(LENGTH_EXTRA[l]));
+
+    let d = DISTANCE_BASE.iter().rposition(|&b| b as usize <= distance).unwrap();
+    w.write_code(d as u32, 5);
+    w.write((distance - DISTANCE_BASE[d] as usize) as u32, u32::from(DISTANCE_EXTRA[d]));
+}
+
+fn hash(data : &[u8], i : usize) -> usize {
+    let v = u32::from(data[i]) << 16 | u32::from(data[i + 1]) << 8 | u32::from(data[i + 2]);
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+// 生の deflate ストリーム
+pub(crate) fn deflate(data : &[u8]) -> Vec<u8> {
+    let mut w = BitWriter{out : Vec::with_capacity(data.len() / 2 + 16), bits : 0, count : 0};
+
+    // BFINAL = 1, BTYPE = 01 (固定ハフマン)
+    w.write(1, 1);
+    w.write(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+
+    let insert = |head : &mut Vec<usize>, prev : &mut Vec<usize>, i : usize| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(data, i);
+            prev[i % WINDOW_SIZE] = head[h];
+            head[h] = i;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+
+        if i + MIN_MATCH <= data.len() {
+            let max = MAX_MATCH.min(data.len() - i);
+            let mut candidate = head[hash(data, i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..].iter().zip(&data[i..i + max]).take_while(|(a, b)| a == b).count();
+                if length > best.0 {
+                    best = (length, i - candidate);
+                    if length == max {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                // 古いエントリが上書きされて前方を指していたら打ち切る
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            write_match(&mut w, best.0, best.1);
+            for j in i..i + best.0 {
+                insert(&mut head, &mut prev, j);
+            }
+            i += best.0;
+        } else {
+            write_literal(&mut w, u32::from(data[i]));
+            insert(&mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+
+    write_literal(&mut w, 256);
+    w.finish()
+}
+
+pub(crate) fn adler32(data : &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += u32::from(x);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+// zlib 形式 (ヘッダ + deflate + Adler-32)
+pub(crate) fn zlib(data : &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+struct BitReader<'a> {
+    data : &'a [u8],
+    pos : usize,
+    bits : u64,
+    count : u32,
+}
+
+impl<'a> BitReader<'a> {
+    // 下位ビットから読む
+    fn read(&mut self, n : u32) -> Result<u32, String> {
+        while self.count < n {
+            match self.data.get(self.pos) {
+                Some(&b) => {
+                    self.bits |= u64::from(b) << self.count;
+                    self.pos += 1;
+                    self.count += 8;
+                },
+                None => return Err("unexpected end of deflate stream".to_string()),
+            }
+        }
+        let v = (self.bits & ((1u64 << n) - 1)) as u32;
+        self.bits >>= n;
+        self.count -= n;
+        Ok(v)
+    }
+
+    // 次のバイト境界まで読み飛ばす
+    fn align(&mut self) {
+        let n = self.count % 8;
+        self.bits >>= n;
+        self.count -= n;
+    }
+}
+
+// 符号長から作る正準ハフマン符号
+struct Huffman {
+    // 長さごとの符号の数
+    counts : [u16; 16],
+    // 符号の順に並べた記号
+    symbols : Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths : &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left = 1i32;
+        for &c in &counts[1..] {
+            left = 2 * left - i32::from(c);
+            if left < 0 {
+                return Err("over-subscribed Huffman code".to_string());
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for l in 1..15 {
+            offsets[l + 1] = offsets[l] + counts[l];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (s, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = s as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+        Ok(Huffman{counts, symbols})
+    }
+
+    // 符号は上位ビットから読む
+    fn decode(&self, r : &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for l in 1..16 {
+            code |= r.read(1)? as i32;
+            let count = i32::from(self.counts[l]);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+fn fixed_huffman() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (s, l) in lengths.iter_mut().enumerate() {
+        *l = match s {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+fn dynamic_huffman(r : &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    const ORDER : [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+    let nlen = r.read(5)? as usize + 257;
+    let ndist = r.read(5)? as usize + 1;
+    let ncode = r.read(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err("too many length or distance codes".to_string());
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &i in &ORDER[..ncode] {
+        code_lengths[i] = r.read(3)? as u8;
+    }
+    let code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (value, repeat) = match code.decode(r)? {
+            s @ 0..=15 => (s as u8, 1),
+            16 => match i.checked_sub(1) {
+                Some(p) => (lengths[p], 3 + r.read(2)? as usize),
+                None => return Err("repeat without a previous length".to_string()),
+            },
+            17 => (0, 3 + r.read(3)? as usize),
+            _ => (0, 11 + r.read(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err("too many code lengths".to_string());
+        }
+        for l in &mut lengths[i..i + repeat] {
+            *l = value;
+        }
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err("missing end-of-block code".to_string());
+    }
+
+    Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+// 生の deflate ストリームを展開する
+pub(crate) fn inflate(data : &[u8]) -> Result<Vec<u8>, String> {
+    let mut r = BitReader{data, pos : 0, bits : 0, count : 0};
+    let mut out = Vec::with_capacity(data.len() * 4);
+
+    loop {
+        let last = r.read(1)? == 1;
+        match r.read(2)? {
+            0 => {
+                r.align();
+                let len = r.read(16)?;
+                if r.read(16)? != !len & 0xffff {
+                    return Err("stored block length mismatch".to_string());
+                }
+                for _ in 0..len {
+                    out.push(r.read(8)? as u8);
+                }
+            },
+            t @ 1..=2 => {
+                let (literal, distance) = if t == 1 {fixed_huffman()} else {dynamic_huffman(&mut r)?};
+                loop {
+                    let s = literal.decode(&mut r)? as usize;
+                    if s < 256 {
+                        out.push(s as u8);
+                        continue;
+                    }
+                    if s == 256 {
+                        break;
+                    }
+                    let l = s - 257;
+                    if l >= LENGTH_BASE.len() {
+                        return Err("invalid length code".to_string());
+                    }
+                    let length = LENGTH_BASE[l] as usize + r.read(u32::from(LENGTH_EXTRA[l]))? as usize;
+                    let d = distance.decode(&mut r)? as usize;
+                    if d >= DISTANCE_BASE.len() {
+                        return Err("invalid distance code".to_string());
+                    }
+                    let dist = DISTANCE_BASE[d] as usize + r.read(u32::from(DISTANCE_EXTRA[d]))? as usize;
+                    if dist > out.len() {
+                        return Err("distance too far back".to_string());
+                    }
+                    // 重なっていてもよいので 1 バイトずつ写す
+                    let start = out.len() - dist;
+                    for k in 0..length {
+                        let b = out[start + k];
+                        out.push(b);
+                    }
+                }
+            },
+            _ => return Err("invalid block type".to_string()),
+        }
+        if last {
+            break;
+        }
+    }
+
+    Ok(out)
+}
+
+// zlib 形式を展開してチェックサムを確かめる
+pub(crate) fn unzlib(data : &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 6 || data[0] & 0x0f != 8 || (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 != 0 {
+        return Err("invalid zlib header".to_string());
+    }
+    if data[1] & 0x20 != 0 {
+        return Err("preset dictionaries are not supported".to_string());
+    }
+    let out = inflate(&data[2..])?;
+    let n = data.len();
+    if u32::from_be_bytes([data[n - 4], data[n - 3], data[n - 2], data[n - 1]]) != adler32(&out) {
+        return Err("Adler-32 checksum mismatch".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // テスト用の xorshift で作ったバイト列
+    fn noise(n : usize, mut s : u64) -> Vec<u8> {
+        (0..n).map(|_| {
+            s ^= s << 13;
+            s ^= s >> 7;
+            s ^= s << 17;
+            (s >> 56) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn adler32_matches_known_values() {
+        assert!(adler32(b"") == 1);
+        assert!(adler32(b"Wikipedia") == 0x11e6_0398);
+        // 途中で剰余を取っても、取らずに計算したものと一致する
+        let data = vec![255u8; 100000];
+        let (a, b) = data.iter().fold((1u64, 0u64), |(a, b), &x| (a + u64::from(x), b + a + u64::from(x)));
+        assert!(u64::from(adler32(&data)) == ((b % 65521) << 16) | (a % 65521));
+    }
+
+    #[test]
+    fn zlib_stream_is_well_formed() {
+        let data : Vec<u8> = b"abcabcabd".iter().cycle().take(20000).cloned().collect();
+        let z = zlib(&data);
+        // CMF = deflate, 32K 窓 / FCHECK / 末尾の Adler-32
+        assert!(z[0] == 0x78 && (u16::from(z[0]) << 8 | u16::from(z[1])) % 31 == 0);
+        assert!(z[z.len() - 4..] == adler32(&data).to_be_bytes());
+        // 最初のブロックは BFINAL = 1, BTYPE = 01
+        assert!(z[2] & 0b111 == 0b011);
+        // 繰り返しは縮む
+        assert!(z.len() < 1000);
+        assert!(deflate(&vec![0; 100000]).len() < 1000);
+    }
+
+    #[test]
+    fn deflate_round_trips() {
+        // 一致がほとんどないもの、長い繰り返し、その混ざったもの (どれも 64 KiB を超える)
+        let mut mixed = noise(40000, 1);
+        mixed.extend(std::iter::repeat_n(7u8, 30000));
+        mixed.extend(b"abcabcabd".iter().cycle().take(20000));
+        mixed.extend(noise(5000, 2));
+        let inputs = vec![vec![], vec![42], b"ab".to_vec(), noise(70000, 3), vec![0; 100000], mixed];
+
+        for data in &inputs {
+            assert!(inflate(&deflate(data)).unwrap() == *data, "{} bytes", data.len());
+            assert!(unzlib(&zlib(data)).unwrap() == *data, "{} bytes", data.len());
+        }
+
+        // チェックサムが合わなければエラー
+        let mut z = zlib(&inputs[3]);
+        let n = z.len();
+        z[n - 1] ^= 1;
+        assert!(unzlib(&z).is_err());
+    }
+
+    #[test]
+    fn inflate_reads_stored_blocks() {
+        let data = noise(70000, 4);
+        let (first, rest) = data.split_at(65535);
+
+        // 最大長の非圧縮ブロック, 空の非圧縮ブロック, 残りを固定ハフマンのブロック
+        // 非圧縮ブロックのヘッダ (BFINAL = 0, BTYPE = 00) はバイト境界までの 1 バイトになる
+        let mut stream = vec![];
+        for block in &[first, &[][..]] {
+            let len = block.len() as u16;
+            stream.push(0);
+            stream.extend_from_slice(&len.to_le_bytes());
+            stream.extend_from_slice(&(!len).to_le_bytes());
+            stream.extend_from_slice(block);
+        }
+        stream.extend(deflate(rest));
+        assert!(inflate(&stream).unwrap() == data);
+
+        // LEN と NLEN が合わなければエラー
+        let mut broken = stream.clone();
+        broken[3] ^= 1;
+        assert!(inflate(&broken).is_err());
+        // 途中で切れていてもパニックしない
+        assert!(inflate(&stream[..1000]).is_err());
+    }
+}
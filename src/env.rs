@@ -1,9 +1,60 @@
 use obj::*;
 use geo::*;
+use bvh::Bvh;
+use light::{Light, AreaLight, AnalyticLight};
+use environment::Environment;
+use mesh::TriangleMesh;
+use material::{Bsdf, Diffuse, Mirror, Fresnel};
 
 use std;
+use std::sync::{Arc, OnceLock};
 use ::rayon::prelude::*;
 
+// 絞りの形
+#[derive(Copy, Clone, PartialEq)]
+pub enum Aperture {
+    Circle,
+    // 羽根の枚数と回転 (ラジアン)
+    Polygon{blades : usize, rotation : f64},
+}
+
+impl Aperture {
+    // [0, 1)^2 の一様乱数を、半径 1 の絞りの中の一様な点に写す
+    pub(crate) fn sample(&self, (u1, u2) : (f64, f64)) -> (f64, f64) {
+        use std::f64::consts::PI;
+
+        match *self {
+            Aperture::Circle => {
+                // concentric mapping (中心 (0.5, 0.5) が原点に写る)
+                let (a, b) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+                if a == 0.0 && b == 0.0 {
+                    (0.0, 0.0)
+                } else if a.abs() > b.abs() {
+                    let t = PI / 4.0 * (b / a);
+                    (a * t.cos(), a * t.sin())
+                } else {
+                    let t = PI / 2.0 - PI / 4.0 * (a / b);
+                    (b * t.cos(), b * t.sin())
+                }
+            },
+            Aperture::Polygon{blades, rotation} => {
+                // 中心と隣り合う 2 頂点がなす三角形をひとつ選び、その中で一様に選ぶ
+                let n = blades as f64;
+                let k = (u1 * n).floor().min(n - 1.0);
+                let u1 = u1 * n - k;
+                let vertex = |i : f64| {
+                    let t = rotation + 2.0 * PI * i / n;
+                    (t.cos(), t.sin())
+                };
+                let (a, b) = (vertex(k), vertex(k + 1.0));
+                let su = u1.sqrt();
+                let (ba, bb) = (su * (1.0 - u2), su * u2);
+                (a.0 * ba + b.0 * bb, a.1 * ba + b.1 * bb)
+            },
+        }
+    }
+}
+
 pub struct Camera {
     pub position : Vec3, // 位置
     pub focus : Vec3, // 注視点
@@ -13,6 +64,9 @@ pub struct Camera {
     pub ue : Vec3,
     pub ve : Vec3,
     pub tm : (f64, f64),
+    pub aperture : f64, // 絞りの半径 (0 ならピンホール)
+    pub focus_distance : f64, // ピントが合う面までの距離
+    pub aperture_shape : Aperture,
 }
 
 impl Default for Camera {
@@ -29,27 +83,39 @@ impl Default for Camera {
 }
 
 impl Camera {
-    pub(crate) fn create_ray(&self, (w, h) : (f64, f64), (rx, ry) : (f64, f64)) -> Ray {
-        Ray {
-            origin : self.position,
-            direction : {
-                let tf = f64::tan(self.fov * 0.5);
-                let rpx = 2.0 * rx / w - 1.0;
-                let rpy = 2.0 * ry / h - 1.0;
+    // lens はレンズ上の位置を選ぶ [0, 1)^2 の値で、(0.5, 0.5) が中心
+    pub fn create_ray(&self, (w, h) : (f64, f64), (rx, ry) : (f64, f64), lens : (f64, f64)) -> Ray {
+        let tf = f64::tan(self.fov * 0.5);
+        let rpx = 2.0 * rx / w - 1.0;
+        let rpy = 2.0 * ry / h - 1.0;
 
-                // カメラ座標系での方向
-                let aspect = w / h;
-                let wd = Vec3::new((aspect * tf * rpx, tf * rpy, -1.0)).normalize();
+        // カメラ座標系での方向
+        let aspect = w / h;
+        let wd = Vec3::new((aspect * tf * rpx, tf * rpy, -1.0)).normalize();
 
+        if self.aperture <= 0.0 {
+            return Ray {
+                origin : self.position,
                 // ワールド座標系に変換
-                self.ue * wd.x + self.ve * wd.y + self.we * wd.z
-            },
+                direction : self.ue * wd.x + self.ve * wd.y + self.we * wd.z,
+            };
+        }
+
+        // 薄レンズ: ピント面上の点はレンズ上のどこから見ても同じ画素に写る
+        let p = wd * (self.focus_distance / -wd.z);
+        let (lx, ly) = self.aperture_shape.sample(lens);
+        let o = Vec3::new((lx * self.aperture, ly * self.aperture, 0.0));
+        let d = (p - o).normalize();
+
+        Ray {
+            origin : self.position + self.ue * o.x + self.ve * o.y,
+            direction : self.ue * d.x + self.ve * d.y + self.we * d.z,
         }
     }
 }
 
 pub trait NewCamera<T> {
-    fn new(T, T, T, f64, (f64, f64)) -> Camera;
+    fn new(position : T, focus : T, upside : T, fov : f64, tm : (f64, f64)) -> Self;
 }
 
 impl NewCamera<(f64, f64, f64)> for Camera {
@@ -71,7 +137,10 @@ impl NewCamera<(f64, f64, f64)> for Camera {
             Camera{
                 position, focus, upside,
                 fov : fov * std::f64::consts::PI / 180.0,
-                we, ue, ve, tm
+                we, ue, ve, tm,
+                aperture : 0.0,
+                focus_distance : Vec3::dot(&(focus - position), &(focus - position)).sqrt(),
+                aperture_shape : Aperture::Circle,
             }
         }
 }
@@ -90,31 +159,59 @@ impl NewCamera<Vec3> for Camera {
             Camera{
                 position, focus, upside,
                 fov : fov * std::f64::consts::PI / 180.0,
-                we, ue, ve, tm
+                we, ue, ve, tm,
+                aperture : 0.0,
+                focus_distance : Vec3::dot(&(focus - position), &(focus - position)).sqrt(),
+                aperture_shape : Aperture::Circle,
             }
         }
 }
 
+// spheres, polygons, meshes, area_lights と大きさが有限の shapes は BVH で、
+// 無限に広がる planes と shapes は総当たりで交差判定する
+// フィールドは *_mut を通して書き換え、そのあと最初に使うときに BVH と光源の一覧を作り直す
 pub struct Scene {
-    pub spheres : Vec<Sphere>,
-    pub planes : Vec<Plane>,
-    pub polygons : Vec<Polygon>,
+    spheres : Vec<Sphere>,
+    planes : Vec<Plane>,
+    polygons : Vec<Polygon>,
+    meshes : Vec<TriangleMesh>,
+    // 明示的に置く面光源
+    area_lights : Vec<AreaLight>,
+    // 点光源, スポットライト, 平行光 (光線は当たらず、影の光線だけで評価する)
+    analytic_lights : Vec<AnalyticLight>,
+    // クレートの外で定義された形状 (光源として直接サンプリングはされない)
+    shapes : Vec<Box<dyn Hit>>,
+    // 何にも当たらなかった光線の放射輝度 (None なら黒)
+    environment : Option<Environment>,
+    // 書き換えるたびに空にする
+    built : OnceLock<Built>,
+}
+
+// フィールドから作る交差判定と光源のための情報
+struct Built {
+    bvh : Bvh,
+    // BVH に入れた shapes の添字
+    bounded : Vec<usize>,
+    unbounded : Vec<usize>,
+    lights : Vec<Light>,
+    // BVH の添字から lights の添字へ
+    light_of : Vec<Option<usize>>,
 }
 
 impl Default for Scene {
     fn default() -> Scene {
         Scene::new(
             vec![
-                Sphere{point : Vec3::new((27.0, 16.5, 47.0)), radius : 16.5, material : Material::Mirror , reflectance : Vec3::new(0.999), le : Vec3::new(0.0)}, // left ball
-                Sphere{point : Vec3::new((73.0, 16.5, 78.0)), radius : 16.5, material : Material::Fresnel(fresnel::GLASSBK7) , reflectance : Vec3::new(0.999), le : Vec3::new(0.0)}, // right ball
-                Sphere{point : Vec3::new((50.0, 681.6 - 0.27, 81.6)), radius : 600., material : Material::Diffuse, reflectance : Vec3::new(0.0), le : Vec3::new(12.0)}, // ceiling holl
+                Sphere{point : Vec3::new((27.0, 16.5, 47.0)), radius : 16.5, material : Arc::new(Mirror{reflectance : Vec3::new(0.999).into()})}, // left ball
+                Sphere{point : Vec3::new((73.0, 16.5, 78.0)), radius : 16.5, material : Arc::new(Fresnel{ior : fresnel::GLASSBK7, reflectance : Vec3::new(0.999).into()})}, // right ball
+                Sphere{point : Vec3::new((50.0, 681.6 - 0.27, 81.6)), radius : 600., material : Arc::new(Diffuse{reflectance : Vec3::new(0.0).into(), le : Vec3::new(12.0).into()})}, // ceiling holl
             ],
             vec![
-                Plane{normal : Vec3::new((0.0, 0.0, 1.0)), point : Vec3::new((0.0, 0.0, 0.0)), material : Material::Diffuse, reflectance : Vec3::new(0.75), le : Vec3::new(0.0)}, // far side wall
-                Plane{normal : Vec3::new((1.0, 0.0, 0.0)), point : Vec3::new((1.0, 0.0, 0.0)), material : Material::Diffuse, reflectance : Vec3::new((0.75, 0.25, 0.25)), le : Vec3::new(0.0)}, // left wall
-                Plane{normal : Vec3::new((-1.0, 0.0, 0.0)), point : Vec3::new((99.0, 0.0, 0.0)), material : Material::Diffuse, reflectance : Vec3::new((0.25, 0.25, 0.75)), le : Vec3::new(0.0)}, // right wall
-                Plane{normal : Vec3::new((0.0, 1.0, 0.0)), point : Vec3::new((0.0, 0.0, 0.0)), material : Material::Diffuse, reflectance : Vec3::new(0.75), le : Vec3::new(0.0)}, // floor
-                Plane{normal : Vec3::new((0.0, -1.0, 0.0)), point : Vec3::new((0.0, 81.6, 0.0)), material : Material::Diffuse, reflectance : Vec3::new(0.75), le : Vec3::new(0.0)}, // ceilling
+                Plane{normal : Vec3::new((0.0, 0.0, 1.0)), point : Vec3::new((0.0, 0.0, 0.0)), material : Arc::new(Diffuse{reflectance : Vec3::new(0.75).into(), le : Vec3::new(0.0).into()})}, // far side wall
+                Plane{normal : Vec3::new((1.0, 0.0, 0.0)), point : Vec3::new((1.0, 0.0, 0.0)), material : Arc::new(Diffuse{reflectance : Vec3::new((0.75, 0.25, 0.25)).into(), le : Vec3::new(0.0).into()})}, // left wall
+                Plane{normal : Vec3::new((-1.0, 0.0, 0.0)), point : Vec3::new((99.0, 0.0, 0.0)), material : Arc::new(Diffuse{reflectance : Vec3::new((0.25, 0.25, 0.75)).into(), le : Vec3::new(0.0).into()})}, // right wall
+                Plane{normal : Vec3::new((0.0, 1.0, 0.0)), point : Vec3::new((0.0, 0.0, 0.0)), material : Arc::new(Diffuse{reflectance : Vec3::new(0.75).into(), le : Vec3::new(0.0).into()})}, // floor
+                Plane{normal : Vec3::new((0.0, -1.0, 0.0)), point : Vec3::new((0.0, 81.6, 0.0)), material : Arc::new(Diffuse{reflectance : Vec3::new(0.75).into(), le : Vec3::new(0.0).into()})}, // ceilling
                 
             ],
             Vec::new()
@@ -122,7 +219,7 @@ impl Default for Scene {
     }
 }
 
-fn compare_hitrecord(hr1 : Option<HitRecord>, hr2 : Option<HitRecord>) -> Option<HitRecord> {
+fn compare_hitrecord<'a>(hr1 : Option<HitRecord<'a>>, hr2 : Option<HitRecord<'a>>) -> Option<HitRecord<'a>> {
     match hr1 {
         Some(hr1) => Some(match hr2 {
             Some(hr2) => if hr1.t < hr2.t {hr1} else {hr2},
@@ -132,21 +229,400 @@ fn compare_hitrecord(hr1 : Option<HitRecord>, hr2 : Option<HitRecord>) -> Option
     }
 }
 
-fn calc_hit<T : Hit>(v : &Vec<T>, ray : &Ray, tm : (f64, f64)) -> Option<HitRecord> {
+fn calc_hit<'a, T : Hit>(v : &'a [T], ray : &Ray, tm : (f64, f64)) -> Option<HitRecord<'a>> {
     v.par_iter().map(|h| h.hit(ray, tm))
     .reduce(|| None, compare_hitrecord)
 }
 
 impl Scene {
     pub fn new(spheres : Vec<Sphere>, planes : Vec<Plane>, polygons : Vec<Polygon>) -> Scene {
-        Scene{spheres, planes, polygons}
+        let mut scene = Scene{
+            spheres, planes, polygons,
+            meshes : Vec::new(),
+            area_lights : Vec::new(),
+            analytic_lights : Vec::new(),
+            shapes : Vec::new(),
+            environment : None,
+            built : OnceLock::new(),
+        };
+        scene.rebuild();
+        scene
+    }
+
+    pub fn spheres(&self) -> &[Sphere] {
+        &self.spheres
+    }
+
+    pub fn planes(&self) -> &[Plane] {
+        &self.planes
+    }
+
+    pub fn polygons(&self) -> &[Polygon] {
+        &self.polygons
+    }
+
+    pub fn meshes(&self) -> &[TriangleMesh] {
+        &self.meshes
+    }
+
+    pub fn area_lights(&self) -> &[AreaLight] {
+        &self.area_lights
+    }
+
+    pub fn analytic_lights(&self) -> &[AnalyticLight] {
+        &self.analytic_lights
+    }
+
+    pub fn shapes(&self) -> &[Box<dyn Hit>] {
+        &self.shapes
+    }
+
+    pub fn environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
+
+    // 以下の *_mut は作ってあった BVH と光源の一覧を捨てる
+    pub fn spheres_mut(&mut self) -> &mut Vec<Sphere> {
+        self.invalidate();
+        &mut self.spheres
+    }
+
+    pub fn planes_mut(&mut self) -> &mut Vec<Plane> {
+        self.invalidate();
+        &mut self.planes
+    }
+
+    pub fn polygons_mut(&mut self) -> &mut Vec<Polygon> {
+        self.invalidate();
+        &mut self.polygons
+    }
+
+    pub fn meshes_mut(&mut self) -> &mut Vec<TriangleMesh> {
+        self.invalidate();
+        &mut self.meshes
+    }
+
+    pub fn area_lights_mut(&mut self) -> &mut Vec<AreaLight> {
+        self.invalidate();
+        &mut self.area_lights
+    }
+
+    pub fn analytic_lights_mut(&mut self) -> &mut Vec<AnalyticLight> {
+        self.invalidate();
+        &mut self.analytic_lights
+    }
+
+    pub fn shapes_mut(&mut self) -> &mut Vec<Box<dyn Hit>> {
+        self.invalidate();
+        &mut self.shapes
+    }
+
+    pub fn environment_mut(&mut self) -> &mut Option<Environment> {
+        self.invalidate();
+        &mut self.environment
+    }
+
+    fn invalidate(&mut self) {
+        self.built.take();
+    }
+
+    fn built(&self) -> &Built {
+        self.built.get_or_init(|| self.build())
+    }
+
+    // 大きさが有限のプリミティブの箱
+    // 添字は BVH と同じく spheres, polygons, meshes, area_lights, shapes の順に並ぶ
+    fn finite_bounds(&self) -> impl Iterator<Item = (Aabb, Option<usize>)> + '_ {
+        self.spheres.iter().filter_map(Hit::bounds).map(|b| (b, None))
+            .chain(self.polygons.iter().filter_map(Hit::bounds).map(|b| (b, None)))
+            .chain(self.meshes.iter().filter_map(Hit::bounds).map(|b| (b, None)))
+            .chain(self.area_lights.iter().filter_map(Hit::bounds).map(|b| (b, None)))
+            .chain(self.shapes.iter().enumerate().filter_map(|(i, s)| s.bounds().map(|b| (b, Some(i)))))
+    }
+
+    // BVH と光源の一覧をすぐに作り直す
+    // 省略しても最初の交差判定のときに作るので、並列に描画を始める前に呼んでおくためのもの
+    pub fn rebuild(&mut self) {
+        self.invalidate();
+        self.built();
+    }
+
+    // BVH の添字は spheres, polygons, meshes, area_lights, shapes の順に並べる
+    // 放射するメッシュは三角形ごとに光源になり、light_of にはその先頭を入れる
+    fn build(&self) -> Built {
+        let (bounds, shapes) : (Vec<_>, Vec<_>) = self.finite_bounds().unzip();
+        let bvh = Bvh::new(&bounds);
+        let bounded = shapes.into_iter().flatten().collect();
+        let unbounded = self.shapes.iter().enumerate()
+            .filter(|(_, s)| s.bounds().is_none())
+            .map(|(i, _)| i)
+            .collect();
+
+        let (n, m, k) = (self.spheres.len(), self.polygons.len(), self.meshes.len());
+        let emissive : Vec<_> = self.spheres.iter().enumerate()
+            .filter(|(_, s)| s.material.is_emissive())
+            .map(|(i, _)| (i, vec![Light::Sphere(i)]))
+            .chain(self.polygons.iter().enumerate()
+                .filter(|(_, p)| p.material.is_emissive())
+                .map(|(i, _)| (n + i, vec![Light::Polygon(i)])))
+            .chain(self.meshes.iter().enumerate()
+                .filter(|(_, mesh)| mesh.material.is_emissive() && mesh.check().is_ok())
+                .map(|(i, mesh)| (n + m + i, (0..mesh.indices().len()).map(|t| Light::Triangle(i, t)).collect())))
+            .chain(self.area_lights.iter().enumerate()
+                .filter(|(_, l)| l.is_emissive())
+                .map(|(i, _)| (n + m + k + i, vec![Light::Area(i)])))
+            .collect();
+
+        let mut lights = Vec::new();
+        let mut light_of = vec![None; bounds.len()];
+        for (prim, ls) in emissive {
+            light_of[prim] = Some(lights.len());
+            lights.extend(ls);
+        }
+        lights.extend((0..self.analytic_lights.len()).map(Light::Analytic));
+        if self.environment.as_ref().map(|e| !e.is_black()).unwrap_or(false) {
+            lights.push(Light::Environment);
+        }
+        Built{bvh, bounded, unbounded, lights, light_of}
+    }
+
+    // 光源として直接サンプリングするもの
+    pub fn lights(&self) -> &[Light] {
+        &self.built().lights
+    }
+
+    // lights のうち analytic_lights が並ぶ範囲
+    pub(crate) fn analytic_range(&self) -> std::ops::Range<usize> {
+        let lights = self.lights();
+        let end = lights.len() - if lights.last() == Some(&Light::Environment) {1} else {0};
+        end - self.analytic_lights.len() .. end
+    }
+
+    // 環境光と平行光を除いた光源の放射束の和 [W]
+    pub fn total_power(&self) -> Vec3 {
+        self.lights().iter()
+            .filter_map(|l| l.power(self))
+            .fold(Vec3::new(0.0), |a, p| a + p)
+    }
+
+    // planes などの無限に広がるものを除いたプリミティブを囲む箱
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.finite_bounds()
+            .map(|(b, _)| b)
+            .fold(None, |a : Option<Aabb>, b| Some(a.map(|a| a.union(&b)).unwrap_or(b)))
+    }
+
+    // tm.0 < t < tm.1 の範囲で最も近い交差
+    pub fn hit(&self, ray : &Ray, tm : (f64, f64)) -> Option<HitRecord<'_>> {
+        let (n, m, k) = (self.spheres.len(), self.polygons.len(), self.meshes.len());
+        let a = self.area_lights.len();
+        let built = self.built();
+        let finite = built.bvh.hit(ray, tm, |i, ray, tm| {
+            let hr = if i < n {
+                self.spheres[i].hit(ray, tm)
+            } else if i < n + m {
+                self.polygons[i - n].hit(ray, tm)
+            } else if i < n + m + k {
+                self.meshes[i - n - m].hit(ray, tm)
+            } else if i < n + m + k + a {
+                self.area_lights[i - n - m - k].hit(ray, tm)
+            } else {
+                self.shapes[built.bounded[i - n - m - k - a]].hit(ray, tm)
+            };
+            hr.map(|hr| HitRecord{light : built.light_of[i].map(|l| l + hr.primitive), ..hr})
+        });
+
+        let infinite = built.unbounded.iter()
+            .map(|&i| self.shapes[i].hit(ray, tm))
+            .fold(calc_hit(&self.planes, ray, tm), compare_hitrecord);
+
+        compare_hitrecord(finite, infinite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::{Bsdf, Diffuse};
+
+    // テスト用の xorshift
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        fn range(&mut self, a : f64, b : f64) -> f64 {
+            a + (b - a) * self.next()
+        }
+
+        fn vec3(&mut self, a : f64, b : f64) -> Vec3 {
+            Vec3::new((self.range(a, b), self.range(a, b), self.range(a, b)))
+        }
+
+        fn direction(&mut self) -> Vec3 {
+            loop {
+                let v = self.vec3(-1.0, 1.0);
+                let l = v.dot(&v);
+                if l > 1e-6 && l <= 1.0 {
+                    return v.normalize();
+                }
+            }
+        }
+    }
+
+    // プリミティブごとに別の材質にして、どれに当たったかを材質で見分ける
+    fn material(le : f64) -> Arc<dyn Bsdf> {
+        Arc::new(Diffuse{reflectance : Vec3::new(0.5).into(), le : Vec3::new(le).into()})
     }
 
-    pub(crate) fn hit(&self, ray : &Ray, tm : (f64, f64)) -> Option<(HitRecord)> {
-        vec![
-            calc_hit(&self.spheres, ray, tm),
-            calc_hit(&self.planes, ray, tm),
-            calc_hit(&self.polygons, ray, tm),
-        ].into_par_iter().reduce(|| None, compare_hitrecord)
+    fn address(m : &dyn Bsdf) -> *const u8 {
+        m as *const dyn Bsdf as *const u8
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn bvh_matches_linear_scan() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let spheres : Vec<Sphere> = (0..80).map(|_| Sphere {
+            point : rng.vec3(-10.0, 10.0),
+            radius : rng.range(0.1, 2.0),
+            material : material(0.0),
+        }).collect();
+        let polygons : Vec<Polygon> = (0..80).map(|_| {
+            let a = rng.vec3(-10.0, 10.0);
+            Polygon {
+                points : [a, a + rng.vec3(-3.0, 3.0), a + rng.vec3(-3.0, 3.0)],
+                material : material(0.0),
+            }
+        }).collect();
+        let planes : Vec<Plane> = (0..3).map(|_| Plane {
+            normal : rng.direction(),
+            point : rng.vec3(-15.0, 15.0),
+            material : material(0.0),
+        }).collect();
+        let scene = Scene::new(spheres, planes, polygons);
+
+        let tm = (1e-4, f64::INFINITY);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray{origin : rng.vec3(-14.0, 14.0), direction : rng.direction()};
+            let linear = scene.spheres().iter().map(|s| s.hit(&ray, tm))
+                .chain(scene.polygons().iter().map(|p| p.hit(&ray, tm)))
+                .chain(scene.planes().iter().map(|p| p.hit(&ray, tm)))
+                .flatten()
+                .min_by(|a, b| a.t.partial_cmp(&b.t).unwrap());
+
+            match (scene.hit(&ray, tm), linear) {
+                (None, None) => (),
+                (Some(a), Some(b)) => {
+                    hits += 1;
+                    assert!(a.t == b.t, "t {} != {}", a.t, b.t);
+                    assert!(address(a.material) == address(b.material), "different primitive at t {}", a.t);
+                    assert!(a.normal == b.normal);
+                },
+                (a, b) => panic!("bvh hit {} but linear scan hit {}", a.is_some(), b.is_some()),
+            }
+        }
+        assert!(hits > 1000);
+    }
+
+    #[test]
+    fn editing_the_scene_invalidates_the_bvh_and_lights() {
+        let mut scene = Scene::new(vec![], vec![], vec![]);
+        let ray = Ray{origin : Vec3::new(0.0), direction : Vec3::new((0.0, 0.0, -1.0))};
+        assert!(scene.hit(&ray, (1e-4, f64::INFINITY)).is_none());
+        assert!(scene.lights().is_empty());
+
+        // rebuild を呼ばなくても、次の交差判定で追加した球が見える
+        scene.spheres_mut().push(Sphere {
+            point : Vec3::new((0.0, 0.0, -5.0)), radius : 1.0,
+            material : material(1.0),
+        });
+        let hr = scene.hit(&ray, (1e-4, f64::INFINITY)).unwrap();
+        assert!((hr.t - 4.0).abs() < 1e-12);
+        assert!(hr.light == Some(0) && scene.lights().len() == 1);
+
+        scene.spheres_mut().clear();
+        assert!(scene.hit(&ray, (1e-4, f64::INFINITY)).is_none());
+        assert!(scene.lights().is_empty());
+    }
+
+    const SIZE : (f64, f64) = (320.0, 240.0);
+
+    fn camera() -> Camera {
+        Camera::new(Vec3::new((1.0, 2.0, 3.0)), Vec3::new((-4.0, 0.5, -6.0)), Vec3::new((0.0, 1.0, 0.0)), 40.0, (1e-4, 1e10))
+    }
+
+    fn close(a : &Vec3, b : &Vec3, eps : f64) -> bool {
+        let d = *a - *b;
+        d.dot(&d).sqrt() <= eps
+    }
+
+    // 光線がカメラから前方に distance だけ離れた、視線に垂直な面と交わる点
+    fn on_plane(c : &Camera, ray : &Ray, distance : f64) -> Vec3 {
+        let t = (distance + (ray.origin - c.position).dot(&c.we)) / -ray.direction.dot(&c.we);
+        ray.origin + ray.direction * t
+    }
+
+    #[test]
+    fn zero_aperture_is_a_pinhole() {
+        let mut rng = Rng(0x3c6e_f372_fe94_f82b);
+        let pinhole = camera();
+        assert!(pinhole.aperture == 0.0);
+        let mut lens = camera();
+        lens.aperture = 0.5;
+
+        // 画面の中心は注視点を向く
+        let center = pinhole.create_ray(SIZE, (SIZE.0 / 2.0, SIZE.1 / 2.0), (0.5, 0.5));
+        assert!(close(&center.direction, &(pinhole.focus - pinhole.position).normalize(), 1e-12));
+
+        for _ in 0..1000 {
+            let pixel = (rng.next() * SIZE.0, rng.next() * SIZE.1);
+            let ray = pinhole.create_ray(SIZE, pixel, (0.5, 0.5));
+            assert!(ray.origin == pinhole.position);
+            assert!((ray.direction.dot(&ray.direction) - 1.0).abs() < 1e-12);
+            // レンズ上の位置は使わない
+            let other = pinhole.create_ray(SIZE, pixel, (rng.next(), rng.next()));
+            assert!(other.origin == ray.origin && other.direction == ray.direction);
+            // 円い絞りの中心を通る光線はピンホールと同じ
+            let through_center = lens.create_ray(SIZE, pixel, (0.5, 0.5));
+            assert!(close(&through_center.origin, &ray.origin, 1e-12) && close(&through_center.direction, &ray.direction, 1e-12));
+        }
+    }
+
+    #[test]
+    fn focus_plane_is_sharp() {
+        let mut rng = Rng(0xa54f_f53a_5f1d_36f1);
+        let pinhole = camera();
+        for &shape in &[Aperture::Circle, Aperture::Polygon{blades : 6, rotation : 15.0 * std::f64::consts::PI / 180.0}] {
+            let mut c = camera();
+            c.aperture = 0.8;
+            c.focus_distance = 7.5;
+            c.aperture_shape = shape;
+
+            for _ in 0..100 {
+                let pixel = (rng.next() * SIZE.0, rng.next() * SIZE.1);
+                // ピント面ではどのレンズ上の位置からもピンホールの光線と同じ点に集まる
+                let expected = on_plane(&pinhole, &pinhole.create_ray(SIZE, pixel, (0.5, 0.5)), c.focus_distance);
+                let mut spread = 0.0f64;
+                for _ in 0..50 {
+                    let ray = c.create_ray(SIZE, pixel, (rng.next(), rng.next()));
+                    // レンズの上から出る
+                    let o = ray.origin - c.position;
+                    assert!(o.dot(&c.we).abs() < 1e-12 && o.dot(&o).sqrt() <= c.aperture + 1e-12);
+                    assert!(close(&on_plane(&c, &ray, c.focus_distance), &expected, 1e-9));
+
+                    // ピント面から外れるとぼける
+                    let near = on_plane(&pinhole, &pinhole.create_ray(SIZE, pixel, (0.5, 0.5)), 3.0);
+                    let d = on_plane(&c, &ray, 3.0) - near;
+                    spread = spread.max(d.dot(&d).sqrt());
+                }
+                assert!(spread > 0.05, "{}", spread);
+            }
+        }
+    }
+}
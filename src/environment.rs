@@ -0,0 +1,233 @@
+use std::path::{Path, PathBuf};
+
+use geo::*;
+use io::{self, ReadImageError};
+
+use std::f64::consts::PI;
+
+fn luminance(v : &Vec3) -> f64 {
+    0.2126 * v.x + 0.7152 * v.y + 0.0722 * v.z
+}
+
+// 区分的に一定な 1 次元の分布
+struct Distribution {
+    // cdf[0] = 0, cdf[n] = 1
+    cdf : Vec<f64>,
+    total : f64,
+}
+
+impl Distribution {
+    fn new(weights : &[f64]) -> Distribution {
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        cdf.push(0.0);
+        for w in weights {
+            let last = cdf[cdf.len() - 1];
+            cdf.push(last + w);
+        }
+        let total = cdf[weights.len()];
+        if total > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= total;
+            }
+        }
+        Distribution{cdf, total}
+    }
+
+    // (区間の添字, 区間の中の位置 [0, 1))
+    fn sample(&self, u : f64) -> (usize, f64) {
+        let n = self.cdf.len() - 1;
+        let i = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(n - 1);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let t = if width > 0.0 {(u - self.cdf[i]) / width} else {0.5};
+        (i, t.clamp(0.0, 1.0 - f64::EPSILON))
+    }
+
+    // 区間 i が選ばれる確率
+    fn probability(&self, i : usize) -> f64 {
+        self.cdf[i + 1] - self.cdf[i]
+    }
+}
+
+// 正距円筒図法の環境マップ
+// 画像の上端が +y, 中央の列が -z の方向に対応する
+pub struct EnvironmentMap {
+    pub size : (usize, usize),
+    // 上の行から順に並ぶ
+    pixels : Vec<Vec3>,
+    // y 軸まわりの回転 (ラジアン)
+    pub rotation : f64,
+    pub intensity : f64,
+    // 読み込んだファイル (シーンファイルに書き出すときに使う)
+    pub path : Option<PathBuf>,
+    // 輝度 * sinθ に比例して行を、行の中で列を選ぶ
+    rows : Distribution,
+    columns : Vec<Distribution>,
+}
+
+impl EnvironmentMap {
+    pub fn new((w, h) : (usize, usize), pixels : Vec<Vec3>, rotation : f64, intensity : f64) -> EnvironmentMap {
+        assert!(w > 0 && h > 0 && pixels.len() == w * h, "environment map size does not match the pixels");
+
+        let columns : Vec<_> = pixels.chunks(w).enumerate().map(|(j, row)| {
+            let sin = (PI * (j as f64 + 0.5) / h as f64).sin();
+            let weights : Vec<_> = row.iter().map(|v| luminance(v).max(0.0) * sin).collect();
+            Distribution::new(&weights)
+        }).collect();
+        let rows = Distribution::new(&columns.iter().map(|c| c.total).collect::<Vec<_>>());
+
+        EnvironmentMap{size : (w, h), pixels, rotation, intensity, path : None, rows, columns}
+    }
+
+    pub fn load<P : AsRef<Path>>(path : P, rotation : f64, intensity : f64) -> Result<EnvironmentMap, ReadImageError> {
+        let (size, pixels) = io::read_hdr_image(&path)?;
+        Ok(EnvironmentMap{path : Some(path.as_ref().to_path_buf()), .. EnvironmentMap::new(size, pixels, rotation, intensity)})
+    }
+
+    // 方向から画像上の (u, v) ∈ [0, 1)^2
+    fn uv(&self, d : &Vec3) -> (f64, f64) {
+        let phi = d.x.atan2(-d.z) - self.rotation;
+        let u = (phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn direction(&self, (u, v) : (f64, f64)) -> Vec3 {
+        let (theta, phi) = (PI * v, 2.0 * PI * u + self.rotation);
+        Vec3::new((theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos()))
+    }
+
+    fn pixel(&self, (u, v) : (f64, f64)) -> (usize, usize) {
+        let (w, h) = self.size;
+        (((u * w as f64) as usize).min(w - 1), ((v * h as f64) as usize).min(h - 1))
+    }
+
+    fn radiance(&self, d : &Vec3) -> Vec3 {
+        let (i, j) = self.pixel(self.uv(d));
+        self.pixels[j * self.size.0 + i] * self.intensity
+    }
+
+    fn sample(&self, (u1, u2) : (f64, f64)) -> Option<(Vec3, f64)> {
+        let (w, h) = self.size;
+        let (j, tv) = self.rows.sample(u1);
+        let (i, tu) = self.columns[j].sample(u2);
+        let uv = ((i as f64 + tu) / w as f64, (j as f64 + tv) / h as f64);
+        let d = self.direction(uv);
+        let pdf = self.pdf(&d);
+        if pdf > 0.0 {
+            Some((d, pdf))
+        } else {
+            None
+        }
+    }
+
+    fn pdf(&self, d : &Vec3) -> f64 {
+        let (w, h) = self.size;
+        let uv = self.uv(d);
+        let sin = (PI * uv.1).sin();
+        if sin <= 0.0 || self.rows.total <= 0.0 {
+            return 0.0;
+        }
+        let (i, j) = self.pixel(uv);
+        let p = self.rows.probability(j) * self.columns[j].probability(i) * (w * h) as f64;
+        p / (2.0 * PI * PI * sin)
+    }
+}
+
+// 何にも当たらなかった光線が運んでくる放射輝度
+pub enum Environment {
+    Constant(Vec3),
+    Map(EnvironmentMap),
+}
+
+impl Environment {
+    // 方向 d から来る放射輝度
+    pub fn radiance(&self, d : &Vec3) -> Vec3 {
+        match self {
+            Environment::Constant(c) => *c,
+            Environment::Map(m) => m.radiance(d),
+        }
+    }
+
+    pub(crate) fn is_black(&self) -> bool {
+        match self {
+            Environment::Constant(c) => c.x.max(c.y.max(c.z)) <= 0.0,
+            Environment::Map(m) => m.rows.total <= 0.0 || m.intensity <= 0.0,
+        }
+    }
+
+    // 方向をひとつ選び (方向, 立体角に関する確率密度) を返す
+    pub(crate) fn sample(&self, (u1, u2) : (f64, f64)) -> Option<(Vec3, f64)> {
+        match self {
+            Environment::Constant(_) => {
+                // 球面上で一様
+                let z = 1.0 - 2.0 * u1;
+                let r = 0.0f64.max(1.0 - z.powi(2)).sqrt();
+                let phi = 2.0 * PI * u2;
+                Some((Vec3::new((r * phi.cos(), r * phi.sin(), z)), 1.0 / (4.0 * PI)))
+            },
+            Environment::Map(m) => m.sample((u1, u2)),
+        }
+    }
+
+    pub(crate) fn pdf(&self, d : &Vec3) -> f64 {
+        match self {
+            Environment::Constant(_) => 1.0 / (4.0 * PI),
+            Environment::Map(m) => m.pdf(d),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // テスト用の xorshift
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+
+    // 明るい画素が散らばった、列ごとに明るさの違う地図
+    fn map() -> EnvironmentMap {
+        let (w, h) = (24, 12);
+        let mut rng = Rng(0x6a09_e667_f3bc_c909);
+        let pixels = (0..w * h).map(|i| {
+            let s = if rng.next() < 0.05 {50.0} else {rng.next()};
+            Vec3::new((s, s * 0.5, (i % w) as f64 / w as f64))
+        }).collect();
+        EnvironmentMap::new((w, h), pixels, 0.7, 2.0)
+    }
+
+    #[test]
+    fn sample_pdf_matches_pdf() {
+        let m = map();
+        let mut rng = Rng(0xbb67_ae85_84ca_a73b);
+        for _ in 0..10000 {
+            let (d, pdf) = m.sample((rng.next(), rng.next())).unwrap();
+            assert!((d.dot(&d) - 1.0).abs() < 1e-12);
+            assert!((m.pdf(&d) - pdf).abs() <= 1e-9 * pdf, "{} != {}", m.pdf(&d), pdf);
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        // pdf * sinθ は画素の中で一定なので、画素を分割した中点で積分すれば誤差は丸めだけになる
+        let m = map();
+        let (w, h) = (m.size.0 * 4, m.size.1 * 4);
+        let mut sum = 0.0;
+        for j in 0..h {
+            for i in 0..w {
+                let (theta, phi) = (PI * (j as f64 + 0.5) / h as f64, 2.0 * PI * (i as f64 + 0.5) / w as f64);
+                let d = Vec3::new((theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos()));
+                sum += m.pdf(&d) * theta.sin() * (PI / h as f64) * (2.0 * PI / w as f64);
+            }
+        }
+        assert!((sum - 1.0).abs() < 1e-9, "{}", sum);
+    }
+}
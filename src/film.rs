@@ -0,0 +1,215 @@
+use std;
+use std::fmt;
+use std::ops::Range;
+
+use geo::*;
+
+// 画素の再構成フィルタ
+// 値は画素の中心からの距離 (画素単位) で決まり、縦横に分離できる
+// 数は半径で、フィルタはその外では 0
+#[derive(Copy, Clone, PartialEq)]
+pub enum Filter {
+    Box(f64),
+    Tent(f64),
+    // 標準偏差は半径の 1/3
+    Gaussian(f64),
+    // B = C = 1/3
+    Mitchell(f64),
+    // 半径と同じ数の山を持つ窓付き sinc
+    Lanczos(f64),
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::Box(0.5)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = match self {
+            Filter::Box(_) => "Box",
+            Filter::Tent(_) => "Tent",
+            Filter::Gaussian(_) => "Gaussian",
+            Filter::Mitchell(_) => "Mitchell",
+            Filter::Lanczos(_) => "Lanczos",
+        };
+
+        write!(f, "{}", n)
+    }
+}
+
+// "Box", "Gaussian:1.5" など
+// 半径を省略したときは Box 0.5, Tent 1, Gaussian 1.5, Mitchell 2, Lanczos 3
+impl std::str::FromStr for Filter {
+    type Err = String;
+    fn from_str(s : &str) -> Result<Filter, String> {
+        let mut it = s.splitn(2, ':');
+        let name = it.next().unwrap_or("");
+        let radius = match it.next() {
+            None => None,
+            Some(r) => match r.parse::<f64>() {
+                Ok(r) if r > 0.0 => Some(r),
+                _ => return Err(format!("invalid filter radius '{}'", r)),
+            },
+        };
+
+        match name {
+            "Box" => Ok(Filter::Box(radius.unwrap_or(0.5))),
+            "Tent" => Ok(Filter::Tent(radius.unwrap_or(1.0))),
+            "Gaussian" => Ok(Filter::Gaussian(radius.unwrap_or(1.5))),
+            "Mitchell" => Ok(Filter::Mitchell(radius.unwrap_or(2.0))),
+            "Lanczos" => Ok(Filter::Lanczos(radius.unwrap_or(3.0))),
+            _ => Err(format!("unknown filter '{}'", s)),
+        }
+    }
+}
+
+fn sinc(x : f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        let px = std::f64::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box(r) | Filter::Tent(r) | Filter::Gaussian(r) | Filter::Mitchell(r) | Filter::Lanczos(r) => r,
+        }
+    }
+
+    // 1 次元のフィルタ
+    fn evaluate_1d(&self, x : f64) -> f64 {
+        let r = self.radius();
+        match *self {
+            // 隣の画素との境目のサンプルはどちらか一方にだけ入れる
+            Filter::Box(_) => if -r < x && x <= r {1.0} else {0.0},
+            Filter::Tent(_) => (1.0 - x.abs() / r).max(0.0),
+            Filter::Gaussian(_) => {
+                let g = |x : f64| (-x * x / (2.0 * (r / 3.0).powi(2))).exp();
+                (g(x) - g(r)).max(0.0)
+            },
+            Filter::Mitchell(_) => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let x = (2.0 * x / r).abs();
+                if x >= 2.0 {
+                    0.0
+                } else if x >= 1.0 {
+                    ((-b - 6.0 * c) * x.powi(3) + (6.0 * b + 30.0 * c) * x.powi(2)
+                        + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3) + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+                        + (6.0 - 2.0 * b)) / 6.0
+                }
+            },
+            Filter::Lanczos(_) => if x.abs() < r {sinc(x) * sinc(x / r)} else {0.0},
+        }
+    }
+
+    // 画素の中心から (dx, dy) だけ離れたサンプルの重み
+    pub fn evaluate(&self, (dx, dy) : (f64, f64)) -> f64 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+}
+
+// サンプルをフィルタの重みで周りの画素に配って足し込む画像
+// 座標は左上が (0, 0) で、画素 (i, j) の中心は (i + 0.5, j + 0.5)
+pub struct Film {
+    pub size : (usize, usize),
+    pub filter : Filter,
+    sums : Vec<Vec3>,
+    weights : Vec<f64>,
+}
+
+// Film の一部の行だけを持つ足し込み先
+// 別々のスレッドで足し込んで、あとから決まった順に Film::merge する
+pub struct FilmTile {
+    size : (usize, usize),
+    filter : Filter,
+    // 持っている行
+    rows : Range<usize>,
+    sums : Vec<Vec3>,
+    weights : Vec<f64>,
+}
+
+// (x, y) のサンプルを、行が rows に入る画素に配る
+fn splat(
+    (size, filter, rows) : ((usize, usize), Filter, Range<usize>),
+    sums : &mut [Vec3],
+    weights : &mut [f64],
+    (x, y) : (f64, f64),
+    v : Vec3)
+{
+    let r = filter.radius();
+    let range = |c : f64, n : usize| {
+        let lo = (c - 0.5 - r).ceil().max(0.0) as usize;
+        let hi = ((c - 0.5 + r).floor() + 1.0).clamp(0.0, n as f64) as usize;
+        lo..hi
+    };
+    let (w, _) = size;
+    let ys = range(y, size.1);
+    for py in ys.start.max(rows.start)..ys.end.min(rows.end) {
+        for px in range(x, w) {
+            let f = filter.evaluate((px as f64 + 0.5 - x, py as f64 + 0.5 - y));
+            if f != 0.0 {
+                let i = (py - rows.start) * w + px;
+                sums[i] = sums[i] + v * f;
+                weights[i] += f;
+            }
+        }
+    }
+}
+
+impl Film {
+    pub fn new(size : (usize, usize), filter : Filter) -> Film {
+        Film {
+            size, filter,
+            sums : vec![Vec3::new(0.0); size.0 * size.1],
+            weights : vec![0.0; size.0 * size.1],
+        }
+    }
+
+    pub fn add_sample(&mut self, p : (f64, f64), v : Vec3) {
+        splat((self.size, self.filter, 0..self.size.1), &mut self.sums, &mut self.weights, p, v);
+    }
+
+    // 行が rows の画素のサンプルを足し込むためのタイル
+    // フィルタの半径だけ上下にはみ出した行も持つ
+    pub fn tile(&self, rows : Range<usize>) -> FilmTile {
+        let r = self.filter.radius().ceil() as usize;
+        let rows = rows.start.saturating_sub(r)..(rows.end + r).min(self.size.1);
+        let n = (rows.end - rows.start) * self.size.0;
+        FilmTile {
+            size : self.size,
+            filter : self.filter,
+            rows,
+            sums : vec![Vec3::new(0.0); n],
+            weights : vec![0.0; n],
+        }
+    }
+
+    pub fn merge(&mut self, tile : FilmTile) {
+        let offset = tile.rows.start * self.size.0;
+        for (i, (s, w)) in tile.sums.into_iter().zip(tile.weights).enumerate() {
+            self.sums[offset + i] = self.sums[offset + i] + s;
+            self.weights[offset + i] += w;
+        }
+    }
+
+    // 重みの和で割った画素の値 (上の行から順)
+    // 重みが 0 以下の画素は 0
+    pub fn pixels(&self) -> Vec<Vec3> {
+        self.sums.iter().zip(&self.weights)
+            .map(|(s, &w)| if w > 0.0 {*s / w} else {Vec3::new(0.0)})
+            .collect()
+    }
+}
+
+impl FilmTile {
+    pub fn add_sample(&mut self, p : (f64, f64), v : Vec3) {
+        splat((self.size, self.filter, self.rows.clone()), &mut self.sums, &mut self.weights, p, v);
+    }
+}
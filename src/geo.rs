@@ -9,7 +9,7 @@ pub struct Vec3 {
 }
 
 pub trait New<T> {
-    fn new(T) -> Self;
+    fn new(v : T) -> Self;
 }
 
 impl New<f64> for Vec3 {
@@ -139,4 +139,269 @@ impl TangentSpace {
             Vec3::new((b, s + n.y.powi(2) * a, -n.y))
         )
     }
-}
\ No newline at end of file
+}
+
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub min : Vec3,
+    pub max : Vec3,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min : Vec3::new(f64::INFINITY),
+            max : Vec3::new(f64::NEG_INFINITY),
+        }
+    }
+
+    pub fn union(&self, other : &Aabb) -> Aabb {
+        Aabb {
+            min : Vec3::new((self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z))),
+            max : Vec3::new((self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z))),
+        }
+    }
+
+    pub fn grow(&self, p : &Vec3) -> Aabb {
+        self.union(&Aabb{min : *p, max : *p})
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            0.0
+        } else {
+            2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+        }
+    }
+
+    // slab法で光線との交差区間を求める
+    pub fn intersect(&self, origin : &Vec3, inv_direction : &Vec3, (tmin, tmax) : (f64, f64)) -> Option<(f64, f64)> {
+        let mut t0 = tmin;
+        let mut t1 = tmax;
+        for &(o, inv, lo, hi) in &[
+            (origin.x, inv_direction.x, self.min.x, self.max.x),
+            (origin.y, inv_direction.y, self.min.y, self.max.y),
+            (origin.z, inv_direction.z, self.min.z, self.max.z),
+        ] {
+            let (near, far) = {
+                let a = (lo - o) * inv;
+                let b = (hi - o) * inv;
+                if a < b {(a, b)} else {(b, a)}
+            };
+            // NaN (0 * inf) は比較で落ちるのでそのまま無視される
+            if near > t0 {t0 = near;}
+            if far < t1 {t1 = far;}
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
+
+// 行優先の 4x4 行列 (列ベクトルに左から掛ける)
+#[derive(Copy, Clone, PartialEq)]
+pub struct Matrix4(pub [[f64; 4]; 4]);
+
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Matrix4(m)
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = self.0[j][i];
+            }
+        }
+        Matrix4(m)
+    }
+
+    // 部分ピボット選択つきの Gauss-Jordan 法
+    // 正則でなければ None
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.0;
+        let mut inv = Matrix4::identity().0;
+
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())?;
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let p = a[col][col];
+            for j in 0..4 {
+                a[col][j] /= p;
+                inv[col][j] /= p;
+            }
+            for i in 0..4 {
+                if i == col {
+                    continue;
+                }
+                let f = a[i][col];
+                for j in 0..4 {
+                    a[i][j] -= f * a[col][j];
+                    inv[i][j] -= f * inv[col][j];
+                }
+            }
+        }
+
+        Some(Matrix4(inv))
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+    fn mul(self, other : Matrix4) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = (0..4).map(|k| self.0[i][k] * other.0[k][j]).sum();
+            }
+        }
+        Matrix4(m)
+    }
+}
+
+// アフィン変換と、その逆変換
+// a * b は b を先に適用する変換
+#[derive(Copy, Clone)]
+pub struct Transform {
+    pub matrix : Matrix4,
+    pub inverse : Matrix4,
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform{matrix : Matrix4::identity(), inverse : Matrix4::identity()}
+    }
+
+    // 正則でなければ None
+    pub fn new(matrix : Matrix4) -> Option<Transform> {
+        matrix.inverse().map(|inverse| Transform{matrix, inverse})
+    }
+
+    pub fn translate(v : &Vec3) -> Transform {
+        let m = |v : Vec3| Matrix4([
+            [1.0, 0.0, 0.0, v.x],
+            [0.0, 1.0, 0.0, v.y],
+            [0.0, 0.0, 1.0, v.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        Transform{matrix : m(*v), inverse : m(-*v)}
+    }
+
+    // 各成分が 0 なら逆変換は無限大を含む
+    pub fn scale(v : &Vec3) -> Transform {
+        let m = |v : Vec3| Matrix4([
+            [v.x, 0.0, 0.0, 0.0],
+            [0.0, v.y, 0.0, 0.0],
+            [0.0, 0.0, v.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        Transform{matrix : m(*v), inverse : m(Vec3::new(1.0) / *v)}
+    }
+
+    // axis まわりに angle (ラジアン) だけ右手系で回す
+    pub fn rotate(axis : &Vec3, angle : f64) -> Transform {
+        let a = axis.normalize();
+        let (s, c) = angle.sin_cos();
+        let matrix = Matrix4([
+            [a.x * a.x * (1.0 - c) + c, a.x * a.y * (1.0 - c) - a.z * s, a.x * a.z * (1.0 - c) + a.y * s, 0.0],
+            [a.y * a.x * (1.0 - c) + a.z * s, a.y * a.y * (1.0 - c) + c, a.y * a.z * (1.0 - c) - a.x * s, 0.0],
+            [a.z * a.x * (1.0 - c) - a.y * s, a.z * a.y * (1.0 - c) + a.x * s, a.z * a.z * (1.0 - c) + c, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        // 回転行列の逆行列は転置
+        Transform{matrix, inverse : matrix.transpose()}
+    }
+
+    // Camera と同じく、-z を position から focus へ、y を upside の側へ向けて
+    // 原点を position に移す変換
+    pub fn look_at(position : &Vec3, focus : &Vec3, upside : &Vec3) -> Transform {
+        let w = (*position - *focus).normalize();
+        let u = upside.cross(&w).normalize();
+        let v = w.cross(&u);
+        let rotation = Matrix4([
+            [u.x, v.x, w.x, 0.0],
+            [u.y, v.y, w.y, 0.0],
+            [u.z, v.z, w.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        Transform::translate(position) * Transform{matrix : rotation, inverse : rotation.transpose()}
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform{matrix : self.inverse, inverse : self.matrix}
+    }
+
+    pub fn point(&self, p : &Vec3) -> Vec3 {
+        let m = &self.matrix.0;
+        let q = Vec3::new((
+            m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        ));
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1.0 { q } else { q / w }
+    }
+
+    pub fn vector(&self, v : &Vec3) -> Vec3 {
+        let m = &self.matrix.0;
+        Vec3::new((
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        ))
+    }
+
+    // 法線は逆行列の転置で変換する (正規化はしない)
+    pub fn normal(&self, n : &Vec3) -> Vec3 {
+        let m = &self.inverse.0;
+        Vec3::new((
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        ))
+    }
+
+    // 箱の 8 頂点を変換したものを囲む箱
+    pub fn bounds(&self, b : &Aabb) -> Aabb {
+        (0..8).fold(Aabb::empty(), |a, i| {
+            let corner = Vec3::new((
+                if i & 1 == 0 {b.min.x} else {b.max.x},
+                if i & 2 == 0 {b.min.y} else {b.max.y},
+                if i & 4 == 0 {b.min.z} else {b.max.z},
+            ));
+            a.grow(&self.point(&corner))
+        })
+    }
+}
+
+impl Mul for Transform {
+    type Output = Transform;
+    fn mul(self, other : Transform) -> Transform {
+        Transform {
+            matrix : self.matrix * other.matrix,
+            inverse : other.inverse * self.inverse,
+        }
+    }
+}
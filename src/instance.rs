@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use geo::*;
+use obj::*;
+use material::Bsdf;
+
+// 共有した形状 (メッシュなど) を変換して置いたもの
+// 光線を物体の座標系に移して交差判定する
+// Scene::shapes に入れて使い、光源として直接サンプリングはされない
+pub struct Instance {
+    pub object : Arc<dyn Hit>,
+    // 物体の座標系からワールド座標系への変換
+    pub transform : Transform,
+    // Some なら物体の材質の代わりに使う
+    pub material : Option<Arc<dyn Bsdf>>,
+}
+
+impl Instance {
+    pub fn new(object : Arc<dyn Hit>, transform : Transform) -> Instance {
+        Instance{object, transform, material : None}
+    }
+}
+
+impl Hit for Instance {
+    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord<'_>> {
+        // 物体の座標系でも方向は単位ベクトルにし、t はその長さで換算する
+        let inverse = self.transform.inverse();
+        let direction = inverse.vector(&ray.direction);
+        let scale = direction.dot(&direction).sqrt();
+        if scale == 0.0 || !scale.is_finite() {
+            return None;
+        }
+        let local = Ray{origin : inverse.point(&ray.origin), direction : direction / scale};
+
+        let hr = self.object.hit(&local, (tmin * scale, tmax * scale))?;
+        let t = hr.t / scale;
+        Some(HitRecord {
+            t,
+            point : ray.direction * t + ray.origin,
+            normal : self.transform.normal(&hr.normal).normalize(),
+            shading_normal : self.transform.normal(&hr.shading_normal).normalize(),
+            dpdu : self.transform.vector(&hr.dpdu),
+            dpdv : self.transform.vector(&hr.dpdv),
+            material : self.material.as_deref().unwrap_or(hr.material),
+            .. hr
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.object.bounds().map(|b| self.transform.bounds(&b))
+    }
+}
@@ -1,12 +1,18 @@
 use std::{fs, io, fmt};
 use std::io::{BufWriter, Write};
+use std::path::Path;
 
 use std;
 
+use deflate;
+use geo::{Vec3, New};
+
 #[derive(Debug)]
 pub enum WriteImageError {
     Io(io::Error),
     VecLen(String),
+    UnsupportedFormat(String),
+    Encode(String),
 }
 
 impl std::convert::From<io::Error> for WriteImageError {
@@ -22,36 +28,817 @@ impl std::fmt::Display for WriteImageError {
         match self {
             Io(e) => write!(f, "{}", e),
             VecLen(s) => write!(f, "{}", s),
+            UnsupportedFormat(s) => write!(f, "unsupported image format: {}", s),
+            Encode(s) => write!(f, "failed to encode image: {}", s),
         }
     }
 }
 
 impl std::error::Error for WriteImageError {
-    fn description(&self) -> &str {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         use io::WriteImageError::*;
         match self {
-            Io(e) => e.description(),
-            VecLen(s) => &s,
+            Io(e) => Some(e),
+            VecLen(_) | UnsupportedFormat(_) | Encode(_) => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExrCompression {
+    None,
+    // 16 行ごとの zlib 圧縮
+    Zip,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+    Pfm,
+    Exr(ExrCompression),
+}
+
+impl ImageFormat {
+    pub fn from_extension(ext : &str) -> Option<ImageFormat> {
+        match ext.to_lowercase().as_str() {
+            "ppm" => Some(ImageFormat::Ppm),
+            "png" => Some(ImageFormat::Png),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" => Some(ImageFormat::Exr(ExrCompression::Zip)),
+            _ => None,
+        }
+    }
+
+    // 浮動小数点の値をそのまま保存する形式か
+    pub fn is_hdr(&self) -> bool {
+        match self {
+            ImageFormat::Ppm | ImageFormat::Png => false,
+            ImageFormat::Pfm | ImageFormat::Exr(_) => true,
         }
     }
+
+    pub fn from_path<P : AsRef<Path>>(path : P) -> Result<ImageFormat, WriteImageError> {
+        let path = path.as_ref();
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(ImageFormat::from_extension)
+            .ok_or_else(|| WriteImageError::UnsupportedFormat(format!("cannot infer the format of '{}'", path.display())))
+    }
+}
+
+// 1 チャンネルあたりの画素値 (u8 または u16)
+pub trait Channel : Copy + Into<u32> {
+    const BITS : u8;
+    fn push_be(self, out : &mut Vec<u8>);
+}
+
+impl Channel for u8 {
+    const BITS : u8 = 8;
+    fn push_be(self, out : &mut Vec<u8>) {
+        out.push(self);
+    }
+}
+
+impl Channel for u16 {
+    const BITS : u8 = 16;
+    fn push_be(self, out : &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+fn write_ppm<C : Channel, W : Write>((w, h) : (usize, usize), colors : Vec<(C, C, C)>, f : &mut W) -> Result<(), WriteImageError> {
+    f.write_all(b"P3\n")?;
+    f.write_all(format!("{} {}\n", w, h).as_bytes())?;
+    f.write_all(format!("{}\n", (1u32 << C::BITS) - 1).as_bytes())?;
+
+    for (r, g, b) in colors.into_iter() {
+        f.write_all(format!("{} {} {}\n", r.into(), g.into(), b.into()).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn crc32(data : &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &x in data {
+        crc ^= u32::from(x);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb8_8320} else {crc >> 1};
+        }
+    }
+    !crc
+}
+
+fn write_chunk<W : Write>(f : &mut W, kind : &[u8; 4], data : &[u8]) -> Result<(), WriteImageError> {
+    f.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+    f.write_all(&body)?;
+    f.write_all(&crc32(&body).to_be_bytes())?;
+    Ok(())
+}
+
+// 行ごとに 5 種類のフィルタを試し、差分の絶対値の和が最小のものを使う
+fn filter_scanlines(raw : &[u8], stride : usize, bpp : usize) -> Vec<u8> {
+    let paeth = |a : u8, b : u8, c : u8| {
+        let p = i16::from(a) + i16::from(b) - i16::from(c);
+        let (pa, pb, pc) = ((p - i16::from(a)).abs(), (p - i16::from(b)).abs(), (p - i16::from(c)).abs());
+        if pa <= pb && pa <= pc {a} else if pb <= pc {b} else {c}
+    };
+
+    let zero = vec![0u8; stride];
+    let mut out = Vec::with_capacity(raw.len() + raw.len() / stride);
+
+    for (y, line) in raw.chunks(stride).enumerate() {
+        let up = if y == 0 {&zero[..]} else {&raw[(y - 1) * stride..y * stride]};
+
+        let filtered : Vec<Vec<u8>> = (0..5u8).map(|t| {
+            (0..stride).map(|i| {
+                let a = if i >= bpp {line[i - bpp]} else {0};
+                let c = if i >= bpp {up[i - bpp]} else {0};
+                let b = up[i];
+                let predictor = match t {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                line[i].wrapping_sub(predictor)
+            }).collect()
+        }).collect();
+
+        let cost = |v : &Vec<u8>| v.iter().map(|&x| u64::from((x as i8).unsigned_abs())).sum::<u64>();
+        let best = (0..5).min_by_key(|&t| cost(&filtered[t])).unwrap();
+
+        out.push(best as u8);
+        out.extend_from_slice(&filtered[best]);
+    }
+
+    out
+}
+
+fn write_png<C : Channel, W : Write>((w, h) : (usize, usize), colors : Vec<(C, C, C)>, f : &mut W) -> Result<(), WriteImageError> {
+    if w == 0 || h == 0 || w > i32::MAX as usize || h > i32::MAX as usize {
+        return Err(WriteImageError::Encode(format!("PNG cannot store a {}x{} image", w, h)));
+    }
+
+    let bpp = 3 * C::BITS as usize / 8;
+    let mut raw = Vec::with_capacity(w * h * bpp);
+    for (r, g, b) in colors.into_iter() {
+        r.push_be(&mut raw);
+        g.push_be(&mut raw);
+        b.push_be(&mut raw);
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(w as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(h as u32).to_be_bytes());
+    // ビット深度, カラータイプ 2 (RGB), 圧縮, フィルタ, インターレースなし
+    ihdr.extend_from_slice(&[C::BITS, 2, 0, 0, 0]);
+
+    f.write_all(b"\x89PNG\r\n\x1a\n")?;
+    write_chunk(f, b"IHDR", &ihdr)?;
+    write_chunk(f, b"IDAT", &deflate::zlib(&filter_scanlines(&raw, w * bpp, bpp)))?;
+    write_chunk(f, b"IEND", &[])?;
+
+    Ok(())
+}
+
+// 拡張子 (.ppm, .png) で形式を選んで、8 または 16 bit で書き出す
+pub fn write_image<C : Channel, P : AsRef<Path>>(size : (usize, usize), colors : Vec<(C, C, C)>, path : P) -> Result<(), WriteImageError> {
+    let format = ImageFormat::from_path(&path)?;
+    write_image_as(format, size, colors, path)
 }
 
-pub fn write_image((w, h) : (usize, usize), colors : Vec<(u8, u8, u8)>, filename : String) -> Result<(), WriteImageError> {
+pub fn write_image_as<C : Channel, P : AsRef<Path>>(format : ImageFormat, (w, h) : (usize, usize), colors : Vec<(C, C, C)>, path : P) -> Result<(), WriteImageError> {
     if colors.len() == w * h {
-        let file = fs::File::create(filename + ".ppm")?;
+        let file = fs::File::create(path)?;
 
         let mut f = BufWriter::new(file);
 
-        f.write(b"P3\n")?;
-        f.write(format!("{} {}\n", w, h).as_bytes())?;
-        f.write(format!("255\n").as_bytes())?;
-
-        for (r, g, b) in colors.into_iter() {
-            f.write(format!("{} {} {}\n", r, g, b).as_bytes())?;
+        match format {
+            ImageFormat::Ppm => write_ppm((w, h), colors, &mut f)?,
+            ImageFormat::Png => write_png((w, h), colors, &mut f)?,
+            ImageFormat::Pfm | ImageFormat::Exr(_) => return Err(WriteImageError::UnsupportedFormat(
+                format!("{:?} stores floating point pixels, use write_hdr_image", format))),
         }
 
+        f.flush()?;
         Ok(())
     } else {
         Err(WriteImageError::VecLen("The length of the colors is not enough".to_string()))
     }
-}
\ No newline at end of file
+}
+
+// Portable Float Map: リトルエンディアンの f32 で、下の行から書く
+fn write_pfm<W : Write>((w, h) : (usize, usize), pixels : &[Vec3], f : &mut W) -> Result<(), WriteImageError> {
+    f.write_all(format!("PF\n{} {}\n-1.0\n", w, h).as_bytes())?;
+
+    let mut line = Vec::with_capacity(w * 12);
+    for row in pixels.chunks(w).rev() {
+        line.clear();
+        for v in row {
+            for c in &[v.x, v.y, v.z] {
+                line.extend_from_slice(&(*c as f32).to_le_bytes());
+            }
+        }
+        f.write_all(&line)?;
+    }
+
+    Ok(())
+}
+
+fn exr_attribute(header : &mut Vec<u8>, name : &str, kind : &str, value : &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+// OpenEXR の ZIP 圧縮の前処理 (偶数番目と奇数番目のバイトを分け、差分をとる)
+fn exr_zip_predict(data : &[u8]) -> Vec<u8> {
+    let half = data.len().div_ceil(2);
+    let mut t = vec![0u8; data.len()];
+    for (i, &x) in data.iter().enumerate() {
+        t[if i % 2 == 0 {i / 2} else {half + i / 2}] = x;
+    }
+
+    let mut p = t.first().cloned().unwrap_or(0);
+    for x in t.iter_mut().skip(1) {
+        let d = (i32::from(*x) - i32::from(p) + 128 + 256) as u8;
+        p = *x;
+        *x = d;
+    }
+    t
+}
+
+// 32 bit float の R, G, B チャンネルを持つスキャンライン形式の OpenEXR
+fn write_exr<W : Write>((w, h) : (usize, usize), pixels : &[Vec3], compression : ExrCompression, f : &mut W) -> Result<(), WriteImageError> {
+    if w == 0 || h == 0 || w > i32::MAX as usize || h > i32::MAX as usize {
+        return Err(WriteImageError::Encode(format!("OpenEXR cannot store a {}x{} image", w, h)));
+    }
+
+    let (lines_per_block, compression_id) = match compression {
+        ExrCompression::None => (1, 0u8),
+        ExrCompression::Zip => (16, 3u8),
+    };
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    // チャンネルは名前順に並べる
+    let mut channels = Vec::new();
+    for name in &["B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        // FLOAT, pLinear + reserved, xSampling, ySampling
+        channels.extend_from_slice(&2i32.to_le_bytes());
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+
+    let window : Vec<u8> = [0i32, 0, w as i32 - 1, h as i32 - 1].iter().flat_map(|v| v.to_le_bytes().to_vec()).collect();
+
+    exr_attribute(&mut header, "channels", "chlist", &channels);
+    exr_attribute(&mut header, "compression", "compression", &[compression_id]);
+    exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    let blocks : Vec<Vec<u8>> = pixels.chunks(w * lines_per_block).enumerate().map(|(i, rows)| {
+        let mut data = Vec::with_capacity(rows.len() * 12);
+        for row in rows.chunks(w) {
+            for channel in 0..3 {
+                for v in row {
+                    let c = match channel {
+                        0 => v.z,
+                        1 => v.y,
+                        _ => v.x,
+                    };
+                    data.extend_from_slice(&(c as f32).to_le_bytes());
+                }
+            }
+        }
+
+        // 圧縮しても小さくならないときは生のまま置いてよい
+        if compression == ExrCompression::Zip {
+            let z = deflate::zlib(&exr_zip_predict(&data));
+            if z.len() < data.len() {
+                data = z;
+            }
+        }
+
+        let mut block = Vec::with_capacity(data.len() + 8);
+        block.extend_from_slice(&((i * lines_per_block) as i32).to_le_bytes());
+        block.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        block.extend(data);
+        block
+    }).collect();
+
+    f.write_all(&header)?;
+
+    let mut offset = (header.len() + 8 * blocks.len()) as u64;
+    for b in &blocks {
+        f.write_all(&offset.to_le_bytes())?;
+        offset += b.len() as u64;
+    }
+    for b in &blocks {
+        f.write_all(b)?;
+    }
+
+    Ok(())
+}
+
+// 拡張子 (.pfm, .exr) で形式を選んで、線形な値のまま書き出す
+pub fn write_hdr_image<P : AsRef<Path>>(size : (usize, usize), pixels : &[Vec3], path : P) -> Result<(), WriteImageError> {
+    let format = ImageFormat::from_path(&path)?;
+    write_hdr_image_as(format, size, pixels, path)
+}
+
+pub fn write_hdr_image_as<P : AsRef<Path>>(format : ImageFormat, (w, h) : (usize, usize), pixels : &[Vec3], path : P) -> Result<(), WriteImageError> {
+    if pixels.len() == w * h {
+        let file = fs::File::create(path)?;
+
+        let mut f = BufWriter::new(file);
+
+        match format {
+            ImageFormat::Pfm => write_pfm((w, h), pixels, &mut f)?,
+            ImageFormat::Exr(c) => write_exr((w, h), pixels, c, &mut f)?,
+            ImageFormat::Ppm | ImageFormat::Png => return Err(WriteImageError::UnsupportedFormat(
+                format!("{:?} stores 8 or 16 bit pixels, tonemap and use write_image", format))),
+        }
+
+        f.flush()?;
+        Ok(())
+    } else {
+        Err(WriteImageError::VecLen("The length of the pixels is not enough".to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadImageError {
+    Io(io::Error),
+    UnsupportedFormat(String),
+    Decode(String),
+}
+
+impl std::convert::From<io::Error> for ReadImageError {
+    fn from(error : io::Error) -> ReadImageError {
+        ReadImageError::Io(error)
+    }
+}
+
+impl std::fmt::Display for ReadImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use io::ReadImageError::*;
+
+        match self {
+            Io(e) => write!(f, "{}", e),
+            UnsupportedFormat(s) => write!(f, "unsupported image format: {}", s),
+            Decode(s) => write!(f, "failed to decode image: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for ReadImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use io::ReadImageError::*;
+        match self {
+            Io(e) => Some(e),
+            UnsupportedFormat(_) | Decode(_) => None,
+        }
+    }
+}
+
+fn decode_error<T, S : Into<String>>(message : S) -> Result<T, ReadImageError> {
+    Err(ReadImageError::Decode(message.into()))
+}
+
+// 空白で区切られたヘッダの語をひとつ読む
+// # から行末まではコメントとして読み飛ばす
+fn header_token<'a>(data : &'a [u8], pos : &mut usize) -> Result<&'a str, ReadImageError> {
+    while *pos < data.len() && (data[*pos].is_ascii_whitespace() || data[*pos] == b'#') {
+        if data[*pos] == b'#' {
+            while *pos < data.len() && data[*pos] != b'\n' {
+                *pos += 1;
+            }
+        } else {
+            *pos += 1;
+        }
+    }
+    let start = *pos;
+    while *pos < data.len() && !data[*pos].is_ascii_whitespace() {
+        *pos += 1;
+    }
+    match std::str::from_utf8(&data[start..*pos]) {
+        Ok(s) if !s.is_empty() => Ok(s),
+        _ => decode_error("truncated header"),
+    }
+}
+
+fn read_pfm(data : &[u8]) -> Result<((usize, usize), Vec<Vec3>), ReadImageError> {
+    let mut pos = 0;
+    let channels = match header_token(data, &mut pos)? {
+        "PF" => 3,
+        "Pf" => 1,
+        m => return decode_error(format!("unknown PFM magic '{}'", m)),
+    };
+    let mut number = |name : &str| -> Result<f64, ReadImageError> {
+        let t = header_token(data, &mut pos)?;
+        t.parse().or_else(|_| decode_error(format!("invalid {} '{}'", name, t)))
+    };
+    let (w, h, scale) = (number("width")?, number("height")?, number("scale")?);
+    if w < 1.0 || h < 1.0 || w.fract() != 0.0 || h.fract() != 0.0 {
+        return decode_error("size must be positive integers");
+    }
+    let (w, h) = (w as usize, h as usize);
+    // ヘッダの後の空白は 1 文字だけ
+    pos += 1;
+
+    let body = &data[pos.min(data.len())..];
+    if body.len() < w * h * channels * 4 {
+        return decode_error("truncated pixel data");
+    }
+    let value = |i : usize| {
+        let b = [body[4 * i], body[4 * i + 1], body[4 * i + 2], body[4 * i + 3]];
+        (if scale < 0.0 {f32::from_le_bytes(b)} else {f32::from_be_bytes(b)}) as f64
+    };
+
+    // 下の行から並んでいる
+    let mut pixels = Vec::with_capacity(w * h);
+    for y in (0..h).rev() {
+        for x in 0..w {
+            let i = (y * w + x) * channels;
+            pixels.push(if channels == 3 {
+                Vec3{x : value(i), y : value(i + 1), z : value(i + 2)}
+            } else {
+                Vec3{x : value(i), y : value(i), z : value(i)}
+            });
+        }
+    }
+
+    Ok(((w, h), pixels))
+}
+
+// Radiance RGBE (.hdr): 走査線ごとの RLE と無圧縮に対応する
+fn read_rgbe(data : &[u8]) -> Result<((usize, usize), Vec<Vec3>), ReadImageError> {
+    if !data.starts_with(b"#?") {
+        return decode_error("missing #? signature");
+    }
+
+    // 空行までがヘッダ
+    let mut pos = 0;
+    loop {
+        let end = match data[pos..].iter().position(|&b| b == b'\n') {
+            Some(e) => pos + e,
+            None => return decode_error("truncated header"),
+        };
+        let line = &data[pos..end];
+        pos = end + 1;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(b"FORMAT=") && line != b"FORMAT=32-bit_rle_rgbe" {
+            return decode_error(format!("unsupported {}", String::from_utf8_lossy(line)));
+        }
+    }
+
+    let (h, w) = match (header_token(data, &mut pos)?, header_token(data, &mut pos)?, header_token(data, &mut pos)?, header_token(data, &mut pos)?) {
+        ("-Y", h, "+X", w) => match (h.parse::<usize>(), w.parse::<usize>()) {
+            (Ok(h), Ok(w)) if h > 0 && w > 0 => (h, w),
+            _ => return decode_error("invalid resolution"),
+        },
+        _ => return decode_error("only -Y H +X W orientation is supported"),
+    };
+    pos += 1;
+
+    let mut rgbe = vec![0u8; w * h * 4];
+    let next = |pos : &mut usize| -> Result<u8, ReadImageError> {
+        match data.get(*pos) {
+            Some(&b) => {
+                *pos += 1;
+                Ok(b)
+            },
+            None => decode_error("truncated pixel data"),
+        }
+    };
+
+    for row in rgbe.chunks_mut(w * 4) {
+        let rle = (8..0x8000).contains(&w) && data.get(pos..pos + 2) == Some(&[2, 2][..])
+            && data.get(pos + 2).map(|&b| b & 0x80 == 0).unwrap_or(false);
+        if !rle {
+            for b in row.iter_mut() {
+                *b = next(&mut pos)?;
+            }
+            continue;
+        }
+
+        let len = match data.get(pos + 3) {
+            Some(&lo) => ((data[pos + 2] as usize) << 8) | lo as usize,
+            None => return decode_error("truncated scanline header"),
+        };
+        if len != w {
+            return decode_error("scanline width mismatch");
+        }
+        pos += 4;
+
+        // 成分ごとに連長圧縮されている
+        for c in 0..4 {
+            let mut x = 0;
+            while x < w {
+                let n = next(&mut pos)? as usize;
+                let (count, run) = if n > 128 {(n - 128, true)} else {(n, false)};
+                if count == 0 || x + count > w {
+                    return decode_error("bad scanline run");
+                }
+                let v = if run {next(&mut pos)?} else {0};
+                for _ in 0..count {
+                    row[x * 4 + c] = if run {v} else {next(&mut pos)?};
+                    x += 1;
+                }
+            }
+        }
+    }
+
+    let pixels = rgbe.chunks(4).map(|p| {
+        if p[3] == 0 {
+            Vec3::new(0.0)
+        } else {
+            let f = 2.0f64.powi(p[3] as i32 - (128 + 8));
+            Vec3{x : p[0] as f64 * f, y : p[1] as f64 * f, z : p[2] as f64 * f}
+        }
+    }).collect();
+
+    Ok(((w, h), pixels))
+}
+
+// 拡張子 (.hdr, .pfm) で形式を選んで、線形な値の画像を読む
+// pixels は上の行から順に並ぶ
+pub fn read_hdr_image<P : AsRef<Path>>(path : P) -> Result<((usize, usize), Vec<Vec3>), ReadImageError> {
+    let path = path.as_ref();
+    let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    let data = fs::read(path)?;
+
+    match ext.as_deref() {
+        Some("hdr") | Some("pic") => read_rgbe(&data),
+        Some("pfm") => read_pfm(&data),
+        _ => Err(ReadImageError::UnsupportedFormat(format!("{} (expected .hdr or .pfm)", path.display()))),
+    }
+}
+
+// P3 (テキスト) と P6 (バイナリ) の PPM
+fn read_ppm(data : &[u8]) -> Result<((usize, usize), Vec<Vec3>), ReadImageError> {
+    let mut pos = 0;
+    let binary = match header_token(data, &mut pos)? {
+        "P3" => false,
+        "P6" => true,
+        m => return decode_error(format!("unknown PPM magic '{}'", m)),
+    };
+    let mut number = |name : &str| -> Result<usize, ReadImageError> {
+        let t = header_token(data, &mut pos)?;
+        match t.parse::<usize>() {
+            Ok(v) if v > 0 => Ok(v),
+            _ => decode_error(format!("invalid {} '{}'", name, t)),
+        }
+    };
+    let (w, h, max) = (number("width")?, number("height")?, number("maximum value")?);
+    if max > 65535 {
+        return decode_error("maximum value must be at most 65535");
+    }
+
+    let n = w * h * 3;
+    let values : Vec<usize> = if binary {
+        // ヘッダの後の空白は 1 文字だけ
+        pos += 1;
+        let bytes = if max > 255 {2} else {1};
+        let body = &data[pos.min(data.len())..];
+        if body.len() < n * bytes {
+            return decode_error("truncated pixel data");
+        }
+        if bytes == 2 {
+            body.chunks(2).take(n).map(|b| (b[0] as usize) << 8 | b[1] as usize).collect()
+        } else {
+            body[..n].iter().map(|&b| b as usize).collect()
+        }
+    } else {
+        (0..n).map(|_| {
+            let t = header_token(data, &mut pos).or_else(|_| decode_error("truncated pixel data"))?;
+            t.parse::<usize>().or_else(|_| decode_error(format!("invalid value '{}'", t)))
+        }).collect::<Result<_, _>>()?
+    };
+
+    let f = |v : usize| v.min(max) as f64 / max as f64;
+    let pixels = values.chunks(3).map(|c| Vec3{x : f(c[0]), y : f(c[1]), z : f(c[2])}).collect();
+    Ok(((w, h), pixels))
+}
+
+// フィルタを戻した走査線を返す
+fn unfilter_scanlines(data : &[u8], stride : usize, bpp : usize, h : usize) -> Result<Vec<u8>, ReadImageError> {
+    if data.len() < (stride + 1) * h {
+        return decode_error("truncated image data");
+    }
+    let paeth = |a : u8, b : u8, c : u8| {
+        let p = i16::from(a) + i16::from(b) - i16::from(c);
+        let (pa, pb, pc) = ((p - i16::from(a)).abs(), (p - i16::from(b)).abs(), (p - i16::from(c)).abs());
+        if pa <= pb && pa <= pc {a} else if pb <= pc {b} else {c}
+    };
+
+    let mut out = vec![0u8; stride * h];
+    for y in 0..h {
+        let filter = data[y * (stride + 1)];
+        let line = &data[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, rest) = out.split_at_mut(y * stride);
+        let up = if y == 0 {None} else {Some(&done[(y - 1) * stride..])};
+        let current = &mut rest[..stride];
+        for i in 0..stride {
+            let a = if i >= bpp {current[i - bpp]} else {0};
+            let b = up.map(|u| u[i]).unwrap_or(0);
+            let c = if i >= bpp {up.map(|u| u[i - bpp]).unwrap_or(0)} else {0};
+            let predictor = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+                4 => paeth(a, b, c),
+                f => return decode_error(format!("unknown filter type {}", f)),
+            };
+            current[i] = line[i].wrapping_add(predictor);
+        }
+    }
+    Ok(out)
+}
+
+// インターレースのない PNG (すべてのカラータイプとビット深度)
+// アルファは捨てる
+fn read_png(data : &[u8]) -> Result<((usize, usize), Vec<Vec3>), ReadImageError> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return decode_error("missing PNG signature");
+    }
+
+    let mut pos = 8;
+    let (mut header, mut palette, mut idat) = (None, Vec::new(), Vec::new());
+    loop {
+        if pos + 8 > data.len() {
+            return decode_error("truncated chunk");
+        }
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let body = match data.get(pos + 8..pos + 8 + len) {
+            Some(b) if pos + 12 + len <= data.len() => b,
+            _ => return decode_error("truncated chunk"),
+        };
+        let crc = &data[pos + 8 + len..pos + 12 + len];
+        if crc32(&data[pos + 4..pos + 8 + len]).to_be_bytes() != crc {
+            return decode_error(format!("CRC mismatch in {} chunk", String::from_utf8_lossy(kind)));
+        }
+        pos += 12 + len;
+
+        match kind {
+            b"IHDR" if body.len() == 13 => header = Some((
+                u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize,
+                u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize,
+                body[8], body[9], body[12],
+            )),
+            b"PLTE" => palette = body.chunks(3).filter(|c| c.len() == 3).map(|c| (c[0], c[1], c[2])).collect(),
+            b"IDAT" => idat.extend_from_slice(body),
+            b"IEND" => break,
+            _ => (),
+        }
+    }
+
+    let (w, h, depth, color, interlace) = match header {
+        Some(h) => h,
+        None => return decode_error("missing IHDR chunk"),
+    };
+    if w == 0 || h == 0 {
+        return decode_error("empty image");
+    }
+    if interlace != 0 {
+        return Err(ReadImageError::UnsupportedFormat("interlaced PNG".to_string()));
+    }
+    let channels = match (color, depth) {
+        (0, 1) | (0, 2) | (0, 4) | (0, 8) | (0, 16) => 1,
+        (2, 8) | (2, 16) => 3,
+        (3, 1) | (3, 2) | (3, 4) | (3, 8) => 1,
+        (4, 8) | (4, 16) => 2,
+        (6, 8) | (6, 16) => 4,
+        _ => return decode_error(format!("invalid color type {} with bit depth {}", color, depth)),
+    };
+    if color == 3 && palette.is_empty() {
+        return decode_error("missing PLTE chunk");
+    }
+
+    let raw = deflate::unzlib(&idat).or_else(decode_error)?;
+    let bits = channels * depth as usize;
+    let stride = (w * bits).div_ceil(8);
+    let raw = unfilter_scanlines(&raw, stride, bits.div_ceil(8), h)?;
+
+    // y 行目の x 番目の画素の c 番目の値
+    let sample = |x : usize, y : usize, c : usize| -> usize {
+        let line = &raw[y * stride..(y + 1) * stride];
+        match depth {
+            16 => {
+                let i = 2 * (x * channels + c);
+                (line[i] as usize) << 8 | line[i + 1] as usize
+            },
+            8 => line[x * channels + c] as usize,
+            d => {
+                let bit = x * d as usize;
+                (line[bit / 8] as usize >> (8 - d as usize - bit % 8)) & ((1 << d) - 1)
+            },
+        }
+    };
+    let max = ((1u32 << depth) - 1) as f64;
+
+    let mut pixels = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            pixels.push(match color {
+                3 => match palette.get(sample(x, y, 0)) {
+                    Some(&(r, g, b)) => Vec3{x : r as f64 / 255.0, y : g as f64 / 255.0, z : b as f64 / 255.0},
+                    None => return decode_error("palette index out of range"),
+                },
+                0 | 4 => Vec3::new(sample(x, y, 0) as f64 / max),
+                _ => Vec3{x : sample(x, y, 0) as f64 / max, y : sample(x, y, 1) as f64 / max, z : sample(x, y, 2) as f64 / max},
+            });
+        }
+    }
+
+    Ok(((w, h), pixels))
+}
+
+// 拡張子 (.png, .ppm) で形式を選んで、8 または 16 bit の画像を読む
+// 値は符号化されたまま [0, 1] にする
+// pixels は上の行から順に並ぶ
+pub fn read_ldr_image<P : AsRef<Path>>(path : P) -> Result<((usize, usize), Vec<Vec3>), ReadImageError> {
+    let path = path.as_ref();
+    let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    let data = fs::read(path)?;
+
+    match ext.as_deref() {
+        Some("png") => read_png(&data),
+        Some("ppm") => read_ppm(&data),
+        _ => Err(ReadImageError::UnsupportedFormat(format!("{} (expected .png or .ppm)", path.display()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // exr_zip_predict を戻す
+    fn exr_unpredict(data : &[u8]) -> Vec<u8> {
+        let mut t = data.to_vec();
+        for i in 1..t.len() {
+            t[i] = (i32::from(t[i - 1]) + i32::from(t[i]) - 128) as u8;
+        }
+        let half = t.len().div_ceil(2);
+        (0..t.len()).map(|i| t[if i % 2 == 0 {i / 2} else {half + i / 2}]).collect()
+    }
+
+    #[test]
+    fn exr_zip_blocks_hold_the_pixels() {
+        // なめらかな画像なのでどのブロックも圧縮される
+        let (w, h) = (11, 37);
+        let pixels : Vec<Vec3> = (0..w * h).map(|i| Vec3::new(((i % w) as f64 * 0.25, (i / w) as f64, 1.0))).collect();
+        let mut data = Vec::new();
+        write_exr((w, h), &pixels, ExrCompression::Zip, &mut data).unwrap();
+
+        // オフセット表は属性の列を終える空の名前の直後にある
+        let le = |pos : usize| i32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let mut pos = 8;
+        while data[pos] != 0 {
+            // 名前と型の 2 つの文字列を飛ばす
+            for _ in 0..2 {
+                pos += data[pos..].iter().position(|&b| b == 0).unwrap() + 1;
+            }
+            pos += 4 + le(pos);
+        }
+        let table = pos + 1;
+
+        for i in 0..h.div_ceil(16) {
+            let offset = le(table + 8 * i);
+            let size = le(offset + 4);
+            let rows = 16.min(h - i * 16);
+            assert!(le(offset) == i * 16 && size < rows * w * 12);
+
+            // 行ごとに B, G, R の順
+            let block = exr_unpredict(&deflate::unzlib(&data[offset + 8..offset + 8 + size]).unwrap());
+            assert!(block.len() == rows * w * 12);
+            for (k, c) in block.chunks(4).enumerate() {
+                let (row, channel, x) = (k / (3 * w), k / w % 3, k % w);
+                let p = pixels[(i * 16 + row) * w + x];
+                assert!(f32::from_le_bytes([c[0], c[1], c[2], c[3]]) == [p.z, p.y, p.x][channel] as f32);
+            }
+        }
+    }
+}
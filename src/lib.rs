@@ -1,7 +1,21 @@
 extern crate rayon;
 
+mod bvh;
+mod deflate;
 pub mod env;
+pub mod environment;
+pub mod film;
 pub mod geo;
 pub mod obj;
 pub mod render;
-pub mod io;
\ No newline at end of file
+pub mod sampler;
+pub mod instance;
+pub mod io;
+pub mod light;
+pub mod material;
+pub mod mesh;
+pub mod noise;
+pub mod scenefile;
+pub mod texture;
+pub mod tonemap;
+pub mod wavefront;
@@ -0,0 +1,536 @@
+use geo::*;
+use obj::*;
+use env::*;
+use material::{Bsdf, BsdfSample};
+
+use std::f64::consts::PI;
+
+// シーンの光源
+// Scene::rebuild が放射する材質を持つ有限のプリミティブ, Scene::area_lights, 環境光から作る
+#[derive(Copy, Clone, PartialEq)]
+pub enum Light {
+    // Scene::spheres での添字
+    Sphere(usize),
+    // Scene::polygons での添字
+    Polygon(usize),
+    // (Scene::meshes での添字, 三角形の添字)
+    Triangle(usize, usize),
+    // Scene::area_lights での添字
+    Area(usize),
+    // Scene::analytic_lights での添字 (Environment の直前に並べる)
+    Analytic(usize),
+    // Scene::environment (常に最後に置く)
+    Environment,
+}
+
+impl Light {
+    // 放射束 [W] (長さの単位を m, 放射輝度を W / (sr m^2) とみなす)
+    // 環境光と平行光はシーンの広さで決まるので None
+    // 材質の放射はテクスチャが変わっていてもよいように面上の 64 点の平均から求める
+    pub fn power(&self, scene : &Scene) -> Option<Vec3> {
+        Some(match *self {
+            Light::Sphere(i) => {
+                let s = &scene.spheres()[i];
+                emitted_power(4.0 * PI * s.radius.powi(2), true, |u| sphere_point(&s.point, s.radius, &*s.material, u))
+            },
+            Light::Polygon(i) => {
+                let poly = &scene.polygons()[i];
+                emitted_power(triangle_area(poly.points), false, |u| triangle_point(poly.points, &*poly.material, 0, |uv| uv, u))
+            },
+            Light::Triangle(m, i) => {
+                let mesh = &scene.meshes()[m];
+                let points = mesh.triangle(i);
+                emitted_power(triangle_area(points), false, |u| triangle_point(points, &*mesh.material, i, |uv| mesh.uv(i, uv), u))
+            },
+            Light::Area(i) => scene.area_lights()[i].power(),
+            Light::Analytic(i) => return scene.analytic_lights()[i].power(),
+            Light::Environment => return None,
+        })
+    }
+}
+
+// 面積 area の面上で一様に選んだ点での、表と裏への放射輝度の和の平均に π area を掛ける
+// closed なら裏 (内側) への放射は外に出ないので数えない
+fn emitted_power<'a, F : Fn((f64, f64)) -> HitRecord<'a>>(area : f64, closed : bool, point_at : F) -> Vec3 {
+    const N : usize = 8;
+    let mut sum = Vec3::new(0.0);
+    for i in 0..N {
+        for j in 0..N {
+            let hr = point_at(((i as f64 + 0.5) / N as f64, (j as f64 + 0.5) / N as f64));
+            sum = sum + hr.material.emitted(&hr, &hr.normal);
+            if !closed {
+                sum = sum + hr.material.emitted(&hr, &-hr.normal);
+            }
+        }
+    }
+    sum * (PI * area / (N * N) as f64)
+}
+
+// 明示的に置く面光源の形
+#[derive(Copy, Clone, PartialEq)]
+pub enum LightShape {
+    // corner から edges の 2 辺で張る平行四辺形 (表は edges.0 × edges.1 の向き)
+    Quad{corner : Vec3, edges : (Vec3, Vec3)},
+    // center を中心とし、表が normal を向いた半径 radius の円板
+    Disk{center : Vec3, normal : Vec3, radius : f64},
+    // 表は外側
+    Sphere{center : Vec3, radius : f64},
+}
+
+impl LightShape {
+    pub fn area(&self) -> f64 {
+        match *self {
+            LightShape::Quad{edges : (e0, e1), ..} => {
+                let n = e0.cross(&e1);
+                n.dot(&n).sqrt()
+            },
+            LightShape::Disk{radius, ..} => PI * radius.powi(2),
+            LightShape::Sphere{radius, ..} => 4.0 * PI * radius.powi(2),
+        }
+    }
+}
+
+// 明示的に置く面光源
+// カメラや反射した光線からも見えるが、光は反射しない
+#[derive(Copy, Clone, PartialEq)]
+pub struct AreaLight {
+    pub shape : LightShape,
+    // 放射輝度
+    pub le : Vec3,
+    // false なら表の側にだけ放射する (球の内側への放射は放射束に数えない)
+    pub two_sided : bool,
+}
+
+// 外に放射する面の数 (球の内側への放射は外に出ない)
+fn sides(shape : &LightShape, two_sided : bool) -> f64 {
+    match shape {
+        LightShape::Sphere{..} => 1.0,
+        _ if two_sided => 2.0,
+        _ => 1.0,
+    }
+}
+
+impl AreaLight {
+    // 放射束が power [W] になる光源
+    pub fn with_power(shape : LightShape, power : Vec3, two_sided : bool) -> AreaLight {
+        AreaLight{shape, le : power / (PI * shape.area() * sides(&shape, two_sided)), two_sided}
+    }
+
+    // 放射束 [W] (Light::power と同じ単位)
+    pub fn power(&self) -> Vec3 {
+        self.le * (PI * self.shape.area() * sides(&self.shape, self.two_sided))
+    }
+
+    // 面上の点 point での HitRecord (法線は表の向き)
+    fn record(&self, t : f64, point : Vec3) -> HitRecord<'_> {
+        match self.shape {
+            LightShape::Quad{corner, edges : (e0, e1)} => {
+                let n = e0.cross(&e1);
+                let (d, nn) = (point - corner, n.dot(&n));
+                let uv = (d.cross(&e1).dot(&n) / nn, e0.cross(&d).dot(&n) / nn);
+                HitRecord{uv, dpdu : e0, dpdv : e1, .. HitRecord::new(t, point, n / nn.sqrt(), self)}
+            },
+            // 直径を [0, 1] にした接平面の座標
+            LightShape::Disk{center, normal, radius} => {
+                let n = normal.normalize();
+                let TangentSpace(tu, tv) = TangentSpace::new(&n);
+                let d = (point - center) / (2.0 * radius);
+                let uv = (d.dot(&tu) + 0.5, d.dot(&tv) + 0.5);
+                HitRecord{uv, dpdu : tu * (2.0 * radius), dpdv : tv * (2.0 * radius), .. HitRecord::new(t, point, n, self)}
+            },
+            LightShape::Sphere{center, radius} => sphere_hit_record(&center, radius, t, point, self),
+        }
+    }
+
+    // [0, 1)^2 の一様乱数を面積に関して一様な面上の点に写す
+    fn point_at(&self, (u1, u2) : (f64, f64)) -> HitRecord<'_> {
+        match self.shape {
+            LightShape::Quad{corner, edges : (e0, e1)} => self.record(0.0, corner + e0 * u1 + e1 * u2),
+            LightShape::Disk{center, normal, radius} => {
+                let TangentSpace(tu, tv) = TangentSpace::new(&normal.normalize());
+                let (x, y) = Aperture::Circle.sample((u1, u2));
+                self.record(0.0, center + (tu * x + tv * y) * radius)
+            },
+            LightShape::Sphere{center, radius} => sphere_point(&center, radius, self, (u1, u2)),
+        }
+    }
+}
+
+impl Hit for AreaLight {
+    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord<'_>> {
+        // 平面との交差
+        let plane = |p : &Vec3, n : &Vec3| {
+            let nd = n.dot(&ray.direction);
+            if nd == 0.0 {
+                return None;
+            }
+            let t = n.dot(&(*p - ray.origin)) / nd;
+            if tmin < t && t < tmax {
+                Some((t, ray.direction * t + ray.origin))
+            } else {
+                None
+            }
+        };
+
+        let (t, point) = match self.shape {
+            LightShape::Quad{corner, edges : (e0, e1)} => {
+                let (t, point) = plane(&corner, &e0.cross(&e1))?;
+                let hr = self.record(t, point);
+                let (a, b) = hr.uv;
+                if !((0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b)) {
+                    return None;
+                }
+                return Some(hr);
+            },
+            LightShape::Disk{center, normal, radius} => {
+                let (t, point) = plane(&center, &normal)?;
+                let d = point - center;
+                if d.dot(&d) > radius.powi(2) {
+                    return None;
+                }
+                (t, point)
+            },
+            LightShape::Sphere{center, radius} => {
+                let t = sphere_intersect(&center, radius, ray, (tmin, tmax))?;
+                (t, ray.direction * t + ray.origin)
+            },
+        };
+        Some(self.record(t, point))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(match self.shape {
+            LightShape::Quad{corner, edges : (e0, e1)} => Aabb{min : corner, max : corner}
+                .grow(&(corner + e0)).grow(&(corner + e1)).grow(&(corner + e0 + e1)),
+            LightShape::Disk{center, normal, radius} => {
+                let n = normal.normalize();
+                let e = |c : f64| radius * (1.0 - c.powi(2)).max(0.0).sqrt();
+                let r = Vec3::new((e(n.x), e(n.y), e(n.z)));
+                Aabb{min : center - r, max : center + r}
+            },
+            LightShape::Sphere{center, radius} => {
+                let r = Vec3::new(radius.abs());
+                Aabb{min : center - r, max : center + r}
+            },
+        })
+    }
+}
+
+impl Bsdf for AreaLight {
+    fn sample(&self, _hr : &HitRecord, _wo : &Vec3, _u : (f64, f64), _uc : f64) -> Option<BsdfSample> {
+        None
+    }
+
+    fn evaluate(&self, _hr : &HitRecord, _wo : &Vec3, _wi : &Vec3) -> Vec3 {
+        Vec3::new(0.0)
+    }
+
+    fn pdf(&self, _hr : &HitRecord, _wo : &Vec3, _wi : &Vec3) -> f64 {
+        0.0
+    }
+
+    fn emitted(&self, hr : &HitRecord, wo : &Vec3) -> Vec3 {
+        if self.two_sided || hr.normal.dot(wo) > 0.0 {
+            self.le
+        } else {
+            Vec3::new(0.0)
+        }
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.le != Vec3::new(0.0)
+    }
+}
+
+// 形を持たない光源
+// 光線が当たることはなく、影の光線だけで評価する
+#[derive(Copy, Clone, PartialEq)]
+pub enum AnalyticLight {
+    // 全方向に放射強度 intensity [W/sr] で放射し、距離の 2 乗で暗くなる
+    Point{position : Vec3, intensity : Vec3},
+    // direction を軸とする半頂角 angle (ラジアン) の円錐の中だけに放射する
+    // 縁から内側 falloff (ラジアン) の範囲でなめらかに 0 になる
+    Spot{position : Vec3, direction : Vec3, intensity : Vec3, angle : f64, falloff : f64},
+    // direction に進む平行光 (太陽)
+    // irradiance は光に垂直な面の放射照度 [W/m^2]
+    // angular_diameter (ラジアン) が正なら、その視直径の円盤から一様に来る
+    Directional{direction : Vec3, irradiance : Vec3, angular_diameter : f64},
+}
+
+impl AnalyticLight {
+    // 放射束 [W] (平行光はシーンの広さで決まるので None)
+    pub fn power(&self) -> Option<Vec3> {
+        match *self {
+            AnalyticLight::Point{intensity, ..} => Some(intensity * (4.0 * PI)),
+            // 縁の smoothstep の積分は幅の半分になる
+            AnalyticLight::Spot{intensity, angle, falloff, ..} => {
+                let (cos_total, cos_start) = spot_cos(angle, falloff);
+                Some(intensity * (2.0 * PI * (1.0 - 0.5 * (cos_start + cos_total))))
+            },
+            AnalyticLight::Directional{..} => None,
+        }
+    }
+}
+
+// (放射が 0 になる cos, 弱まり始める cos)
+fn spot_cos(angle : f64, falloff : f64) -> (f64, f64) {
+    (angle.cos(), (angle - falloff).max(0.0).cos())
+}
+
+// 軸との角度の cos が cos の方向に放射する割合
+fn spot_falloff(cos : f64, angle : f64, falloff : f64) -> f64 {
+    let (cos_total, cos_start) = spot_cos(angle, falloff);
+    if cos <= cos_total {
+        0.0
+    } else if cos >= cos_start {
+        1.0
+    } else {
+        let t = (cos - cos_total) / (cos_start - cos_total);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+pub(crate) struct LightSample {
+    pub(crate) direction : Vec3,
+    // 環境光では無限大
+    pub(crate) distance : f64,
+    pub(crate) le : Vec3,
+    // 立体角に関する確率密度 (光源の選択確率を含む, デルタ分布なら選択確率だけ)
+    pub(crate) pdf : f64,
+    // 光線が当たらない光源 (AnalyticLight) なので MIS をせず、影の光線は光源の手前までにする
+    pub(crate) analytic : bool,
+}
+
+// 点 p から見た球の立体角 2π(1 - cosθmax) のうち 1 - cosθmax
+// 点が球の内側にあるときは None
+fn sphere_cone(center : &Vec3, radius : f64, p : &Vec3) -> Option<f64> {
+    let d2 = (*center - *p).dot(&(*center - *p));
+    let r2 = radius.powi(2);
+    if d2 <= r2 {
+        return None;
+    }
+    let sin2 = r2 / d2;
+    let cos_max = (1.0 - sin2).sqrt();
+    // 1 - cosθmax を桁落ちしないように計算する
+    Some(sin2 / (1.0 + cos_max))
+}
+
+// 球面上で面積に関して一様に点を選ぶ
+fn sphere_point<'a>(center : &Vec3, radius : f64, material : &'a dyn Bsdf, (u1, u2) : (f64, f64)) -> HitRecord<'a> {
+    let z = 1.0 - 2.0 * u1;
+    let r = 0.0f64.max(1.0 - z.powi(2)).sqrt();
+    let phi = 2.0 * PI * u2;
+    let point = *center + Vec3::new((r * phi.cos(), z, r * phi.sin())) * radius.abs();
+    sphere_hit_record(center, radius, 0.0, point, material)
+}
+
+// axis を軸とし、1 - cos(半頂角) が one_minus_cos の円錐の中で一様に方向を選ぶ
+fn cone_direction(axis : &Vec3, one_minus_cos : f64, (u1, u2) : (f64, f64)) -> Vec3 {
+    let cos = 1.0 - u1 * one_minus_cos;
+    let sin = 0.0f64.max(1.0 - cos.powi(2)).sqrt();
+    let phi = 2.0 * PI * u2;
+    let TangentSpace(t, b) = TangentSpace::new(axis);
+    (t * (sin * phi.cos()) + b * (sin * phi.sin()) + *axis * cos).normalize()
+}
+
+// 点 p から見える球の範囲の円錐内で一様に方向を選ぶ
+fn sample_sphere(center : &Vec3, radius : f64, material : &dyn Bsdf, p : &Vec3, (u1, u2) : (f64, f64), select : f64) -> Option<LightSample> {
+    let one_minus_cos = sphere_cone(center, radius, p)?;
+
+    let direction = cone_direction(&(*center - *p).normalize(), one_minus_cos, (u1, u2));
+
+    // 数値誤差で外れたときは接点までの距離を使う
+    let ray = Ray{origin : *p, direction};
+    let distance = sphere_intersect(center, radius, &ray, (0.1f64.powi(4), f64::INFINITY))
+        .unwrap_or_else(|| ((*center - *p).dot(&(*center - *p)) - radius.powi(2)).sqrt());
+
+    let hr = sphere_hit_record(center, radius, distance, *p + direction * distance, material);
+
+    Some(LightSample {
+        direction,
+        distance,
+        le : material.emitted(&hr, &-direction),
+        pdf : select / (2.0 * PI * one_minus_cos),
+        analytic : false,
+    })
+}
+
+fn triangle_area([a, b, c] : [Vec3; 3]) -> f64 {
+    let cross = (b - a).cross(&(c - a));
+    cross.dot(&cross).sqrt() * 0.5
+}
+
+// 三角形上で面積に関して一様に点を選ぶ
+// uv は重心座標 (b1, b2) から表面の uv を求める
+fn triangle_point<'a, F : Fn((f64, f64)) -> (f64, f64)>(points : [Vec3; 3], material : &'a dyn Bsdf, primitive : usize, uv : F, (u1, u2) : (f64, f64)) -> HitRecord<'a> {
+    let [a, b, c] = points;
+    let su = u1.sqrt();
+    let (b0, b1) = (1.0 - su, u2 * su);
+    let b2 = 1.0 - b0 - b1;
+    let q = a * b0 + b * b1 + c * b2;
+    let n = (b - a).cross(&(c - a)).normalize();
+    HitRecord{primitive, uv : uv((b1, b2)), .. HitRecord::new(0.0, q, n, material)}
+}
+
+// 面積 area の面上で一様に選んだ点 hr を、点 p からの方向のサンプルにする
+fn sample_area(hr : HitRecord, area : f64, p : &Vec3, select : f64) -> Option<LightSample> {
+    let v = hr.point - *p;
+    let d2 = v.dot(&v);
+    let distance = d2.sqrt();
+    let direction = v / distance;
+    let cos = hr.normal.dot(&direction).abs();
+    if cos == 0.0 || distance == 0.0 {
+        return None;
+    }
+
+    Some(LightSample {
+        direction,
+        distance,
+        le : hr.material.emitted(&hr, &-direction),
+        pdf : select * d2 / (area * cos),
+        analytic : false,
+    })
+}
+
+// sample_area がその方向を選ぶ確率密度
+fn area_pdf(area : f64, direction : &Vec3, hr : &HitRecord, select : f64) -> f64 {
+    let cos = hr.normal.dot(direction).abs();
+    if cos == 0.0 {
+        0.0
+    } else {
+        select * hr.t.powi(2) / (area * cos)
+    }
+}
+
+// 点 p から光源 light 上の一点に向かう方向をサンプリングする
+// select は光源 light を選んだ確率
+pub(crate) fn sample(scene : &Scene, light : usize, p : &Vec3, (u1, u2) : (f64, f64), select : f64) -> Option<LightSample> {
+    match scene.lights()[light] {
+        Light::Sphere(i) => {
+            let s = &scene.spheres()[i];
+            sample_sphere(&s.point, s.radius, &*s.material, p, (u1, u2), select)
+        },
+        Light::Polygon(i) => {
+            let poly = &scene.polygons()[i];
+            let hr = triangle_point(poly.points, &*poly.material, 0, |uv| uv, (u1, u2));
+            sample_area(hr, triangle_area(poly.points), p, select)
+        },
+        Light::Triangle(m, i) => {
+            let mesh = &scene.meshes()[m];
+            let points = mesh.triangle(i);
+            let hr = triangle_point(points, &*mesh.material, i, |uv| mesh.uv(i, uv), (u1, u2));
+            sample_area(hr, triangle_area(points), p, select)
+        },
+        Light::Area(i) => {
+            let l = &scene.area_lights()[i];
+            match l.shape {
+                LightShape::Sphere{center, radius} => sample_sphere(&center, radius, l, p, (u1, u2), select),
+                _ => {
+                    let hr = l.point_at((u1, u2));
+                    // 裏から見えない光源は選ばない
+                    if !l.two_sided && hr.normal.dot(&(*p - hr.point)) <= 0.0 {
+                        return None;
+                    }
+                    sample_area(hr, l.shape.area(), p, select)
+                },
+            }
+        },
+        Light::Analytic(i) => sample_analytic(&scene.analytic_lights()[i], p, (u1, u2), select),
+        Light::Environment => {
+            let env = scene.environment()?;
+            let (direction, pdf) = env.sample((u1, u2))?;
+
+            Some(LightSample {
+                direction,
+                distance : f64::INFINITY,
+                le : env.radiance(&direction),
+                pdf : select * pdf,
+                analytic : false,
+            })
+        },
+    }
+}
+
+fn sample_analytic(light : &AnalyticLight, p : &Vec3, u : (f64, f64), select : f64) -> Option<LightSample> {
+    // 点光源とスポットライトの (方向, 距離, 照度)
+    let point = |position : &Vec3, intensity : Vec3| {
+        let v = *position - *p;
+        let d2 = v.dot(&v);
+        let distance = d2.sqrt();
+        if distance == 0.0 {
+            None
+        } else {
+            Some((v / distance, distance, intensity / d2))
+        }
+    };
+
+    let (direction, distance, le, pdf) = match *light {
+        AnalyticLight::Point{position, intensity} => {
+            let (direction, distance, le) = point(&position, intensity)?;
+            (direction, distance, le, select)
+        },
+        AnalyticLight::Spot{position, direction : axis, intensity, angle, falloff} => {
+            let (direction, distance, le) = point(&position, intensity)?;
+            let f = spot_falloff(axis.normalize().dot(&-direction), angle, falloff);
+            if f == 0.0 {
+                return None;
+            }
+            (direction, distance, le * f, select)
+        },
+        AnalyticLight::Directional{direction, irradiance, angular_diameter} => {
+            let axis = -direction.normalize();
+            if angular_diameter <= 0.0 {
+                (axis, f64::INFINITY, irradiance, select)
+            } else {
+                // 半頂角 a の円盤の放射輝度 L は irradiance = π L sin^2 a を満たす
+                let a = 0.5 * angular_diameter;
+                let one_minus_cos = 2.0 * (0.5 * a).sin().powi(2);
+                let le = irradiance / (PI * a.sin().powi(2));
+                (cone_direction(&axis, one_minus_cos, u), f64::INFINITY, le, select / (2.0 * PI * one_minus_cos))
+            }
+        },
+    };
+
+    Some(LightSample{direction, distance, le, pdf, analytic : true})
+}
+
+// 点 p から direction に進んで光源 light 上の hr に当たったときに、
+// sample がその方向を選ぶ確率密度
+pub(crate) fn pdf(scene : &Scene, light : usize, p : &Vec3, direction : &Vec3, hr : &HitRecord) -> f64 {
+    let select = 1.0 / scene.lights().len() as f64;
+
+    match scene.lights()[light] {
+        Light::Sphere(i) => {
+            let s = &scene.spheres()[i];
+            match sphere_cone(&s.point, s.radius, p) {
+                Some(one_minus_cos) => select / (2.0 * PI * one_minus_cos),
+                None => 0.0,
+            }
+        },
+        Light::Polygon(i) => area_pdf(triangle_area(scene.polygons()[i].points), direction, hr, select),
+        Light::Triangle(m, i) => area_pdf(triangle_area(scene.meshes()[m].triangle(i)), direction, hr, select),
+        Light::Area(i) => {
+            let l = &scene.area_lights()[i];
+            match l.shape {
+                LightShape::Sphere{center, radius} => match sphere_cone(&center, radius, p) {
+                    Some(one_minus_cos) => select / (2.0 * PI * one_minus_cos),
+                    None => 0.0,
+                },
+                _ if !l.two_sided && hr.normal.dot(direction) >= 0.0 => 0.0,
+                _ => area_pdf(l.shape.area(), direction, hr, select),
+            }
+        },
+        // 当たることはない
+        Light::Analytic(_) => 0.0,
+        // 環境光には当たらないので environment_pdf を使う
+        Light::Environment => 0.0,
+    }
+}
+
+// 何にも当たらずに direction へ抜けたときに、sample がその方向を選ぶ確率密度
+pub(crate) fn environment_pdf(scene : &Scene, direction : &Vec3) -> f64 {
+    match (scene.lights().last(), scene.environment()) {
+        (Some(Light::Environment), Some(env)) => env.pdf(direction) / scene.lights().len() as f64,
+        _ => 0.0,
+    }
+}
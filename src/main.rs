@@ -1,7 +1,8 @@
 extern crate raytrace;
-use raytrace::{io, render};
+use raytrace::{environment, film, io, render, sampler, scenefile, tonemap};
 
-use std::{fmt, time};
+use std::{env, fmt, time};
+use std::path::Path;
 
 extern crate rayon;
 
@@ -11,16 +12,54 @@ macro_rules! measure {
       let start = time::Instant::now();
       let result = $x;
       let end = start.elapsed();
-      println!("Time: {}.{:03}sec", end.as_secs(), end.subsec_nanos() / 1_000_000);
+      println!("Time: {}.{:03}sec", end.as_secs(), end.subsec_millis());
       result
     }
   };
 }
 
+const USAGE : &str = "\
+usage:
+  raytrace render <scene> [options]
+  raytrace info <scene>
+
+render options:
+  --size <W>x<H>        image resolution
+  --spp <N>             samples per pixel
+  --max-depth <N|none>  maximum number of bounces
+  --roulette-depth <N|none>
+                        bounces before Russian roulette may end a path
+  --mode <MODE>         Shade, Normal, NormalColor, Depth[:RANGE], DepthNormalColor[:RANGE]
+  -o, --output <PATH>   output image (default: result-<spp>-<depth>.png or result-<mode>.png)
+  --format <FORMAT>     output format, inferred from the extension when omitted (ppm, png, pfm, exr)
+  --bit-depth <8|16>    bits per channel of ppm and png (default: 8)
+  --exr-compression <none|zip>
+                        compression of exr scanlines (default: zip)
+  --threads <N>         number of worker threads (default: all cores)
+  --sampler <SAMPLER>   Independent, Stratified, Halton, Sobol
+  --filter <FILTER>     Box, Tent, Gaussian, Mitchell, Lanczos, optionally with a radius (Gaussian:1.5)
+  --exposure <EV>       scale radiance by 2^EV before tone mapping ppm and png output
+  --tonemap <OP>        Linear, Reinhard, ReinhardExtended[:WHITE], Aces, Uncharted2
+  --no-dither           quantize ppm and png output without dithering
+  --adaptive <THRESHOLD>[:<N>]
+                        add N samples (default: 16) at a time to pixels whose relative error
+                        exceeds THRESHOLD, up to --spp
+  --heatmap <PATH>      also write the number of samples per pixel as an image
+  --seed <N>            random seed
+  --no-light-sampling   only sample the BSDF, without next event estimation
+                        (point, spot and directional lights are still sampled)";
+
 enum MyError {
+    Usage(String),
+    Scene(scenefile::SceneFileError),
     Run(rayon::ThreadPoolBuildError),
     WriteImage(io::WriteImageError),
-    Command(std::io::Error),
+}
+
+impl std::convert::From<scenefile::SceneFileError> for MyError {
+    fn from(error : scenefile::SceneFileError) -> MyError {
+        MyError::Scene(error)
+    }
 }
 
 impl std::convert::From<rayon::ThreadPoolBuildError> for MyError {
@@ -35,102 +74,323 @@ impl std::convert::From<io::WriteImageError> for MyError {
     }
 }
 
-impl std::convert::From<std::io::Error> for MyError {
-    fn from(error : std::io::Error) -> MyError {
-        MyError::Command(error)
-    }
-}
-
 impl std::fmt::Debug for MyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use MyError::*;
-        
+
         match self {
+            Usage(s) => write!(f, "{}\n\n{}", s, USAGE),
+            Scene(e) => write!(f, "{}", e),
             Run(e) => write!(f, "{:?}", e),
-            WriteImage(e) => write!(f, "{:?}", e),
-            Command(e) => write!(f, "{:?}", e),
+            WriteImage(e) => write!(f, "{}", e),
         }
     }
 }
 
-fn main() -> Result<(), MyError> {
-    
-    /*
-    let rs = render::RenderSetting {
-        spp : 5000,
-        reflect_n : 50,
-        scene : {
-            use raytrace::env::*;
-            use raytrace::obj::*;
-            use raytrace::geo::*;
-            let k = 10.0f64.powi(5);
-            Scene::new(
-                vec![
-                    Sphere{point : Vec3::new((k + 1.0  , 40.8        , 81.6)), radius : k   , material : Material::Diffuse, reflectance : Vec3::new((0.75, 0.25, 0.25))   , le : Vec3::new(0.0) }, // left wall
-                    Sphere{point : Vec3::new((-k + 99.0, 40.8        , 81.6)), radius : k   , material : Material::Diffuse, reflectance : Vec3::new((0.25, 0.25, 0.75))   , le : Vec3::new(0.0)}, // right wall
-                    Sphere{point : Vec3::new((50.0     , 40.8        , k   )), radius : k   , material : Material::Mirror, reflectance : Vec3::new(0.75)  , le : Vec3::new(0.0)}, // far side wall
-
-                    Sphere{point : Vec3::new((50.0     , k           , 81.6)), radius : k   , material : Material::Diffuse, reflectance : Vec3::new(0.75)  , le : Vec3::new(0.0)}, // floor
-                    Sphere{point : Vec3::new((50.0     , -k + 81.6   , 81.6)), radius : k   , material : Material::Diffuse, reflectance : Vec3::new(0.75)  , le : Vec3::new(0.0)}, // ceilling
-
-                    Sphere{point : Vec3::new((27.0, 56.5, 47.0)), radius :  6.5, material : Material::Fresnel(fresnel::GLASSBK7), reflectance : Vec3::new((0.15, 1.0, 0.15)), le : Vec3::new(0.0)},
-                    Sphere{point : Vec3::new((83.0, 46.5, 98.0)), radius :  8.5, material : Material::Diffuse, reflectance : Vec3::new(0.999), le : Vec3::new((0.5, 1.0, 0.5))},
-                    Sphere{point : Vec3::new((23.0, 46.5, 98.0)), radius :  5.5, material : Material::Diffuse, reflectance : Vec3::new(0.999), le : Vec3::new(0.0)},
-                    Sphere{point : Vec3::new((27.0,  0.0, 98.0)), radius : 14.5, material : Material::Fresnel(fresnel::GLASSBK7), reflectance : Vec3::new((0.25, 0.25, 0.75)), le : Vec3::new(0.0)},
-                    Sphere{point : Vec3::new((27.0, 26.0, 98.0)), radius :  8.5, material : Material::Mirror, reflectance : Vec3::new(0.999), le : Vec3::new(0.0)},
-                    Sphere{point : Vec3::new((73.0, 16.5, 78.0)), radius : 16.5, material : Material::Diffuse, reflectance : Vec3::new(0.999), le : Vec3::new(0.0)},
-
-                    Sphere{point : Vec3::new((50.0     , 681.6 - 0.27, 81.6)), radius : 600., material : Material::Diffuse, reflectance : Vec3::new(0.0)   , le : Vec3::new(3.0)}, // ceiling holl
-                ],
-                Vec::new(),
-                Vec::new()
-            )
-        },
-        camera : Default::default(),
-        mode : render::RenderMode::NormalColor,
-        ..Default::default()
-    };
-    
-    */
-    
-    let rs = render::RenderSetting {
-        spp : 1000,
-        reflect_n : 10,
-        mode : render::RenderMode::DepthNormalColor(500.0),
-        .. Default::default()
+fn parse_format(s : &str) -> Result<io::ImageFormat, MyError> {
+    io::ImageFormat::from_extension(s).ok_or_else(|| MyError::Usage(format!("unsupported output format '{}'", s)))
+}
+
+struct RenderArgs {
+    scene : String,
+    size : Option<(usize, usize)>,
+    spp : Option<usize>,
+    max_depth : Option<Option<usize>>,
+    roulette_depth : Option<Option<usize>>,
+    mode : Option<render::RenderMode>,
+    sampler : Option<sampler::SamplerKind>,
+    filter : Option<film::Filter>,
+    exposure : Option<f64>,
+    operator : Option<tonemap::Operator>,
+    dither : Option<bool>,
+    adaptive : Option<render::Adaptive>,
+    heatmap : Option<String>,
+    output : Option<String>,
+    format : Option<io::ImageFormat>,
+    bit_depth : Option<u8>,
+    exr_compression : Option<io::ExrCompression>,
+    threads : Option<usize>,
+    seed : Option<u64>,
+    light_sampling : Option<bool>,
+}
+
+fn parse_number<T : std::str::FromStr>(flag : &str, v : &str) -> Result<T, MyError> {
+    v.parse().map_err(|_| MyError::Usage(format!("invalid value '{}' for {}", v, flag)))
+}
+
+fn parse_optional_number<T : std::str::FromStr>(flag : &str, v : &str) -> Result<Option<T>, MyError> {
+    if v == "none" {
+        Ok(None)
+    } else {
+        parse_number(flag, v).map(Some)
+    }
+}
+
+fn parse_render_args(args : &[String]) -> Result<RenderArgs, MyError> {
+    let mut ra = RenderArgs {
+        scene : String::new(),
+        size : None, spp : None, max_depth : None, roulette_depth : None, mode : None, sampler : None, filter : None,
+        exposure : None, operator : None, dither : None, adaptive : None, heatmap : None,
+        output : None, format : None, bit_depth : None, exr_compression : None,
+        threads : None, seed : None, light_sampling : None,
     };
+    let mut scene = None;
 
-    println!("render::run");
-    let cs = measure!(render::run(&rs))?;
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        if !a.starts_with('-') {
+            if scene.is_some() {
+                return Err(MyError::Usage(format!("unexpected argument '{}'", a)));
+            }
+            scene = Some(a.clone());
+            continue;
+        }
 
-    let n = "test";
-    
-    let f = match &rs.mode {
-        render::RenderMode::Shade => {
-            format!("result{}-{}-{}", n, &rs.spp, &rs.reflect_n)
-        },
-        _ => {
-            format!("result{}-{}", n, &rs.mode)
+        if a == "--no-light-sampling" {
+            ra.light_sampling = Some(false);
+            continue;
+        }
+        if a == "--no-dither" {
+            ra.dither = Some(false);
+            continue;
+        }
+
+        let v = match it.next() {
+            Some(v) => v.as_str(),
+            None => return Err(MyError::Usage(format!("{} requires a value", a))),
+        };
+
+        match a.as_str() {
+            "--size" => {
+                let wh : Vec<_> = v.split('x').collect();
+                ra.size = match wh.as_slice() {
+                    [w, h] => Some((parse_number(a, w)?, parse_number(a, h)?)),
+                    _ => return Err(MyError::Usage(format!("invalid value '{}' for --size, expected <W>x<H>", v))),
+                };
+            },
+            "--spp" => ra.spp = Some(parse_number(a, v)?),
+            "--max-depth" => ra.max_depth = Some(parse_optional_number(a, v)?),
+            "--roulette-depth" => ra.roulette_depth = Some(parse_optional_number(a, v)?),
+            "--mode" => ra.mode = Some(v.parse().map_err(MyError::Usage)?),
+            "--sampler" => ra.sampler = Some(v.parse().map_err(MyError::Usage)?),
+            "--filter" => ra.filter = Some(v.parse().map_err(MyError::Usage)?),
+            "--exposure" => ra.exposure = Some(parse_number(a, v)?),
+            "--tonemap" => ra.operator = Some(v.parse().map_err(MyError::Usage)?),
+            "--adaptive" => ra.adaptive = Some(parse_adaptive(v)?),
+            "--heatmap" => ra.heatmap = Some(v.to_string()),
+            "-o" | "--output" => ra.output = Some(v.to_string()),
+            "--format" => ra.format = Some(parse_format(v)?),
+            "--bit-depth" => ra.bit_depth = match v {
+                "8" => Some(8),
+                "16" => Some(16),
+                _ => return Err(MyError::Usage(format!("invalid value '{}' for --bit-depth, expected 8 or 16", v))),
+            },
+            "--exr-compression" => ra.exr_compression = match v {
+                "none" => Some(io::ExrCompression::None),
+                "zip" => Some(io::ExrCompression::Zip),
+                _ => return Err(MyError::Usage(format!("invalid value '{}' for --exr-compression, expected none or zip", v))),
+            },
+            "--threads" => ra.threads = Some(parse_number(a, v)?),
+            "--seed" => ra.seed = Some(parse_number(a, v)?),
+            _ => return Err(MyError::Usage(format!("unknown option '{}'", a))),
         }
+    }
+
+    ra.scene = scene.ok_or_else(|| MyError::Usage("missing scene file".to_string()))?;
+
+    if ra.size.map(|(w, h)| w == 0 || h == 0).unwrap_or(false) {
+        return Err(MyError::Usage("--size must be positive".to_string()));
+    }
+    if ra.spp == Some(0) {
+        return Err(MyError::Usage("--spp must be positive".to_string()));
+    }
+    if ra.threads == Some(0) {
+        return Err(MyError::Usage("--threads must be positive".to_string()));
+    }
+
+    Ok(ra)
+}
+
+fn render(args : &[String]) -> Result<(), MyError> {
+    let ra = parse_render_args(args)?;
+
+    let mut rs = scenefile::load(&ra.scene)?;
+    if let Some(size) = ra.size {
+        rs.window_size = size;
+    }
+    if let Some(spp) = ra.spp {
+        rs.spp = spp;
+    }
+    if let Some(d) = ra.max_depth {
+        rs.reflect_n = d;
+    }
+    if let Some(d) = ra.roulette_depth {
+        rs.roulette_depth = d;
+    }
+    if rs.reflect_n.is_none() && rs.roulette_depth.is_none() {
+        return Err(MyError::Usage("--max-depth none needs Russian roulette, set --roulette-depth".to_string()));
+    }
+    if let Some(mode) = ra.mode {
+        rs.mode = mode;
+    }
+    if let Some(s) = ra.sampler {
+        rs.sampler = s;
+    }
+    if let Some(f) = ra.filter {
+        rs.filter = f;
+    }
+    if let Some(ev) = ra.exposure {
+        rs.tonemap.exposure = ev;
+    }
+    if let Some(op) = ra.operator {
+        rs.tonemap.operator = op;
+    }
+    if let Some(d) = ra.dither {
+        rs.tonemap.dither = d;
+    }
+    if ra.adaptive.is_some() {
+        rs.adaptive = ra.adaptive;
+    }
+    if let Some(v) = ra.light_sampling {
+        rs.light_sampling = v;
+    }
+    if let Some(seed) = ra.seed {
+        rs.seed = seed;
+    }
+
+    // 出力形式は --format, 拡張子の順に決める
+    let output = ra.output.unwrap_or_else(|| match rs.mode {
+        render::RenderMode::Shade => format!("result-{}-{}.png", rs.spp, depth_str(rs.reflect_n)),
+        _ => format!("result-{}.png", rs.mode),
+    });
+    let infer_format = |output : &str| match Path::new(output).extension().and_then(|e| e.to_str()) {
+        Some(e) => parse_format(e),
+        None => Err(MyError::Usage(format!("cannot infer the format of '{}', use --format", output))),
+    };
+    let mut format = match ra.format {
+        Some(f) => f,
+        None => infer_format(&output)?,
+    };
+    if let (io::ImageFormat::Exr(_), Some(c)) = (format, ra.exr_compression) {
+        format = io::ImageFormat::Exr(c);
+    }
+    let heatmap = match ra.heatmap {
+        Some(ref path) => Some((path, infer_format(path)?)),
+        None => None,
     };
 
-    io::write_image(rs.window_size, cs, format!("img/ppm/{}", &f))?;
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(n) = ra.threads {
+        pool = pool.num_threads(n);
+    }
+    let pool = pool.build()?;
 
-    let f_ppm = format!("img/ppm/{}.ppm", &f);
-    let f_png = format!("img/png/{}.png", &f);
+    println!("render::run {} ({}x{}, {})", ra.scene, rs.window_size.0, rs.window_size.1, rs.mode);
+    let fb = measure!(pool.install(|| render::render(&rs)));
 
-    use std::process::Command;
+    write_output(&fb, format, ra.bit_depth, &rs.tonemap, &output)?;
 
-    if true {
-        let mut p = Command::new("imgcat").arg(&f_ppm).spawn()?;
-        println!("imgcat {}", &f_ppm);
-        measure!(p.wait())?;
+    if let Some((path, format)) = heatmap {
+        let samples = fb.samples.iter().sum::<usize>() as f64 / fb.samples.len() as f64;
+        println!("average spp {:.1}", samples);
+        let tonemap = tonemap::Tonemap{dither : false, .. Default::default()};
+        write_output(&fb.sample_heatmap(), format, ra.bit_depth, &tonemap, path)?;
     }
 
-    
-    let mut p = Command::new("convert").arg(&f_ppm).arg(&f_png).spawn()?;
-    println!("convert {} {}", &f_ppm, &f_png);
-    measure!(p.wait())?;
+    Ok(())
+}
 
+// HDR 形式はトーンマップせずにそのまま書く
+fn write_output(fb : &render::Framebuffer, format : io::ImageFormat, bit_depth : Option<u8>, tonemap : &tonemap::Tonemap, output : &str) -> Result<(), MyError> {
+    if format.is_hdr() {
+        io::write_hdr_image_as(format, fb.size, &fb.pixels, output)?;
+    } else if bit_depth == Some(16) {
+        io::write_image_as(format, fb.size, fb.to_rgb16(tonemap), output)?;
+    } else {
+        io::write_image_as(format, fb.size, fb.to_rgb8(tonemap), output)?;
+    }
+    println!("wrote {}", output);
     Ok(())
-}
\ No newline at end of file
+}
+
+// "0.05" か "0.05:16"
+fn parse_adaptive(v : &str) -> Result<render::Adaptive, MyError> {
+    let invalid = || MyError::Usage(format!("invalid value '{}' for --adaptive, expected <THRESHOLD>[:<N>]", v));
+    let mut it = v.splitn(2, ':');
+    let threshold = it.next().and_then(|t| t.parse::<f64>().ok()).filter(|&t| t > 0.0).ok_or_else(invalid)?;
+    let pass_spp = match it.next() {
+        None => 16,
+        Some(n) => n.parse::<usize>().ok().filter(|&n| n >= 2).ok_or_else(invalid)?,
+    };
+    Ok(render::Adaptive{threshold, pass_spp})
+}
+
+fn depth_str(d : Option<usize>) -> String {
+    d.map(|d| d.to_string()).unwrap_or_else(|| "none".to_string())
+}
+
+fn info(args : &[String]) -> Result<(), MyError> {
+    let scene = match args {
+        [scene] => scene,
+        _ => return Err(MyError::Usage("info takes exactly one scene file".to_string())),
+    };
+
+    let rs = scenefile::load(scene)?;
+    let s = &rs.scene;
+
+    println!("{}", scene);
+    println!("  size      {}x{}", rs.window_size.0, rs.window_size.1);
+    println!("  spp       {}", rs.spp);
+    println!("  max depth {}", depth_str(rs.reflect_n));
+    println!("  roulette  {}", depth_str(rs.roulette_depth));
+    println!("  mode      {}", rs.mode);
+    println!("  sampler   {}", rs.sampler);
+    println!("  seed      {}", rs.seed);
+    println!("  filter    {} {}", rs.filter, rs.filter.radius());
+    match rs.tonemap.operator {
+        tonemap::Operator::ReinhardExtended(white) => println!("  tonemap   ReinhardExtended (white {})", white),
+        op => println!("  tonemap   {}", op),
+    }
+    println!("  exposure  {}", rs.tonemap.exposure);
+    println!("  dither    {}", rs.tonemap.dither);
+    match rs.adaptive {
+        Some(a) => println!("  adaptive  threshold {}, {} samples per pass", a.threshold, a.pass_spp),
+        None => println!("  adaptive  off"),
+    }
+    println!("  spheres   {}", s.spheres().len());
+    println!("  planes    {}", s.planes().len());
+    println!("  polygons  {}", s.polygons().len());
+    println!("  meshes    {} ({} triangles)", s.meshes().len(), s.meshes().iter().map(|m| m.indices().len()).sum::<usize>());
+    println!("  shapes    {}", s.shapes().len());
+    let power = s.total_power();
+    println!("  lights    {} ({} area lights, {} analytic lights), total power ({}, {}, {}) W without the environment and directional lights",
+        s.lights().len(), s.area_lights().len(), s.analytic_lights().len(), power.x, power.y, power.z);
+    match s.environment() {
+        None => println!("  environment none"),
+        Some(&environment::Environment::Constant(c)) => println!("  environment constant ({}, {}, {})", c.x, c.y, c.z),
+        Some(environment::Environment::Map(m)) => println!("  environment {}x{} map {}", m.size.0, m.size.1,
+            m.path.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+    }
+    match s.bounds() {
+        Some(b) => println!("  bounds    ({}, {}, {}) - ({}, {}, {}) (planes excluded)",
+            b.min.x, b.min.y, b.min.z, b.max.x, b.max.y, b.max.z),
+        None => println!("  bounds    none (no finite primitives)"),
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), MyError> {
+    let args : Vec<String> = env::args().skip(1).collect();
+
+    match args.split_first() {
+        Some((c, rest)) if c == "render" => render(rest),
+        Some((c, rest)) if c == "info" => info(rest),
+        Some((c, _)) if c == "-h" || c == "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        Some((c, _)) => Err(MyError::Usage(format!("unknown command '{}'", c))),
+        None => Err(MyError::Usage("missing command".to_string())),
+    }
+}
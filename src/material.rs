@@ -0,0 +1,699 @@
+use std::any::Any;
+
+use geo::*;
+use obj::*;
+use texture::Texture;
+
+use std;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+pub struct BsdfSample {
+    // 次に進む方向 (単位ベクトル)
+    pub direction : Vec3,
+    // f * cos / pdf (経路の throughput に掛ける値)
+    pub weight : Vec3,
+    // 立体角に関する確率密度 (specular のときは使わない)
+    pub pdf : f64,
+    // デルタ分布からのサンプル (MIS の対象にならない)
+    pub specular : bool,
+}
+
+// 表面での光の散乱と放射
+// wo は交点から視点 (光線の来た方) に向かう単位ベクトル, wi は光の来る方向
+//
+// 放射する材質は emitted と is_emissive の両方を実装すること
+// is_emissive が true の材質を持つ有限のプリミティブは光源として直接サンプリングされる
+pub trait Bsdf : Any + Send + Sync {
+    // u, uc は [0, 1) の一様乱数
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, u : (f64, f64), uc : f64) -> Option<BsdfSample>;
+
+    // BSDF の値 (cos は含まない)
+    fn evaluate(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> Vec3;
+
+    // sample が wi を選ぶ確率密度
+    fn pdf(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> f64;
+
+    fn emitted(&self, _hr : &HitRecord, _wo : &Vec3) -> Vec3 {
+        Vec3::new(0.0)
+    }
+
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    // デルタ分布だけからなり、光源の直接サンプリングが役に立たない
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    // NormalColor などのプレビューで使う色
+    fn reflectance(&self, _hr : &HitRecord) -> Vec3 {
+        Vec3::new(1.0)
+    }
+
+    // 光線が当たった点で一度だけ呼び、陰影付けに使う HitRecord にする
+    // shading_normal を変える材質は、material も中身の材質に差し替える
+    fn shading<'a>(&'a self, hr : HitRecord<'a>, _wo : &Vec3) -> HitRecord<'a> {
+        hr
+    }
+}
+
+// 光線が当たった点で陰影付けに使う HitRecord
+// 材質の shading を適用したあと、shading_normal を valid_shading_normal で直す
+pub fn shading_point<'a>(hr : HitRecord<'a>, wo : &Vec3) -> HitRecord<'a> {
+    let hr = hr.material.shading(hr, wo);
+    HitRecord{shading_normal : valid_shading_normal(&hr.normal, &hr.shading_normal, wo), .. hr}
+}
+
+// wo を鏡面反射した方向が幾何的な面 ng の下に潜らないように ns を ng の側に曲げる
+// 潜ったままだと反射光が面に遮られて黒い斑点になる (Cycles の ensure_valid_reflection)
+pub(crate) fn valid_shading_normal(ng : &Vec3, ns : &Vec3, wo : &Vec3) -> Vec3 {
+    // wo の側に向けて考え、最後に元の向きに戻す
+    let s = if ng.dot(wo) >= 0.0 {1.0} else {-1.0};
+    let (ng, n) = (*ng * s, *ns * s);
+
+    let threshold = (0.9 * ng.dot(wo)).min(0.01);
+    if ng.dot(&reflect(wo, &n)) >= threshold {
+        return *ns;
+    }
+
+    // ng と n を含む平面の中で、反射方向の ng 成分がちょうど threshold になる法線を探す
+    let x = n - ng * n.dot(&ng);
+    let len = x.dot(&x).sqrt();
+    if len == 0.0 {
+        return ng * s;
+    }
+    let x = x / len;
+    let (ix, iz) = (wo.dot(&x), wo.dot(&ng));
+    let a = ix.powi(2) + iz.powi(2);
+    let b = (ix.powi(2) * (a - threshold.powi(2))).max(0.0).sqrt();
+    let c = iz * threshold + a;
+    // 候補の法線の ng 成分の 2 乗
+    let (z1, z2) = (0.5 * (c + b) / a, 0.5 * (c - b) / a);
+    let valid = |z2 : f64| z2 > 1e-5 && z2 <= 1.0 + 1e-5;
+    let normal = |z2 : f64| ((1.0 - z2).max(0.0).sqrt(), z2.max(0.0).sqrt());
+
+    let (nx, nz) = match (valid(z1), valid(z2)) {
+        (true, true) => {
+            // 反射方向が面の上に残る方を選び、両方残るなら元の法線に近い (反射が低い) 方
+            let (n1, n2) = (normal(z1), normal(z2));
+            let r = |(nx, nz) : (f64, f64)| 2.0 * (nx * ix + nz * iz) * nz - iz;
+            let (r1, r2) = (r(n1), r(n2));
+            if r1 >= 1e-5 && r2 >= 1e-5 {
+                if r1 < r2 {n1} else {n2}
+            } else if r1 > r2 {
+                n1
+            } else {
+                n2
+            }
+        },
+        (true, false) => normal(z1),
+        (false, true) => normal(z2),
+        (false, false) => return ng * s,
+    };
+    (x * nx + ng * nz) * s
+}
+
+// wo と同じ側を向いた法線
+pub(crate) fn facing_normal(hr : &HitRecord, wo : &Vec3) -> Vec3 {
+    hr.shading_normal * if hr.shading_normal.dot(wo) > 0.0 {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
+pub(crate) fn reflect(wo : &Vec3, n : &Vec3) -> Vec3 {
+    *n * 2.0 * wo.dot(n) - *wo
+}
+
+// 完全拡散反射
+#[derive(Clone)]
+pub struct Diffuse {
+    pub reflectance : Texture,
+    // 両面から放射する
+    pub le : Texture,
+}
+
+impl Bsdf for Diffuse {
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, (u1, u2) : (f64, f64), _ : f64) -> Option<BsdfSample> {
+        let n = facing_normal(hr, wo);
+
+        let (u, v) = {
+            let t = TangentSpace::new(&n);
+            (t.0, t.1)
+        };
+
+        // cos に比例した半球上の方向
+        let d = {
+            let r = u1.sqrt();
+            let t : f64 = 2.0 * std::f64::consts::PI * u2;
+            let (x, y) = (r * t.cos(), r * t.sin());
+            Vec3{x, y,
+                z : 0.0f64.max(1.0 - x.powi(2) - y.powi(2)).sqrt()
+            }
+        };
+
+        Some(BsdfSample {
+            direction : u * d.x + v * d.y + n * d.z,
+            weight : self.reflectance.evaluate(hr),
+            pdf : d.z / std::f64::consts::PI,
+            specular : false,
+        })
+    }
+
+    fn evaluate(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> Vec3 {
+        if facing_normal(hr, wo).dot(wi) > 0.0 {
+            self.reflectance.evaluate(hr) / std::f64::consts::PI
+        } else {
+            Vec3::new(0.0)
+        }
+    }
+
+    fn pdf(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> f64 {
+        facing_normal(hr, wo).dot(wi).max(0.0) / std::f64::consts::PI
+    }
+
+    fn emitted(&self, hr : &HitRecord, _wo : &Vec3) -> Vec3 {
+        self.le.evaluate(hr)
+    }
+
+    fn is_emissive(&self) -> bool {
+        !self.le.is_black()
+    }
+
+    fn reflectance(&self, hr : &HitRecord) -> Vec3 {
+        self.reflectance.evaluate(hr)
+    }
+}
+
+// 完全鏡面反射
+#[derive(Clone)]
+pub struct Mirror {
+    pub reflectance : Texture,
+}
+
+impl Bsdf for Mirror {
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, _ : (f64, f64), _ : f64) -> Option<BsdfSample> {
+        Some(BsdfSample {
+            direction : reflect(wo, &hr.shading_normal),
+            weight : self.reflectance.evaluate(hr),
+            pdf : 1.0,
+            specular : true,
+        })
+    }
+
+    fn evaluate(&self, _hr : &HitRecord, _wo : &Vec3, _wi : &Vec3) -> Vec3 {
+        Vec3::new(0.0)
+    }
+
+    fn pdf(&self, _hr : &HitRecord, _wo : &Vec3, _wi : &Vec3) -> f64 {
+        0.0
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn reflectance(&self, hr : &HitRecord) -> Vec3 {
+        self.reflectance.evaluate(hr)
+    }
+}
+
+// 滑らかな誘電体 (屈折率 ior, 外側は真空)
+#[derive(Clone)]
+pub struct Fresnel {
+    pub ior : f64,
+    pub reflectance : Texture,
+}
+
+impl Bsdf for Fresnel {
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, _ : (f64, f64), uc : f64) -> Option<BsdfSample> {
+        let ior = self.ior;
+        let wi = *wo;
+        let into = wi.dot(&hr.shading_normal) > 0.0;
+        let n = hr.shading_normal * if into {1.0} else {-1.0};
+        let eta = if into {
+            1.0 / ior
+        } else {
+            ior
+        };
+
+        let wt = {
+            // Snell's law (vector form)
+            let t = wi.dot(&n);
+            let t2 = 1.0 - eta.powi(2) * (1.0 - t.powi(2));
+            if t2 < 0.0 {
+                None
+            } else {
+                Some((n * t - wi) * eta - n * t2.sqrt())
+            }
+        };
+
+        let direction = if let Some(wt) = wt {
+            // Schlick's approximation
+            let fr = {
+                let cos = if into {
+                    wi.dot(&hr.shading_normal)
+                } else {
+                    wt.dot(&hr.shading_normal)
+                };
+                let r = (1.0 - ior) / (1.0 + ior);
+                r.powi(2) + (1.0 - r.powi(2)) * (1.0 - cos).powi(5)
+            };
+            // Select reflection or refraction
+            // according to the fresnel term
+
+            if uc < fr {
+                reflect(wo, &hr.shading_normal)
+            } else {
+                wt
+            }
+        } else {
+            // Total internal reflection
+            reflect(wo, &hr.shading_normal)
+        };
+
+        Some(BsdfSample {
+            direction,
+            weight : self.reflectance.evaluate(hr),
+            pdf : 1.0,
+            specular : true,
+        })
+    }
+
+    fn evaluate(&self, _hr : &HitRecord, _wo : &Vec3, _wi : &Vec3) -> Vec3 {
+        Vec3::new(0.0)
+    }
+
+    fn pdf(&self, _hr : &HitRecord, _wo : &Vec3, _wi : &Vec3) -> f64 {
+        0.0
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn reflectance(&self, hr : &HitRecord) -> Vec3 {
+        self.reflectance.evaluate(hr)
+    }
+}
+
+// 法線を z 軸とする局所座標系
+// 法線は wo の側に向け、t, b は形状の外側を向いた法線と uv の向きから決める
+struct Frame {
+    t : Vec3,
+    b : Vec3,
+    n : Vec3,
+}
+
+impl Frame {
+    fn new(hr : &HitRecord, wo : &Vec3) -> Frame {
+        let (t, b) = hr.tangent_frame();
+        Frame{t, b, n : facing_normal(hr, wo)}
+    }
+
+    fn to_local(&self, v : &Vec3) -> Vec3 {
+        Vec3{x : v.dot(&self.t), y : v.dot(&self.b), z : v.dot(&self.n)}
+    }
+
+    fn to_world(&self, v : &Vec3) -> Vec3 {
+        self.t * v.x + self.b * v.y + self.n * v.z
+    }
+}
+
+// GGX (Trowbridge-Reitz) の微小面分布
+// alpha は接線方向と従法線方向の粗さ
+struct Ggx {
+    ax : f64,
+    ay : f64,
+}
+
+impl Ggx {
+    fn new((ax, ay) : (f64, f64)) -> Ggx {
+        // 0 に近いと D が発散するので下限を設ける
+        Ggx{ax : ax.max(0.1f64.powi(4)), ay : ay.max(0.1f64.powi(4))}
+    }
+
+    fn d(&self, m : &Vec3) -> f64 {
+        if m.z <= 0.0 {
+            return 0.0;
+        }
+        let e = (m.x / self.ax).powi(2) + (m.y / self.ay).powi(2) + m.z.powi(2);
+        1.0 / (PI * self.ax * self.ay * e.powi(2))
+    }
+
+    fn lambda(&self, w : &Vec3) -> f64 {
+        if w.z == 0.0 {
+            return f64::INFINITY;
+        }
+        let a2 = ((self.ax * w.x).powi(2) + (self.ay * w.y).powi(2)) / w.z.powi(2);
+        ((1.0 + a2).sqrt() - 1.0) * 0.5
+    }
+
+    fn g1(&self, w : &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // height-correlated masking-shadowing
+    fn g2(&self, wo : &Vec3, wi : &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // wo から見える微小面の法線を、見える面積に比例して選ぶ (Heitz 2018)
+    // wo.z > 0 であること
+    fn sample_visible(&self, wo : &Vec3, (u1, u2) : (f64, f64)) -> Vec3 {
+        let vh = Vec3::new((self.ax * wo.x, self.ay * wo.y, wo.z)).normalize();
+
+        let len2 = vh.x.powi(2) + vh.y.powi(2);
+        let t1 = if len2 > 0.0 {
+            Vec3::new((-vh.y, vh.x, 0.0)) / len2.sqrt()
+        } else {
+            Vec3::new((1.0, 0.0, 0.0))
+        };
+        let t2 = vh.cross(&t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1.powi(2)).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * 0.0f64.max(1.0 - p1.powi(2) - p2.powi(2)).sqrt();
+
+        Vec3::new((self.ax * nh.x, self.ay * nh.y, nh.z.max(0.0))).normalize()
+    }
+
+    // sample_visible が m を選ぶ確率密度
+    fn visible_pdf(&self, wo : &Vec3, m : &Vec3) -> f64 {
+        self.g1(wo) * wo.dot(m).max(0.0) * self.d(m) / wo.z
+    }
+}
+
+// 誘電体の Fresnel 反射率
+// eta は入射側に対する反対側の屈折率の比, cos_i > 0
+fn fresnel_dielectric(cos_i : f64, eta : f64) -> f64 {
+    let sin2_t = (1.0 - cos_i.powi(2)) / eta.powi(2);
+    if sin2_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (rs.powi(2) + rp.powi(2)) * 0.5
+}
+
+// 導体の Fresnel 反射率 (真空から入射する)
+fn fresnel_conductor(cos_i : f64, ior : &fresnel::ComplexIor) -> Vec3 {
+    let f = |eta : f64, k : f64| {
+        let cos2 = cos_i.powi(2);
+        let sin2 = 1.0 - cos2;
+        let t0 = eta.powi(2) - k.powi(2) - sin2;
+        let a2b2 = (t0.powi(2) + 4.0 * eta.powi(2) * k.powi(2)).sqrt();
+        let t1 = a2b2 + cos2;
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_i * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2.powi(2);
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        (rs + rp) * 0.5
+    };
+    Vec3{
+        x : f(ior.eta.x, ior.k.x),
+        y : f(ior.eta.y, ior.k.y),
+        z : f(ior.eta.z, ior.k.z),
+    }
+}
+
+// GGX の微小面を持つ金属 (両面)
+// alpha の 1 つ目は u の方向の粗さ
+#[derive(Clone)]
+pub struct Conductor {
+    pub ior : fresnel::ComplexIor,
+    // GGX の粗さ (接線方向, 従法線方向), 等しければ等方的
+    pub alpha : (f64, f64),
+    pub reflectance : Texture,
+}
+
+impl Bsdf for Conductor {
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, u : (f64, f64), _ : f64) -> Option<BsdfSample> {
+        let frame = Frame::new(hr, wo);
+        let ggx = Ggx::new(self.alpha);
+        let wo = frame.to_local(wo);
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let m = ggx.sample_visible(&wo, u);
+        let wi = reflect(&wo, &m);
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        let cos = wo.dot(&m);
+        Some(BsdfSample {
+            direction : frame.to_world(&wi),
+            weight : self.reflectance.evaluate(hr) * fresnel_conductor(cos, &self.ior) * (ggx.g2(&wo, &wi) / ggx.g1(&wo)),
+            pdf : ggx.visible_pdf(&wo, &m) / (4.0 * cos),
+            specular : false,
+        })
+    }
+
+    fn evaluate(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> Vec3 {
+        let frame = Frame::new(hr, wo);
+        let ggx = Ggx::new(self.alpha);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::new(0.0);
+        }
+
+        let m = (wo + wi).normalize();
+        self.reflectance.evaluate(hr) * fresnel_conductor(wo.dot(&m), &self.ior)
+            * (ggx.d(&m) * ggx.g2(&wo, &wi) / (4.0 * wo.z * wi.z))
+    }
+
+    fn pdf(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> f64 {
+        let frame = Frame::new(hr, wo);
+        let ggx = Ggx::new(self.alpha);
+        let (wo, wi) = (frame.to_local(wo), frame.to_local(wi));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+
+        let m = (wo + wi).normalize();
+        ggx.visible_pdf(&wo, &m) / (4.0 * wo.dot(&m))
+    }
+
+    fn reflectance(&self, hr : &HitRecord) -> Vec3 {
+        self.reflectance.evaluate(hr) * fresnel_conductor(1.0, &self.ior)
+    }
+}
+
+// GGX の微小面を持つ誘電体 (すりガラス, 外側は真空)
+#[derive(Clone)]
+pub struct RoughDielectric {
+    pub ior : f64,
+    // GGX の粗さ (接線方向, 従法線方向), 等しければ等方的
+    pub alpha : (f64, f64),
+    pub reflectance : Texture,
+}
+
+impl RoughDielectric {
+    // wo の側に対する反対側の屈折率の比
+    fn eta(&self, hr : &HitRecord, wo : &Vec3) -> f64 {
+        if hr.shading_normal.dot(wo) > 0.0 {
+            self.ior
+        } else {
+            1.0 / self.ior
+        }
+    }
+
+    // 局所座標系での (f, pdf)
+    fn evaluate_local(&self, ggx : &Ggx, eta : f64, wo : &Vec3, wi : &Vec3) -> (f64, f64) {
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return (0.0, 0.0);
+        }
+
+        if wi.z > 0.0 {
+            let m = (*wo + *wi).normalize();
+            let cos = wo.dot(&m);
+            let fr = fresnel_dielectric(cos, eta);
+            (
+                fr * ggx.d(&m) * ggx.g2(wo, wi) / (4.0 * wo.z * wi.z),
+                fr * ggx.visible_pdf(wo, &m) / (4.0 * cos),
+            )
+        } else {
+            // 屈折の半ベクトル
+            let m = -(*wo + *wi * eta).normalize();
+            let m = if m.z < 0.0 {-m} else {m};
+            let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+            if cos_o <= 0.0 || cos_i >= 0.0 {
+                return (0.0, 0.0);
+            }
+            let ft = 1.0 - fresnel_dielectric(cos_o, eta);
+            // dωm / dωi
+            let jacobian = eta.powi(2) * -cos_i / (cos_o + eta * cos_i).powi(2);
+            (
+                ft * ggx.d(&m) * ggx.g2(wo, wi) * cos_o * jacobian / (wo.z * -wi.z),
+                ft * ggx.visible_pdf(wo, &m) * jacobian,
+            )
+        }
+    }
+}
+
+impl Bsdf for RoughDielectric {
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, u : (f64, f64), uc : f64) -> Option<BsdfSample> {
+        let frame = Frame::new(hr, wo);
+        let ggx = Ggx::new(self.alpha);
+        let eta = self.eta(hr, wo);
+        let wo = frame.to_local(wo);
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let m = ggx.sample_visible(&wo, u);
+        let cos_o = wo.dot(&m);
+        let fr = fresnel_dielectric(cos_o, eta);
+
+        // Select reflection or refraction
+        // according to the fresnel term
+        let wi = if uc < fr {
+            let wi = reflect(&wo, &m);
+            if wi.z <= 0.0 {
+                return None;
+            }
+            wi
+        } else {
+            // Snell's law (vector form)
+            let cos_t = (1.0 - (1.0 - cos_o.powi(2)) / eta.powi(2)).max(0.0).sqrt();
+            let wi = m * (cos_o / eta - cos_t) - wo / eta;
+            if wi.z >= 0.0 {
+                return None;
+            }
+            wi
+        };
+
+        let (_, pdf) = self.evaluate_local(&ggx, eta, &wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            direction : frame.to_world(&wi),
+            weight : self.reflectance.evaluate(hr) * (ggx.g2(&wo, &wi) / ggx.g1(&wo)),
+            pdf,
+            specular : false,
+        })
+    }
+
+    fn evaluate(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> Vec3 {
+        let frame = Frame::new(hr, wo);
+        let (f, _) = self.evaluate_local(&Ggx::new(self.alpha), self.eta(hr, wo), &frame.to_local(wo), &frame.to_local(wi));
+        self.reflectance.evaluate(hr) * f
+    }
+
+    fn pdf(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> f64 {
+        let frame = Frame::new(hr, wo);
+        let (_, pdf) = self.evaluate_local(&Ggx::new(self.alpha), self.eta(hr, wo), &frame.to_local(wo), &frame.to_local(wi));
+        pdf
+    }
+
+    fn reflectance(&self, hr : &HitRecord) -> Vec3 {
+        self.reflectance.evaluate(hr)
+    }
+}
+
+// 陰影付けの法線の変え方
+#[derive(Clone)]
+pub enum NormalMap {
+    // 接空間の法線 (x, y, z) を (x, y, z) * 0.5 + 0.5 で色にしたもの
+    // x は dpdu, y は dpdv の向き, z は元の陰影付けの法線 (OpenGL の流儀)
+    Tangent(Texture),
+    // 各成分の平均 * scale だけ表面を法線の向きにずらしたとみなす
+    Bump{height : Texture, scale : f64},
+}
+
+// 高さの傾きを差分で求めるときの uv の幅
+const BUMP_DELTA : f64 = 1.0 / 4096.0;
+
+impl NormalMap {
+    // hr での陰影付けの法線 (形状の外側に向けたもの)
+    pub fn normal(&self, hr : &HitRecord) -> Vec3 {
+        let n = hr.shading_normal;
+        let m = match self {
+            NormalMap::Tangent(texture) => {
+                let c = texture.evaluate(hr) * 2.0 - Vec3::new(1.0);
+                let (t, b) = hr.tangent_frame();
+                t * c.x + b * c.y + n * c.z
+            },
+            NormalMap::Bump{height, scale} => {
+                let (u, v) = hr.uv;
+                let h = |du : f64, dv : f64| {
+                    let shifted = HitRecord{uv : (u + du, v + dv), point : hr.point + hr.dpdu * du + hr.dpdv * dv, .. *hr};
+                    let c = height.evaluate(&shifted);
+                    (c.x + c.y + c.z) / 3.0 * scale
+                };
+                let h0 = h(0.0, 0.0);
+                let dhdu = (h(BUMP_DELTA, 0.0) - h0) / BUMP_DELTA;
+                let dhdv = (h(0.0, BUMP_DELTA) - h0) / BUMP_DELTA;
+
+                // 陰影付けの法線に垂直な面の上で dpdu, dpdv をずらした面の法線
+                let tangent = |d : Vec3| d - n * n.dot(&d);
+                let m = (tangent(hr.dpdu) + n * dhdu).cross(&(tangent(hr.dpdv) + n * dhdv));
+                if m.dot(&n) < 0.0 {-m} else {m}
+            },
+        };
+
+        let len = m.dot(&m).sqrt();
+        if len > 0.0 && len.is_finite() {
+            m / len
+        } else {
+            n
+        }
+    }
+}
+
+// material の陰影付けの法線を map で変えたもの
+// 描画では shading_point で中身の material に置き換わる
+#[derive(Clone)]
+pub struct Perturbed {
+    pub material : Arc<dyn Bsdf>,
+    pub map : NormalMap,
+}
+
+impl Bsdf for Perturbed {
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, u : (f64, f64), uc : f64) -> Option<BsdfSample> {
+        self.material.sample(hr, wo, u, uc)
+    }
+
+    fn evaluate(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> Vec3 {
+        self.material.evaluate(hr, wo, wi)
+    }
+
+    fn pdf(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> f64 {
+        self.material.pdf(hr, wo, wi)
+    }
+
+    fn emitted(&self, hr : &HitRecord, wo : &Vec3) -> Vec3 {
+        self.material.emitted(hr, wo)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn is_specular(&self) -> bool {
+        self.material.is_specular()
+    }
+
+    fn reflectance(&self, hr : &HitRecord) -> Vec3 {
+        self.material.reflectance(hr)
+    }
+
+    fn shading<'a>(&'a self, hr : HitRecord<'a>, wo : &Vec3) -> HitRecord<'a> {
+        let hr = HitRecord{shading_normal : self.map.normal(&hr), material : &*self.material, .. hr};
+        self.material.shading(hr, wo)
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use geo::*;
+use obj::*;
+use bvh::Bvh;
+use material::Bsdf;
+
+use std;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MeshError {
+    NormalCount{normals : usize, positions : usize},
+    UvCount{uvs : usize, positions : usize},
+    IndexOutOfRange{triangle : usize, index : usize, positions : usize},
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use mesh::MeshError::*;
+
+        match self {
+            NormalCount{normals, positions} => write!(f, "{} normals for {} positions, expected none or as many as positions", normals, positions),
+            UvCount{uvs, positions} => write!(f, "{} uvs for {} positions, expected none or as many as positions", uvs, positions),
+            IndexOutOfRange{triangle, index, positions} => write!(f, "triangle {}: vertex index {} out of range (0..{})", triangle, index, positions),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+// 頂点を共有する三角形の集まり
+// 添字は positions, normals, uvs に共通で、normals と uvs は空でもよい
+// 頂点は *_mut を通して書き換え、そのあと最初に使うときに BVH を作り直す
+pub struct TriangleMesh {
+    positions : Vec<Vec3>,
+    normals : Vec<Vec3>,
+    uvs : Vec<(f64, f64)>,
+    indices : Vec<[usize; 3]>,
+    pub material : Arc<dyn Bsdf>,
+    // 読み込んだファイル (シーンファイルに書き出すときに使う)
+    pub path : Option<PathBuf>,
+    // 書き換えるたびに空にする
+    // 壊れたメッシュ (添字が範囲外など) は何にも当たらない
+    built : OnceLock<Result<Bvh, MeshError>>,
+}
+
+impl TriangleMesh {
+    pub fn new(positions : Vec<Vec3>, normals : Vec<Vec3>, uvs : Vec<(f64, f64)>, indices : Vec<[usize; 3]>, material : Arc<dyn Bsdf>) -> Result<TriangleMesh, MeshError> {
+        let mut mesh = TriangleMesh{positions, normals, uvs, indices, material, path : None, built : OnceLock::new()};
+        mesh.rebuild()?;
+        Ok(mesh)
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn normals(&self) -> &[Vec3] {
+        &self.normals
+    }
+
+    pub fn uvs(&self) -> &[(f64, f64)] {
+        &self.uvs
+    }
+
+    pub fn indices(&self) -> &[[usize; 3]] {
+        &self.indices
+    }
+
+    // 以下の *_mut は作ってあった BVH を捨てる
+    pub fn positions_mut(&mut self) -> &mut Vec<Vec3> {
+        self.invalidate();
+        &mut self.positions
+    }
+
+    pub fn normals_mut(&mut self) -> &mut Vec<Vec3> {
+        self.invalidate();
+        &mut self.normals
+    }
+
+    pub fn uvs_mut(&mut self) -> &mut Vec<(f64, f64)> {
+        self.invalidate();
+        &mut self.uvs
+    }
+
+    pub fn indices_mut(&mut self) -> &mut Vec<[usize; 3]> {
+        self.invalidate();
+        &mut self.indices
+    }
+
+    fn invalidate(&mut self) {
+        self.built.take();
+    }
+
+    fn built(&self) -> &Result<Bvh, MeshError> {
+        self.built.get_or_init(|| self.build())
+    }
+
+    // BVH をすぐに作り直し、メッシュが壊れていればその理由を返す
+    pub fn rebuild(&mut self) -> Result<(), MeshError> {
+        self.invalidate();
+        match self.built() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(*e),
+        }
+    }
+
+    // 法線とテクスチャ座標の数、三角形の添字が頂点の数と合っているか調べる
+    pub fn check(&self) -> Result<(), MeshError> {
+        let n = self.positions.len();
+        if !self.normals.is_empty() && self.normals.len() != n {
+            return Err(MeshError::NormalCount{normals : self.normals.len(), positions : n});
+        }
+        if !self.uvs.is_empty() && self.uvs.len() != n {
+            return Err(MeshError::UvCount{uvs : self.uvs.len(), positions : n});
+        }
+        for (triangle, t) in self.indices.iter().enumerate() {
+            if let Some(&index) = t.iter().find(|&&i| i >= n) {
+                return Err(MeshError::IndexOutOfRange{triangle, index, positions : n});
+            }
+        }
+        Ok(())
+    }
+
+    fn build(&self) -> Result<Bvh, MeshError> {
+        self.check()?;
+        let bounds : Vec<_> = (0..self.indices.len()).map(|i| {
+            let [a, b, c] = self.triangle(i);
+            Aabb{min : a, max : a}.grow(&b).grow(&c)
+        }).collect();
+        Ok(Bvh::new(&bounds))
+    }
+
+    pub fn triangle(&self, i : usize) -> [Vec3; 3] {
+        let [a, b, c] = self.indices[i];
+        [self.positions[a], self.positions[b], self.positions[c]]
+    }
+
+    // 三角形 i の重心座標 (b1, b2) の位置の uv
+    // 頂点の uv がなければ重心座標をそのまま使う
+    pub fn uv(&self, i : usize, (b1, b2) : (f64, f64)) -> (f64, f64) {
+        if self.uvs.is_empty() {
+            return (b1, b2);
+        }
+        let b0 = 1.0 - b1 - b2;
+        let [ia, ib, ic] = self.indices[i];
+        let (ua, ub, uc) = (self.uvs[ia], self.uvs[ib], self.uvs[ic]);
+        (ua.0 * b0 + ub.0 * b1 + uc.0 * b2, ua.1 * b0 + ub.1 * b1 + uc.1 * b2)
+    }
+
+    // 三角形 i の uv に関する位置の偏微分
+    // 頂点の uv がないか、uv が縮退しているときは重心座標に関する偏微分
+    fn derivatives(&self, i : usize, (e1, e2) : (Vec3, Vec3)) -> (Vec3, Vec3) {
+        if !self.uvs.is_empty() {
+            let [ia, ib, ic] = self.indices[i];
+            let (ua, ub, uc) = (self.uvs[ia], self.uvs[ib], self.uvs[ic]);
+            let (du1, dv1) = (ub.0 - ua.0, ub.1 - ua.1);
+            let (du2, dv2) = (uc.0 - ua.0, uc.1 - ua.1);
+            let det = du1 * dv2 - dv1 * du2;
+            if det.abs() > 1e-12 {
+                return ((e1 * dv2 - e2 * dv1) / det, (e2 * du1 - e1 * du2) / det);
+            }
+        }
+        (e1, e2)
+    }
+
+    // Möller–Trumbore
+    fn hit_triangle(&self, i : usize, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord<'_>> {
+        let [a, b, c] = self.triangle(i);
+        let (e1, e2) = (b - a, c - a);
+
+        let p = ray.direction.cross(&e2);
+        let det = e1.dot(&p);
+        if det == 0.0 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let s = ray.origin - a;
+        let b1 = s.dot(&p) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let q = s.cross(&e1);
+        let b2 = ray.direction.dot(&q) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = e2.dot(&q) * inv_det;
+        if !(tmin < t && t < tmax) {
+            return None;
+        }
+
+        let b0 = 1.0 - b1 - b2;
+        let [ia, ib, ic] = self.indices[i];
+        let normal = e1.cross(&e2).normalize();
+
+        let mut hr = HitRecord::new(t, ray.direction * t + ray.origin, normal, &*self.material);
+        if !self.normals.is_empty() {
+            let n = self.normals[ia] * b0 + self.normals[ib] * b1 + self.normals[ic] * b2;
+            if n != Vec3::new(0.0) {
+                hr.shading_normal = n.normalize();
+            }
+        }
+        hr.uv = self.uv(i, (b1, b2));
+        let (dpdu, dpdv) = self.derivatives(i, (e1, e2));
+        hr.dpdu = dpdu;
+        hr.dpdv = dpdv;
+        hr.primitive = i;
+        Some(hr)
+    }
+}
+
+impl Hit for TriangleMesh {
+    fn hit(&self, ray : &Ray, tm : (f64, f64)) -> Option<HitRecord<'_>> {
+        match self.built() {
+            Ok(bvh) => bvh.hit(ray, tm, |i, ray, tm| self.hit_triangle(i, ray, tm)),
+            Err(_) => None,
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.positions.iter().fold(Aabb::empty(), |b, p| b.grow(p)))
+    }
+}
@@ -0,0 +1,293 @@
+use std;
+use std::fmt;
+
+use geo::*;
+use sampler::hash;
+
+// 勾配ノイズの種類
+#[derive(Copy, Clone, PartialEq)]
+pub enum Basis {
+    // Perlin の improved noise (2002)
+    Perlin,
+    // 3 次元の simplex noise (Gustavson の実装に従う)
+    Simplex,
+}
+
+impl fmt::Display for Basis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = match self {
+            Basis::Perlin => "Perlin",
+            Basis::Simplex => "Simplex",
+        };
+
+        write!(f, "{}", n)
+    }
+}
+
+impl std::str::FromStr for Basis {
+    type Err = String;
+    fn from_str(s : &str) -> Result<Basis, String> {
+        match s {
+            "Perlin" => Ok(Basis::Perlin),
+            "Simplex" => Ok(Basis::Simplex),
+            _ => Err(format!("unknown noise basis '{}'", s)),
+        }
+    }
+}
+
+// seed で決まる並べ替え表を持つノイズ
+// 同じ seed なら同じ値になる
+#[derive(Clone)]
+pub struct Noise {
+    pub seed : u64,
+    // 0..256 の並べ替えを 2 回続けたもの
+    perm : Vec<u8>,
+}
+
+fn fade(t : f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t : f64, a : f64, b : f64) -> f64 {
+    a + t * (b - a)
+}
+
+// 立方体の辺の中点に向かう 12 方向 (16 個にするため 4 つ重複させる)
+fn perlin_gradient(h : u8, x : f64, y : f64, z : f64) -> f64 {
+    let h = h & 15;
+    let u = if h < 8 {x} else {y};
+    let v = if h < 4 {y} else if h == 12 || h == 14 {x} else {z};
+    (if h & 1 == 0 {u} else {-u}) + (if h & 2 == 0 {v} else {-v})
+}
+
+const SIMPLEX_GRADIENTS : [[f64; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0],
+];
+
+// [0, 1) の値
+fn to_unit(x : u64) -> f64 {
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Noise {
+    pub fn new(seed : u64) -> Noise {
+        let mut perm = vec![0u8; 512];
+        for (i, p) in perm.iter_mut().take(256).enumerate() {
+            *p = i as u8;
+        }
+        // Fisher-Yates
+        for i in (1..256).rev() {
+            let j = (hash(&[seed, i as u64]) % (i as u64 + 1)) as usize;
+            perm.swap(i, j);
+        }
+        for i in 0..256 {
+            perm[256 + i] = perm[i];
+        }
+        Noise{seed, perm}
+    }
+
+    fn p(&self, i : usize) -> usize {
+        self.perm[i] as usize
+    }
+
+    // およそ [-1, 1] の値で、格子点では 0
+    pub fn perlin(&self, v : &Vec3) -> f64 {
+        let (fx, fy, fz) = (v.x.floor(), v.y.floor(), v.z.floor());
+        let (xi, yi, zi) = ((fx as i64 & 255) as usize, (fy as i64 & 255) as usize, (fz as i64 & 255) as usize);
+        let (x, y, z) = (v.x - fx, v.y - fy, v.z - fz);
+        let (u, w, t) = (fade(x), fade(y), fade(z));
+
+        let a = self.p(xi) + yi;
+        let (aa, ab) = (self.p(a) + zi, self.p(a + 1) + zi);
+        let b = self.p(xi + 1) + yi;
+        let (ba, bb) = (self.p(b) + zi, self.p(b + 1) + zi);
+
+        let g = |i : usize, x : f64, y : f64, z : f64| perlin_gradient(self.perm[i], x, y, z);
+        lerp(t,
+            lerp(w,
+                lerp(u, g(aa, x, y, z), g(ba, x - 1.0, y, z)),
+                lerp(u, g(ab, x, y - 1.0, z), g(bb, x - 1.0, y - 1.0, z))),
+            lerp(w,
+                lerp(u, g(aa + 1, x, y, z - 1.0), g(ba + 1, x - 1.0, y, z - 1.0)),
+                lerp(u, g(ab + 1, x, y - 1.0, z - 1.0), g(bb + 1, x - 1.0, y - 1.0, z - 1.0))))
+    }
+
+    // およそ [-1, 1] の値
+    pub fn simplex(&self, v : &Vec3) -> f64 {
+        const F3 : f64 = 1.0 / 3.0;
+        const G3 : f64 = 1.0 / 6.0;
+
+        // 斜交座標で単体を含む立方体を探す
+        let s = (v.x + v.y + v.z) * F3;
+        let (i, j, k) = ((v.x + s).floor(), (v.y + s).floor(), (v.z + s).floor());
+        let t = (i + j + k) * G3;
+        let x0 = [v.x - (i - t), v.y - (j - t), v.z - (k - t)];
+
+        // 立方体を 6 つに分けた単体のどれに入るか
+        let (o1, o2) = if x0[0] >= x0[1] {
+            if x0[1] >= x0[2] {
+                ([1, 0, 0], [1, 1, 0])
+            } else if x0[0] >= x0[2] {
+                ([1, 0, 0], [1, 0, 1])
+            } else {
+                ([0, 0, 1], [1, 0, 1])
+            }
+        } else if x0[1] < x0[2] {
+            ([0, 0, 1], [0, 1, 1])
+        } else if x0[0] < x0[2] {
+            ([0, 1, 0], [0, 1, 1])
+        } else {
+            ([0, 1, 0], [1, 1, 0])
+        };
+
+        let (ii, jj, kk) = ((i as i64 & 255) as usize, (j as i64 & 255) as usize, (k as i64 & 255) as usize);
+        let corners = [([0, 0, 0], 0.0), (o1, G3), (o2, 2.0 * G3), ([1, 1, 1], 3.0 * G3)];
+
+        let n : f64 = corners.iter().map(|&(o, g)| {
+            let d = [x0[0] - o[0] as f64 + g, x0[1] - o[1] as f64 + g, x0[2] - o[2] as f64 + g];
+            let t = 0.6 - d[0] * d[0] - d[1] * d[1] - d[2] * d[2];
+            if t < 0.0 {
+                0.0
+            } else {
+                let gi = self.p(ii + o[0] + self.p(jj + o[1] + self.p(kk + o[2]))) % 12;
+                let gr = SIMPLEX_GRADIENTS[gi];
+                t.powi(4) * (gr[0] * d[0] + gr[1] * d[1] + gr[2] * d[2])
+            }
+        }).sum();
+
+        32.0 * n
+    }
+
+    pub fn gradient(&self, basis : Basis, v : &Vec3) -> f64 {
+        match basis {
+            Basis::Perlin => self.perlin(v),
+            Basis::Simplex => self.simplex(v),
+        }
+    }
+
+    // 周波数を 2 倍, 振幅を 1/2 にしながら octaves 個を重ねる
+    // 振幅の和で割るのでおよそ [-1, 1]
+    pub fn fbm(&self, basis : Basis, v : &Vec3, octaves : usize) -> f64 {
+        self.octaves(v, octaves, |p| self.gradient(basis, p))
+    }
+
+    // fbm の各オクターブの絶対値を重ねたもの ([0, 1])
+    pub fn turbulence(&self, basis : Basis, v : &Vec3, octaves : usize) -> f64 {
+        self.octaves(v, octaves, |p| self.gradient(basis, p).abs())
+    }
+
+    fn octaves<F : Fn(&Vec3) -> f64>(&self, v : &Vec3, octaves : usize, f : F) -> f64 {
+        let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, 1.0);
+        for _ in 0..octaves.max(1) {
+            sum += amplitude * f(&(*v * frequency));
+            total += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum / total
+    }
+
+    // 格子の各セルに 1 つずつ置いた特徴点のうち、最も近いものまでの距離 (Worley)
+    pub fn worley(&self, v : &Vec3) -> f64 {
+        let (fx, fy, fz) = (v.x.floor(), v.y.floor(), v.z.floor());
+        let mut nearest = f64::INFINITY;
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let cell = (fx as i64 + dx, fy as i64 + dy, fz as i64 + dz);
+                    let h = |axis : u64| to_unit(hash(&[self.seed, cell.0 as u64, cell.1 as u64, cell.2 as u64, axis]));
+                    let feature = Vec3{x : cell.0 as f64 + h(0), y : cell.1 as f64 + h(1), z : cell.2 as f64 + h(2)};
+                    let d = feature - *v;
+                    nearest = nearest.min(d.dot(&d));
+                }
+            }
+        }
+        nearest.sqrt()
+    }
+}
+
+// ノイズから作る模様
+#[derive(Copy, Clone, PartialEq)]
+pub enum Pattern {
+    // 勾配ノイズそのもの
+    Noise,
+    Fbm,
+    Turbulence,
+    // 最も近い特徴点までの距離
+    Worley,
+    // x 方向の縞を turbulence で乱したもの
+    Marble,
+    // y 軸まわりの年輪を fbm で乱したもの
+    Wood,
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = match self {
+            Pattern::Noise => "Noise",
+            Pattern::Fbm => "Fbm",
+            Pattern::Turbulence => "Turbulence",
+            Pattern::Worley => "Worley",
+            Pattern::Marble => "Marble",
+            Pattern::Wood => "Wood",
+        };
+
+        write!(f, "{}", n)
+    }
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = String;
+    fn from_str(s : &str) -> Result<Pattern, String> {
+        match s {
+            "Noise" => Ok(Pattern::Noise),
+            "Fbm" => Ok(Pattern::Fbm),
+            "Turbulence" => Ok(Pattern::Turbulence),
+            "Worley" => Ok(Pattern::Worley),
+            "Marble" => Ok(Pattern::Marble),
+            "Wood" => Ok(Pattern::Wood),
+            _ => Err(format!("unknown pattern '{}'", s)),
+        }
+    }
+}
+
+// 3 次元の位置で決まる [0, 1] の値
+#[derive(Clone)]
+pub struct Solid {
+    pub pattern : Pattern,
+    // Worley 以外で使う勾配ノイズ
+    pub basis : Basis,
+    // 位置に掛ける倍率 (大きいほど模様が細かい)
+    pub frequency : f64,
+    // Fbm, Turbulence, Marble, Wood で重ねる数
+    pub octaves : usize,
+    pub noise : Noise,
+}
+
+impl Solid {
+    pub fn new(pattern : Pattern, seed : u64) -> Solid {
+        Solid{pattern, basis : Basis::Perlin, frequency : 1.0, octaves : 6, noise : Noise::new(seed)}
+    }
+
+    pub fn value(&self, p : &Vec3) -> f64 {
+        let q = *p * self.frequency;
+        let n = &self.noise;
+        let v = match self.pattern {
+            Pattern::Noise => 0.5 + 0.5 * n.gradient(self.basis, &q),
+            Pattern::Fbm => 0.5 + 0.5 * n.fbm(self.basis, &q, self.octaves),
+            Pattern::Turbulence => n.turbulence(self.basis, &q, self.octaves),
+            Pattern::Worley => n.worley(&q),
+            Pattern::Marble => {
+                let t = n.turbulence(self.basis, &q, self.octaves);
+                0.5 + 0.5 * (std::f64::consts::PI * (q.x + 4.0 * t)).sin()
+            },
+            Pattern::Wood => {
+                let r = (q.x * q.x + q.z * q.z).sqrt() + 0.4 * n.fbm(self.basis, &q, self.octaves);
+                r.rem_euclid(1.0)
+            },
+        };
+        v.clamp(0.0, 1.0)
+    }
+}
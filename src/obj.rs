@@ -1,110 +1,201 @@
 use geo::*;
+use material::Bsdf;
+
+use std::any::Any;
+use std::f64::consts::PI;
+use std::sync::Arc;
 
 #[derive(Copy, Clone)]
-pub(crate) struct Ray {
+pub struct Ray {
     pub origin : Vec3,
+    // 単位ベクトル
     pub direction: Vec3,
 }
 
 #[derive(Copy, Clone)]
-pub(crate) struct HitRecord {
-    pub(crate) t : f64,
-    pub(crate) point : Vec3,
-    pub(crate) normal : Vec3,
-    pub(crate) reflectance : Vec3,
-    pub(crate) le : Vec3,
-    pub(crate) material :Material,
+pub struct HitRecord<'a> {
+    pub t : f64,
+    pub point : Vec3,
+    // 形状の外側を向いた単位法線
+    pub normal : Vec3,
+    // 陰影付けに使う単位法線 (頂点法線を補間したものなど)
+    pub shading_normal : Vec3,
+    // 表面上の座標 (テクスチャを引くのに使う)
+    pub uv : (f64, f64),
+    // uv に関する point の偏微分 (接空間の向きと、バンプマップの傾きに使う)
+    pub dpdu : Vec3,
+    pub dpdv : Vec3,
+    pub material : &'a dyn Bsdf,
+    // 形状の中での添字 (TriangleMesh の三角形の番号など)
+    pub primitive : usize,
+    // Scene::lights での添字
+    pub(crate) light : Option<usize>,
 }
 
-pub(crate) trait Hit : Copy + Clone + Send + Sync {
-    fn hit(&self, &Ray, (f64, f64)) -> Option<HitRecord>;
+impl<'a> HitRecord<'a> {
+    // dpdu, dpdv は法線から適当に決めた接線と従法線にしておく
+    pub fn new(t : f64, point : Vec3, normal : Vec3, material : &'a dyn Bsdf) -> HitRecord<'a> {
+        let TangentSpace(dpdu, dpdv) = TangentSpace::new(&normal);
+        HitRecord{t, point, normal, shading_normal : normal, uv : (0.0, 0.0), dpdu, dpdv, material, primitive : 0, light : None}
+    }
+
+    // shading_normal に垂直な単位接線と単位従法線
+    // 接線は dpdu の向きで、従法線は dpdv の側を向く
+    pub fn tangent_frame(&self) -> (Vec3, Vec3) {
+        let n = self.shading_normal;
+        let t = self.dpdu - n * n.dot(&self.dpdu);
+        let len = t.dot(&t).sqrt();
+        // dpdu が 0 か法線と平行なら適当な接線にする
+        if len <= 1e-12 * self.dpdu.dot(&self.dpdu).sqrt() || !len.is_finite() {
+            let TangentSpace(t, b) = TangentSpace::new(&n);
+            return (t, b);
+        }
+        let t = t / len;
+        let b = n.cross(&t);
+        (t, if b.dot(&self.dpdv) < 0.0 {-b} else {b})
+    }
+}
+
+// 光線との交差判定
+// Scene::shapes に入れればクレートの外で定義した形状も描画できる
+// Any はシーンファイルに書き出すときに具体的な型を調べるため
+pub trait Hit : Any + Send + Sync {
+    // tm.0 < t < tm.1 の範囲で最も近い交差
+    fn hit(&self, ray : &Ray, tm : (f64, f64)) -> Option<HitRecord<'_>>;
+
+    // 形状を囲む箱 (None なら無限に広がっていて BVH に入れられない)
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
 }
 
 pub mod fresnel {
+    use geo::Vec3;
+
     pub const VACCUM : f64 = 1.0;
     pub const GLASSBK7 : f64 = 1.5168;
-}
 
-#[derive(Copy, Clone)]
-pub enum Material {
-    Diffuse,
-    Mirror,
-    Fresnel(f64),
+    // 金属の複素屈折率 eta + ik (RGB をおよそ 650, 550, 450nm で代表させた値)
+    #[derive(Copy, Clone, PartialEq)]
+    pub struct ComplexIor {
+        pub eta : Vec3,
+        pub k : Vec3,
+    }
+
+    pub const GOLD : ComplexIor = ComplexIor {
+        eta : Vec3{x : 0.143, y : 0.374, z : 1.442},
+        k : Vec3{x : 3.983, y : 2.385, z : 1.603},
+    };
+    pub const COPPER : ComplexIor = ComplexIor {
+        eta : Vec3{x : 0.200, y : 0.924, z : 1.102},
+        k : Vec3{x : 3.912, y : 2.452, z : 2.142},
+    };
+    pub const ALUMINIUM : ComplexIor = ComplexIor {
+        eta : Vec3{x : 1.657, y : 0.880, z : 0.521},
+        k : Vec3{x : 9.224, y : 6.270, z : 4.837},
+    };
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Sphere {
     pub point : Vec3,
     pub radius : f64,
-    pub material : Material,
-    pub reflectance : Vec3,
-    pub le : Vec3,
+    pub material : Arc<dyn Bsdf>,
 }
 
-unsafe impl Send for Sphere {}
+// 中心 center, 半径 radius の球と光線が tmin < t < tmax で最初に交わる t
+pub(crate) fn sphere_intersect(center : &Vec3, radius : f64, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<f64> {
+    let op = *center - ray.origin;
+    let b = op.dot(&ray.direction);
+    let det = b.powi(2) - op.dot(&op) + radius.powi(2);
+    if det >= 0.0 {
+        let det_sqrt = det.sqrt();
+        let t1 = b - det_sqrt;
+        let t2 = b + det_sqrt;
 
-impl Hit for Sphere {
-    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord> {
-        let op = self.point - ray.origin;
-        let b = op.dot(&ray.direction);
-        let det = b.powi(2) - op.dot(&op) + self.radius.powi(2);
-        if det >= 0.0 {
-            let det_sqrt = det.sqrt();
-            let t1 = b - det_sqrt;
-            let t2 = b + det_sqrt;
-
-            let hr = |t| {
-                let point = ray.direction * t + ray.origin;
-                Some(HitRecord{
-                    t,
-                    point,
-                    normal : (point - self.point) / self.radius,
-                    reflectance : self.reflectance,
-                    le : self.le,
-                    material :self.material,
-                })
-            };
-
-            if tmin < t1 && t1 < tmax {
-                hr(t1)
-            } else if tmin < t2 && t2 < tmax {
-                hr(t2)
-            } else {
-                None
-            }
+        if tmin < t1 && t1 < tmax {
+            Some(t1)
+        } else if tmin < t2 && t2 < tmax {
+            Some(t2)
         } else {
             None
         }
+    } else {
+        None
     }
 }
 
-#[derive(Copy, Clone)]
+// 中心 center の球面上の点 point での HitRecord (法線は (point - center) / radius)
+pub(crate) fn sphere_hit_record<'a>(center : &Vec3, radius : f64, t : f64, point : Vec3, material : &'a dyn Bsdf) -> HitRecord<'a> {
+    let mut hr = HitRecord::new(t, point, (point - *center) / radius, material);
+    hr.uv = sphere_uv(center, &point);
+
+    // uv に関する偏微分 (極では dpdv の向きが決まらないので HitRecord::new のまま)
+    let r = radius.abs();
+    let d = (point - *center) / r;
+    let sin = (d.x.powi(2) + d.z.powi(2)).sqrt();
+    if sin >= 1e-12 {
+        hr.dpdu = Vec3::new((-d.z, 0.0, d.x)) * (2.0 * PI * r);
+        hr.dpdv = Vec3::new((-d.y * d.x / sin, sin, -d.y * d.z / sin)) * (PI * r);
+    }
+    hr
+}
+
+fn sphere_uv(center : &Vec3, point : &Vec3) -> (f64, f64) {
+    let d = (*point - *center).normalize();
+    let u = (d.x.atan2(-d.z) / (2.0 * PI)).rem_euclid(1.0);
+    let v = 1.0 - d.y.clamp(-1.0, 1.0).acos() / PI;
+    (u, v)
+}
+
+impl Sphere {
+    // 球面上の点の (経度, 緯度) を [0, 1] にしたもの
+    // u は -z 方向から x 方向へ回り、v は +y の極で 1
+    pub fn uv(&self, point : &Vec3) -> (f64, f64) {
+        sphere_uv(&self.point, point)
+    }
+}
+
+impl Hit for Sphere {
+    fn hit(&self, ray : &Ray, tm : (f64, f64)) -> Option<HitRecord<'_>> {
+        let t = sphere_intersect(&self.point, self.radius, ray, tm)?;
+        Some(sphere_hit_record(&self.point, self.radius, t, ray.direction * t + ray.origin, &*self.material))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius.abs());
+        Some(Aabb{min : self.point - r, max : self.point + r})
+    }
+}
+
+#[derive(Clone)]
 pub struct Plane {
     pub normal : Vec3,
     pub point : Vec3,
-    pub material : Material,
-    pub reflectance : Vec3,
-    pub le : Vec3,
+    pub material : Arc<dyn Bsdf>,
 }
 
-unsafe impl Send for Plane {}
+impl Plane {
+    // point を原点とし、法線に垂直な 2 軸に沿って長さ 1 ごとに並ぶ座標
+    pub fn uv(&self, p : &Vec3) -> (f64, f64) {
+        let TangentSpace(t, b) = TangentSpace::new(&self.normal.normalize());
+        let d = *p - self.point;
+        (d.dot(&t), d.dot(&b))
+    }
+}
 
 impl Hit for Plane {
-    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord> {
+    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord<'_>> {
         let nd = self.normal.dot(&ray.direction);
 
         if nd != 0.0 {
             // normal * (t * ray.direction + ray.origin - point) == 0
             let t = self.normal.dot(&(self.point - ray.origin)) / nd;
             if tmin < t && t < tmax {
-                return Some(HitRecord {
-                    t : t,
-                    point : ray.direction * t + ray.origin,
-                    normal : self.normal.normalize(),
-                    reflectance : self.reflectance,
-                    le : self.le,
-                    material : self.material,
-                });
+                let point = ray.direction * t + ray.origin;
+                // uv の軸は HitRecord::new の dpdu, dpdv と同じ
+                let hr = HitRecord::new(t, point, self.normal.normalize(), &*self.material);
+                return Some(HitRecord{uv : self.uv(&point), .. hr});
             }
         }
 
@@ -112,16 +203,12 @@ impl Hit for Plane {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Polygon {
     pub points : [Vec3; 3],
-    pub material : Material,
-    pub reflectance : Vec3,
-    pub le : Vec3,
+    pub material : Arc<dyn Bsdf>,
 }
 
-unsafe impl Send for Polygon {}
-
 impl Polygon {
     fn normal(&self) -> Option<Vec3> {
         let [a, b, c] = self.points;
@@ -132,32 +219,34 @@ impl Polygon {
             None
         }
     }
+
+    // 重心座標 (b1, b2) (point = a + b1 (b - a) + b2 (c - a))
+    pub fn uv(&self, point : &Vec3) -> (f64, f64) {
+        let [a, b, c] = self.points;
+        let (e1, e2, d) = (b - a, c - a, *point - a);
+        let n = e1.cross(&e2);
+        let nn = n.dot(&n);
+        (d.cross(&e2).dot(&n) / nn, e1.cross(&d).dot(&n) / nn)
+    }
 }
 
 impl Hit for Polygon {
-    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord> {
+    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord<'_>> {
         if let Some(normal) = self.normal().map(Vec3::normalize) {
             let nd = normal.dot(&ray.direction);
             if nd != 0.0 {
-                let [a, b, c] = self.points;
+                let a = self.points[0];
                 // normal * (t * ray.direction + ray.origin - a) == 0
                 let t = normal.dot(&(a - ray.origin)) / nd;
                 if tmin < t && t < tmax {
                     let point = ray.direction * t + ray.origin;
 
-                    let ap = (a - c).cross(&(point - a)).normalize();
-                    let bp = (b - a).cross(&(point - b)).normalize();
-                    let cp = (c - b).cross(&(point - c)).normalize();
-                    
-                    if ap == bp && ap == cp {
-                        return Some(HitRecord {
-                            t,
-                            point,
-                            normal,
-                            reflectance : self.reflectance,
-                            le : self.le,
-                            material : self.material,
-                        });
+                    // 重心座標がすべて 0 以上なら三角形の内側
+                    let (b1, b2) = self.uv(&point);
+                    if b1 >= 0.0 && b2 >= 0.0 && b1 + b2 <= 1.0 {
+                        let [a, b, c] = self.points;
+                        let hr = HitRecord::new(t, point, normal, &*self.material);
+                        return Some(HitRecord{uv : (b1, b2), dpdu : b - a, dpdv : c - a, .. hr});
                     }
                 }
             }
@@ -165,4 +254,9 @@ impl Hit for Polygon {
 
         None
     }
-}
\ No newline at end of file
+
+    fn bounds(&self) -> Option<Aabb> {
+        let [a, b, c] = self.points;
+        Some(Aabb{min : a, max : a}.grow(&b).grow(&c))
+    }
+}
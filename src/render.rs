@@ -4,15 +4,18 @@ use rayon::prelude::*;
 use geo::*;
 use obj::*;
 use env::*;
+use light;
+use material;
+use sampler::{Sampler, SamplerKind};
+use film::{Film, Filter};
+use tonemap::{Tonemap, luminance};
 
 use std;
 use std::fmt;
 
-extern crate rand;
-use render::rand::random;
-
 pub enum RenderMode {
     Shade,
+    // 法線の各成分を [-1, 1] から [0, 1] に写した色
     Normal,
     NormalColor,
     Depth(f64),
@@ -34,13 +37,65 @@ impl fmt::Display for RenderMode {
     }
 }
 
+// "Shade", "Normal", "NormalColor", "Depth:500", "DepthNormalColor:500"
+// Depth の範囲を省略したときは 500
+impl std::str::FromStr for RenderMode {
+    type Err = String;
+    fn from_str(s : &str) -> Result<RenderMode, String> {
+        let mut it = s.splitn(2, ':');
+        let name = it.next().unwrap_or("");
+        let range = match it.next() {
+            None => 500.0,
+            Some(d) => match d.parse::<f64>() {
+                Ok(d) if d > 0.0 => d,
+                _ => return Err(format!("invalid depth range '{}'", d)),
+            },
+        };
+
+        match name {
+            "Shade" => Ok(RenderMode::Shade),
+            "Normal" => Ok(RenderMode::Normal),
+            "NormalColor" => Ok(RenderMode::NormalColor),
+            "Depth" => Ok(RenderMode::Depth(range)),
+            "DepthNormalColor" => Ok(RenderMode::DepthNormalColor(range)),
+            _ => Err(format!("unknown render mode '{}'", s)),
+        }
+    }
+}
+
+// 適応的サンプリング
+// 画素ごとに pass_spp ずつサンプルを足し、輝度の平均の相対誤差が threshold 以下になったら止める
+// 画素ごとのサンプル数の上限は RenderSetting::spp
+#[derive(Copy, Clone, PartialEq)]
+pub struct Adaptive {
+    pub threshold : f64,
+    pub pass_spp : usize,
+}
+
 pub struct RenderSetting {
     pub window_size : (usize, usize),
     pub spp : usize,
-    pub reflect_n : usize,
+    // 反射回数の上限 (None なら Russian roulette だけで打ち切る)
+    pub reflect_n : Option<usize>,
+    // この反射回数以降は Russian roulette で経路を打ち切る
+    pub roulette_depth : Option<usize>,
     pub camera : Camera,
     pub scene : Scene,
     pub mode : RenderMode,
+    // 光源を直接サンプリングし、MIS で BSDF のサンプリングと組み合わせる
+    // false でも Scene::analytic_lights だけはサンプリングする
+    pub light_sampling : bool,
+    // Shade 以外のモードで何にも当たらなかった画素の色
+    pub background : Vec3,
+    pub sampler : SamplerKind,
+    // 乱数列は (seed, 画素, サンプルの番号) で決まり、スレッド数によらず同じ画像になる
+    pub seed : u64,
+    // Shade の画素の再構成フィルタ
+    pub filter : Filter,
+    // 8 bit, 16 bit の画像にするときの変換 (HDR 形式の出力には使わない)
+    pub tonemap : Tonemap,
+    // None ならすべての画素に spp 個のサンプルを使う
+    pub adaptive : Option<Adaptive>,
 }
 
 impl Default for RenderSetting {
@@ -48,147 +103,347 @@ impl Default for RenderSetting {
         RenderSetting {
             window_size : (1200, 800),
             spp : 1000,
-            reflect_n : 10,
+            reflect_n : Some(10),
+            roulette_depth : Some(3),
             camera : Default::default(),
             scene : Default::default(),
             mode : RenderMode::Shade,
+            light_sampling : true,
+            background : Vec3::new(0.0),
+            sampler : SamplerKind::Sobol,
+            seed : 0,
+            filter : Filter::default(),
+            tonemap : Tonemap::default(),
+            adaptive : None,
         }
     }
 }
 
-fn tonemap(v : Vec3) -> (u8, u8, u8) {
-    let f = |a : f64| ((a.abs().powf(1.0 / 2.2) * 255.0) as i32).max(0).min(255) as u8;
-    (f(v.x), f(v.y), f(v.z))
+// トーンマップ前の線形な値を持つ画像
+// pixels は上の行から順に並ぶ
+pub struct Framebuffer {
+    pub size : (usize, usize),
+    pub pixels : Vec<Vec3>,
+    // 画素ごとに使ったサンプル数 (Shade 以外では 1)
+    pub samples : Vec<usize>,
+}
+
+// 0 から 1 の値を青, 緑, 黄, 赤と変わる色にする
+fn heat(t : f64) -> Vec3 {
+    let stops = [(0.0, 0.0, 1.0), (0.0, 1.0, 0.0), (1.0, 1.0, 0.0), (1.0, 0.0, 0.0)];
+    let x = t.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+    let i = (x as usize).min(stops.len() - 2);
+    let f = x - i as f64;
+    Vec3::new(stops[i]) * (1.0 - f) + Vec3::new(stops[i + 1]) * f
+}
+
+impl Framebuffer {
+    // 画素ごとのサンプル数を、最も多い画素に対する割合で色にした画像
+    pub fn sample_heatmap(&self) -> Framebuffer {
+        let max = self.samples.iter().cloned().max().unwrap_or(0).max(1);
+        Framebuffer {
+            size : self.size,
+            pixels : self.samples.iter().map(|&n| heat(n as f64 / max as f64)).collect(),
+            samples : self.samples.clone(),
+        }
+    }
+
+    pub fn to_rgb8(&self, tonemap : &Tonemap) -> Vec<(u8, u8, u8)> {
+        self.pixels.iter().enumerate().map(|(i, v)| tonemap.to_rgb8(*v, i)).collect()
+    }
+
+    pub fn to_rgb16(&self, tonemap : &Tonemap) -> Vec<(u16, u16, u16)> {
+        self.pixels.iter().enumerate().map(|(i, v)| tonemap.to_rgb16(*v, i)).collect()
+    }
 }
 
 pub fn run(rs : &RenderSetting) -> Result<Vec<(u8, u8, u8)>, rayon::ThreadPoolBuildError> {
-    
+    Ok(render(rs).to_rgb8(&rs.tonemap))
+}
+
+pub fn render(rs : &RenderSetting) -> Framebuffer {
+    let (pixels, samples) = radiance(rs);
+    Framebuffer {
+        size : rs.window_size,
+        pixels,
+        samples,
+    }
+}
+
+fn power_heuristic(a : f64, b : f64) -> f64 {
+    if a == 0.0 && b == 0.0 {
+        0.0
+    } else {
+        a.powi(2) / (a.powi(2) + b.powi(2))
+    }
+}
+
+// 光源 l の ls が点 p から遮られずに見えるか
+fn unoccluded(scene : &Scene, p : &Vec3, l : usize, ls : &light::LightSample) -> bool {
+    let shadow = Ray{origin : *p, direction : ls.direction};
+    let eps = 0.1f64.powi(4);
+    if ls.analytic {
+        // 光源の手前までに何もなければよい
+        return scene.hit(&shadow, (eps, ls.distance * (1.0 - eps))).is_none();
+    }
+    // 最初に当たるのが選んだ光源なら遮られていない
+    match scene.hit(&shadow, (eps, ls.distance * (1.0 + eps) + eps)) {
+        Some(h) => h.light == Some(l),
+        None => ls.distance.is_infinite(),
+    }
+}
+
+// 点 hr から lights の範囲の光源をひとつ選んで直接光を見積もる
+// wo は視点側に向かう方向、u_select は光源の選択に、u は光源上の点の選択に使う
+fn direct_light(scene : &Scene, lights : std::ops::Range<usize>, hr : &HitRecord, wo : &Vec3, u_select : f64, u : (f64, f64)) -> Vec3 {
+    if lights.is_empty() {
+        return Vec3::new(0.0);
+    }
+
+    let l = lights.start + ((u_select * lights.len() as f64) as usize).min(lights.len() - 1);
+    let ls = match light::sample(scene, l, &hr.point, u, 1.0 / lights.len() as f64) {
+        Some(ls) => ls,
+        None => return Vec3::new(0.0),
+    };
+
+    let f = hr.material.evaluate(hr, wo, &ls.direction);
+    let cos = hr.shading_normal.dot(&ls.direction).abs();
+    if f.x.max(f.y.max(f.z)) <= 0.0 || cos == 0.0 || ls.pdf <= 0.0 {
+        return Vec3::new(0.0);
+    }
+
+    if !unoccluded(scene, &hr.point, l, &ls) {
+        return Vec3::new(0.0);
+    }
+
+    // BSDF のサンプリングでは当たらない光源なら MIS をしない
+    let weight = if ls.analytic {1.0} else {power_heuristic(ls.pdf, hr.material.pdf(hr, wo, &ls.direction))};
+    ls.le * f * (cos / ls.pdf * weight)
+}
+
+// プレビュー用に analytic_lights をすべて足した、点 hr での拡散反射
+// 光源がなければ None
+fn analytic_shade(scene : &Scene, hr : &HitRecord) -> Option<Vec3> {
+    let lights = scene.analytic_range();
+    if lights.is_empty() {
+        return None;
+    }
+    let e = lights.fold(Vec3::new(0.0), |e, l| {
+        match light::sample(scene, l, &hr.point, (0.5, 0.5), 1.0) {
+            Some(ls) if unoccluded(scene, &hr.point, l, &ls) => {
+                e + ls.le * (hr.shading_normal.dot(&ls.direction).max(0.0) / ls.pdf)
+            },
+            _ => e,
+        }
+    });
+    Some(hr.material.reflectance(hr) * e / std::f64::consts::PI)
+}
+
+// カメラからの光線 ray が運んでくる放射輝度をひとつ見積もる
+fn trace(rs : &RenderSetting, mut ray : Ray, sampler : &mut dyn Sampler) -> Vec3 {
+    let mut sum = Vec3::new(0.0);
+    let mut thp = Vec3::new(1.0);
+
+    // 直前の反射が specular でなかったときの (位置, BSDF による方向の確率密度)
+    // 光源に当たったときの MIS の重みに使う
+    let mut bsdf_vertex : Option<(Vec3, f64)> = None;
+
+    'reflect: for depth in 0.. {
+        if rs.reflect_n.map(|n| depth >= n).unwrap_or(false) {
+            break 'reflect;
+        }
+
+        // 使わなかった値も取り出して、反射ごとに同じ次元を使う
+        let u_select = sampler.get_1d();
+        let u_light = sampler.get_2d();
+        let u_bsdf = sampler.get_2d();
+        let u_component = sampler.get_1d();
+        let u_roulette = sampler.get_1d();
+
+        let hr = match rs.scene.hit(&ray, (0.1f64.powi(4), 10.0f64.powi(10))) {
+            Some(hr) => hr,
+            None => {
+                if let Some(env) = rs.scene.environment() {
+                    let weight = match bsdf_vertex {
+                        Some((_, bsdf_pdf)) if rs.light_sampling => {
+                            power_heuristic(bsdf_pdf, light::environment_pdf(&rs.scene, &ray.direction))
+                        },
+                        _ => 1.0,
+                    };
+                    sum = sum + thp * env.radiance(&ray.direction) * weight;
+                }
+                break 'reflect;
+            },
+        };
+        let wo = -ray.direction;
+        let hr = material::shading_point(hr, &wo);
+
+        let weight = match (bsdf_vertex, hr.light) {
+            (Some((p, bsdf_pdf)), Some(l)) if rs.light_sampling => {
+                power_heuristic(bsdf_pdf, light::pdf(&rs.scene, l, &p, &ray.direction, &hr))
+            },
+            _ => 1.0,
+        };
+        sum = sum + thp * hr.material.emitted(&hr, &wo) * weight;
+
+        // 反射回数の上限を超える光は総当たりでも数えないので加えない
+        // analytic_lights には光線が当たらないので、light_sampling でなくてもサンプリングする
+        if !hr.material.is_specular() && rs.reflect_n.map(|n| depth + 1 < n).unwrap_or(true) {
+            let lights = if rs.light_sampling {0..rs.scene.lights().len()} else {rs.scene.analytic_range()};
+            sum = sum + thp * direct_light(&rs.scene, lights, &hr, &wo, u_select, u_light);
+        }
+
+        let bs = match hr.material.sample(&hr, &wo, u_bsdf, u_component) {
+            Some(bs) => bs,
+            None => break 'reflect,
+        };
+        bsdf_vertex = if bs.specular {
+            None
+        } else {
+            Some((hr.point, bs.pdf))
+        };
+
+        // Update Ray
+        ray = Ray {
+            origin : hr.point,
+            direction : bs.direction,
+        };
+
+        // Update throughput
+        thp = thp * bs.weight;
+
+        if thp.x.max(thp.y.max(thp.z)) == 0.0 {
+            break 'reflect;
+        }
+
+        // 生き残った経路を 1 / q 倍するので期待値は変わらない
+        // 反射率 1 の鏡の間を往復する経路も終わるように、q は 1 未満に抑える
+        if rs.roulette_depth.map(|d| depth + 1 >= d).unwrap_or(false) {
+            let q = thp.x.max(thp.y.max(thp.z)).min(0.95);
+            if u_roulette >= q {
+                break 'reflect;
+            }
+            thp = thp / q;
+        }
+    }
+
+    sum
+}
+
+// Shade でひとつのタスクが受け持つ行の数
+const TILE_ROWS : usize = 8;
+
+// 画素のサンプルの輝度の平均と分散 (Welford の方法)
+#[derive(Copy, Clone, Default)]
+struct PixelStats {
+    count : usize,
+    mean : f64,
+    m2 : f64,
+}
+
+impl PixelStats {
+    fn add(&mut self, v : f64) {
+        self.count += 1;
+        let d = v - self.mean;
+        self.mean += d / self.count as f64;
+        self.m2 += d * (v - self.mean);
+    }
+
+    // 平均の標準誤差と平均の比
+    fn relative_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let variance = self.m2 / (self.count - 1) as f64;
+        (variance / self.count as f64).sqrt() / self.mean.abs().max(1e-4)
+    }
+}
+
+// Shade のサンプルをフィルタの重みで周りの画素に配り、画素ごとのサンプル数を返す
+// タイルごとに並列に足し込んで上から順に Film にまとめるので、結果はスレッド数によらない
+// adaptive なら、誤差が大きい画素にだけサンプルを足すパスを繰り返す
+fn shade(rs : &RenderSetting) -> (Vec<Vec3>, Vec<usize>) {
+    let (w, h) = rs.window_size;
+    let mut film = Film::new(rs.window_size, rs.filter);
+    let mut stats = vec![PixelStats::default(); w * h];
+    let pass_spp = rs.adaptive.map(|a| a.pass_spp.max(2)).unwrap_or(rs.spp);
+
+    loop {
+        // 周り 3x3 の画素の誤差の最大で判定して、たまたま最初のサンプルがそろった画素を止めないようにする
+        let active : Vec<bool> = (0..w * h).into_par_iter().map(|i| {
+            let st = &stats[i];
+            let (x, y) = (i % w, i / w);
+            st.count < rs.spp && (st.count == 0 || rs.adaptive.map(|a| {
+                (y.saturating_sub(1)..(y + 2).min(h))
+                    .flat_map(|y| (x.saturating_sub(1)..(x + 2).min(w)).map(move |x| y * w + x))
+                    .any(|j| stats[j].relative_error() > a.threshold)
+            }).unwrap_or(false))
+        }).collect();
+        if !active.contains(&true) {
+            break;
+        }
+
+        let tiles : Vec<_> = stats.par_chunks_mut(TILE_ROWS * w).zip(active.par_chunks(TILE_ROWS * w)).enumerate()
+            .map(|(t, (stats, active))| {
+                let rows = t * TILE_ROWS..((t + 1) * TILE_ROWS).min(h);
+                let mut tile = film.tile(rows.clone());
+                let mut sampler = rs.sampler.sampler(rs.spp, rs.seed);
+
+                for (k, st) in stats.iter_mut().enumerate().filter(|&(k, _)| active[k]) {
+                    let (px, py) = (k % w, rows.start + k / w);
+                    for s in st.count..(st.count + pass_spp).min(rs.spp) {
+                        sampler.start_pixel_sample((px, py), s);
+                        let (jx, jy) = sampler.get_2d();
+                        let lens = sampler.get_2d();
+                        // Film は下向き、カメラは上向きの座標
+                        let (fx, fy) = (px as f64 + jx, py as f64 + jy);
+                        let ray = rs.camera.create_ray((w as f64, h as f64), (fx, h as f64 - fy), lens);
+                        let v = trace(rs, ray, &mut *sampler);
+                        tile.add_sample((fx, fy), v);
+                        st.add(luminance(&v));
+                    }
+                }
+                tile
+            }).collect();
+
+        for tile in tiles {
+            film.merge(tile);
+        }
+    }
+
+    (film.pixels(), stats.iter().map(|st| st.count).collect())
+}
+
+// 画素の値とサンプル数
+fn radiance(rs : &RenderSetting) -> (Vec<Vec3>, Vec<usize>) {
+    if let RenderMode::Shade = rs.mode {
+        return shade(rs);
+    }
+
     let (w, h) = rs.window_size;
 
     let colors : Vec<_> = (0..w*h).into_par_iter()
         .map(|i| {
-            let i : f64 = i as f64;
             let (w, h) = (w as f64, h as f64);
-            let (x, y) = (i % w, h - i / w);
+            // 画素の中心
+            let (x, y) = ((i % rs.window_size.0) as f64 + 0.5, h - (i / rs.window_size.0) as f64 - 0.5);
 
             let c = &rs.camera;
 
-            let create_ray = |rx, ry| c.create_ray((w, h), (rx, ry));
+            let create_ray = |rx, ry| c.create_ray((w, h), (rx, ry), (0.5, 0.5));
             
             let v : Vec3 = match rs.mode {
-                RenderMode::Shade => (0..rs.spp).into_par_iter().map(|_|{
-                    let mut ray = create_ray(x + random::<f64>(), y + random::<f64>());
-
-                    let mut sum = Vec3::new(0.0);
-                    let mut thp = Vec3::new(1.0);
-
-                    'reflect: for _depth in 0..rs.reflect_n {
-                        let h = rs.scene.hit(&ray, (0.1f64.powi(4), 10.0f64.powi(10)));
-
-                        if let Some(hr) = h {
-                            sum = sum + thp * hr.le;
-
-                            let reflect_mirror = move || {
-                                let wi = -ray.direction;
-                                hr.normal * 2.0 * wi.dot(&hr.normal) - wi
-                            };
-
-                            // Update Ray
-                            ray = Ray {
-                                origin : hr.point,
-                                direction : match hr.material {
-                                    Material::Diffuse => {
-                                        let n = hr.normal * if hr.normal.dot(&-ray.direction) > 0.0 {
-                                            1.0
-                                        } else {
-                                            -1.0
-                                        };
-
-                                        let (u, v) = {
-                                            let t = TangentSpace::new(&n);
-                                            (t.0, t.1)
-                                        };
-                                        
-                                        let d = {
-                                            let r = random::<f64>().sqrt();
-                                            let t : f64 = 2.0 * std::f64::consts::PI * random::<f64>();
-                                            let (x, y) = (r * t.cos(), r * t.sin());
-                                            Vec3{x, y,
-                                                z : 0.0f64.max(1.0 - x.powi(2) - y.powi(2)).sqrt()
-                                            }
-                                        };
-                                        u * d.x + v * d.y + n * d.z
-                                    },
-
-                                    Material::Mirror => reflect_mirror(),
-                                    
-                                    Material::Fresnel(ior) => {
-                                        let wi = -ray.direction;
-                                        let into = wi.dot(&hr.normal) > 0.0;
-                                        let n = hr.normal * if into {1.0} else {-1.0};
-                                        let eta = if into {
-                                            1.0 / ior
-                                        } else {
-                                            ior
-                                        };
-
-                                        let wt = {
-                                            // Snell's law (vector form)
-                                            let t = wi.dot(&n);
-                                            let t2 = 1.0 - eta.powi(2) * (1.0 - t.powi(2));
-                                            if t2 < 0.0 {
-                                                None
-                                            } else {
-                                                Some((n * t - wi) * eta - n * t2.sqrt())
-                                            }
-                                        };
-
-                                        if let Some(wt) = wt {
-                                            // Schlick's approximation
-                                            let fr = {
-                                                let cos = if into {
-                                                    wi.dot(&hr.normal)
-                                                } else {
-                                                    wt.dot(&hr.normal)
-                                                };
-                                                let r = (1.0 - ior) / (1.0 + ior);
-                                                r.powi(2) + (1.0 - r.powi(2)) * (1.0 - cos).powi(5)
-                                            };
-                                            // Select reflection or refraction
-                                            // according to the fresnel term
-
-                                            if random::<f64>() < fr {
-                                                reflect_mirror()
-                                            } else {
-                                                wt
-                                            }
-                                        } else {
-                                            // Total internal reflection
-                                            reflect_mirror()
-                                        }
-                                    }
-                                }
-                            };
-                            
-                            // Update throughput
-                            thp = thp * hr.reflectance;
-                        }
-                        
-                        if thp.x.max(thp.y.max(thp.z)) == 0.0 {
-                            break 'reflect;
-                        }
-                    }
-                    sum / (rs.spp as f64)
-                }).reduce(|| Vec3::new(0.0), |s, x| s + x),
+                RenderMode::Shade => unreachable!(),
 
                 RenderMode::Normal => {
                     let ray = create_ray(x, y);
 
                     let h = rs.scene.hit(&ray, c.tm);
                     if let Some(hr) = h {
-                        hr.normal
+                        let hr = material::shading_point(hr, &-ray.direction);
+                        hr.shading_normal * 0.5 + Vec3::new(0.5)
                     } else {
-                        Vec3::new(0.0)
+                        rs.background
                     }
                 },
                 RenderMode::NormalColor => {
@@ -196,9 +451,12 @@ pub fn run(rs : &RenderSetting) -> Result<Vec<(u8, u8, u8)>, rayon::ThreadPoolBu
 
                     let h = rs.scene.hit(&ray, c.tm);
                     if let Some(hr) = h {
-                        hr.reflectance * hr.normal.dot(&&-ray.direction)
+                        let hr = material::shading_point(hr, &-ray.direction);
+                        // analytic_lights があればそれで照らし、なければ視点から照らす
+                        analytic_shade(&rs.scene, &hr)
+                            .unwrap_or_else(|| hr.material.reflectance(&hr) * hr.shading_normal.dot(&-ray.direction))
                     } else {
-                        Vec3::new(0.0)
+                        rs.background
                     }
                 },
                 RenderMode::Depth(d) => {
@@ -208,7 +466,7 @@ pub fn run(rs : &RenderSetting) -> Result<Vec<(u8, u8, u8)>, rayon::ThreadPoolBu
                     if let Some(hr) = h {
                         Vec3::new(1.0 - hr.t / d)
                     } else {
-                        Vec3::new(0.0)
+                        rs.background
                     }
                 },
                 RenderMode::DepthNormalColor(d) => {
@@ -216,15 +474,16 @@ pub fn run(rs : &RenderSetting) -> Result<Vec<(u8, u8, u8)>, rayon::ThreadPoolBu
 
                     let h = rs.scene.hit(&ray, c.tm);
                     if let Some(hr) = h {
-                        let r = hr.reflectance * hr.normal.dot(&&-ray.direction);
+                        let hr = material::shading_point(hr, &-ray.direction);
+                        let r = hr.material.reflectance(&hr) * hr.shading_normal.dot(&-ray.direction);
                         r / r.x.max(r.y.max(r.z)) * (1.0 - hr.t / d)
                     } else {
-                        Vec3::new(0.0)
+                        rs.background
                     }
                 }
             };
-            tonemap(v)
+            v
         }).collect();
     
-    Ok(colors)
+    (colors, vec![1; w * h])
 }
\ No newline at end of file
@@ -0,0 +1,352 @@
+use std;
+use std::fmt;
+
+// [0, 1) の乱数列を画素とサンプルごとに作るもの
+// 値は次元の順に取り出し、同じ次元は同じ画素のサンプル間でよく散らばる
+pub trait Sampler {
+    // 画素 pixel の index 番目のサンプルを始め、次元を 0 に戻す
+    fn start_pixel_sample(&mut self, pixel : (usize, usize), index : usize);
+    fn get_1d(&mut self) -> f64;
+    // 2 次元を使う
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl fmt::Display for SamplerKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = match self {
+            SamplerKind::Independent => "Independent",
+            SamplerKind::Stratified => "Stratified",
+            SamplerKind::Halton => "Halton",
+            SamplerKind::Sobol => "Sobol",
+        };
+
+        write!(f, "{}", n)
+    }
+}
+
+impl std::str::FromStr for SamplerKind {
+    type Err = String;
+    fn from_str(s : &str) -> Result<SamplerKind, String> {
+        match s {
+            "Independent" => Ok(SamplerKind::Independent),
+            "Stratified" => Ok(SamplerKind::Stratified),
+            "Halton" => Ok(SamplerKind::Halton),
+            "Sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("unknown sampler '{}'", s)),
+        }
+    }
+}
+
+impl SamplerKind {
+    // spp は画素ごとのサンプル数 (Stratified の層の数を決める)
+    pub fn sampler(&self, spp : usize, seed : u64) -> Box<dyn Sampler> {
+        match *self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(spp, seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+// splitmix64 の出力関数
+fn mix(mut x : u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+pub(crate) fn hash(values : &[u64]) -> u64 {
+    values.iter().fold(0, |h, &v| mix(h ^ mix(v)))
+}
+
+// 上位 53 bit を [0, 1) の値にする
+fn to_unit(x : u64) -> f64 {
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn to_unit32(x : u32) -> f64 {
+    x as f64 / (1u64 << 32) as f64
+}
+
+// 画素のサンプルごとの乱数の流れ
+#[derive(Copy, Clone)]
+struct Stream(u64);
+
+impl Stream {
+    fn new(seed : u64, pixel : (usize, usize), index : usize) -> Stream {
+        Stream(hash(&[seed, pixel.0 as u64, pixel.1 as u64, index as u64]))
+    }
+
+    fn next(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        to_unit(mix(self.0))
+    }
+}
+
+// 次元ごとに独立な一様乱数
+pub struct IndependentSampler {
+    seed : u64,
+    stream : Stream,
+}
+
+impl IndependentSampler {
+    pub fn new(seed : u64) -> IndependentSampler {
+        IndependentSampler{seed, stream : Stream(seed)}
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel : (usize, usize), index : usize) {
+        self.stream = Stream::new(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.stream.next()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.stream.next(), self.stream.next())
+    }
+}
+
+// [0, n) の並べ替えの i 番目 (Kensler, "Correlated Multi-Jittered Sampling")
+fn permute(mut i : u32, n : u32, p : u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    i.wrapping_add(p) % n
+}
+
+// 次元ごとに spp 個の層に分け、サンプルの番号を層に並べ替えて割り当てる
+// 2 次元は縦横の格子に分ける
+pub struct StratifiedSampler {
+    spp : u32,
+    // 2 次元の格子の横の数
+    columns : u32,
+    seed : u64,
+    pixel_seed : u64,
+    index : u32,
+    dimension : u64,
+    stream : Stream,
+}
+
+impl StratifiedSampler {
+    pub fn new(spp : usize, seed : u64) -> StratifiedSampler {
+        let spp = spp.clamp(1, u32::MAX as usize) as u32;
+        StratifiedSampler {
+            spp,
+            columns : (spp as f64).sqrt().ceil() as u32,
+            seed,
+            pixel_seed : seed,
+            index : 0,
+            dimension : 0,
+            stream : Stream(seed),
+        }
+    }
+
+    // この次元で index が入る層
+    fn stratum(&mut self, n : u32) -> u32 {
+        let p = hash(&[self.pixel_seed, self.dimension]) as u32;
+        self.dimension += 1;
+        permute(self.index % n, n, p)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel : (usize, usize), index : usize) {
+        self.pixel_seed = hash(&[self.seed, pixel.0 as u64, pixel.1 as u64]);
+        self.index = index as u32;
+        self.dimension = 0;
+        self.stream = Stream::new(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let s = self.stratum(self.spp);
+        (s as f64 + self.stream.next()) / self.spp as f64
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        // spp が平方数でなければ空の層ができる
+        let (nx, ny) = (self.columns, self.spp.div_ceil(self.columns));
+        let s = self.stratum(self.spp);
+        self.dimension += 1;
+        (
+            ((s % nx) as f64 + self.stream.next()) / nx as f64,
+            ((s / nx) as f64 + self.stream.next()) / ny as f64,
+        )
+    }
+}
+
+const PRIMES : [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+// 桁ごとに並べ替えた base 進の radical inverse
+// 各桁の並べ替えはそれより上の桁で決まる (Owen scrambling)
+fn scrambled_radical_inverse(base : u64, mut i : u64, seed : u64) -> f64 {
+    let inv = 1.0 / base as f64;
+    let (mut value, mut scale) = (0.0, inv);
+    let mut h = seed;
+    // i を使い切った後も並べ替えた 0 が続くので、十分な精度まで桁を足す
+    while i > 0 || scale > 1e-9 {
+        let d = i % base;
+        value += permute(d as u32, base as u32, h as u32) as f64 * scale;
+        h = hash(&[h, d]);
+        scale *= inv;
+        i /= base;
+    }
+    value.min(1.0 - f64::EPSILON / 2.0)
+}
+
+// 次元ごとに素数を底にした Halton 列
+// 桁は次元ごとに Owen 風に並べ替え、画素ごとに Cranley-Patterson 回転でずらす
+// 素数を使い切った次元は独立な乱数にする
+pub struct HaltonSampler {
+    seed : u64,
+    pixel_seed : u64,
+    index : u64,
+    dimension : usize,
+    stream : Stream,
+}
+
+impl HaltonSampler {
+    pub fn new(seed : u64) -> HaltonSampler {
+        HaltonSampler{seed, pixel_seed : seed, index : 0, dimension : 0, stream : Stream(seed)}
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel : (usize, usize), index : usize) {
+        self.pixel_seed = hash(&[self.seed, pixel.0 as u64, pixel.1 as u64]);
+        self.index = index as u64;
+        self.dimension = 0;
+        self.stream = Stream::new(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let d = self.dimension;
+        self.dimension += 1;
+        match PRIMES.get(d) {
+            Some(&base) => {
+                let v = scrambled_radical_inverse(base, self.index, hash(&[self.seed, d as u64]));
+                let shift = to_unit(hash(&[self.pixel_seed, d as u64]));
+                (v + shift).fract()
+            },
+            None => self.stream.next(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let x = self.get_1d();
+        (x, self.get_1d())
+    }
+}
+
+fn laine_karras_permutation(mut x : u32, seed : u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+// 2 進の Owen scrambling (Burley, "Practical Hash-based Owen Scrambling")
+fn nested_uniform_scramble(x : u32, seed : u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Sobol 列の最初の 2 次元 (van der Corput と x + 1 の生成行列)
+fn sobol(i : u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut v = 1u32 << 31;
+    for k in 0..32 {
+        if i & (1 << k) != 0 {
+            x ^= 1 << (31 - k);
+            y ^= v;
+        }
+        v ^= v >> 1;
+    }
+    (x, y)
+}
+
+// Owen scrambling した Sobol 列
+// 1 次元と 2 次元の値ごとに、番号を並べ替えた 2 次元の Sobol 列を使う (padding)
+pub struct SobolSampler {
+    seed : u64,
+    pixel_seed : u64,
+    index : u32,
+    dimension : u64,
+}
+
+impl SobolSampler {
+    pub fn new(seed : u64) -> SobolSampler {
+        SobolSampler{seed, pixel_seed : seed, index : 0, dimension : 0}
+    }
+
+    fn next(&mut self) -> ((u32, u32), u64) {
+        let h = hash(&[self.pixel_seed, self.dimension]);
+        self.dimension += 2;
+        let i = nested_uniform_scramble(self.index, h as u32);
+        (sobol(i), h >> 32)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel : (usize, usize), index : usize) {
+        self.pixel_seed = hash(&[self.seed, pixel.0 as u64, pixel.1 as u64]);
+        self.index = index as u32;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let ((x, _), h) = self.next();
+        to_unit32(nested_uniform_scramble(x, mix(h) as u32))
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let ((x, y), h) = self.next();
+        let h = mix(h);
+        (
+            to_unit32(nested_uniform_scramble(x, h as u32)),
+            to_unit32(nested_uniform_scramble(y, (h >> 32) as u32)),
+        )
+    }
+}
@@ -0,0 +1,1421 @@
+use std::{fs, io, fmt};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::Write;
+use std::path::{Path, PathBuf, Component};
+use std::any::Any;
+use std::sync::Arc;
+
+use std;
+
+use geo::*;
+use obj::*;
+use env::*;
+use render::*;
+use environment::{Environment, EnvironmentMap};
+use io::ReadImageError;
+use material::{Bsdf, Diffuse, Mirror, Fresnel, Conductor, RoughDielectric, NormalMap, Perturbed};
+use mesh::TriangleMesh;
+use instance::Instance;
+use light::{AreaLight, AnalyticLight, LightShape};
+use tonemap::Operator;
+use texture::{Texture, Image};
+use noise::{Noise, Solid, Pattern};
+use wavefront;
+
+// シーン記述ファイル
+//
+//   # コメント
+//   render
+//     size 1200 800
+//     spp 1000
+//     depth 10              (none で上限なし)
+//     roulette 3            (この反射回数から Russian roulette, none で使わない)
+//     mode Shade            (Normal, NormalColor, Depth 500, DepthNormalColor 500)
+//     light_sampling true   (false で BSDF のサンプリングだけにする (点光源などは除く))
+//     sampler Sobol         (Independent, Stratified, Halton)
+//     seed 0                (同じ seed なら同じ画像になる)
+//     filter Gaussian 1.5   (Box, Tent, Gaussian, Mitchell, Lanczos と半径, 半径は省略可)
+//     exposure 0            (8 bit, 16 bit の画像にするときに 2^exposure 倍する)
+//     tonemap Aces          (Linear, Reinhard, ReinhardExtended 4, Uncharted2)
+//     dither true
+//     adaptive 0.05 16      (相対誤差の閾値と 1 パスのサンプル数 (省略すると 16), spp が上限)
+//     background 0 0 0      (Shade 以外のモードで何にも当たらなかった画素の色)
+//   end
+//
+//   camera
+//     position 50 52 295.6
+//     focus 50 51.957388 294.6
+//     up 0 1 0
+//     fov 30                (度)
+//     range 0.0001 10000000000
+//     aperture 0            (絞りの半径, 0 でピンホール)
+//     focus_distance 100    (省略すると focus までの距離)
+//     blades 6 15           (多角形の絞りの羽根の枚数と回転 (度), 省略すると円)
+//   end
+//
+//   environment             (省略すると黒)
+//     file sky.hdr          (正距円筒図法の .hdr か .pfm, シーンファイルからの相対パス)
+//     rotation 90           (y 軸まわりの回転 (度))
+//     intensity 1
+//   end
+//
+//   file の代わりに color 1 1 1 で一様な環境光にできる
+//
+//   texture tiles
+//     type Checker          (Constant, Image)
+//     even 0.9 0.9 0.9      (Checker の 2 色, 数 3 つか前に定義した texture の名前)
+//     odd 0.1 0.1 0.1
+//     scale 8 8             (uv * scale + offset の位置で引く, 省略すると 1 1 と 0 0)
+//     offset 0 0
+//     factor 1 1 1          (値に成分ごとに掛ける, 省略すると 1 1 1)
+//   end
+//
+//   Constant : color
+//   Image    : file (.png, .ppm は sRGB, .hdr, .pfm は線形な値, シーンファイルからの相対パス),
+//              wrap Repeat (Clamp, Mirror, 省略すると Repeat),
+//              srgb false (.png, .ppm も [0, 1] の値をそのまま使う, 法線マップや高さに使う)
+//
+//   texture veins           (ワールド座標の位置で決まる模様, scale と offset は low と high の uv に掛かる)
+//     type Marble           (Noise, Fbm, Turbulence, Worley, Wood)
+//     low 0.9 0.9 0.85      (模様の値 0 と 1 の色, 数 3 つか texture の名前)
+//     high 0.3 0.3 0.35
+//     frequency 0.05        (位置に掛ける倍率, 省略すると 1)
+//     octaves 6             (Fbm, Turbulence, Marble, Wood で重ねる数, 省略すると 6)
+//     basis Perlin          (Simplex, Worley 以外で使う勾配ノイズ)
+//     seed 0                (並べ替え表の種)
+//   end
+//
+//   material white
+//     type Diffuse          (Mirror, Fresnel 1.5168, Conductor gold, RoughDielectric 1.5168)
+//     reflectance 0.75 0.75 0.75   (数 3 つか texture の名前)
+//     emission 0 0 0        (Diffuse のみ, 数 3 つか texture の名前)
+//     roughness 0.2 0.05    (Conductor と RoughDielectric の GGX の粗さ, 1 つなら等方的)
+//     normal_map normals    (接空間の法線を色にした texture, x が u, y が v の向き)
+//     bump heights          (normal_map の代わりに、成分の平均を高さとする texture)
+//     bump_scale 0.5        (高さに掛ける倍率, 省略すると 1)
+//   end
+//
+//   Conductor には gold, copper, aluminium か、eta と k を 3 つずつ書く
+//   Conductor と RoughDielectric の reflectance は省略すると 1
+//
+//   sphere left ball        (名前は省略可)
+//     center 27 16.5 47
+//     radius 16.5
+//     material white
+//   end
+//
+//   plane    : normal, point, material
+//   polygon  : points (9 個の数), material
+//   mesh     : file (.obj, シーンファイルからの相対パス), material (省略すると .mtl に従う)
+//
+//   instance                (同じ file の instance は読み込んだメッシュを共有する)
+//     file bunny.obj
+//     scale 2               (1 つか 3 つの数)
+//     rotate 0 1 0 90       (軸と角度 (度))
+//     translate 50 0 80
+//     material white        (省略すると .mtl に従う)
+//   end
+//
+//   instance の変換は scale, rotate, translate の順に適用する
+//   代わりに matrix で 4x4 のアフィン変換の上 3 行 (12 個の数, 行優先) を書いてもよい
+//
+//   light ceiling           (光を反射しない面光源, 名前は省略可)
+//     type Quad             (Disk, Sphere)
+//     corner 40 81.5 60     (Quad の頂点と、そこから伸びる 2 辺 (表は edge0 × edge1 の向き))
+//     edge0 20 0 0
+//     edge1 0 0 20
+//     power 100 100 100     (放射束 [W], 代わりに radiance で放射輝度を書いてもよい)
+//     two_sided false       (true で裏からも放射する, 省略すると false)
+//   end
+//
+//   Disk   : center, normal (表の向き), radius
+//   Sphere : center, radius (表は外側)
+//
+//   形を持たない光源も light で置く (光線は当たらず、影の光線だけで評価する)
+//   Point       : position, intensity (放射強度 [W/sr], 代わりに power でもよい)
+//   Spot        : position, direction (向き), angle (円錐の半頂角 (度)),
+//                 falloff (縁からなめらかに暗くなる幅 (度), 省略すると 0), intensity か power
+//   Directional : direction (光の進む向き), irradiance (光に垂直な面の放射照度 [W/m^2]),
+//                 angular_diameter (視直径 (度), 省略すると 0)
+//
+// render と camera を省略したときは Default の値を使う
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(PathBuf, io::Error),
+    Parse{file : PathBuf, line : usize, message : String},
+    Invalid{object : String, message : String},
+    Mesh{object : String, error : wavefront::LoadObjError},
+    Image{object : String, error : ReadImageError},
+}
+
+impl std::fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use scenefile::SceneFileError::*;
+
+        match self {
+            Io(p, e) => write!(f, "{}: {}", p.display(), e),
+            Parse{file, line, message} => write!(f, "{}:{}: {}", file.display(), line, message),
+            Invalid{object, message} => write!(f, "{}: {}", object, message),
+            Mesh{object, error} => write!(f, "{}: {}", object, error),
+            Image{object, error} => write!(f, "{}: {}", object, error),
+        }
+    }
+}
+
+impl std::error::Error for SceneFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use scenefile::SceneFileError::*;
+        match self {
+            Io(_, e) => Some(e),
+            Mesh{error, ..} => Some(error),
+            Image{error, ..} => Some(error),
+            Parse{..} | Invalid{..} => None,
+        }
+    }
+}
+
+struct Property<'a> {
+    line : usize,
+    key : &'a str,
+    args : Vec<&'a str>,
+}
+
+struct Block<'a> {
+    file : &'a Path,
+    line : usize,
+    kind : &'a str,
+    name : String,
+    // エラーメッセージで使う名前
+    object : String,
+    properties : Vec<Property<'a>>,
+}
+
+fn parse_error<T, S : Into<String>>(file : &Path, line : usize, message : S) -> Result<T, SceneFileError> {
+    Err(SceneFileError::Parse{file : file.to_path_buf(), line, message : message.into()})
+}
+
+impl<'a> Block<'a> {
+    fn invalid<T, S : Into<String>>(&self, message : S) -> Result<T, SceneFileError> {
+        Err(SceneFileError::Invalid{object : self.object.clone(), message : message.into()})
+    }
+
+    fn check_keys(&self, keys : &[&str]) -> Result<(), SceneFileError> {
+        for p in &self.properties {
+            if !keys.contains(&p.key) {
+                return parse_error(self.file, p.line, format!("unknown property '{}' in {}", p.key, self.kind));
+            }
+            if self.properties.iter().filter(|q| q.key == p.key).count() > 1 {
+                return parse_error(self.file, p.line, format!("duplicate property '{}' in {}", p.key, self.object));
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, key : &str) -> Option<&Property<'a>> {
+        self.properties.iter().find(|p| p.key == key)
+    }
+
+    fn floats(&self, key : &str, n : usize) -> Result<Option<Vec<f64>>, SceneFileError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(p) => {
+                if p.args.len() != n {
+                    return parse_error(self.file, p.line, format!("'{}' expects {} numbers, found {}", key, n, p.args.len()));
+                }
+                p.args.iter().map(|a| match a.parse::<f64>() {
+                    Ok(v) => Ok(v),
+                    Err(_) => parse_error(self.file, p.line, format!("invalid number '{}'", a)),
+                }).collect::<Result<Vec<_>, _>>().map(Some)
+            }
+        }
+    }
+
+    fn float(&self, key : &str) -> Result<Option<f64>, SceneFileError> {
+        Ok(self.floats(key, 1)?.map(|v| v[0]))
+    }
+
+    fn vec3(&self, key : &str) -> Result<Option<Vec3>, SceneFileError> {
+        Ok(self.floats(key, 3)?.map(|v| Vec3::new((v[0], v[1], v[2]))))
+    }
+
+    fn usize(&self, key : &str) -> Result<Option<usize>, SceneFileError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(p) => match p.args.iter().map(|a| a.parse::<usize>()).collect::<Vec<_>>().as_slice() {
+                [Ok(v)] => Ok(Some(*v)),
+                _ => parse_error(self.file, p.line, format!("'{}' expects a non-negative integer", key)),
+            }
+        }
+    }
+
+    // 数または none
+    fn optional_usize(&self, key : &str) -> Result<Option<Option<usize>>, SceneFileError> {
+        match self.get(key) {
+            Some(p) if p.args.as_slice() == ["none"] => Ok(Some(None)),
+            _ => Ok(self.usize(key)?.map(Some)),
+        }
+    }
+
+    fn bool(&self, key : &str) -> Result<Option<bool>, SceneFileError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(p) => match p.args.as_slice() {
+                ["true"] => Ok(Some(true)),
+                ["false"] => Ok(Some(false)),
+                _ => parse_error(self.file, p.line, format!("'{}' expects true or false", key)),
+            }
+        }
+    }
+
+    fn require<T>(&self, key : &str, v : Option<T>) -> Result<T, SceneFileError> {
+        match v {
+            Some(v) => Ok(v),
+            None => self.invalid(format!("missing '{}'", key)),
+        }
+    }
+}
+
+fn parse_blocks<'a>(file : &'a Path, source : &'a str) -> Result<Vec<Block<'a>>, SceneFileError> {
+    let mut blocks = Vec::new();
+    let mut counts : HashMap<&str, usize> = HashMap::new();
+    let mut current : Option<Block> = None;
+
+    for (line, keyword, args) in wavefront::statements(source) {
+        if keyword == "end" {
+            match current.take() {
+                Some(b) => blocks.push(b),
+                None => return parse_error(file, line, "'end' without a block"),
+            }
+            continue;
+        }
+
+        if let Some(b) = current.as_mut() {
+            b.properties.push(Property{line, key : keyword, args});
+            continue;
+        }
+
+        match keyword {
+            "render" | "camera" | "environment" | "texture" | "material" | "sphere" | "plane" | "polygon" | "mesh" | "instance" | "light" => {
+                let name = args.join(" ");
+                let index = {
+                    let c = counts.entry(keyword).or_insert(0);
+                    *c += 1;
+                    *c - 1
+                };
+                let object = match keyword {
+                    "render" | "camera" | "environment" => keyword.to_string(),
+                    "material" | "texture" if name.is_empty() => return parse_error(file, line, format!("{} without a name", keyword)),
+                    _ if name.is_empty() => format!("{} #{}", keyword, index),
+                    _ => format!("{} '{}'", keyword, name),
+                };
+                current = Some(Block{file, line, kind : keyword, name, object, properties : Vec::new()});
+            },
+            _ => return parse_error(file, line, format!("unknown block '{}'", keyword)),
+        }
+    }
+
+    if let Some(b) = current {
+        return parse_error(file, b.line, format!("{} is not closed with 'end'", b.object));
+    }
+
+    Ok(blocks)
+}
+
+fn non_negative(v : &Vec3) -> bool {
+    v.x >= 0.0 && v.y >= 0.0 && v.z >= 0.0
+}
+
+// GGX の粗さ (1 つなら等方的)
+fn parse_roughness(b : &Block) -> Result<(f64, f64), SceneFileError> {
+    let alpha = match b.get("roughness") {
+        None => return b.invalid("missing 'roughness'"),
+        Some(p) => match p.args.iter().map(|a| a.parse::<f64>()).collect::<Vec<_>>().as_slice() {
+            [Ok(a)] => (*a, *a),
+            [Ok(ax), Ok(ay)] => (*ax, *ay),
+            _ => return parse_error(b.file, p.line, "'roughness' expects 1 or 2 numbers"),
+        },
+    };
+    if alpha.0 < 0.0 || alpha.1 < 0.0 {
+        return b.invalid("roughness must not be negative");
+    }
+    Ok(alpha)
+}
+
+fn parse_transform(b : &Block) -> Result<Transform, SceneFileError> {
+    if let Some(v) = b.floats("matrix", 12)? {
+        if b.get("scale").is_some() || b.get("rotate").is_some() || b.get("translate").is_some() {
+            return b.invalid("'matrix' cannot be used with scale, rotate or translate");
+        }
+        let m = Matrix4([
+            [v[0], v[1], v[2], v[3]],
+            [v[4], v[5], v[6], v[7]],
+            [v[8], v[9], v[10], v[11]],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        return match Transform::new(m) {
+            Some(t) => Ok(t),
+            None => b.invalid("matrix must be invertible"),
+        };
+    }
+
+    let scale = match b.get("scale") {
+        None => Vec3::new(1.0),
+        Some(p) => match p.args.iter().map(|a| a.parse::<f64>()).collect::<Vec<_>>().as_slice() {
+            [Ok(s)] => Vec3::new(*s),
+            [Ok(x), Ok(y), Ok(z)] => Vec3::new((*x, *y, *z)),
+            _ => return parse_error(b.file, p.line, "'scale' expects 1 or 3 numbers"),
+        },
+    };
+    if scale.x == 0.0 || scale.y == 0.0 || scale.z == 0.0 {
+        return b.invalid("scale must not be zero");
+    }
+
+    let rotation = match b.floats("rotate", 4)? {
+        None => Transform::identity(),
+        Some(v) => {
+            let axis = Vec3::new((v[0], v[1], v[2]));
+            if axis == Vec3::new(0.0) {
+                return b.invalid("rotation axis must not be zero");
+            }
+            Transform::rotate(&axis, v[3].to_radians())
+        },
+    };
+
+    let translation = b.vec3("translate")?.unwrap_or_else(|| Vec3::new(0.0));
+
+    Ok(Transform::translate(&translation) * rotation * Transform::scale(&scale))
+}
+
+fn parse_conductor(b : &Block, args : &[&str]) -> Result<fresnel::ComplexIor, SceneFileError> {
+    match args {
+        ["gold"] => Ok(fresnel::GOLD),
+        ["copper"] => Ok(fresnel::COPPER),
+        ["aluminium"] => Ok(fresnel::ALUMINIUM),
+        _ => match args.iter().map(|a| a.parse::<f64>()).collect::<Result<Vec<_>, _>>() {
+            Ok(ref v) if v.len() == 6 && v.iter().all(|v| *v >= 0.0) => Ok(fresnel::ComplexIor{
+                eta : Vec3::new((v[0], v[1], v[2])),
+                k : Vec3::new((v[3], v[4], v[5])),
+            }),
+            _ => b.invalid(format!("invalid conductor '{}', expected gold, copper, aluminium or 6 numbers", args.join(" "))),
+        },
+    }
+}
+
+// 数 3 つの色か、定義済みの texture の名前
+fn parse_color(b : &Block, key : &str, textures : &HashMap<String, Texture>) -> Result<Option<Texture>, SceneFileError> {
+    let p = match b.get(key) {
+        None => return Ok(None),
+        Some(p) => p,
+    };
+    let texture = match p.args.iter().map(|a| a.parse::<f64>()).collect::<Vec<_>>().as_slice() {
+        [Ok(x), Ok(y), Ok(z)] => Texture::Constant(Vec3::new((*x, *y, *z))),
+        _ => match textures.get(&p.args.join(" ")) {
+            Some(t) => t.clone(),
+            None => return parse_error(b.file, p.line, format!("'{}' expects 3 numbers or a texture name, found '{}'", key, p.args.join(" "))),
+        },
+    };
+    if !texture.is_non_negative() {
+        return b.invalid(format!("{} must not be negative", key));
+    }
+    Ok(Some(texture))
+}
+
+fn parse_texture(b : &Block, dir : &Path, textures : &HashMap<String, Texture>, images : &mut HashMap<(PathBuf, bool), Arc<Image>>) -> Result<Texture, SceneFileError> {
+    let p = match b.get("type") {
+        None => return b.invalid("missing 'type'"),
+        Some(p) => p,
+    };
+
+    let texture = match p.args.as_slice() {
+        ["Constant"] => {
+            b.check_keys(&["type", "color", "scale", "offset", "factor"])?;
+            b.require("color", parse_color(b, "color", textures)?)?
+        },
+        ["Checker"] => {
+            b.check_keys(&["type", "even", "odd", "scale", "offset", "factor"])?;
+            let even = b.require("even", parse_color(b, "even", textures)?)?;
+            let odd = b.require("odd", parse_color(b, "odd", textures)?)?;
+            Texture::Checker(Box::new(even), Box::new(odd))
+        },
+        ["Image"] => {
+            b.check_keys(&["type", "file", "wrap", "srgb", "scale", "offset", "factor"])?;
+            let path = match b.get("file") {
+                Some(p) if !p.args.is_empty() => dir.join(p.args.join(" ")),
+                _ => return b.invalid("missing 'file'"),
+            };
+            let wrap = match b.get("wrap") {
+                None => Default::default(),
+                Some(p) => match p.args.join(" ").parse() {
+                    Ok(w) => w,
+                    Err(e) => return parse_error(b.file, p.line, e),
+                },
+            };
+            let srgb = b.bool("srgb")?.unwrap_or(true);
+            let image = match images.entry((path, srgb)) {
+                Entry::Occupied(e) => e.get().clone(),
+                Entry::Vacant(e) => {
+                    let path = &e.key().0;
+                    let image = if srgb {Image::load(path)} else {Image::load_linear(path)};
+                    let image = image.map_err(|error| SceneFileError::Image{object : b.object.clone(), error})?;
+                    e.insert(Arc::new(image)).clone()
+                },
+            };
+            Texture::Image(image, wrap)
+        },
+        [pattern] if pattern.parse::<Pattern>().is_ok() => {
+            b.check_keys(&["type", "low", "high", "frequency", "octaves", "basis", "seed", "scale", "offset", "factor"])?;
+            let low = b.require("low", parse_color(b, "low", textures)?)?;
+            let high = b.require("high", parse_color(b, "high", textures)?)?;
+            let mut solid = Solid::new(pattern.parse().unwrap(), 0);
+            if let Some(f) = b.float("frequency")? {
+                if f <= 0.0 {
+                    return b.invalid("frequency must be positive");
+                }
+                solid.frequency = f;
+            }
+            if let Some(n) = b.usize("octaves")? {
+                if n == 0 {
+                    return b.invalid("octaves must be positive");
+                }
+                solid.octaves = n;
+            }
+            if let Some(p) = b.get("basis") {
+                solid.basis = match p.args.join(" ").parse() {
+                    Ok(basis) => basis,
+                    Err(e) => return parse_error(b.file, p.line, e),
+                };
+            }
+            if let Some(p) = b.get("seed") {
+                solid = match p.args.iter().map(|a| a.parse::<u64>()).collect::<Vec<_>>().as_slice() {
+                    [Ok(seed)] => Solid{noise : Noise::new(*seed), .. solid},
+                    _ => return parse_error(b.file, p.line, "'seed' expects a non-negative integer"),
+                };
+            }
+            Texture::Solid{solid, low : Box::new(low), high : Box::new(high)}
+        },
+        _ => return parse_error(b.file, p.line, format!("unknown texture type '{}'", p.args.join(" "))),
+    };
+
+    let scale = b.floats("scale", 2)?.map(|v| (v[0], v[1]));
+    let offset = b.floats("offset", 2)?.map(|v| (v[0], v[1]));
+    let texture = if scale.is_none() && offset.is_none() {
+        texture
+    } else {
+        Texture::Transform {
+            texture : Box::new(texture),
+            scale : scale.unwrap_or((1.0, 1.0)),
+            offset : offset.unwrap_or((0.0, 0.0)),
+        }
+    };
+    match b.vec3("factor")? {
+        None => Ok(texture),
+        Some(factor) if factor.x >= 0.0 && factor.y >= 0.0 && factor.z >= 0.0 => Ok(Texture::Scale{texture : Box::new(texture), factor}),
+        Some(_) => b.invalid("factor must not be negative"),
+    }
+}
+
+fn parse_material(b : &Block, textures : &HashMap<String, Texture>) -> Result<Arc<dyn Bsdf>, SceneFileError> {
+    b.check_keys(&["type", "reflectance", "emission", "roughness", "normal_map", "bump", "bump_scale"])?;
+
+    let p = match b.get("type") {
+        None => return b.invalid("missing 'type'"),
+        Some(p) => p,
+    };
+    let rough = matches!(p.args.first(), Some(&"Conductor") | Some(&"RoughDielectric"));
+    if !rough && b.get("roughness").is_some() {
+        return b.invalid("roughness is only supported by Conductor and RoughDielectric");
+    }
+
+    // 粗い材質の反射率は省略すると 1
+    let reflectance = parse_color(b, "reflectance", textures)?.unwrap_or_else(|| Vec3::new(if rough {1.0} else {0.0}).into());
+    let le = parse_color(b, "emission", textures)?.unwrap_or_else(|| Vec3::new(0.0).into());
+
+    if !le.is_black() && p.args.as_slice() != ["Diffuse"] {
+        return b.invalid("emission is only supported by Diffuse");
+    }
+
+    let material : Arc<dyn Bsdf> = match p.args.as_slice() {
+        ["Diffuse"] => Arc::new(Diffuse{reflectance, le}),
+        ["Mirror"] => Arc::new(Mirror{reflectance}),
+        ["Fresnel", ior] => match ior.parse::<f64>() {
+            Ok(ior) if ior > 0.0 => Arc::new(Fresnel{ior, reflectance}),
+            _ => return b.invalid(format!("invalid index of refraction '{}'", ior)),
+        },
+        ["Conductor", ior @ ..] => Arc::new(Conductor{ior : parse_conductor(b, ior)?, alpha : parse_roughness(b)?, reflectance}),
+        ["RoughDielectric", ior] => match ior.parse::<f64>() {
+            Ok(ior) if ior > 0.0 => Arc::new(RoughDielectric{ior, alpha : parse_roughness(b)?, reflectance}),
+            _ => return b.invalid(format!("invalid index of refraction '{}'", ior)),
+        },
+        _ => return parse_error(b.file, p.line, format!("unknown material type '{}'", p.args.join(" "))),
+    };
+
+    let map = match (parse_color(b, "normal_map", textures)?, parse_color(b, "bump", textures)?) {
+        (None, None) => None,
+        (Some(t), None) => Some(NormalMap::Tangent(t)),
+        (None, Some(height)) => Some(NormalMap::Bump{height, scale : b.float("bump_scale")?.unwrap_or(1.0)}),
+        (Some(_), Some(_)) => return b.invalid("'normal_map' and 'bump' cannot be used together"),
+    };
+    if b.get("bump_scale").is_some() && !matches!(map, Some(NormalMap::Bump{..})) {
+        return b.invalid("bump_scale needs 'bump'");
+    }
+    Ok(match map {
+        Some(map) => Arc::new(Perturbed{material, map}),
+        None => material,
+    })
+}
+
+// type が Point, Spot, Directional の light
+fn is_analytic_light(b : &Block) -> bool {
+    matches!(b.get("type").map(|p| p.args.as_slice()), Some(["Point"]) | Some(["Spot"]) | Some(["Directional"]))
+}
+
+fn parse_analytic_light(b : &Block) -> Result<AnalyticLight, SceneFileError> {
+    let p = b.require("type", b.get("type"))?;
+    let direction = || -> Result<Vec3, SceneFileError> {
+        let direction = b.require("direction", b.vec3("direction")?)?;
+        if direction == Vec3::new(0.0) {
+            return b.invalid("direction must not be zero");
+        }
+        Ok(direction)
+    };
+    let angle = |key : &str, default : Option<f64>| -> Result<f64, SceneFileError> {
+        let degrees = match default {
+            Some(d) => b.float(key)?.unwrap_or(d),
+            None => b.require(key, b.float(key)?)?,
+        };
+        if !(0.0..=180.0).contains(&degrees) {
+            return b.invalid(format!("{} must be between 0 and 180 degrees", key));
+        }
+        Ok(degrees.to_radians())
+    };
+    // intensity か power から放射強度にする
+    let with_intensity = |light : &dyn Fn(Vec3) -> AnalyticLight| -> Result<AnalyticLight, SceneFileError> {
+        match (b.vec3("intensity")?, b.vec3("power")?) {
+            (Some(intensity), None) if non_negative(&intensity) => Ok(light(intensity)),
+            (None, Some(power)) if non_negative(&power) => {
+                let unit = light(Vec3::new(1.0)).power().map(|p| p.x).unwrap_or(0.0);
+                if unit <= 0.0 {
+                    return b.invalid("'power' needs a light that emits in some direction");
+                }
+                Ok(light(power / unit))
+            },
+            (Some(_), None) | (None, Some(_)) => b.invalid("intensity and power must not be negative"),
+            (None, None) => b.invalid("either 'intensity' or 'power' is required"),
+            (Some(_), Some(_)) => b.invalid("'intensity' and 'power' cannot be used together"),
+        }
+    };
+
+    match p.args.as_slice() {
+        ["Point"] => {
+            b.check_keys(&["type", "position", "intensity", "power"])?;
+            let position = b.require("position", b.vec3("position")?)?;
+            with_intensity(&|intensity| AnalyticLight::Point{position, intensity})
+        },
+        ["Spot"] => {
+            b.check_keys(&["type", "position", "direction", "angle", "falloff", "intensity", "power"])?;
+            let position = b.require("position", b.vec3("position")?)?;
+            let (direction, angle, falloff) = (direction()?, angle("angle", None)?, angle("falloff", Some(0.0))?);
+            with_intensity(&|intensity| AnalyticLight::Spot{position, direction, intensity, angle, falloff})
+        },
+        _ => {
+            b.check_keys(&["type", "direction", "irradiance", "angular_diameter"])?;
+            let direction = direction()?;
+            let irradiance = b.require("irradiance", b.vec3("irradiance")?)?;
+            if !non_negative(&irradiance) {
+                return b.invalid("irradiance must not be negative");
+            }
+            let angular_diameter = angle("angular_diameter", Some(0.0))?;
+            Ok(AnalyticLight::Directional{direction, irradiance, angular_diameter})
+        },
+    }
+}
+
+fn parse_light(b : &Block) -> Result<AreaLight, SceneFileError> {
+    let p = match b.get("type") {
+        None => return b.invalid("missing 'type'"),
+        Some(p) => p,
+    };
+    let radius = || -> Result<f64, SceneFileError> {
+        let radius = b.require("radius", b.float("radius")?)?;
+        if radius <= 0.0 {
+            return b.invalid("radius must be positive");
+        }
+        Ok(radius)
+    };
+
+    let shape = match p.args.as_slice() {
+        ["Quad"] => {
+            b.check_keys(&["type", "corner", "edge0", "edge1", "power", "radiance", "two_sided"])?;
+            let corner = b.require("corner", b.vec3("corner")?)?;
+            let edges = (b.require("edge0", b.vec3("edge0")?)?, b.require("edge1", b.vec3("edge1")?)?);
+            if edges.0.cross(&edges.1) == Vec3::new(0.0) {
+                return b.invalid("edges must not be parallel");
+            }
+            LightShape::Quad{corner, edges}
+        },
+        ["Disk"] => {
+            b.check_keys(&["type", "center", "normal", "radius", "power", "radiance", "two_sided"])?;
+            let center = b.require("center", b.vec3("center")?)?;
+            let normal = b.require("normal", b.vec3("normal")?)?;
+            if normal == Vec3::new(0.0) {
+                return b.invalid("normal must not be zero");
+            }
+            LightShape::Disk{center, normal, radius : radius()?}
+        },
+        ["Sphere"] => {
+            b.check_keys(&["type", "center", "radius", "power", "radiance", "two_sided"])?;
+            LightShape::Sphere{center : b.require("center", b.vec3("center")?)?, radius : radius()?}
+        },
+        _ => return parse_error(b.file, p.line, format!("unknown light type '{}'", p.args.join(" "))),
+    };
+
+    let two_sided = b.bool("two_sided")?.unwrap_or(false);
+    match (b.vec3("power")?, b.vec3("radiance")?) {
+        (Some(power), None) if non_negative(&power) => Ok(AreaLight::with_power(shape, power, two_sided)),
+        (None, Some(le)) if non_negative(&le) => Ok(AreaLight{shape, le, two_sided}),
+        (Some(_), None) | (None, Some(_)) => b.invalid("power and radiance must not be negative"),
+        (None, None) => b.invalid("either 'power' or 'radiance' is required"),
+        (Some(_), Some(_)) => b.invalid("'power' and 'radiance' cannot be used together"),
+    }
+}
+
+fn parse_environment(b : &Block, dir : &Path) -> Result<Environment, SceneFileError> {
+    b.check_keys(&["color", "file", "rotation", "intensity"])?;
+
+    match (b.vec3("color")?, b.get("file")) {
+        (Some(c), None) => {
+            if b.get("rotation").is_some() || b.get("intensity").is_some() {
+                return b.invalid("rotation and intensity need 'file'");
+            }
+            if !non_negative(&c) {
+                return b.invalid("color must not be negative");
+            }
+            Ok(Environment::Constant(c))
+        },
+        (None, Some(p)) if !p.args.is_empty() => {
+            let rotation = b.float("rotation")?.unwrap_or(0.0).to_radians();
+            let intensity = b.float("intensity")?.unwrap_or(1.0);
+            if intensity < 0.0 {
+                return b.invalid("intensity must not be negative");
+            }
+            EnvironmentMap::load(dir.join(p.args.join(" ")), rotation, intensity)
+                .map(Environment::Map)
+                .map_err(|error| SceneFileError::Image{object : b.object.clone(), error})
+        },
+        (None, None) | (None, Some(_)) => b.invalid("either 'color' or 'file' is required"),
+        (Some(_), Some(_)) => b.invalid("'color' and 'file' cannot be used together"),
+    }
+}
+
+fn parse_mode(b : &Block) -> Result<Option<RenderMode>, SceneFileError> {
+    match b.get("mode") {
+        None => Ok(None),
+        Some(p) => {
+            let depth = |d : &str| match d.parse::<f64>() {
+                Ok(d) if d > 0.0 => Ok(d),
+                _ => b.invalid(format!("invalid depth range '{}'", d)),
+            };
+            match p.args.as_slice() {
+                ["Shade"] => Ok(Some(RenderMode::Shade)),
+                ["Normal"] => Ok(Some(RenderMode::Normal)),
+                ["NormalColor"] => Ok(Some(RenderMode::NormalColor)),
+                ["Depth", d] => Ok(Some(RenderMode::Depth(depth(d)?))),
+                ["DepthNormalColor", d] => Ok(Some(RenderMode::DepthNormalColor(depth(d)?))),
+                _ => parse_error(b.file, p.line, format!("unknown mode '{}'", p.args.join(" "))),
+            }
+        }
+    }
+}
+
+fn parse_camera(b : &Block) -> Result<Camera, SceneFileError> {
+    b.check_keys(&["position", "focus", "up", "fov", "range", "aperture", "focus_distance", "blades"])?;
+
+    let d = Camera::default();
+    let position = b.vec3("position")?.unwrap_or(d.position);
+    let focus = b.vec3("focus")?.unwrap_or(d.focus);
+    let upside = b.vec3("up")?.unwrap_or(d.upside);
+    let fov = b.float("fov")?.unwrap_or_else(|| d.fov.to_degrees());
+    let tm = b.floats("range", 2)?.map(|v| (v[0], v[1])).unwrap_or(d.tm);
+
+    if position == focus {
+        return b.invalid("position and focus must differ");
+    }
+    if (position - focus).cross(&upside) == Vec3::new(0.0) {
+        return b.invalid("up must not be parallel to the view direction");
+    }
+    if !(0.0 < fov && fov < 180.0) {
+        return b.invalid("fov must be between 0 and 180 degrees");
+    }
+    if !(0.0 <= tm.0 && tm.0 < tm.1) {
+        return b.invalid("range must satisfy 0 <= near < far");
+    }
+
+    let mut camera = Camera::new(position, focus, upside, fov, tm);
+
+    if let Some(a) = b.float("aperture")? {
+        if a < 0.0 {
+            return b.invalid("aperture must not be negative");
+        }
+        camera.aperture = a;
+    }
+    if let Some(d) = b.float("focus_distance")? {
+        if d <= 0.0 {
+            return b.invalid("focus_distance must be positive");
+        }
+        camera.focus_distance = d;
+    }
+    if let Some(p) = b.get("blades") {
+        let (blades, rotation) = match p.args.as_slice() {
+            [n] => (n.parse::<usize>(), Ok(0.0)),
+            [n, r] => (n.parse::<usize>(), r.parse::<f64>()),
+            _ => return parse_error(b.file, p.line, "'blades' expects a count and an optional rotation"),
+        };
+        match (blades, rotation) {
+            (Ok(blades), Ok(rotation)) if blades >= 3 => {
+                camera.aperture_shape = Aperture::Polygon{blades, rotation : rotation.to_radians()};
+            },
+            (Ok(_), Ok(_)) => return b.invalid("blades must be at least 3"),
+            _ => return parse_error(b.file, p.line, format!("invalid blades '{}'", p.args.join(" "))),
+        }
+    }
+
+    Ok(camera)
+}
+
+// シーン記述を読み込む
+// file はエラーメッセージとメッシュの相対パスの解決に使う
+pub fn parse(source : &str, file : &Path) -> Result<RenderSetting, SceneFileError> {
+    let blocks = parse_blocks(file, source)?;
+    let dir = file.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut rs = RenderSetting {
+        scene : Scene::new(Vec::new(), Vec::new(), Vec::new()),
+        .. Default::default()
+    };
+
+    for kind in &["render", "camera", "environment"] {
+        if let Some(b) = blocks.iter().filter(|b| b.kind == *kind).nth(1) {
+            return parse_error(file, b.line, format!("duplicate {} block", kind));
+        }
+    }
+
+    // instance が共有するメッシュ
+    let mut instanced : HashMap<PathBuf, Vec<Arc<TriangleMesh>>> = HashMap::new();
+
+    // texture はそれより前に定義した texture を参照できる
+    let mut textures = HashMap::new();
+    let mut images = HashMap::new();
+    for b in blocks.iter().filter(|b| b.kind == "texture") {
+        let t = parse_texture(b, dir, &textures, &mut images)?;
+        if textures.insert(b.name.clone(), t).is_some() {
+            return b.invalid("defined more than once");
+        }
+    }
+
+    let mut materials = HashMap::new();
+    for b in blocks.iter().filter(|b| b.kind == "material") {
+        if materials.insert(b.name.clone(), parse_material(b, &textures)?).is_some() {
+            return b.invalid("defined more than once");
+        }
+    }
+
+    let material = |b : &Block| -> Result<Arc<dyn Bsdf>, SceneFileError> {
+        match b.get("material") {
+            None => b.invalid("missing 'material'"),
+            Some(p) => match materials.get(&p.args.join(" ")) {
+                Some(m) => Ok(m.clone()),
+                None => b.invalid(format!("unknown material '{}'", p.args.join(" "))),
+            }
+        }
+    };
+
+    for b in &blocks {
+        match b.kind {
+            "render" => {
+                b.check_keys(&["size", "spp", "depth", "roulette", "mode", "light_sampling", "background", "sampler", "seed", "filter", "exposure", "tonemap", "dither", "adaptive"])?;
+                if let Some(v) = b.floats("size", 2)? {
+                    if v[0] < 1.0 || v[1] < 1.0 || v[0].fract() != 0.0 || v[1].fract() != 0.0 {
+                        return b.invalid("size must be positive integers");
+                    }
+                    rs.window_size = (v[0] as usize, v[1] as usize);
+                }
+                if let Some(spp) = b.usize("spp")? {
+                    if spp == 0 {
+                        return b.invalid("spp must be positive");
+                    }
+                    rs.spp = spp;
+                }
+                if let Some(depth) = b.optional_usize("depth")? {
+                    rs.reflect_n = depth;
+                }
+                if let Some(depth) = b.optional_usize("roulette")? {
+                    rs.roulette_depth = depth;
+                }
+                if rs.reflect_n.is_none() && rs.roulette_depth.is_none() {
+                    return b.invalid("either depth or roulette must be set, or paths never end");
+                }
+                if let Some(mode) = parse_mode(b)? {
+                    rs.mode = mode;
+                }
+                if let Some(v) = b.bool("light_sampling")? {
+                    rs.light_sampling = v;
+                }
+                if let Some(p) = b.get("seed") {
+                    rs.seed = match p.args.iter().map(|a| a.parse::<u64>()).collect::<Vec<_>>().as_slice() {
+                        [Ok(v)] => *v,
+                        _ => return parse_error(b.file, p.line, "'seed' expects a non-negative integer"),
+                    };
+                }
+                if let Some(p) = b.get("filter") {
+                    rs.filter = match p.args.join(":").parse() {
+                        Ok(f) => f,
+                        Err(e) => return parse_error(b.file, p.line, e),
+                    };
+                }
+                if let Some(ev) = b.float("exposure")? {
+                    rs.tonemap.exposure = ev;
+                }
+                if let Some(p) = b.get("tonemap") {
+                    rs.tonemap.operator = match p.args.join(":").parse() {
+                        Ok(op) => op,
+                        Err(e) => return parse_error(b.file, p.line, e),
+                    };
+                }
+                if let Some(v) = b.bool("dither")? {
+                    rs.tonemap.dither = v;
+                }
+                if let Some(p) = b.get("adaptive") {
+                    let invalid = || parse_error(b.file, p.line, "'adaptive' expects a positive threshold and optionally samples per pass (at least 2)");
+                    let threshold = match p.args.first().map(|a| a.parse::<f64>()) {
+                        Some(Ok(t)) if t > 0.0 => t,
+                        _ => return invalid(),
+                    };
+                    let pass_spp = match p.args.get(1..).unwrap_or(&[]) {
+                        [] => 16,
+                        [n] => match n.parse::<usize>() {
+                            Ok(n) if n >= 2 => n,
+                            _ => return invalid(),
+                        },
+                        _ => return invalid(),
+                    };
+                    rs.adaptive = Some(Adaptive{threshold, pass_spp});
+                }
+                if let Some(p) = b.get("sampler") {
+                    rs.sampler = match p.args.join(" ").parse() {
+                        Ok(s) => s,
+                        Err(e) => return parse_error(b.file, p.line, e),
+                    };
+                }
+                if let Some(v) = b.vec3("background")? {
+                    rs.background = v;
+                }
+            },
+            "camera" => rs.camera = parse_camera(b)?,
+            "environment" => *rs.scene.environment_mut() = Some(parse_environment(b, dir)?),
+            "texture" | "material" => (),
+            "light" if is_analytic_light(b) => rs.scene.analytic_lights_mut().push(parse_analytic_light(b)?),
+            "light" => rs.scene.area_lights_mut().push(parse_light(b)?),
+            "sphere" => {
+                b.check_keys(&["center", "radius", "material"])?;
+                let m = material(b)?;
+                let point = b.require("center", b.vec3("center")?)?;
+                let radius = b.require("radius", b.float("radius")?)?;
+                if radius <= 0.0 {
+                    return b.invalid("radius must be positive");
+                }
+                rs.scene.spheres_mut().push(Sphere{point, radius, material : m});
+            },
+            "plane" => {
+                b.check_keys(&["normal", "point", "material"])?;
+                let m = material(b)?;
+                let normal = b.require("normal", b.vec3("normal")?)?;
+                let point = b.require("point", b.vec3("point")?)?;
+                if normal == Vec3::new(0.0) {
+                    return b.invalid("normal must not be zero");
+                }
+                rs.scene.planes_mut().push(Plane{normal, point, material : m});
+            },
+            "polygon" => {
+                b.check_keys(&["points", "material"])?;
+                let m = material(b)?;
+                let v = b.require("points", b.floats("points", 9)?)?;
+                let points = [
+                    Vec3::new((v[0], v[1], v[2])),
+                    Vec3::new((v[3], v[4], v[5])),
+                    Vec3::new((v[6], v[7], v[8])),
+                ];
+                if (points[1] - points[0]).cross(&(points[2] - points[0])) == Vec3::new(0.0) {
+                    return b.invalid("points must not be collinear");
+                }
+                rs.scene.polygons_mut().push(Polygon{points, material : m});
+            },
+            "mesh" => {
+                b.check_keys(&["file", "material"])?;
+                let path = match b.get("file") {
+                    Some(p) if !p.args.is_empty() => dir.join(p.args.join(" ")),
+                    _ => return b.invalid("missing 'file'"),
+                };
+                let mut meshes = wavefront::load_meshes(&path)
+                    .map_err(|error| SceneFileError::Mesh{object : b.object.clone(), error})?;
+                if b.get("material").is_some() {
+                    let m = material(b)?;
+                    for mesh in meshes.iter_mut() {
+                        mesh.material = m.clone();
+                    }
+                }
+                rs.scene.meshes_mut().extend(meshes);
+            },
+            "instance" => {
+                b.check_keys(&["file", "scale", "rotate", "translate", "matrix", "material"])?;
+                let path = match b.get("file") {
+                    Some(p) if !p.args.is_empty() => dir.join(p.args.join(" ")),
+                    _ => return b.invalid("missing 'file'"),
+                };
+                let transform = parse_transform(b)?;
+                let m = match b.get("material") {
+                    Some(_) => Some(material(b)?),
+                    None => None,
+                };
+
+                let meshes = match instanced.entry(path) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => {
+                        let meshes = wavefront::load_meshes(e.key())
+                            .map_err(|error| SceneFileError::Mesh{object : b.object.clone(), error})?;
+                        e.insert(meshes.into_iter().map(Arc::new).collect())
+                    },
+                };
+                for mesh in meshes.iter() {
+                    rs.scene.shapes_mut().push(Box::new(Instance {
+                        object : mesh.clone(),
+                        transform,
+                        material : m.clone(),
+                    }));
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(rs)
+}
+
+pub fn load<P : AsRef<Path>>(path : P) -> Result<RenderSetting, SceneFileError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| SceneFileError::Io(path.to_path_buf(), e))?;
+    parse(&source, path)
+}
+
+fn optional_str(v : Option<usize>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_else(|| "none".to_string())
+}
+
+fn vec3_str(v : &Vec3) -> String {
+    format!("{} {} {}", v.x, v.y, v.z)
+}
+
+// 読み戻したときに同じ値になる最短の桁数で度数法の角度を書く
+fn degrees_str(radians : f64) -> String {
+    (0..17)
+        .map(|p| format!("{:.*}", p, radians.to_degrees()))
+        .find(|s| s.parse::<f64>().map(|d| d.to_radians() == radians).unwrap_or(false))
+        .unwrap_or_else(|| format!("{}", radians.to_degrees()))
+}
+
+fn roughness_str((ax, ay) : (f64, f64)) -> String {
+    if ax == ay {
+        format!("{}", ax)
+    } else {
+        format!("{} {}", ax, ay)
+    }
+}
+
+// 絶対パスにして . と .. を取り除く (シンボリックリンクは辿らない)
+fn absolute_path(p : &Path) -> PathBuf {
+    let p = if p.is_absolute() {
+        p.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(p)
+    };
+    let mut out = PathBuf::new();
+    for c in p.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                out.pop();
+            },
+            c => out.push(c.as_os_str()),
+        }
+    }
+    out
+}
+
+// 読み込んだファイルのパス path を、書き出すシーンファイルのディレクトリ dir からの相対パスにする
+// 共通の部分がなければ (別のドライブなど) 絶対パスにする
+fn relative_path(path : &Path, dir : &Path) -> PathBuf {
+    let (path, dir) = (absolute_path(path), absolute_path(dir));
+    let common = path.components().zip(dir.components()).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return path;
+    }
+    let mut relative = PathBuf::new();
+    for _ in dir.components().skip(common) {
+        relative.push("..");
+    }
+    for c in path.components().skip(common) {
+        relative.push(c.as_os_str());
+    }
+    relative
+}
+
+// 書き出す texture ブロック (同じ内容はひとつにまとめる)
+struct TextureBlocks {
+    blocks : Vec<String>,
+    name_of : HashMap<String, String>,
+    // 書き出すシーンファイルのディレクトリ
+    dir : PathBuf,
+}
+
+impl TextureBlocks {
+    // material などのプロパティに書く値
+    // 一定の色は数 3 つ、それ以外は texture ブロックを足してその名前にする
+    fn reference(&mut self, t : &Texture) -> io::Result<String> {
+        if let Texture::Constant(v) = t {
+            return Ok(vec3_str(v));
+        }
+        let body = self.body(t)?;
+        if let Some(name) = self.name_of.get(&body) {
+            return Ok(name.clone());
+        }
+        let name = format!("t{}", self.blocks.len());
+        self.blocks.push(body.clone());
+        self.name_of.insert(body, name.clone());
+        Ok(name)
+    }
+
+    fn body(&mut self, t : &Texture) -> io::Result<String> {
+        // 入れ子の Transform と Scale はひとつの scale, offset と factor にまとめる
+        // (Scale は uv を変えないので順番によらない)
+        let (mut t, mut scale, mut offset, mut factor) = (t, (1.0, 1.0), (0.0, 0.0), Vec3::new(1.0));
+        loop {
+            match t {
+                Texture::Transform{texture, scale : s, offset : o} => {
+                    offset = (offset.0 * s.0 + o.0, offset.1 * s.1 + o.1);
+                    scale = (scale.0 * s.0, scale.1 * s.1);
+                    t = texture;
+                },
+                Texture::Scale{texture, factor : f} => {
+                    factor = factor * *f;
+                    t = texture;
+                },
+                _ => break,
+            }
+        }
+
+        let mut body = match t {
+            Texture::Constant(v) => format!("  type Constant\n  color {}\n", vec3_str(v)),
+            Texture::Checker(even, odd) => format!("  type Checker\n  even {}\n  odd {}\n", self.reference(even)?, self.reference(odd)?),
+            Texture::Image(image, wrap) => match image.path {
+                Some(ref p) => format!("  type Image\n  file {}\n  wrap {}\n{}", relative_path(p, &self.dir).display(), wrap, if image.srgb {""} else {"  srgb false\n"}),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "image texture without a file cannot be written to a scene file")),
+            },
+            Texture::Solid{solid, low, high} => format!(
+                "  type {}\n  low {}\n  high {}\n  frequency {}\n  octaves {}\n  basis {}\n  seed {}\n",
+                solid.pattern, self.reference(low)?, self.reference(high)?, solid.frequency, solid.octaves, solid.basis, solid.noise.seed),
+            Texture::Transform{..} | Texture::Scale{..} => unreachable!(),
+        };
+        if scale != (1.0, 1.0) || offset != (0.0, 0.0) {
+            body += &format!("  scale {} {}\n  offset {} {}\n", scale.0, scale.1, offset.0, offset.1);
+        }
+        if factor != Vec3::new(1.0) {
+            body += &format!("  factor {}\n", vec3_str(&factor));
+        }
+        Ok(body)
+    }
+}
+
+// material ブロックの中身
+// シーンファイルで表せない材質はエラーにする
+fn material_str(m : &dyn Bsdf, textures : &mut TextureBlocks) -> io::Result<String> {
+    let m : &dyn Any = m;
+    if let Some(d) = m.downcast_ref::<Perturbed>() {
+        // 中身の材質が Perturbed なら書けない
+        if (&*d.material as &dyn Any).is::<Perturbed>() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "nested normal maps cannot be written to a scene file"));
+        }
+        let map = match d.map {
+            NormalMap::Tangent(ref t) => format!("  normal_map {}\n", textures.reference(t)?),
+            NormalMap::Bump{ref height, scale} => format!("  bump {}\n  bump_scale {}\n", textures.reference(height)?, scale),
+        };
+        Ok(material_str(&*d.material, textures)? + &map)
+    } else if let Some(d) = m.downcast_ref::<Diffuse>() {
+        Ok(format!("  type Diffuse\n  reflectance {}\n  emission {}\n", textures.reference(&d.reflectance)?, textures.reference(&d.le)?))
+    } else if let Some(d) = m.downcast_ref::<Mirror>() {
+        Ok(format!("  type Mirror\n  reflectance {}\n", textures.reference(&d.reflectance)?))
+    } else if let Some(d) = m.downcast_ref::<Fresnel>() {
+        Ok(format!("  type Fresnel {}\n  reflectance {}\n", d.ior, textures.reference(&d.reflectance)?))
+    } else if let Some(d) = m.downcast_ref::<Conductor>() {
+        let ior = match d.ior {
+            i if i == fresnel::GOLD => "gold".to_string(),
+            i if i == fresnel::COPPER => "copper".to_string(),
+            i if i == fresnel::ALUMINIUM => "aluminium".to_string(),
+            i => format!("{} {}", vec3_str(&i.eta), vec3_str(&i.k)),
+        };
+        Ok(format!("  type Conductor {}\n  reflectance {}\n  roughness {}\n", ior, textures.reference(&d.reflectance)?, roughness_str(d.alpha)))
+    } else if let Some(d) = m.downcast_ref::<RoughDielectric>() {
+        Ok(format!("  type RoughDielectric {}\n  reflectance {}\n  roughness {}\n", d.ior, textures.reference(&d.reflectance)?, roughness_str(d.alpha)))
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "material cannot be written to a scene file"))
+    }
+}
+
+fn mode_str(m : &RenderMode) -> String {
+    match m {
+        RenderMode::Depth(d) | RenderMode::DepthNormalColor(d) => format!("{} {}", m, d),
+        _ => format!("{}", m),
+    }
+}
+
+// シーン記述を書き出す
+// 画像などのファイルは dir (書き出すシーンファイルのディレクトリ) からの相対パスで書く
+// 同じファイルから続けて読み込んだメッシュはまとめて mesh で書き、
+// 材質がすべて同じでなければ material を省いて .mtl に従わせる
+// メッシュの Instance も同じように instance で書き、変換は matrix で書く
+// ファイルから読み込んでいないメッシュと、それ以外の Scene::shapes はシーンファイルで表せないのでエラーにする
+pub fn write<W : Write, P : AsRef<Path>>(rs : &RenderSetting, dir : P, w : &mut W) -> io::Result<()> {
+    let dir = dir.as_ref();
+    let no_file = || io::Error::new(io::ErrorKind::InvalidData, "mesh without a file cannot be written to a scene file");
+
+    // (ファイル, すべてのメッシュに使う材質)
+    let meshes = rs.scene.meshes().chunk_by(|a, b| a.path == b.path).map(|run| {
+        let path = run[0].path.as_ref().ok_or_else(no_file)?;
+        let m = &run[0].material;
+        Ok((path, if run.iter().all(|o| Arc::ptr_eq(&o.material, m)) {Some(m)} else {None}))
+    }).collect::<io::Result<Vec<_>>>()?;
+
+    // (ファイル, 変換, 代わりに使う材質)
+    let instances = rs.scene.shapes().iter().map(|s| {
+        (&**s as &dyn Any).downcast_ref::<Instance>()
+            .and_then(|i| (&*i.object as &dyn Any).downcast_ref::<TriangleMesh>().map(|mesh| (mesh, i)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "shapes other than mesh instances cannot be written to a scene file"))
+            .and_then(|(mesh, i)| Ok((mesh.path.as_ref().ok_or_else(no_file)?, i.transform, i.material.as_ref())))
+    }).collect::<io::Result<Vec<_>>>()?;
+    let same_material = |a : Option<&Arc<dyn Bsdf>>, b : Option<&Arc<dyn Bsdf>>| match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    };
+    // 同じ instance ブロックから作ったもの
+    let instances : Vec<_> = instances.chunk_by(|a, b| a.0 == b.0 && a.1.matrix == b.1.matrix && same_material(a.2, b.2))
+        .map(|run| run[0])
+        .collect();
+
+    writeln!(w, "render")?;
+    writeln!(w, "  size {} {}", rs.window_size.0, rs.window_size.1)?;
+    writeln!(w, "  spp {}", rs.spp)?;
+    writeln!(w, "  depth {}", optional_str(rs.reflect_n))?;
+    writeln!(w, "  roulette {}", optional_str(rs.roulette_depth))?;
+    writeln!(w, "  mode {}", mode_str(&rs.mode))?;
+    writeln!(w, "  light_sampling {}", rs.light_sampling)?;
+    writeln!(w, "  sampler {}", rs.sampler)?;
+    writeln!(w, "  seed {}", rs.seed)?;
+    writeln!(w, "  filter {} {}", rs.filter, rs.filter.radius())?;
+    writeln!(w, "  exposure {}", rs.tonemap.exposure)?;
+    match rs.tonemap.operator {
+        Operator::ReinhardExtended(white) => writeln!(w, "  tonemap ReinhardExtended {}", white)?,
+        op => writeln!(w, "  tonemap {}", op)?,
+    }
+    writeln!(w, "  dither {}", rs.tonemap.dither)?;
+    if let Some(a) = rs.adaptive {
+        writeln!(w, "  adaptive {} {}", a.threshold, a.pass_spp)?;
+    }
+    writeln!(w, "  background {}", vec3_str(&rs.background))?;
+    writeln!(w, "end")?;
+    writeln!(w)?;
+
+    let c = &rs.camera;
+    writeln!(w, "camera")?;
+    writeln!(w, "  position {}", vec3_str(&c.position))?;
+    writeln!(w, "  focus {}", vec3_str(&c.focus))?;
+    writeln!(w, "  up {}", vec3_str(&c.upside))?;
+    writeln!(w, "  fov {}", degrees_str(c.fov))?;
+    writeln!(w, "  range {} {}", c.tm.0, c.tm.1)?;
+    writeln!(w, "  aperture {}", c.aperture)?;
+    writeln!(w, "  focus_distance {}", c.focus_distance)?;
+    if let Aperture::Polygon{blades, rotation} = c.aperture_shape {
+        writeln!(w, "  blades {} {}", blades, degrees_str(rotation))?;
+    }
+    writeln!(w, "end")?;
+
+    match rs.scene.environment() {
+        None => (),
+        Some(&Environment::Constant(c)) => {
+            writeln!(w)?;
+            writeln!(w, "environment")?;
+            writeln!(w, "  color {}", vec3_str(&c))?;
+            writeln!(w, "end")?;
+        },
+        Some(Environment::Map(m)) => {
+            let path = match m.path {
+                Some(ref p) => p,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "environment map without a file cannot be written to a scene file")),
+            };
+            writeln!(w)?;
+            writeln!(w, "environment")?;
+            writeln!(w, "  file {}", relative_path(path, dir).display())?;
+            writeln!(w, "  rotation {}", degrees_str(m.rotation))?;
+            writeln!(w, "  intensity {}", m.intensity)?;
+            writeln!(w, "end")?;
+        },
+    }
+
+    // 同じ内容の材質はひとつのマテリアルにまとめる
+    let s = &rs.scene;
+    let used = s.spheres().iter().map(|o| &o.material)
+        .chain(s.planes().iter().map(|o| &o.material))
+        .chain(s.polygons().iter().map(|o| &o.material))
+        .chain(meshes.iter().filter_map(|&(_, m)| m))
+        .chain(instances.iter().filter_map(|&(_, _, m)| m));
+
+    let mut textures = TextureBlocks{blocks : Vec::new(), name_of : HashMap::new(), dir : dir.to_path_buf()};
+    let mut materials : Vec<String> = Vec::new();
+    let mut name_of = HashMap::new();
+    for m in used {
+        if let Entry::Vacant(e) = name_of.entry(material_str(&**m, &mut textures)?) {
+            materials.push(e.key().clone());
+            e.insert(format!("m{}", materials.len() - 1));
+        }
+    }
+
+    // 参照される texture は参照する側より前にある
+    for (i, t) in textures.blocks.iter().enumerate() {
+        writeln!(w)?;
+        writeln!(w, "texture t{}", i)?;
+        write!(w, "{}", t)?;
+        writeln!(w, "end")?;
+    }
+
+    for (i, m) in materials.iter().enumerate() {
+        writeln!(w)?;
+        writeln!(w, "material m{}", i)?;
+        write!(w, "{}", m)?;
+        writeln!(w, "end")?;
+    }
+
+    let mut name = |m : &Arc<dyn Bsdf>| material_str(&**m, &mut textures).map(|k| &name_of[&k]);
+
+    for o in s.spheres() {
+        writeln!(w)?;
+        writeln!(w, "sphere")?;
+        writeln!(w, "  center {}", vec3_str(&o.point))?;
+        writeln!(w, "  radius {}", o.radius)?;
+        writeln!(w, "  material {}", name(&o.material)?)?;
+        writeln!(w, "end")?;
+    }
+
+    for o in s.planes() {
+        writeln!(w)?;
+        writeln!(w, "plane")?;
+        writeln!(w, "  normal {}", vec3_str(&o.normal))?;
+        writeln!(w, "  point {}", vec3_str(&o.point))?;
+        writeln!(w, "  material {}", name(&o.material)?)?;
+        writeln!(w, "end")?;
+    }
+
+    for o in s.polygons() {
+        let [a, b, c] = o.points;
+        writeln!(w)?;
+        writeln!(w, "polygon")?;
+        writeln!(w, "  points {} {} {}", vec3_str(&a), vec3_str(&b), vec3_str(&c))?;
+        writeln!(w, "  material {}", name(&o.material)?)?;
+        writeln!(w, "end")?;
+    }
+
+    for &(path, m) in &meshes {
+        writeln!(w)?;
+        writeln!(w, "mesh")?;
+        writeln!(w, "  file {}", relative_path(path, dir).display())?;
+        if let Some(m) = m {
+            writeln!(w, "  material {}", name(m)?)?;
+        }
+        writeln!(w, "end")?;
+    }
+
+    // 読み直したときに放射輝度が変わらないように radiance で書く
+    for l in s.area_lights() {
+        writeln!(w)?;
+        writeln!(w, "light")?;
+        match l.shape {
+            LightShape::Quad{corner, edges : (e0, e1)} => {
+                writeln!(w, "  type Quad")?;
+                writeln!(w, "  corner {}", vec3_str(&corner))?;
+                writeln!(w, "  edge0 {}", vec3_str(&e0))?;
+                writeln!(w, "  edge1 {}", vec3_str(&e1))?;
+            },
+            LightShape::Disk{center, normal, radius} => {
+                writeln!(w, "  type Disk")?;
+                writeln!(w, "  center {}", vec3_str(&center))?;
+                writeln!(w, "  normal {}", vec3_str(&normal))?;
+                writeln!(w, "  radius {}", radius)?;
+            },
+            LightShape::Sphere{center, radius} => {
+                writeln!(w, "  type Sphere")?;
+                writeln!(w, "  center {}", vec3_str(&center))?;
+                writeln!(w, "  radius {}", radius)?;
+            },
+        }
+        writeln!(w, "  radiance {}", vec3_str(&l.le))?;
+        writeln!(w, "  two_sided {}", l.two_sided)?;
+        writeln!(w, "end")?;
+    }
+
+    for l in s.analytic_lights() {
+        writeln!(w)?;
+        writeln!(w, "light")?;
+        match *l {
+            AnalyticLight::Point{position, intensity} => {
+                writeln!(w, "  type Point")?;
+                writeln!(w, "  position {}", vec3_str(&position))?;
+                writeln!(w, "  intensity {}", vec3_str(&intensity))?;
+            },
+            AnalyticLight::Spot{position, direction, intensity, angle, falloff} => {
+                writeln!(w, "  type Spot")?;
+                writeln!(w, "  position {}", vec3_str(&position))?;
+                writeln!(w, "  direction {}", vec3_str(&direction))?;
+                writeln!(w, "  angle {}", degrees_str(angle))?;
+                writeln!(w, "  falloff {}", degrees_str(falloff))?;
+                writeln!(w, "  intensity {}", vec3_str(&intensity))?;
+            },
+            AnalyticLight::Directional{direction, irradiance, angular_diameter} => {
+                writeln!(w, "  type Directional")?;
+                writeln!(w, "  direction {}", vec3_str(&direction))?;
+                writeln!(w, "  irradiance {}", vec3_str(&irradiance))?;
+                writeln!(w, "  angular_diameter {}", degrees_str(angular_diameter))?;
+            },
+        }
+        writeln!(w, "end")?;
+    }
+
+    for &(path, transform, m) in &instances {
+        writeln!(w)?;
+        writeln!(w, "instance")?;
+        writeln!(w, "  file {}", relative_path(path, dir).display())?;
+        let rows = &transform.matrix.0[..3];
+        writeln!(w, "  matrix {}", rows.iter().flatten().map(|v| v.to_string()).collect::<Vec<_>>().join(" "))?;
+        if let Some(m) = m {
+            writeln!(w, "  material {}", name(m)?)?;
+        }
+        writeln!(w, "end")?;
+    }
+
+    Ok(())
+}
+
+pub fn save<P : AsRef<Path>>(rs : &RenderSetting, path : P) -> Result<(), SceneFileError> {
+    let path = path.as_ref();
+    let io_error = |e| SceneFileError::Io(path.to_path_buf(), e);
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut f = io::BufWriter::new(fs::File::create(path).map_err(io_error)?);
+    write(rs, dir, &mut f).and_then(|_| f.flush()).map_err(io_error)
+}
@@ -0,0 +1,200 @@
+use std;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use geo::*;
+use obj::HitRecord;
+use io::{self, ReadImageError};
+use tonemap::srgb_eotf;
+use noise::Solid;
+
+// 画像の範囲 [0, 1) の外の uv の扱い
+#[derive(Copy, Clone, PartialEq, Default)]
+pub enum Wrap {
+    #[default]
+    Repeat,
+    // 端の画素を伸ばす
+    Clamp,
+    // 1 ごとに折り返す
+    Mirror,
+}
+
+impl fmt::Display for Wrap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = match self {
+            Wrap::Repeat => "Repeat",
+            Wrap::Clamp => "Clamp",
+            Wrap::Mirror => "Mirror",
+        };
+
+        write!(f, "{}", n)
+    }
+}
+
+impl std::str::FromStr for Wrap {
+    type Err = String;
+    fn from_str(s : &str) -> Result<Wrap, String> {
+        match s {
+            "Repeat" => Ok(Wrap::Repeat),
+            "Clamp" => Ok(Wrap::Clamp),
+            "Mirror" => Ok(Wrap::Mirror),
+            _ => Err(format!("unknown wrap mode '{}'", s)),
+        }
+    }
+}
+
+impl Wrap {
+    // 長さ n の範囲に入れた添字
+    fn index(&self, i : i64, n : usize) -> usize {
+        let n = n as i64;
+        (match *self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Clamp => i.clamp(0, n - 1),
+            Wrap::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n {i} else {2 * n - 1 - i}
+            },
+        }) as usize
+    }
+}
+
+// 線形な値の画像
+// pixels は上の行から順に並び、v = 1 が上端になる
+pub struct Image {
+    pub size : (usize, usize),
+    pub pixels : Vec<Vec3>,
+    // 読み込んだファイル (シーンファイルに書き出すときに使う)
+    pub path : Option<PathBuf>,
+    // ファイルの値を sRGB から線形な値に直したか
+    pub srgb : bool,
+}
+
+impl Image {
+    pub fn new(size : (usize, usize), pixels : Vec<Vec3>) -> Image {
+        assert!(size.0 > 0 && size.1 > 0 && pixels.len() == size.0 * size.1, "pixels must have width * height elements");
+        Image{size, pixels, path : None, srgb : false}
+    }
+
+    // .hdr, .pfm はそのまま, .png, .ppm は sRGB から線形な値に直して読む
+    pub fn load<P : AsRef<Path>>(path : P) -> Result<Image, ReadImageError> {
+        Image::load_with(path.as_ref(), true)
+    }
+
+    // .png, .ppm も [0, 1] にした値をそのまま使う (法線マップや高さなど色でない値)
+    pub fn load_linear<P : AsRef<Path>>(path : P) -> Result<Image, ReadImageError> {
+        Image::load_with(path.as_ref(), false)
+    }
+
+    fn load_with(path : &Path, srgb : bool) -> Result<Image, ReadImageError> {
+        let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+        let (size, pixels, srgb) = match ext.as_deref() {
+            Some("png") | Some("ppm") => {
+                let (size, pixels) = io::read_ldr_image(path)?;
+                if srgb {
+                    (size, pixels.into_iter().map(|v| Vec3{x : srgb_eotf(v.x), y : srgb_eotf(v.y), z : srgb_eotf(v.z)}).collect(), true)
+                } else {
+                    (size, pixels, false)
+                }
+            },
+            _ => {
+                let (size, pixels) = io::read_hdr_image(path)?;
+                (size, pixels, false)
+            },
+        };
+        Ok(Image{path : Some(path.to_path_buf()), srgb, .. Image::new(size, pixels)})
+    }
+
+    fn texel(&self, (x, y) : (i64, i64), wrap : Wrap) -> Vec3 {
+        let (w, h) = self.size;
+        self.pixels[wrap.index(y, h) * w + wrap.index(x, w)]
+    }
+
+    // 周りの 4 画素を双線形補間する
+    pub fn bilinear(&self, (u, v) : (f64, f64), wrap : Wrap) -> Vec3 {
+        let (w, h) = self.size;
+        let x = u * w as f64 - 0.5;
+        let y = (1.0 - v) * h as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        self.texel((x0, y0), wrap) * ((1.0 - fx) * (1.0 - fy))
+            + self.texel((x0 + 1, y0), wrap) * (fx * (1.0 - fy))
+            + self.texel((x0, y0 + 1), wrap) * ((1.0 - fx) * fy)
+            + self.texel((x0 + 1, y0 + 1), wrap) * (fx * fy)
+    }
+}
+
+// 表面の位置で変わる色 (反射率や放射輝度に使う)
+#[derive(Clone)]
+pub enum Texture {
+    Constant(Vec3),
+    // u と v の整数部分の和が偶数なら 0 番目, 奇数なら 1 番目
+    Checker(Box<Texture>, Box<Texture>),
+    Image(Arc<Image>, Wrap),
+    // uv * scale + offset の位置で texture を引く
+    Transform{texture : Box<Texture>, scale : (f64, f64), offset : (f64, f64)},
+    // HitRecord::point (ワールド座標) での solid の値 t で low と high を補間する
+    Solid{solid : Solid, low : Box<Texture>, high : Box<Texture>},
+    // texture の値に成分ごとに factor を掛ける (.mtl の map_Kd と Kd など)
+    Scale{texture : Box<Texture>, factor : Vec3},
+}
+
+impl From<Vec3> for Texture {
+    fn from(v : Vec3) -> Texture {
+        Texture::Constant(v)
+    }
+}
+
+impl Texture {
+    pub fn evaluate(&self, hr : &HitRecord) -> Vec3 {
+        match self {
+            Texture::Constant(v) => *v,
+            Texture::Checker(even, odd) => {
+                let (u, v) = hr.uv;
+                if (u.floor() + v.floor()).rem_euclid(2.0) == 0.0 {
+                    even.evaluate(hr)
+                } else {
+                    odd.evaluate(hr)
+                }
+            },
+            Texture::Image(image, wrap) => image.bilinear(hr.uv, *wrap),
+            Texture::Transform{texture, scale, offset} => {
+                let (u, v) = hr.uv;
+                let uv = (u * scale.0 + offset.0, v * scale.1 + offset.1);
+                texture.evaluate(&HitRecord{uv, .. *hr})
+            },
+            Texture::Solid{solid, low, high} => {
+                let t = solid.value(&hr.point);
+                low.evaluate(hr) * (1.0 - t) + high.evaluate(hr) * t
+            },
+            Texture::Scale{texture, factor} => texture.evaluate(hr) * *factor,
+        }
+    }
+
+    // どこでも 0
+    pub fn is_black(&self) -> bool {
+        match self {
+            Texture::Constant(v) => *v == Vec3::new(0.0),
+            Texture::Checker(even, odd) => even.is_black() && odd.is_black(),
+            Texture::Image(image, _) => image.pixels.iter().all(|p| *p == Vec3::new(0.0)),
+            Texture::Transform{texture, ..} => texture.is_black(),
+            Texture::Solid{low, high, ..} => low.is_black() && high.is_black(),
+            Texture::Scale{texture, factor} => *factor == Vec3::new(0.0) || texture.is_black(),
+        }
+    }
+
+    // どこでも各成分が 0 以上
+    pub fn is_non_negative(&self) -> bool {
+        let non_negative = |v : &Vec3| v.x >= 0.0 && v.y >= 0.0 && v.z >= 0.0;
+        match self {
+            Texture::Constant(v) => non_negative(v),
+            Texture::Checker(even, odd) => even.is_non_negative() && odd.is_non_negative(),
+            Texture::Image(image, _) => image.pixels.iter().all(non_negative),
+            Texture::Transform{texture, ..} => texture.is_non_negative(),
+            Texture::Solid{low, high, ..} => low.is_non_negative() && high.is_non_negative(),
+            Texture::Scale{texture, factor} => non_negative(factor) && texture.is_non_negative(),
+        }
+    }
+}
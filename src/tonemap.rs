@@ -0,0 +1,184 @@
+use std;
+use std::fmt;
+
+use geo::*;
+use sampler::hash;
+
+// 露出を掛けた線形な値を [0, 1] に収める曲線
+#[derive(Copy, Clone, PartialEq)]
+pub enum Operator {
+    // 1 を超える値はそのまま切り捨てる
+    Linear,
+    // 輝度 L を L / (1 + L) にする
+    Reinhard,
+    // 輝度が white のときにちょうど 1 になる Reinhard
+    ReinhardExtended(f64),
+    // Narkowicz による ACES filmic の近似
+    Aces,
+    // Hable の Uncharted 2 filmic
+    Uncharted2,
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = match self {
+            Operator::Linear => "Linear",
+            Operator::Reinhard => "Reinhard",
+            Operator::ReinhardExtended(_) => "ReinhardExtended",
+            Operator::Aces => "Aces",
+            Operator::Uncharted2 => "Uncharted2",
+        };
+
+        write!(f, "{}", n)
+    }
+}
+
+// "Linear", "Reinhard", "ReinhardExtended:4", "Aces", "Uncharted2"
+// ReinhardExtended の white を省略したときは 4
+impl std::str::FromStr for Operator {
+    type Err = String;
+    fn from_str(s : &str) -> Result<Operator, String> {
+        let mut it = s.splitn(2, ':');
+        let name = it.next().unwrap_or("");
+        let white = match it.next() {
+            None => 4.0,
+            Some(w) => match w.parse::<f64>() {
+                Ok(w) if w > 0.0 && name == "ReinhardExtended" => w,
+                _ => return Err(format!("invalid parameter '{}' for {}", w, name)),
+            },
+        };
+
+        match name {
+            "Linear" => Ok(Operator::Linear),
+            "Reinhard" => Ok(Operator::Reinhard),
+            "ReinhardExtended" => Ok(Operator::ReinhardExtended(white)),
+            "Aces" => Ok(Operator::Aces),
+            "Uncharted2" => Ok(Operator::Uncharted2),
+            _ => Err(format!("unknown tonemap operator '{}'", s)),
+        }
+    }
+}
+
+pub(crate) fn luminance(v : &Vec3) -> f64 {
+    0.2126 * v.x + 0.7152 * v.y + 0.0722 * v.z
+}
+
+fn map_each<F : Fn(f64) -> f64>(v : Vec3, f : F) -> Vec3 {
+    Vec3::new((f(v.x), f(v.y), f(v.z)))
+}
+
+fn uncharted2(x : f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+impl Operator {
+    pub fn apply(&self, v : Vec3) -> Vec3 {
+        match *self {
+            Operator::Linear => v,
+            // 色相が変わらないように輝度で縮める
+            Operator::Reinhard | Operator::ReinhardExtended(_) => {
+                let l = luminance(&v);
+                if l <= 0.0 {
+                    return v;
+                }
+                let mapped = match *self {
+                    Operator::ReinhardExtended(white) => l * (1.0 + l / white.powi(2)) / (1.0 + l),
+                    _ => l / (1.0 + l),
+                };
+                v * (mapped / l)
+            },
+            Operator::Aces => map_each(v, |x| {
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                x * (a * x + b) / (x * (c * x + d) + e)
+            }),
+            Operator::Uncharted2 => {
+                // 露出を 2 倍してから白色点 11.2 で正規化する
+                let white = uncharted2(11.2);
+                map_each(v, |x| uncharted2(2.0 * x) / white)
+            },
+        }
+    }
+}
+
+// sRGB の OETF (線形な値から符号化した値へ)
+pub fn srgb_oetf(x : f64) -> f64 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// sRGB の EOTF (符号化した値から線形な値へ)
+pub fn srgb_eotf(x : f64) -> f64 {
+    if x <= 0.040_45 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// 線形な放射輝度を表示用の整数にする
+// 露出, 曲線, sRGB の OETF, ディザの順に適用し、負の値は 0 にする
+#[derive(Copy, Clone, PartialEq)]
+pub struct Tonemap {
+    // 2^exposure 倍する
+    pub exposure : f64,
+    pub operator : Operator,
+    // 量子化の前に 1 段階分の三角分布のノイズを足して帯状のむらを防ぐ
+    // ちょうど 0 の黒と、1 で切り捨てた白には足さない
+    pub dither : bool,
+}
+
+impl Default for Tonemap {
+    fn default() -> Tonemap {
+        Tonemap {
+            exposure : 0.0,
+            operator : Operator::Linear,
+            dither : true,
+        }
+    }
+}
+
+impl Tonemap {
+    // 曲線を適用して [0, 1] に切り詰めた、符号化する前の線形な値
+    fn clipped(&self, v : Vec3) -> Vec3 {
+        let v = map_each(v * 2f64.powf(self.exposure), |x| x.max(0.0));
+        map_each(self.operator.apply(v), |x| x.clamp(0.0, 1.0))
+    }
+
+    // [0, 1] の sRGB で符号化された値
+    pub fn map(&self, v : Vec3) -> Vec3 {
+        map_each(self.clipped(v), srgb_oetf)
+    }
+
+    // index は画素の番号で、ディザのノイズを決める
+    fn quantize(&self, v : Vec3, index : usize, max : f64) -> [f64; 3] {
+        let l = self.clipped(v);
+        let mut out = [l.x, l.y, l.z];
+        for (c, o) in out.iter_mut().enumerate() {
+            // 黒と白は符号化する前の値で判定する (srgb_oetf(1.0) は丸め誤差で 1 をわずかに下回る)
+            let noise = if self.dither && *o > 0.0 && *o < 1.0 {
+                let h = hash(&[index as u64, c as u64]);
+                // 2 つの一様乱数の和で [-1, 1) の三角分布
+                let u = |x : u64| (x & 0xffff_ffff) as f64 / (1u64 << 32) as f64;
+                u(h) + u(h >> 32) - 1.0
+            } else {
+                0.0
+            };
+            *o = (srgb_oetf(*o) * max + noise).round().clamp(0.0, max);
+        }
+        out
+    }
+
+    pub fn to_rgb8(&self, v : Vec3, index : usize) -> (u8, u8, u8) {
+        let [r, g, b] = self.quantize(v, index, 255.0);
+        (r as u8, g as u8, b as u8)
+    }
+
+    pub fn to_rgb16(&self, v : Vec3, index : usize) -> (u16, u16, u16) {
+        let [r, g, b] = self.quantize(v, index, 65535.0);
+        (r as u16, g as u16, b as u16)
+    }
+}
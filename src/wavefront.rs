@@ -0,0 +1,425 @@
+use std::{fs, io, fmt};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use std;
+
+use geo::*;
+use obj::*;
+use env::Scene;
+use material::{Bsdf, Diffuse, Mirror, Fresnel, NormalMap, Perturbed};
+use mesh::{TriangleMesh, MeshError};
+use texture::{Texture, Image, Wrap};
+use io::ReadImageError;
+
+#[derive(Debug)]
+pub enum LoadObjError {
+    Io(PathBuf, io::Error),
+    Parse{file : PathBuf, line : usize, message : String},
+    Mesh(PathBuf, MeshError),
+    // map_Kd などの画像
+    Image(PathBuf, ReadImageError),
+}
+
+impl std::fmt::Display for LoadObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use wavefront::LoadObjError::*;
+
+        match self {
+            Io(p, e) => write!(f, "{}: {}", p.display(), e),
+            Parse{file, line, message} => write!(f, "{}:{}: {}", file.display(), line, message),
+            Mesh(p, e) => write!(f, "{}: {}", p.display(), e),
+            Image(p, e) => write!(f, "{}: {}", p.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for LoadObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use wavefront::LoadObjError::*;
+        match self {
+            Io(_, e) => Some(e),
+            Image(_, e) => Some(e),
+            Parse{..} => None,
+            Mesh(_, e) => Some(e),
+        }
+    }
+}
+
+// usemtl より前の面に使う材質
+fn default_material() -> Arc<dyn Bsdf> {
+    Arc::new(Diffuse{reflectance : Vec3::new(0.75).into(), le : Vec3::new(0.0).into()})
+}
+
+struct Lines<'a> {
+    file : &'a Path,
+    line : usize,
+}
+
+impl<'a> Lines<'a> {
+    fn error<T, S : Into<String>>(&self, message : S) -> Result<T, LoadObjError> {
+        Err(LoadObjError::Parse{file : self.file.to_path_buf(), line : self.line, message : message.into()})
+    }
+
+    fn floats(&self, args : &[&str], n : usize) -> Result<Vec<f64>, LoadObjError> {
+        if args.len() < n {
+            return self.error(format!("expected {} numbers, found {}", n, args.len()));
+        }
+        args.iter().take(n).map(|a| match a.parse::<f64>() {
+            Ok(v) => Ok(v),
+            Err(_) => self.error(format!("invalid number '{}'", a)),
+        }).collect()
+    }
+
+    fn vec3(&self, args : &[&str]) -> Result<Vec3, LoadObjError> {
+        let v = self.floats(args, 3)?;
+        Ok(Vec3::new((v[0], v[1], v[2])))
+    }
+}
+
+fn read(path : &Path) -> Result<String, LoadObjError> {
+    fs::read_to_string(path).map_err(|e| LoadObjError::Io(path.to_path_buf(), e))
+}
+
+// 空行とコメントを除き、(行番号, キーワード, 引数) を返す
+pub(crate) fn statements(source : &str) -> impl Iterator<Item = (usize, &str, Vec<&str>)> {
+    source.lines().enumerate().filter_map(|(i, l)| {
+        let l = l.split('#').next().unwrap_or("");
+        let mut words = l.split_whitespace();
+        words.next().map(|k| (i + 1, k, words.collect()))
+    })
+}
+
+fn load_mtl(path : &Path, materials : &mut HashMap<String, Arc<dyn Bsdf>>) -> Result<(), LoadObjError> {
+    let source = read(path)?;
+
+    // illum と Ni は順不同なので、最後にまとめて材質を決める
+    struct Entry {
+        name : String,
+        kd : Option<Vec3>,
+        ke : Option<Vec3>,
+        map_kd : Option<Texture>,
+        map_ke : Option<Texture>,
+        illum : u32,
+        ior : f64,
+        map : Option<NormalMap>,
+        // 最後の Ke か map_Ke の行
+        emission_line : usize,
+    }
+
+    // 画像があれば Kd (Ke) を掛ける
+    fn texture(color : Option<Vec3>, map : Option<Texture>, default : f64) -> Texture {
+        match (map, color) {
+            (Some(t), None) => t,
+            (Some(t), Some(c)) if c == Vec3::new(1.0) => t,
+            (Some(t), Some(c)) => Texture::Scale{texture : Box::new(t), factor : c},
+            (None, c) => c.unwrap_or_else(|| Vec3::new(default)).into(),
+        }
+    }
+
+    // 放射できるのは拡散面だけなので、鏡やガラスの 0 でない Ke はエラーにする
+    fn finish(path : &Path, e : Entry, materials : &mut HashMap<String, Arc<dyn Bsdf>>) -> Result<(), LoadObjError> {
+        let reflectance = texture(e.kd, e.map_kd, 0.75);
+        let le = texture(e.ke, e.map_ke, 0.0);
+        if matches!(e.illum, 3 | 4 | 6 | 7) && !le.is_black() {
+            let l = Lines{file : path, line : e.emission_line};
+            return l.error(format!("material '{}': emission is only supported for diffuse materials, not illum {}", e.name, e.illum));
+        }
+        let material : Arc<dyn Bsdf> = match e.illum {
+            3 => Arc::new(Mirror{reflectance}),
+            4 | 6 | 7 => Arc::new(Fresnel{ior : e.ior, reflectance}),
+            _ => Arc::new(Diffuse{reflectance, le}),
+        };
+        let material = match e.map {
+            Some(map) => Arc::new(Perturbed{material, map}),
+            None => material,
+        };
+        materials.insert(e.name, material);
+        Ok(())
+    }
+
+    let mut current : Option<Entry> = None;
+    // 同じ画像を使う材質は読み込んだ画像を共有する
+    let mut images : HashMap<(PathBuf, bool), Arc<Image>> = HashMap::new();
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    for (line, keyword, args) in statements(&source) {
+        let l = Lines{file : path, line};
+
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return l.error("newmtl without a name");
+            }
+            if let Some(e) = current.take() {
+                finish(path, e, materials)?;
+            }
+            current = Some(Entry{
+                name : args.join(" "),
+                kd : None,
+                ke : None,
+                map_kd : None,
+                map_ke : None,
+                illum : 2,
+                ior : fresnel::GLASSBK7,
+                map : None,
+                emission_line : line,
+            });
+            continue;
+        }
+
+        let e = match current.as_mut() {
+            Some(e) => e,
+            None => return l.error(format!("'{}' before newmtl", keyword)),
+        };
+
+        // map_Kd, map_Ke の画像には Kd, Ke を掛ける (Kd を書かなければ画像の値そのまま)
+        // -s などのオプションは読み飛ばし、最後の引数をファイル名とする
+        // 色でない画像 (bump, norm) は sRGB から直さない
+        let mut image = |srgb : bool| -> Result<Texture, LoadObjError> {
+            let file = match args.last() {
+                Some(f) => dir.join(f),
+                None => return l.error(format!("{} without a file", keyword)),
+            };
+            let image = match images.get(&(file.clone(), srgb)) {
+                Some(i) => i.clone(),
+                None => {
+                    let i = if srgb {Image::load(&file)} else {Image::load_linear(&file)};
+                    let i = Arc::new(i.map_err(|e| LoadObjError::Image(file.clone(), e))?);
+                    images.insert((file, srgb), i.clone());
+                    i
+                },
+            };
+            Ok(Texture::Image(image, Wrap::Repeat))
+        };
+
+        match keyword {
+            "Kd" => e.kd = Some(l.vec3(&args)?),
+            "Ke" => {
+                e.ke = Some(l.vec3(&args)?);
+                e.emission_line = line;
+            },
+            "map_Kd" => e.map_kd = Some(image(true)?),
+            "map_Ke" => {
+                e.map_ke = Some(image(true)?);
+                e.emission_line = line;
+            },
+            // 高さに -bm の倍率を掛ける (省略すると 1)
+            "bump" | "map_Bump" => {
+                let scale = match args.iter().position(|a| *a == "-bm") {
+                    Some(i) => match args.get(i + 1).map(|a| a.parse::<f64>()) {
+                        Some(Ok(s)) => s,
+                        _ => return l.error("-bm expects a number"),
+                    },
+                    None => 1.0,
+                };
+                e.map = Some(NormalMap::Bump{height : image(false)?, scale});
+            },
+            "norm" => e.map = Some(NormalMap::Tangent(image(false)?)),
+            "Ni" => e.ior = l.floats(&args, 1)?[0],
+            "illum" => e.illum = match args.first().map(|a| a.parse::<u32>()) {
+                Some(Ok(i)) => i,
+                _ => return l.error("illum expects an integer"),
+            },
+            // Ka, Ks, Ns, d, map_Ks などは使わない
+            _ => (),
+        }
+    }
+
+    if let Some(e) = current.take() {
+        finish(path, e, materials)?;
+    }
+
+    Ok(())
+}
+
+// 1 始まりの添字を 0 始まりにする
+// 負の添字は末尾からの相対指定
+fn index(l : &Lines, what : &str, arg : &str, count : usize) -> Result<usize, LoadObjError> {
+    let i = match arg.parse::<i64>() {
+        Ok(i) => i,
+        Err(_) => return l.error(format!("invalid {} index '{}'", what, arg)),
+    };
+
+    let index = if i > 0 {
+        i - 1
+    } else {
+        count as i64 + i
+    };
+
+    if i == 0 || index < 0 || index as usize >= count {
+        l.error(format!("{} index {} out of range (1..={})", what, i, count))
+    } else {
+        Ok(index as usize)
+    }
+}
+
+// 面の頂点指定 (v, v/vt, v//vn, v/vt/vn) から (v, vt, vn) の添字を取り出す
+type FaceVertex = (usize, Option<usize>, Option<usize>);
+
+fn face_vertex(l : &Lines, arg : &str, (nv, nt, nn) : (usize, usize, usize)) -> Result<FaceVertex, LoadObjError> {
+    let mut it = arg.split('/');
+    let v = index(l, "vertex", it.next().unwrap_or(""), nv)?;
+    let vt = match it.next() {
+        None | Some("") => None,
+        Some(a) => Some(index(l, "texture coordinate", a, nt)?),
+    };
+    let vn = match it.next() {
+        None | Some("") => None,
+        Some(a) => Some(index(l, "normal", a, nn)?),
+    };
+    Ok((v, vt, vn))
+}
+
+// 材質ごとに頂点を共有しながら組み立てるメッシュ
+struct MeshBuilder {
+    material : Arc<dyn Bsdf>,
+    positions : Vec<Vec3>,
+    normals : Vec<Vec3>,
+    uvs : Vec<(f64, f64)>,
+    indices : Vec<[usize; 3]>,
+    vertex_of : HashMap<FaceVertex, usize>,
+    has_normals : bool,
+    has_uvs : bool,
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, fv : FaceVertex, vertices : &[Vec3], normals : &[Vec3], uvs : &[(f64, f64)]) -> usize {
+        if let Some(&i) = self.vertex_of.get(&fv) {
+            return i;
+        }
+        let (v, vt, vn) = fv;
+        self.positions.push(vertices[v]);
+        self.normals.push(vn.map(|i| normals[i]).unwrap_or_default());
+        self.uvs.push(vt.map(|i| uvs[i]).unwrap_or_default());
+        self.vertex_of.insert(fv, self.positions.len() - 1);
+        self.positions.len() - 1
+    }
+
+    fn finish(mut self) -> Result<TriangleMesh, MeshError> {
+        if !self.has_normals {
+            self.normals.clear();
+        }
+        if !self.has_uvs {
+            self.uvs.clear();
+        }
+        TriangleMesh::new(self.positions, self.normals, self.uvs, self.indices, self.material)
+    }
+}
+
+// Wavefront OBJ ファイルを読み込み、材質ごとにひとつのメッシュにする
+// n 角形は最初の頂点を中心に扇状に分割する
+// 法線 (テクスチャ座標) の有無が違う面は別のメッシュにする
+// 一部の頂点にしか法線 (テクスチャ座標) がない面ではそれを使わない
+pub fn load_meshes<P : AsRef<Path>>(path : P) -> Result<Vec<TriangleMesh>, LoadObjError> {
+    let path = path.as_ref();
+    let source = read(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut materials = HashMap::new();
+    let mut vertices : Vec<Vec3> = Vec::new();
+    let mut normals : Vec<Vec3> = Vec::new();
+    let mut uvs : Vec<(f64, f64)> = Vec::new();
+
+    // (usemtl の名前 (最初は ""), 法線の有無, テクスチャ座標の有無) からメッシュへ
+    let mut meshes : Vec<MeshBuilder> = Vec::new();
+    let mut mesh_of : HashMap<(String, bool, bool), usize> = HashMap::new();
+    let mut current = String::new();
+    let mut mtl = default_material();
+
+    for (line, keyword, args) in statements(&source) {
+        let l = Lines{file : path, line};
+
+        match keyword {
+            "v" => vertices.push(l.vec3(&args)?),
+            "vn" => normals.push(l.vec3(&args)?),
+            "vt" => {
+                // 3 つ目の w は使わない
+                if args.len() < 2 || args.len() > 3 {
+                    return l.error(format!("expected 2 or 3 numbers, found {}", args.len()));
+                }
+                let v = l.floats(&args[..2], 2)?;
+                uvs.push((v[0], v[1]));
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return l.error(format!("face needs at least 3 vertices, found {}", args.len()));
+                }
+                let mut fvs = args.iter()
+                    .map(|a| face_vertex(&l, a, (vertices.len(), uvs.len(), normals.len())))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let has_normals = fvs.iter().all(|fv| fv.2.is_some());
+                let has_uvs = fvs.iter().all(|fv| fv.1.is_some());
+                for fv in fvs.iter_mut() {
+                    if !has_normals {
+                        fv.2 = None;
+                    }
+                    if !has_uvs {
+                        fv.1 = None;
+                    }
+                }
+
+                let m = *mesh_of.entry((current.clone(), has_normals, has_uvs)).or_insert_with(|| {
+                    meshes.push(MeshBuilder {
+                        material : mtl.clone(),
+                        positions : Vec::new(),
+                        normals : Vec::new(),
+                        uvs : Vec::new(),
+                        indices : Vec::new(),
+                        vertex_of : HashMap::new(),
+                        has_normals,
+                        has_uvs,
+                    });
+                    meshes.len() - 1
+                });
+                let mesh = &mut meshes[m];
+                let indices : Vec<_> = fvs.into_iter().map(|fv| mesh.vertex(fv, &vertices, &normals, &uvs)).collect();
+                for i in 1..indices.len() - 1 {
+                    mesh.indices.push([indices[0], indices[i], indices[i + 1]]);
+                }
+            },
+            "mtllib" => {
+                if args.is_empty() {
+                    return l.error("mtllib without a file name");
+                }
+                for name in args {
+                    load_mtl(&dir.join(name), &mut materials)?;
+                }
+            },
+            "usemtl" => {
+                let name = args.join(" ");
+                mtl = match materials.get(&name) {
+                    Some(m) => m.clone(),
+                    None => return l.error(format!("unknown material '{}'", name)),
+                };
+                current = name;
+            },
+            // o, g, s などは形状に影響しない
+            _ => (),
+        }
+    }
+
+    meshes.into_iter().map(|b| {
+        let mut mesh = b.finish().map_err(|e| LoadObjError::Mesh(path.to_path_buf(), e))?;
+        mesh.path = Some(path.to_path_buf());
+        Ok(mesh)
+    }).collect()
+}
+
+// Wavefront OBJ ファイルを読み込み三角形の列にする
+// 頂点の法線とテクスチャ座標は使わない
+pub fn load<P : AsRef<Path>>(path : P) -> Result<Vec<Polygon>, LoadObjError> {
+    Ok(load_meshes(path)?.into_iter().flat_map(|mesh| {
+        (0..mesh.indices().len())
+            .map(|i| Polygon{points : mesh.triangle(i), material : mesh.material.clone()})
+            .collect::<Vec<_>>()
+    }).collect())
+}
+
+// 読み込んだメッシュをシーンに追加し、三角形の数を返す
+pub fn append<P : AsRef<Path>>(scene : &mut Scene, path : P) -> Result<usize, LoadObjError> {
+    let meshes = load_meshes(path)?;
+    let n = meshes.iter().map(|m| m.indices().len()).sum();
+    scene.meshes_mut().extend(meshes);
+    Ok(n)
+}
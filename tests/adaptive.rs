@@ -0,0 +1,66 @@
+extern crate raytrace;
+
+mod common;
+
+use raytrace::env::*;
+use raytrace::environment::Environment;
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::render::*;
+
+use std::sync::Arc;
+
+const LE : f64 = 1.0;
+const ALBEDO : f64 = 0.5;
+const PASS_SPP : usize = 8;
+const MAX_SPP : usize = 256;
+
+// 上半分は一様な環境光 (分散 0)、下半分はそれに照らされた拡散反射の床 (1 回の反射で a * le)
+fn horizon() -> Framebuffer {
+    let camera = Camera::new(Vec3::new((0.0, 1.0, 0.0)), Vec3::new((0.0, 1.0, -1.0)), Vec3::new((0.0, 1.0, 0.0)), 60.0, (0.1f64.powi(4), 10.0f64.powi(10)));
+    let mut scene = Scene::new(
+        Vec::new(),
+        vec![Plane{
+            normal : Vec3::new((0.0, 1.0, 0.0)),
+            point : Vec3::new(0.0),
+            material : Arc::new(Diffuse{reflectance : Vec3::new(ALBEDO).into(), le : Vec3::new(0.0).into()}),
+        }],
+        Vec::new(),
+    );
+    *scene.environment_mut() = Some(Environment::Constant(Vec3::new(LE)));
+    scene.rebuild();
+
+    let rs = RenderSetting {
+        spp : MAX_SPP,
+        reflect_n : Some(2),
+        roulette_depth : None,
+        adaptive : Some(Adaptive{threshold : 0.02, pass_spp : PASS_SPP}),
+        ..common::setting(camera, scene)
+    };
+
+    render(&rs)
+}
+
+#[test]
+fn adaptive_sampling_spends_samples_on_noisy_pixels() {
+    let fb = horizon();
+    let (w, h) = fb.size;
+
+    // 一番上の行は空だけ、一番下の行は床だけが見える
+    // 床は上限より前に閾値を下回る
+    let sky = &fb.samples[..w];
+    let floor = &fb.samples[(h - 1) * w..];
+    assert!(sky.iter().all(|&n| n == PASS_SPP), "{:?}", sky);
+    assert!(floor.iter().all(|&n| n > 4 * PASS_SPP && n < MAX_SPP), "{:?}", floor);
+
+    let expected = ALBEDO * LE;
+    let mean = fb.pixels[(h - 1) * w..].iter().map(|v| v.x).sum::<f64>() / w as f64;
+    assert!((mean - expected).abs() < 0.02 * expected, "{} != {}", mean, expected);
+
+    // 最もサンプルが多い画素は赤くなる
+    let heatmap = fb.sample_heatmap();
+    let busiest = (0..w * h).max_by_key(|&i| fb.samples[i]).unwrap();
+    assert!(heatmap.pixels[busiest] == Vec3::new((1.0, 0.0, 0.0)));
+    assert!(heatmap.pixels[0].z > 0.5);
+}
@@ -0,0 +1,96 @@
+extern crate raytrace;
+
+mod common;
+
+use raytrace::env::*;
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::render::*;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// クレートの外で定義した、半球上で一様にサンプリングする拡散反射
+struct UniformDiffuse {
+    albedo : f64,
+    le : f64,
+}
+
+impl UniformDiffuse {
+    fn normal(hr : &HitRecord, wo : &Vec3) -> Vec3 {
+        if hr.normal.dot(wo) > 0.0 {hr.normal} else {hr.normal * -1.0}
+    }
+}
+
+impl Bsdf for UniformDiffuse {
+    fn sample(&self, hr : &HitRecord, wo : &Vec3, (u1, u2) : (f64, f64), _ : f64) -> Option<BsdfSample> {
+        let n = UniformDiffuse::normal(hr, wo);
+        let a = if n.x.abs() > 0.9 {Vec3::new((0.0, 1.0, 0.0))} else {Vec3::new((1.0, 0.0, 0.0))};
+        let u = a.cross(&n).normalize();
+        let v = n.cross(&u);
+
+        let (z, phi) = (u1, 2.0 * PI * u2);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        Some(BsdfSample {
+            direction : u * (r * phi.cos()) + v * (r * phi.sin()) + n * z,
+            weight : Vec3::new(2.0 * self.albedo * z),
+            pdf : 0.5 / PI,
+            specular : false,
+        })
+    }
+
+    fn evaluate(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> Vec3 {
+        Vec3::new(if UniformDiffuse::normal(hr, wo).dot(wi) > 0.0 {self.albedo / PI} else {0.0})
+    }
+
+    fn pdf(&self, hr : &HitRecord, wo : &Vec3, wi : &Vec3) -> f64 {
+        if UniformDiffuse::normal(hr, wo).dot(wi) > 0.0 {0.5 / PI} else {0.0}
+    }
+
+    fn emitted(&self, _hr : &HitRecord, _wo : &Vec3) -> Vec3 {
+        Vec3::new(self.le)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.le > 0.0
+    }
+}
+
+// 放射する球の内側から見た画像の平均
+fn furnace(material : Arc<dyn Bsdf>, light_sampling : bool) -> f64 {
+    let camera = Camera::default();
+    let scene = Scene::new(
+        vec![Sphere{point : camera.position, radius : 10.0, material}],
+        Vec::new(),
+        Vec::new(),
+    );
+
+    let rs = RenderSetting {
+        spp : 256,
+        reflect_n : None,
+        roulette_depth : Some(1),
+        light_sampling,
+        ..common::setting(camera, scene)
+    };
+
+    common::mean(&render(&rs))
+}
+
+#[test]
+fn user_defined_bsdf_renders_through_scene() {
+    // 無限回の反射を数えると le / (1 - albedo) になる
+    let expected = 1.0 / (1.0 - 0.8);
+    for &light_sampling in &[false, true] {
+        let l = furnace(Arc::new(UniformDiffuse{albedo : 0.8, le : 1.0}), light_sampling);
+        assert!((l - expected).abs() < 0.05 * expected, "light_sampling {}: {} != {}", light_sampling, l, expected);
+    }
+}
+
+#[test]
+fn user_defined_bsdf_matches_builtin_diffuse() {
+    // 同じ反射率ならサンプリングの仕方が違っても同じ値に収束する
+    let builtin = furnace(Arc::new(Diffuse{reflectance : Vec3::new(0.5).into(), le : Vec3::new(1.0).into()}), true);
+    let user = furnace(Arc::new(UniformDiffuse{albedo : 0.5, le : 1.0}), true);
+    assert!((builtin - user).abs() < 0.03 * builtin, "{} != {}", builtin, user);
+}
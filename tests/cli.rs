@@ -0,0 +1,40 @@
+use std::process::{Command, Output};
+
+fn raytrace(args : &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_raytrace"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stderr(o : &Output) -> String {
+    String::from_utf8_lossy(&o.stderr).into_owned()
+}
+
+#[test]
+fn info_prints_the_scene_summary() {
+    let o = raytrace(&["info", "scenes/cornell.scene"]);
+    assert!(o.status.success(), "{}", stderr(&o));
+    let out = String::from_utf8_lossy(&o.stdout);
+    assert!(out.contains("spheres   3"), "{}", out);
+    assert!(out.contains("planes    5"), "{}", out);
+}
+
+#[test]
+fn malformed_options_are_rejected() {
+    for (args, message) in [
+        (&["--size", "640"][..], "expected <W>x<H>"),
+        (&["--size", "0x480"][..], "--size must be positive"),
+        (&["--size", "ax480"][..], "invalid value 'a' for --size"),
+        (&["--frobnicate", "1"][..], "unknown option '--frobnicate'"),
+        (&["--max-depth", "none", "--roulette-depth", "none"][..], "--max-depth none needs Russian roulette"),
+        (&["--adaptive", "0.05:1"][..], "invalid value '0.05:1' for --adaptive"),
+    ].iter() {
+        let mut a = vec!["render", "scenes/cornell.scene"];
+        a.extend_from_slice(args);
+        let o = raytrace(&a);
+        assert!(!o.status.success(), "{:?} was accepted", args);
+        assert!(stderr(&o).contains(message), "{:?}: {}", args, stderr(&o));
+    }
+}
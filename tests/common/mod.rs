@@ -0,0 +1,27 @@
+// 描画して平均をとる結合テストで共有する部品
+// テストごとに使うものが違うので、使わない関数があっても警告しない
+#![allow(dead_code)]
+
+use raytrace::env::*;
+use raytrace::geo::*;
+use raytrace::render::*;
+
+// position から target を見る画角 30° のカメラ
+pub fn camera(position : Vec3, target : Vec3, up : Vec3) -> Camera {
+    Camera::new(position, target, up, 30.0, (0.1f64.powi(4), 10.0f64.powi(10)))
+}
+
+// 16x16 の画像を描く設定 (spp などは呼び出し側で上書きする)
+pub fn setting(camera : Camera, scene : Scene) -> RenderSetting {
+    RenderSetting {
+        window_size : (16, 16),
+        camera,
+        scene,
+        ..Default::default()
+    }
+}
+
+// 全画素の R, G, B の平均
+pub fn mean(fb : &Framebuffer) -> f64 {
+    fb.pixels.iter().map(|v| v.x + v.y + v.z).sum::<f64>() / (3 * fb.pixels.len()) as f64
+}
@@ -0,0 +1,81 @@
+extern crate raytrace;
+
+use raytrace::film::*;
+use raytrace::geo::*;
+
+// テスト用の xorshift
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn filters() -> Vec<Filter> {
+    ["Box", "Box:1.5", "Tent", "Gaussian", "Mitchell", "Lanczos", "Lanczos:1"].iter().map(|s| s.parse().unwrap()).collect()
+}
+
+// 画素ごとに n x n に層別したサンプルの位置 (行の順)
+fn samples((w, h) : (usize, usize), n : usize, rng : &mut Rng) -> Vec<(f64, f64)> {
+    let mut out = vec![];
+    for y in 0..h * n {
+        for x in 0..w * n {
+            out.push(((x as f64 + rng.next()) / n as f64, (y as f64 + rng.next()) / n as f64));
+        }
+    }
+    out
+}
+
+#[test]
+fn flat_image_is_reconstructed_without_bias() {
+    let mut rng = Rng(0x1234_5678_9abc_def1);
+    let size = (9, 7);
+    let color = Vec3::new((0.25, 1.5, 3.0));
+    for filter in filters() {
+        let mut film = Film::new(size, filter);
+        for p in samples(size, 4, &mut rng) {
+            film.add_sample(p, color);
+        }
+        // 端の画素も、はみ出したフィルタの部分の重みで割り引かれない
+        for (i, v) in film.pixels().iter().enumerate() {
+            let d = *v - color;
+            assert!(d.dot(&d).sqrt() < 1e-12, "{} at ({}, {}): {} {} {}", filter, i % size.0, i / size.0, v.x, v.y, v.z);
+        }
+    }
+}
+
+#[test]
+fn merged_tiles_equal_an_untiled_film() {
+    let mut rng = Rng(0x0fed_cba9_8765_4321);
+    let size = (8, 13);
+    for filter in filters() {
+        let points = samples(size, 3, &mut rng);
+        let values : Vec<Vec3> = points.iter().map(|_| Vec3::new((rng.next(), rng.next() * 4.0, rng.next() * rng.next()))).collect();
+
+        let mut whole = Film::new(size, filter);
+        for (&p, &v) in points.iter().zip(&values) {
+            whole.add_sample(p, v);
+        }
+
+        // 描画と同じく、サンプルは自分の行のタイルに足し込む
+        let mut tiled = Film::new(size, filter);
+        for rows in &[0..1, 1..5, 5..6, 6..13] {
+            let mut tile = tiled.tile(rows.clone());
+            for (&p, &v) in points.iter().zip(&values) {
+                if rows.contains(&(p.1 as usize)) {
+                    tile.add_sample(p, v);
+                }
+            }
+            tiled.merge(tile);
+        }
+
+        for (a, b) in whole.pixels().iter().zip(tiled.pixels()) {
+            let d = *a - b;
+            assert!(d.dot(&d).sqrt() < 1e-12, "{}: {} != {}", filter, a.y, b.y);
+        }
+    }
+}
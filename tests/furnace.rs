@@ -0,0 +1,89 @@
+extern crate raytrace;
+
+mod common;
+
+use raytrace::env::*;
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::render::*;
+
+use std::sync::Arc;
+
+// カメラを、放射輝度 le で反射率 a の拡散反射をする球の内側に置くと、
+// 無限回の反射を数えた放射輝度はどこでも le / (1 - a) になる
+const LE : f64 = 1.0;
+const ALBEDO : f64 = 0.8;
+
+fn furnace(reflect_n : Option<usize>, roulette_depth : Option<usize>, light_sampling : bool) -> f64 {
+    let camera = Camera::default();
+    let scene = Scene::new(
+        vec![Sphere{
+            point : camera.position,
+            radius : 10.0,
+            material : Arc::new(Diffuse{reflectance : Vec3::new(ALBEDO).into(), le : Vec3::new(LE).into()}),
+        }],
+        Vec::new(),
+        Vec::new(),
+    );
+
+    let rs = RenderSetting {
+        spp : 64,
+        reflect_n,
+        roulette_depth,
+        light_sampling,
+        ..common::setting(camera, scene)
+    };
+
+    common::mean(&render(&rs))
+}
+
+#[test]
+fn russian_roulette_is_unbiased() {
+    let expected = LE / (1.0 - ALBEDO);
+    for &light_sampling in &[false, true] {
+        let l = furnace(None, Some(1), light_sampling);
+        assert!((l - expected).abs() < 0.05 * expected, "light_sampling {}: {} != {}", light_sampling, l, expected);
+    }
+}
+
+#[test]
+fn bounce_cap_is_biased() {
+    // 5 回で打ち切ると 1 + a + a^2 + a^3 + a^4 しか届かない
+    let capped = (0..5).map(|k| LE * ALBEDO.powi(k)).sum::<f64>();
+    let l = furnace(Some(5), Some(1), false);
+    assert!((l - capped).abs() < 0.05 * capped, "{} != {}", l, capped);
+}
+
+#[test]
+fn closed_mirror_box_terminates() {
+    // 反射率 1 の鏡で閉じた箱の中では throughput が減らないので、
+    // 反射回数の上限がなくても Russian roulette だけで経路が終わる
+    let camera = Camera::default();
+    let c = camera.position;
+    let mirror = |normal : (f64, f64, f64), offset : f64| Plane {
+        normal : Vec3::new(normal),
+        point : c - Vec3::new(normal) * offset,
+        material : Arc::new(Mirror{reflectance : Vec3::new(1.0).into()}),
+    };
+    let scene = Scene::new(
+        vec![Sphere{point : c + Vec3::new((0.0, 3.0, -4.0)), radius : 1.0, material : Arc::new(Diffuse{reflectance : Vec3::new(0.0).into(), le : Vec3::new(1.0).into()})}],
+        vec![
+            mirror((1.0, 0.0, 0.0), 10.0), mirror((-1.0, 0.0, 0.0), 10.0),
+            mirror((0.0, 1.0, 0.0), 10.0), mirror((0.0, -1.0, 0.0), 10.0),
+            mirror((0.0, 0.0, 1.0), 10.0), mirror((0.0, 0.0, -1.0), 10.0),
+        ],
+        Vec::new(),
+    );
+
+    let rs = RenderSetting {
+        spp : 16,
+        reflect_n : None,
+        roulette_depth : Some(3),
+        ..common::setting(camera, scene)
+    };
+
+    let fb = render(&rs);
+    assert!(fb.pixels.iter().all(|v| v.x.is_finite() && v.y.is_finite() && v.z.is_finite()));
+    assert!(fb.pixels.iter().any(|v| v.x > 0.0));
+}
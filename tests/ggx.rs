@@ -0,0 +1,86 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+
+// テスト用の xorshift
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// 反射率 1 の材質 (導体は k を大きくして Fresnel 反射率をほぼ 1 にする)
+fn materials(alpha : (f64, f64)) -> Vec<(&'static str, Box<dyn Bsdf>)> {
+    vec![
+        ("Conductor", Box::new(Conductor{ior : fresnel::ComplexIor{eta : Vec3::new(1.0), k : Vec3::new(1e4)}, alpha, reflectance : Vec3::new(1.0).into()})),
+        ("RoughDielectric", Box::new(RoughDielectric{ior : fresnel::GLASSBK7, alpha, reflectance : Vec3::new(1.0).into()})),
+    ]
+}
+
+const ALPHAS : [(f64, f64); 5] = [(0.01, 0.01), (0.1, 0.1), (0.5, 0.5), (1.0, 1.0), (0.05, 0.5)];
+
+// 表と裏から、垂直に近い向きと斜めの向きの wo
+fn directions() -> Vec<Vec3> {
+    [(0.1, 0.0, 1.0), (0.6, 0.3, 0.5), (-0.9, 0.2, 0.2), (0.3, -0.2, -1.0), (0.7, 0.5, -0.4)].iter()
+        .map(|&v| Vec3::new(v).normalize())
+        .collect()
+}
+
+#[test]
+fn sample_agrees_with_evaluate_and_pdf() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let normal = Vec3::new((0.0, 0.0, 1.0));
+    for &alpha in &ALPHAS {
+        for (name, m) in materials(alpha) {
+            let hr = HitRecord::new(1.0, Vec3::new(0.0), normal, &*m);
+            for wo in directions() {
+                let mut n = 0;
+                for _ in 0..2000 {
+                    let s = match m.sample(&hr, &wo, (rng.next(), rng.next()), rng.next()) {
+                        Some(s) => s,
+                        None => continue,
+                    };
+                    n += 1;
+                    // sample の pdf と pdf() が同じ方向で一致する
+                    let pdf = m.pdf(&hr, &wo, &s.direction);
+                    assert!((pdf - s.pdf).abs() <= 1e-6 * s.pdf, "{} {:?}: pdf {} != {}", name, alpha, pdf, s.pdf);
+                    // weight は f * |cos| / pdf
+                    let expected = m.evaluate(&hr, &wo, &s.direction) * (s.direction.dot(&normal).abs() / s.pdf);
+                    let d = s.weight - expected;
+                    assert!(d.dot(&d).sqrt() <= 1e-6 * expected.dot(&expected).sqrt().max(1.0), "{} {:?}: weight differs", name, alpha);
+                }
+                assert!(n > 1000, "{} {:?}: only {} samples", name, alpha, n);
+            }
+        }
+    }
+}
+
+#[test]
+fn white_furnace_does_not_gain_energy() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let normal = Vec3::new((0.0, 0.0, 1.0));
+    const N : usize = 20000;
+    for &alpha in &ALPHAS {
+        for (name, m) in materials(alpha) {
+            let hr = HitRecord::new(1.0, Vec3::new(0.0), normal, &*m);
+            for wo in directions() {
+                // 失敗したサンプルは 0 として数える
+                let albedo = (0..N).filter_map(|_| m.sample(&hr, &wo, (rng.next(), rng.next()), rng.next()))
+                    .map(|s| (s.weight.x + s.weight.y + s.weight.z) / 3.0)
+                    .sum::<f64>() / N as f64;
+                assert!(albedo <= 1.0 + 1e-3, "{} {:?} {:?}: albedo {}", name, alpha, (wo.x, wo.y, wo.z), albedo);
+                // 単散乱だけなので粗いほどエネルギーを失うが、十分に滑らかならほとんど失わない
+                if alpha.0.max(alpha.1) <= 0.01 {
+                    assert!(albedo >= 0.99, "{} {:?} {:?}: albedo {}", name, alpha, (wo.x, wo.y, wo.z), albedo);
+                }
+            }
+        }
+    }
+}
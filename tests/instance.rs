@@ -0,0 +1,135 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::instance::Instance;
+use raytrace::mesh::TriangleMesh;
+use raytrace::material::*;
+
+use std::sync::Arc;
+
+// テスト用の xorshift
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, a : f64, b : f64) -> f64 {
+        a + (b - a) * self.next()
+    }
+
+    fn vec3(&mut self, a : f64, b : f64) -> Vec3 {
+        Vec3::new((self.range(a, b), self.range(a, b), self.range(a, b)))
+    }
+
+    // 軸ごとに 0.2 から 5 倍の拡大 (符号も変える), 回転, 平行移動を重ねた変換
+    fn transform(&mut self) -> Transform {
+        let sign = |x : f64| if x < 0.5 {-1.0} else {1.0};
+        let scale = Vec3::new((
+            sign(self.next()) * self.range(0.2, 5.0),
+            sign(self.next()) * self.range(0.2, 5.0),
+            sign(self.next()) * self.range(0.2, 5.0),
+        ));
+        Transform::translate(&self.vec3(-10.0, 10.0))
+            * Transform::rotate(&self.vec3(-1.0, 1.0), self.range(0.0, 6.3))
+            * Transform::scale(&scale)
+            * Transform::rotate(&self.vec3(-1.0, 1.0), self.range(0.0, 6.3))
+    }
+}
+
+fn assert_identity(m : &Matrix4, what : &str) {
+    for (i, row) in m.0.iter().enumerate() {
+        for (j, v) in row.iter().enumerate() {
+            let expected = if i == j {1.0} else {0.0};
+            assert!((v - expected).abs() < 1e-9, "{}: [{}][{}] = {}", what, i, j, v);
+        }
+    }
+}
+
+#[test]
+fn inverse_and_normals_of_affine_transforms() {
+    let mut rng = Rng(0x853c_49e6_748f_ea9b);
+    for _ in 0..200 {
+        // 合成した変換と、一般の 3x4 行列から作った変換
+        let composed = rng.transform();
+        let mut m = Matrix4::identity();
+        for row in m.0.iter_mut().take(3) {
+            for v in row.iter_mut() {
+                *v = rng.range(-3.0, 3.0);
+            }
+        }
+        let general = match Transform::new(m) {
+            Some(t) => t,
+            None => continue,
+        };
+
+        for t in &[composed, general] {
+            assert_identity(&(t.inverse * t.matrix), "inverse * M");
+            assert_identity(&(t.matrix * t.inverse), "M * inverse");
+            assert_identity(&(t.inverse().matrix * t.matrix), "inverse() * M");
+
+            // 面の 2 本の接線を変換したものは、変換した法線と垂直なまま
+            let (a, b) = (rng.vec3(-1.0, 1.0), rng.vec3(-1.0, 1.0));
+            let n = a.cross(&b);
+            let (ta, tb, tn) = (t.vector(&a), t.vector(&b), t.normal(&n).normalize());
+            assert!(ta.dot(&tn).abs() < 1e-9 * ta.dot(&ta).sqrt(), "normal is not perpendicular");
+            assert!(tb.dot(&tn).abs() < 1e-9 * tb.dot(&tb).sqrt(), "normal is not perpendicular");
+        }
+    }
+}
+
+fn address(m : &dyn Bsdf) -> *const u8 {
+    m as *const dyn Bsdf as *const u8
+}
+
+#[test]
+fn instance_hit_matches_transformed_primitive() {
+    let mut rng = Rng(0xda94_2042_e4dd_58b5);
+    let material : Arc<dyn Bsdf> = Arc::new(Diffuse{reflectance : Vec3::new(0.5).into(), le : Vec3::new(0.0).into()});
+    let other : Arc<dyn Bsdf> = Arc::new(Mirror{reflectance : Vec3::new(1.0).into()});
+    let points = [Vec3::new((0.0, 0.0, 0.0)), Vec3::new((1.0, 0.0, 0.0)), Vec3::new((0.0, 1.0, 0.0))];
+    let triangle = |points : &[Vec3]| TriangleMesh::new(points.to_vec(), vec![], vec![], vec![[0, 1, 2]], material.clone()).unwrap();
+    let shared = Arc::new(triangle(&points));
+
+    let tm = (1e-6, f64::INFINITY);
+    let mut hits = 0;
+    for k in 0..200 {
+        let transform = rng.transform();
+        let mut instance = Instance::new(shared.clone(), transform);
+        if k % 2 == 1 {
+            instance.material = Some(other.clone());
+        }
+        // 手で変換した三角形 (変換が向きを反転すると表裏も入れ替わる)
+        let moved = triangle(&points.iter().map(|p| transform.point(p)).collect::<Vec<_>>());
+
+        for _ in 0..20 {
+            // 三角形の中の点に向けて撃つ
+            let (u, v) = (rng.next(), rng.next());
+            let (u, v) = if u + v > 1.0 {(1.0 - u, 1.0 - v)} else {(u, v)};
+            let target = transform.point(&Vec3::new((u, v, 0.0)));
+            let origin = target + rng.vec3(-20.0, 20.0);
+            let ray = Ray{origin, direction : (target - origin).normalize()};
+
+            let (hi, hm) = match (instance.hit(&ray, tm), moved.hit(&ray, tm)) {
+                (Some(hi), Some(hm)) => (hi, hm),
+                (None, None) => continue,
+                (hi, hm) => panic!("instance hit {} but transformed triangle hit {}", hi.is_some(), hm.is_some()),
+            };
+            hits += 1;
+            // t はワールド座標での距離
+            assert!((hi.t - hm.t).abs() < 1e-9 * hm.t.max(1.0), "t {} != {}", hi.t, hm.t);
+            let d = hi.point - hm.point;
+            assert!(d.dot(&d).sqrt() < 1e-8 * hm.t.max(1.0));
+            assert!(hi.normal.cross(&hm.normal).dot(&hi.normal.cross(&hm.normal)).sqrt() < 1e-9);
+            assert!((hi.normal.dot(&hi.normal) - 1.0).abs() < 1e-12);
+            let expected = if k % 2 == 1 {&*other} else {&*material};
+            assert!(address(hi.material) == address(expected), "material override");
+        }
+    }
+    assert!(hits > 2000);
+}
@@ -0,0 +1,172 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::io::*;
+
+use std::convert::TryInto;
+use std::path::PathBuf;
+
+// テストごとに別の一時ファイル
+fn temp_path(name : &str) -> PathBuf {
+    std::env::temp_dir().join(format!("raytrace-io-{}-{}", std::process::id(), name))
+}
+
+fn hdr_pixels((w, h) : (usize, usize)) -> Vec<Vec3> {
+    (0..w * h).map(|i| Vec3::new(((i % w) as f64 * 0.37, (i / w) as f64 * 1e3, -(i as f64).sqrt()))).collect()
+}
+
+fn f32s(data : &[u8]) -> impl Iterator<Item = f32> + '_ {
+    data.chunks(4).map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+}
+
+#[test]
+fn pfm_layout() {
+    let (w, h) = (13, 7);
+    let pixels = hdr_pixels((w, h));
+    let path = temp_path("layout.pfm");
+    write_hdr_image((w, h), &pixels, &path).unwrap();
+    let data = std::fs::read(&path).unwrap();
+
+    // 負の倍率はリトルエンディアン, 行は下から
+    let header = b"PF\n13 7\n-1.0\n";
+    assert!(data.starts_with(header));
+    assert!(data.len() == header.len() + w * h * 12);
+    for (k, v) in f32s(&data[header.len()..]).enumerate() {
+        let (row, x, channel) = (k / (3 * w), k / 3 % w, k % 3);
+        let p = pixels[(h - 1 - row) * w + x];
+        assert!(v == [p.x, p.y, p.z][channel] as f32);
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+fn le32(data : &[u8], pos : usize) -> i32 {
+    i32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+#[test]
+fn exr_header_and_offset_table() {
+    let (w, h) = (11, 37);
+    let pixels = hdr_pixels((w, h));
+    let path = temp_path("layout.exr");
+    for &(compression, lines, id) in &[(ExrCompression::None, 1, 0u8), (ExrCompression::Zip, 16, 3u8)] {
+        write_hdr_image_as(ImageFormat::Exr(compression), (w, h), &pixels, &path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert!(data[..8] == [0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+        // 属性 (名前, 型, 値) を空の名前まで読む
+        let mut attributes = vec![];
+        let mut pos = 8;
+        let string = |pos : &mut usize| {
+            let end = *pos + data[*pos..].iter().position(|&b| b == 0).unwrap();
+            let s = String::from_utf8(data[*pos..end].to_vec()).unwrap();
+            *pos = end + 1;
+            s
+        };
+        loop {
+            let name = string(&mut pos);
+            if name.is_empty() {
+                break;
+            }
+            let kind = string(&mut pos);
+            let len = le32(&data, pos) as usize;
+            attributes.push((name, kind, data[pos + 4..pos + 4 + len].to_vec()));
+            pos += 4 + len;
+        }
+        let attribute = |name : &str| attributes.iter().find(|a| a.0 == name).map(|a| (a.1.as_str(), &a.2[..])).unwrap();
+        for name in &["channels", "compression", "dataWindow", "displayWindow", "lineOrder", "pixelAspectRatio", "screenWindowCenter", "screenWindowWidth"] {
+            assert!(attributes.iter().any(|a| a.0 == *name), "missing {}", name);
+        }
+        assert!(attribute("compression") == ("compression", &[id][..]));
+        let window = attribute("dataWindow").1;
+        assert!((0..4).map(|i| le32(window, 4 * i)).collect::<Vec<_>>() == [0, 0, w as i32 - 1, h as i32 - 1]);
+        let (kind, channels) = attribute("channels");
+        assert!(kind == "chlist" && channels.starts_with(b"B\0") && channels.len() == 3 * 18 + 1);
+
+        // オフセット表は各ブロックの先頭を順に指し、最後のブロックはファイルの終わりで終わる
+        let n = h.div_ceil(lines);
+        let offsets : Vec<usize> = (0..n).map(|i| u64::from_le_bytes(data[pos + 8 * i..pos + 8 * i + 8].try_into().unwrap()) as usize).collect();
+        assert!(offsets[0] == pos + 8 * n);
+        for (i, &offset) in offsets.iter().enumerate() {
+            assert!(le32(&data, offset) == (i * lines) as i32);
+            let size = le32(&data, offset + 4) as usize;
+            let end = offsets.get(i + 1).cloned().unwrap_or(data.len());
+            assert!(offset + 8 + size == end);
+
+            // 圧縮しないブロックの中身は行ごとに B, G, R の順
+            let rows = lines.min(h - i * lines);
+            let block = &data[offset + 8..end];
+            assert!(block.len() <= rows * w * 12);
+            if compression == ExrCompression::None {
+                for (k, v) in f32s(block).enumerate() {
+                    let (row, channel, x) = (k / (3 * w), k / w % 3, k % w);
+                    let p = pixels[(i * lines + row) * w + x];
+                    assert!(v == [p.z, p.y, p.x][channel] as f32);
+                }
+            }
+        }
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn png_round_trips() {
+    // 同じ色の続く行と、ばらばらの行が混ざった 8 bit と 16 bit の画像
+    let (w, h) = (37, 23);
+    let colors16 : Vec<(u16, u16, u16)> = (0..w * h).map(|i| {
+        let (x, y) = (i % w, i / w);
+        if y % 3 == 0 {(1000, 2000, 65535)} else {((x * 1777 + y * 31) as u16, (x * y * 997) as u16, (i * 40503) as u16)}
+    }).collect();
+    let colors8 : Vec<(u8, u8, u8)> = colors16.iter().map(|&(r, g, b)| ((r >> 8) as u8, g as u8, (b >> 4) as u8)).collect();
+
+    let path = temp_path("round-trip.png");
+    write_image((w, h), colors8.clone(), &path).unwrap();
+    let (size, pixels) = read_ldr_image(&path).unwrap();
+    assert!(size == (w, h));
+    for (p, &(r, g, b)) in pixels.iter().zip(&colors8) {
+        assert!(p.x == r as f64 / 255.0 && p.y == g as f64 / 255.0 && p.z == b as f64 / 255.0);
+    }
+
+    write_image((w, h), colors16.clone(), &path).unwrap();
+    let (size, pixels) = read_ldr_image(&path).unwrap();
+    assert!(size == (w, h));
+    for (p, &(r, g, b)) in pixels.iter().zip(&colors16) {
+        assert!(p.x == r as f64 / 65535.0 && p.y == g as f64 / 65535.0 && p.z == b as f64 / 65535.0);
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+// 8x2 の画像の 1 行目を連長圧縮, 2 行目を平坦に書いた .hdr
+fn rgbe_file() -> Vec<u8> {
+    let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n".to_vec();
+    data.extend_from_slice(&[2, 2, 0, 8]);
+    // r: 8 個とも 128, g: 0..8 をそのまま, b: 64 を 8 個, e: 129 を 8 個
+    data.extend_from_slice(&[128 + 8, 128]);
+    data.push(8);
+    data.extend(0..8u8);
+    data.extend_from_slice(&[128 + 8, 64, 128 + 8, 129]);
+    for _ in 0..8 {
+        data.extend_from_slice(&[1, 2, 3, 128]);
+    }
+    data
+}
+
+#[test]
+fn truncated_hdr_is_an_error() {
+    let path = temp_path("truncated.hdr");
+    let data = rgbe_file();
+    std::fs::write(&path, &data).unwrap();
+    let image = read_hdr_image(&path);
+    assert!(image.is_ok());
+    let ((w, h), pixels) = image.unwrap();
+    assert_eq!((w, h), (8, 2));
+    // 指数 e の画素の値は v * 2^(e - 136)
+    assert!(pixels[3].x == 1.0 && pixels[3].y == 3.0 / 128.0 && pixels[3].z == 0.5);
+    assert!(pixels[8].x == 1.0 / 256.0 && pixels[8].z == 3.0 / 256.0);
+
+    // どこで切れていてもパニックせずにエラーになる
+    for len in 0..data.len() {
+        std::fs::write(&path, &data[..len]).unwrap();
+        assert!(read_hdr_image(&path).is_err(), "{} bytes", len);
+    }
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,181 @@
+extern crate raytrace;
+
+mod common;
+
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::env::*;
+use raytrace::light::*;
+use raytrace::material::*;
+use raytrace::render::*;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+fn close(a : Vec3, b : Vec3) -> bool {
+    let d = a - b;
+    d.dot(&d).sqrt() <= 1e-9 * b.dot(&b).sqrt().max(1.0)
+}
+
+#[test]
+fn power_adds_up() {
+    let shapes = [
+        LightShape::Quad{corner : Vec3::new(0.0), edges : (Vec3::new((2.0, 0.0, 0.0)), Vec3::new((0.0, 1.0, 3.0)))},
+        LightShape::Disk{center : Vec3::new(1.0), normal : Vec3::new((0.0, 2.0, 0.0)), radius : 0.5},
+        LightShape::Sphere{center : Vec3::new(-1.0), radius : 0.25},
+    ];
+    let power = Vec3::new((10.0, 20.0, 30.0));
+    for &shape in &shapes {
+        for &two_sided in &[false, true] {
+            assert!(close(AreaLight::with_power(shape, power, two_sided).power(), power));
+        }
+    }
+    // 両面に放射すると同じ放射輝度でも 2 倍
+    let one = AreaLight{shape : shapes[0], le : Vec3::new(1.0), two_sided : false};
+    assert!(close(AreaLight{two_sided : true, .. one}.power(), one.power() * 2.0));
+
+    // 放射する三角形 (両面, 面積 2) と面光源を足したもの, 環境光は入れない
+    let le = Vec3::new((1.0, 2.0, 3.0));
+    let mut scene = Scene::new(vec![], vec![], vec![Polygon {
+        points : [Vec3::new(0.0), Vec3::new((2.0, 0.0, 0.0)), Vec3::new((0.0, 2.0, 0.0))],
+        material : Arc::new(Diffuse{reflectance : Vec3::new(0.0).into(), le : le.into()}),
+    }]);
+    *scene.area_lights_mut() = shapes.iter().map(|&shape| AreaLight::with_power(shape, power, false)).collect();
+    *scene.environment_mut() = Some(raytrace::environment::Environment::Constant(Vec3::new(1.0)));
+    scene.rebuild();
+
+    assert_eq!(scene.lights().len(), 5);
+    assert!(scene.lights()[1..4] == [Light::Area(0), Light::Area(1), Light::Area(2)]);
+    assert!(scene.lights()[4].power(&scene).is_none());
+    assert!(close(scene.total_power(), le * (2.0 * PI * 2.0) + power * 3.0));
+}
+
+#[test]
+fn power_matches_closed_form() {
+    // 外にだけ放射する閉じた球と、表にだけ放射する四角形の放射束は π A Le
+    let (r, le) = (2.0, Vec3::new((1.0, 2.0, 3.0)));
+    let sphere = 4.0 * PI * PI * r * r;
+    let quad = LightShape::Quad{corner : Vec3::new((5.0, 0.0, 0.0)), edges : (Vec3::new((3.0, 0.0, 0.0)), Vec3::new((0.0, 0.0, 2.0)))};
+    let mut scene = Scene::new(vec![Sphere {
+        point : Vec3::new(0.0),
+        radius : r,
+        material : Arc::new(Diffuse{reflectance : Vec3::new(0.0).into(), le : le.into()}),
+    }], vec![], vec![]);
+    *scene.area_lights_mut() = vec![
+        AreaLight{shape : LightShape::Sphere{center : Vec3::new((-6.0, 0.0, 0.0)), radius : r}, le, two_sided : true},
+        AreaLight{shape : quad, le, two_sided : false},
+    ];
+
+    assert!(scene.lights() == [Light::Sphere(0), Light::Area(0), Light::Area(1)]);
+    assert!(close(scene.lights()[0].power(&scene).unwrap(), le * sphere));
+    assert!(close(scene.lights()[1].power(&scene).unwrap(), le * sphere));
+    assert!(close(scene.lights()[2].power(&scene).unwrap(), le * (PI * 6.0)));
+    assert!(close(scene.total_power(), le * (2.0 * sphere + PI * 6.0)));
+}
+
+#[test]
+fn one_sided_light_is_dark_from_behind() {
+    // 下向きの四角形
+    let light = AreaLight {
+        shape : LightShape::Quad{corner : Vec3::new((-1.0, 2.0, -1.0)), edges : (Vec3::new((2.0, 0.0, 0.0)), Vec3::new((0.0, 0.0, 2.0)))},
+        le : Vec3::new(3.0),
+        two_sided : false,
+    };
+    let mut scene = Scene::new(vec![], vec![], vec![]);
+    scene.area_lights_mut().push(light);
+    scene.rebuild();
+
+    let look = |y : f64| {
+        let direction = Vec3::new((0.0, if y < 2.0 {1.0} else {-1.0}, 0.0));
+        let ray = Ray{origin : Vec3::new((0.3, y, 0.4)), direction};
+        let hr = scene.hit(&ray, (1e-4, f64::INFINITY)).unwrap();
+        assert!((hr.t - (y - 2.0).abs()).abs() < 1e-12);
+        hr.material.emitted(&hr, &-direction)
+    };
+    assert!(look(0.0) == Vec3::new(3.0));
+    assert!(look(4.0) == Vec3::new(0.0));
+
+    // 外れた光線は当たらない
+    let ray = Ray{origin : Vec3::new((1.5, 0.0, 0.0)), direction : Vec3::new((0.0, 1.0, 0.0))};
+    assert!(scene.hit(&ray, (1e-4, f64::INFINITY)).is_none());
+}
+
+// 法線が +z の床を、カメラと同じ向きに進む平行光で照らす
+// カメラの後ろに置いた球が画面の中央に影を落とす
+fn sunlit_floor(mode : RenderMode, light_sampling : bool, angular_diameter : f64) -> (Framebuffer, f64) {
+    let camera = Camera::default();
+    let direction = (camera.focus - camera.position).normalize();
+    let albedo = 0.5;
+    let irradiance = 2.0;
+    let mut scene = Scene::new(
+        vec![Sphere{
+            point : camera.position - direction * 100.0,
+            radius : 30.0,
+            material : Arc::new(Diffuse{reflectance : Vec3::new(albedo).into(), le : Vec3::new(0.0).into()}),
+        }],
+        vec![Plane{
+            normal : Vec3::new((0.0, 0.0, 1.0)),
+            point : Vec3::new(0.0),
+            material : Arc::new(Diffuse{reflectance : Vec3::new(albedo).into(), le : Vec3::new(0.0).into()}),
+        }],
+        Vec::new(),
+    );
+    scene.analytic_lights_mut().push(AnalyticLight::Directional{direction, irradiance : Vec3::new(irradiance), angular_diameter});
+    scene.rebuild();
+
+    let rs = RenderSetting {
+        spp : 16,
+        reflect_n : None,
+        roulette_depth : None,
+        mode,
+        light_sampling,
+        ..common::setting(camera, scene)
+    };
+    (render(&rs), albedo / PI * irradiance * -direction.z)
+}
+
+#[test]
+fn directional_light_casts_shadows() {
+    let cases = [
+        (RenderMode::Shade, true, 0.0),
+        (RenderMode::Shade, false, 0.0),
+        (RenderMode::Shade, true, 5f64.to_radians()),
+        (RenderMode::NormalColor, true, 0.0),
+    ];
+    for (mode, light_sampling, angular_diameter) in cases {
+        let name = format!("{} light_sampling {} angular_diameter {}", mode, light_sampling, angular_diameter);
+        let (fb, expected) = sunlit_floor(mode, light_sampling, angular_diameter);
+        let pixel = |x : usize, y : usize| fb.pixels[y * fb.size.0 + x];
+        for &(x, y) in &[(7, 7), (8, 7), (7, 8), (8, 8)] {
+            assert!(pixel(x, y) == Vec3::new(0.0), "{}: no shadow", name);
+        }
+        for &(x, y) in &[(0, 0), (15, 0), (0, 15), (15, 15)] {
+            let l = pixel(x, y).x;
+            assert!((l - expected).abs() < 0.01 * expected, "{}: {} != {}", name, l, expected);
+        }
+    }
+}
+
+#[test]
+fn analytic_light_power() {
+    let intensity = Vec3::new((1.0, 2.0, 3.0));
+    let position = Vec3::new(0.0);
+    let direction = Vec3::new((0.0, -1.0, 0.0));
+    let point = AnalyticLight::Point{position, intensity};
+    assert!(close(point.power().unwrap(), intensity * (4.0 * PI)));
+
+    // 縁でぼかさなければ円錐の立体角 2π (1 - cos) に放射する
+    let spot = |angle : f64, falloff : f64| AnalyticLight::Spot{position, direction, intensity, angle, falloff}.power().unwrap();
+    assert!(close(spot(0.5 * PI, 0.0), intensity * (2.0 * PI)));
+    assert!(close(spot(0.3, 0.0), intensity * (2.0 * PI * (1.0 - 0.3f64.cos()))));
+    // ぼかすと少し暗くなるが、ぼかし始める円錐よりは明るい
+    assert!(spot(0.3, 0.1).x < spot(0.3, 0.0).x && spot(0.3, 0.1).x > spot(0.2, 0.0).x);
+
+    // 平行光はシーンの放射束に含めず、光源は環境光の直前に並ぶ
+    let mut scene = Scene::new(vec![], vec![], vec![]);
+    *scene.analytic_lights_mut() = vec![point, AnalyticLight::Directional{direction, irradiance : Vec3::new(1.0), angular_diameter : 0.0}];
+    *scene.environment_mut() = Some(raytrace::environment::Environment::Constant(Vec3::new(1.0)));
+    scene.rebuild();
+    assert!(scene.lights() == [Light::Analytic(0), Light::Analytic(1), Light::Environment]);
+    assert!(close(scene.total_power(), intensity * (4.0 * PI)));
+}
@@ -0,0 +1,151 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::mesh::{TriangleMesh, MeshError};
+
+use std::sync::Arc;
+
+// テスト用の xorshift
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn vec3(&mut self, a : f64, b : f64) -> Vec3 {
+        Vec3::new((a + (b - a) * self.next(), a + (b - a) * self.next(), a + (b - a) * self.next()))
+    }
+
+    fn direction(&mut self) -> Vec3 {
+        loop {
+            let v = self.vec3(-1.0, 1.0);
+            let l = v.dot(&v);
+            if l > 1e-6 && l <= 1.0 {
+                return v.normalize();
+            }
+        }
+    }
+}
+
+fn material() -> Arc<dyn Bsdf> {
+    Arc::new(Diffuse{reflectance : Vec3::new(0.5).into(), le : Vec3::new(0.0).into()})
+}
+
+fn close(a : &Vec3, b : &Vec3, eps : f64) -> bool {
+    let d = *a - *b;
+    d.dot(&d).sqrt() <= eps
+}
+
+#[test]
+fn mesh_hits_match_brute_force() {
+    let mut rng = Rng(0x6a09_e667_f3bc_c909);
+    // 頂点を共有する三角形の集まり (同じ 3 頂点の三角形は重なるので除く)
+    let positions : Vec<Vec3> = (0..150).map(|_| rng.vec3(-10.0, 10.0)).collect();
+    let indices : Vec<[usize; 3]> = (0..200).map(|_| {
+        let a = (rng.next() * 150.0) as usize;
+        let near = |r : &mut Rng| (a + 1 + (r.next() * 10.0) as usize) % 150;
+        [a, near(&mut rng), near(&mut rng)]
+    }).filter(|t| t[0] != t[1] && t[1] != t[2] && t[0] != t[2]).collect();
+    let mut seen = std::collections::HashSet::new();
+    let indices : Vec<[usize; 3]> = indices.into_iter().filter(|t| {
+        let mut key = *t;
+        key.sort();
+        seen.insert(key)
+    }).collect();
+    let mesh = TriangleMesh::new(positions, vec![], vec![], indices, material()).unwrap();
+    // 三角形ひとつずつのメッシュを総当たりしたものと比べる
+    let singles : Vec<TriangleMesh> = mesh.indices().iter()
+        .map(|&[a, b, c]| TriangleMesh::new(vec![mesh.positions()[a], mesh.positions()[b], mesh.positions()[c]], vec![], vec![], vec![[0, 1, 2]], material()).unwrap())
+        .collect();
+
+    let tm = (1e-4, f64::INFINITY);
+    let mut hits = 0;
+    for _ in 0..3000 {
+        let ray = Ray{origin : rng.vec3(-12.0, 12.0), direction : rng.direction()};
+        let linear = singles.iter().enumerate()
+            .filter_map(|(i, p)| p.hit(&ray, tm).map(|hr| (i, hr)))
+            .min_by(|a, b| a.1.t.partial_cmp(&b.1.t).unwrap());
+
+        match (mesh.hit(&ray, tm), linear) {
+            (None, None) => (),
+            (Some(a), Some((i, b))) => {
+                hits += 1;
+                assert!(a.primitive == i, "triangle {} != {}", a.primitive, i);
+                assert!((a.t - b.t).abs() < 1e-9 * b.t.max(1.0), "t {} != {}", a.t, b.t);
+                assert!(close(&a.point, &b.point, 1e-8) && close(&a.normal, &b.normal, 1e-12));
+                // 頂点の uv がなければどちらも重心座標
+                assert!((a.uv.0 - b.uv.0).abs() < 1e-8 && (a.uv.1 - b.uv.1).abs() < 1e-8);
+            },
+            (a, b) => panic!("mesh hit {} but brute force hit {}", a.is_some(), b.is_some()),
+        }
+    }
+    assert!(hits > 500);
+}
+
+#[test]
+fn vertex_attributes_are_interpolated() {
+    // 2 つの三角形からなる曲がった四角形
+    let positions = vec![Vec3::new((0.0, 0.0, 0.0)), Vec3::new((2.0, 0.0, 0.0)), Vec3::new((2.0, 2.0, 0.5)), Vec3::new((0.0, 2.0, 0.0))];
+    let normals : Vec<Vec3> = [(0.0, 0.0, 1.0), (0.6, 0.0, 0.8), (0.0, -0.6, 0.8), (-0.28, 0.0, 0.96)].iter().map(|&n| Vec3::new(n)).collect();
+    let uvs = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+    let mesh = TriangleMesh::new(positions.clone(), normals.clone(), uvs.clone(), vec![[0, 1, 2], [0, 2, 3]], material()).unwrap();
+
+    let shoot = |target : Vec3| {
+        let origin = target + Vec3::new((0.1, 0.2, 5.0));
+        let ray = Ray{origin, direction : (target - origin).normalize()};
+        mesh.hit(&ray, (1e-6, f64::INFINITY)).unwrap()
+    };
+
+    // 頂点のごく近くでは頂点の法線と uv になる
+    // (頂点, その頂点を含む三角形)
+    for &(v, t) in &[(0, 0), (1, 0), (2, 0), (3, 1)] {
+        let [a, b, c] = mesh.triangle(t);
+        let corner = mesh.indices()[t].iter().position(|&j| j == v).unwrap();
+        let w = |k : usize| if k == corner {1.0 - 2e-7} else {1e-7};
+        let hr = shoot(a * w(0) + b * w(1) + c * w(2));
+        assert!(close(&hr.shading_normal, &normals[v], 1e-6), "normal at vertex {}", v);
+        assert!((hr.uv.0 - uvs[v].0).abs() < 1e-6 && (hr.uv.1 - uvs[v].1).abs() < 1e-6, "uv at vertex {}", v);
+    }
+
+    // 重心では 3 つの頂点の平均
+    let hr = shoot((positions[0] + positions[1] + positions[2]) / 3.0);
+    assert!(hr.primitive == 0);
+    assert!(close(&hr.shading_normal, &((normals[0] + normals[1] + normals[2]) / 3.0).normalize(), 1e-9));
+    assert!((hr.uv.0 - 2.0 / 3.0).abs() < 1e-9 && (hr.uv.1 - 1.0 / 3.0).abs() < 1e-9);
+    // 幾何法線は三角形の面に垂直
+    let [a, b, c] = mesh.triangle(0);
+    assert!(close(&hr.normal, &(b - a).cross(&(c - a)).normalize(), 1e-12));
+}
+
+#[test]
+fn edits_are_validated_and_picked_up() {
+    let positions = vec![Vec3::new((0.0, 0.0, 0.0)), Vec3::new((1.0, 0.0, 0.0)), Vec3::new((0.0, 1.0, 0.0))];
+    assert!(TriangleMesh::new(positions.clone(), vec![], vec![], vec![[0, 1, 3]], material()).err()
+        == Some(MeshError::IndexOutOfRange{triangle : 0, index : 3, positions : 3}));
+    assert!(TriangleMesh::new(positions.clone(), vec![Vec3::new((0.0, 0.0, 1.0))], vec![], vec![[0, 1, 2]], material()).err()
+        == Some(MeshError::NormalCount{normals : 1, positions : 3}));
+
+    let mut mesh = TriangleMesh::new(positions, vec![], vec![], vec![[0, 1, 2]], material()).unwrap();
+    let ray = Ray{origin : Vec3::new((0.2, 0.2, 1.0)), direction : Vec3::new((0.0, 0.0, -1.0))};
+    let tm = (1e-6, f64::INFINITY);
+    assert!((mesh.hit(&ray, tm).unwrap().t - 1.0).abs() < 1e-12);
+
+    // rebuild を呼ばなくても書き換えた頂点が使われる
+    for p in mesh.positions_mut().iter_mut() {
+        *p = *p + Vec3::new((0.0, 0.0, -1.0));
+    }
+    assert!((mesh.hit(&ray, tm).unwrap().t - 2.0).abs() < 1e-12);
+
+    // 壊れたメッシュは何にも当たらず、rebuild が理由を返す
+    mesh.indices_mut().push([0, 1, 5]);
+    assert!(mesh.hit(&ray, tm).is_none());
+    assert!(mesh.rebuild() == Err(MeshError::IndexOutOfRange{triangle : 1, index : 5, positions : 3}));
+    mesh.indices_mut().pop();
+    assert!(mesh.rebuild().is_ok() && mesh.hit(&ray, tm).is_some());
+}
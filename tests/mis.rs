@@ -0,0 +1,84 @@
+extern crate raytrace;
+
+mod common;
+
+use raytrace::env::*;
+use raytrace::environment::*;
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::render::*;
+
+use std::sync::Arc;
+
+fn diffuse(reflectance : f64, le : f64) -> Arc<dyn Bsdf> {
+    Arc::new(Diffuse{reflectance : Vec3::new(reflectance).into(), le : Vec3::new(le).into()})
+}
+
+// 放射する球と三角形に照らされた床を見下ろした画像の平均
+fn mean_radiance(light_sampling : bool) -> f64 {
+    let position = Vec3::new((0.0, 10.0, 0.0));
+    let camera = common::camera(position, Vec3::new(0.0), Vec3::new((0.0, 0.0, -1.0)));
+    let scene = Scene::new(
+        vec![Sphere{point : Vec3::new((3.0, 2.0, 0.0)), radius : 1.0, material : diffuse(0.0, 4.0)}],
+        vec![Plane{normal : Vec3::new((0.0, 1.0, 0.0)), point : Vec3::new(0.0), material : diffuse(0.5, 0.0)}],
+        vec![Polygon {
+            points : [Vec3::new((-3.0, 1.0, -2.0)), Vec3::new((-1.0, 3.0, -2.0)), Vec3::new((-3.0, 3.0, 1.0))],
+            material : diffuse(0.0, 3.0),
+        }],
+    );
+
+    let rs = RenderSetting {
+        spp : 2048,
+        reflect_n : Some(3),
+        roulette_depth : None,
+        light_sampling,
+        ..common::setting(camera, scene)
+    };
+
+    common::mean(&render(&rs))
+}
+
+#[test]
+fn light_sampling_converges_to_bsdf_sampling() {
+    // 光源のサンプリングをしてもしなくても同じ値に収束する
+    let nee = mean_radiance(true);
+    let bsdf = mean_radiance(false);
+    assert!(nee > 0.0);
+    assert!((nee - bsdf).abs() < 0.03 * bsdf, "{} != {}", nee, bsdf);
+}
+
+// 環境マップに照らされた拡散反射の球を見た画像の平均
+fn environment_radiance(light_sampling : bool) -> f64 {
+    let (w, h) = (16, 8);
+    // 空の一部に明るい帯がある
+    let pixels = (0..w * h).map(|i| {
+        let (x, y) = (i % w, i / w);
+        if (1..4).contains(&y) && (3..9).contains(&x) {Vec3::new((5.0, 4.0, 3.0))} else {Vec3::new((0.1, 0.2, 0.3))}
+    }).collect();
+
+    let camera = common::camera(Vec3::new((0.0, 0.0, 4.0)), Vec3::new(0.0), Vec3::new((0.0, 1.0, 0.0)));
+    let mut scene = Scene::new(
+        vec![Sphere{point : Vec3::new(0.0), radius : 1.0, material : diffuse(0.5, 0.0)}],
+        Vec::new(),
+        Vec::new(),
+    );
+    *scene.environment_mut() = Some(Environment::Map(EnvironmentMap::new((w, h), pixels, 0.3, 1.0)));
+
+    let rs = RenderSetting {
+        spp : 512,
+        reflect_n : Some(3),
+        roulette_depth : None,
+        light_sampling,
+        ..common::setting(camera, scene)
+    };
+
+    common::mean(&render(&rs))
+}
+
+#[test]
+fn environment_sampling_converges_to_bsdf_sampling() {
+    let nee = environment_radiance(true);
+    let bsdf = environment_radiance(false);
+    assert!((nee - bsdf).abs() < 0.02 * bsdf, "{} != {}", nee, bsdf);
+}
@@ -0,0 +1,80 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::mesh::TriangleMesh;
+
+use std::sync::Arc;
+
+fn gray() -> Arc<dyn Bsdf> {
+    Arc::new(Diffuse{reflectance : Vec3::new(0.5).into(), le : Vec3::new(0.0).into()})
+}
+
+#[test]
+fn derivatives_follow_uv() {
+    let eps = 1e-6;
+
+    // dpdu, dpdv の向きに少し動くと uv もその分だけ変わる
+    let sphere = Sphere{point : Vec3::new((1.0, 2.0, 3.0)), radius : 2.0, material : gray()};
+    for &d in &[(0.3, 0.5, -0.8), (-0.7, -0.2, 0.4), (0.1, 0.9, 0.2)] {
+        let d = Vec3::new(d).normalize();
+        let ray = Ray{origin : sphere.point + d * 5.0, direction : -d};
+        let hr = sphere.hit(&ray, (0.0, f64::INFINITY)).unwrap();
+        let (u, v) = hr.uv;
+        let (du, _) = sphere.uv(&(hr.point + hr.dpdu * eps));
+        let (_, dv) = sphere.uv(&(hr.point + hr.dpdv * eps));
+        assert!(((du - u) / eps - 1.0).abs() < 1e-4);
+        assert!(((dv - v) / eps - 1.0).abs() < 1e-4);
+    }
+
+    let mesh = TriangleMesh::new(
+        vec![Vec3::new((0.0, 0.0, 0.0)), Vec3::new((2.0, 0.0, 0.0)), Vec3::new((0.0, 0.0, -3.0))],
+        vec![],
+        vec![(0.25, 0.5), (0.75, 0.25), (0.5, 1.0)],
+        vec![[0, 1, 2]],
+        gray()).unwrap();
+    let ray = Ray{origin : Vec3::new((0.5, 1.0, -0.5)), direction : Vec3::new((0.0, -1.0, 0.0))};
+    let hr = mesh.hit(&ray, (0.0, f64::INFINITY)).unwrap();
+    // 頂点 1 と 2 への辺を dpdu, dpdv で表すと uv の差になる
+    let e = |i : usize| mesh.positions()[i] - mesh.positions()[0];
+    let uv = |i : usize| (mesh.uvs()[i].0 - mesh.uvs()[0].0, mesh.uvs()[i].1 - mesh.uvs()[0].1);
+    for i in 1..3 {
+        let p = hr.dpdu * uv(i).0 + hr.dpdv * uv(i).1 - e(i);
+        assert!(p.dot(&p).sqrt() < 1e-12);
+    }
+}
+
+#[test]
+fn shading_normal_keeps_reflection_above_surface() {
+    let floor = |map : NormalMap| Plane {
+        normal : Vec3::new((0.0, 1.0, 0.0)),
+        point : Vec3::new(0.0),
+        material : Arc::new(Perturbed{material : gray(), map}),
+    };
+
+    // 高さが一定なら法線は変わらない
+    let plane = floor(NormalMap::Bump{height : Vec3::new(0.7).into(), scale : 3.0});
+    let ray = Ray{origin : Vec3::new((0.3, 1.0, 0.2)), direction : Vec3::new((0.0, -1.0, 0.0))};
+    let hr = plane.hit(&ray, (0.0, f64::INFINITY)).unwrap();
+    let hr = shading_point(hr, &-ray.direction);
+    let d = hr.shading_normal - Vec3::new((0.0, 1.0, 0.0));
+    assert!(d.dot(&d) < 1e-18);
+
+    // 大きく傾いた法線マップでも、どの方向から見ても鏡面反射が面の上に残る
+    let plane = floor(NormalMap::Tangent(Vec3::new((0.95, 0.5, 0.55)).into()));
+    for i in 0..64 {
+        let phi = i as f64 * 2.0 * std::f64::consts::PI / 64.0;
+        for &cos in &[0.02, 0.1, 0.3, 0.7, 1.0] {
+            let sin = (1.0f64 - cos * cos).sqrt();
+            let wo = Vec3::new((sin * phi.cos(), cos, sin * phi.sin()));
+            let ray = Ray{origin : wo, direction : -wo};
+            let hr = plane.hit(&ray, (0.0, f64::INFINITY)).unwrap();
+            let hr = shading_point(hr, &wo);
+            let n = hr.shading_normal;
+            assert!((n.dot(&n) - 1.0).abs() < 1e-9);
+            let r = n * 2.0 * wo.dot(&n) - wo;
+            assert!(r.dot(&hr.normal) > 0.0, "reflection below the surface at phi {} cos {}", phi, cos);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+extern crate raytrace;
+
+mod common;
+
+use raytrace::env::*;
+use raytrace::environment::Environment;
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::render::*;
+use raytrace::sampler::SamplerKind;
+
+use std::sync::Arc;
+
+// 一様な環境光 le の下で反射率 a の拡散反射をする床を見下ろすと、
+// 1 回の反射までを数えた放射輝度は床のどこでも a * le になる
+const LE : f64 = 1.0;
+const ALBEDO : f64 = 0.5;
+
+// 正確な値との二乗平均平方根誤差
+fn rmse(sampler : SamplerKind, spp : usize) -> f64 {
+    let position = Vec3::new((0.0, 10.0, 0.0));
+    let camera = common::camera(position, Vec3::new(0.0), Vec3::new((0.0, 0.0, -1.0)));
+    let mut scene = Scene::new(
+        Vec::new(),
+        vec![Plane{
+            normal : Vec3::new((0.0, 1.0, 0.0)),
+            point : Vec3::new(0.0),
+            material : Arc::new(Diffuse{reflectance : Vec3::new(ALBEDO).into(), le : Vec3::new(0.0).into()}),
+        }],
+        Vec::new(),
+    );
+    *scene.environment_mut() = Some(Environment::Constant(Vec3::new(LE)));
+    scene.rebuild();
+
+    let rs = RenderSetting {
+        spp,
+        reflect_n : Some(2),
+        roulette_depth : None,
+        sampler,
+        ..common::setting(camera, scene)
+    };
+
+    let fb = render(&rs);
+    let expected = ALBEDO * LE;
+    let se = fb.pixels.iter().map(|v| (v.x - expected).powi(2)).sum::<f64>();
+    (se / fb.pixels.len() as f64).sqrt()
+}
+
+#[test]
+fn low_discrepancy_samplers_reduce_noise() {
+    let independent = rmse(SamplerKind::Independent, 16);
+    assert!(independent > 0.0);
+    for &s in &[SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol] {
+        let e = rmse(s, 16);
+        assert!(e < 0.8 * independent, "{}: {} is not below independent {}", s, e, independent);
+    }
+}
+
+#[test]
+fn sobol_converges_faster() {
+    // 独立な乱数の誤差は 1 / sqrt(spp) でしか減らない
+    let ratio = rmse(SamplerKind::Sobol, 64) / rmse(SamplerKind::Sobol, 4);
+    assert!(ratio < 0.5 * 0.25, "{}", ratio);
+}
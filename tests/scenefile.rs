@@ -0,0 +1,151 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::io;
+use raytrace::environment::Environment;
+use raytrace::instance::Instance;
+use raytrace::material::*;
+use raytrace::mesh::TriangleMesh;
+use raytrace::render::*;
+use raytrace::scenefile;
+use raytrace::texture::Texture;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// テストごとに別の一時ディレクトリ
+// シーンファイルを相対パスで読んだときの扱いを確かめるため、できれば現在のディレクトリからの相対パスにする
+fn temp_dir(name : &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("raytrace-scenefile-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let cwd = std::env::current_dir().unwrap();
+    dir.strip_prefix(&cwd).map(Path::to_path_buf).unwrap_or(dir)
+}
+
+fn write_string(rs : &RenderSetting) -> String {
+    let mut out = vec![];
+    scenefile::write(rs, Path::new("."), &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn default_setting_round_trips() {
+    let rs = RenderSetting::default();
+    let written = write_string(&rs);
+    let parsed = scenefile::parse(&written, Path::new("default.scene")).unwrap();
+
+    let (a, b) = (&rs.camera, &parsed.camera);
+    assert!(a.position == b.position && a.focus == b.focus && a.upside == b.upside && a.fov == b.fov);
+    assert!(a.we == b.we && a.ue == b.ue && a.ve == b.ve && a.tm == b.tm);
+    assert!(a.aperture == b.aperture && a.focus_distance == b.focus_distance && a.aperture_shape == b.aperture_shape);
+
+    assert!(rs.window_size == parsed.window_size && rs.spp == parsed.spp);
+    assert!(rs.reflect_n == parsed.reflect_n && rs.roulette_depth == parsed.roulette_depth);
+    assert!(matches!(parsed.mode, RenderMode::Shade) && rs.light_sampling == parsed.light_sampling);
+    assert!(rs.background == parsed.background && rs.sampler == parsed.sampler && rs.seed == parsed.seed);
+    assert!(rs.filter == parsed.filter && rs.tonemap == parsed.tonemap && rs.adaptive == parsed.adaptive);
+
+    let (a, b) = (&rs.scene, &parsed.scene);
+    assert!(a.spheres().len() == b.spheres().len());
+    for (s, t) in a.spheres().iter().zip(b.spheres()) {
+        assert!(s.point == t.point && s.radius == t.radius);
+    }
+    assert!(a.planes().len() == b.planes().len());
+    for (s, t) in a.planes().iter().zip(b.planes()) {
+        assert!(s.normal == t.normal && s.point == t.point);
+    }
+    assert!(a.polygons().len() == b.polygons().len());
+    for (s, t) in a.polygons().iter().zip(b.polygons()) {
+        assert!(s.points == t.points);
+    }
+    assert!(a.lights() == b.lights());
+
+    // 材質なども含めて、書き直しても同じ内容になる
+    assert!(write_string(&parsed) == written);
+}
+
+#[test]
+fn meshes_and_instances_are_written_as_files() {
+    let root = temp_dir("meshes");
+    fs::create_dir_all(root.join("models")).unwrap();
+    fs::write(root.join("models/tri.obj"), "v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+        vt 0 0\nvt 1 0\nvt 0 1\n\
+        vn 0 0.6 0.8\nvn 0 0 1\nvn 0.6 0 0.8\n\
+        f 1/1/1 2/2/2 3/3/3\n").unwrap();
+    let source = "material m\n  type Diffuse\n  reflectance 0.5 0.5 0.5\n  emission 0 0 0\nend\n\n\
+        mesh\n  file ../models/tri.obj\n  material m\nend\n\n\
+        instance\n  file ../models/tri.obj\n  scale 1 2 3\n  rotate 0 1 0 30\n  translate 5 0 -2\n  material m\nend\n\n\
+        instance\n  file ../models/tri.obj\n  translate 0 4 0\nend\n";
+    fs::create_dir_all(root.join("scenes")).unwrap();
+    fs::write(root.join("scenes/a.scene"), source).unwrap();
+
+    let rs = scenefile::load(root.join("scenes/a.scene")).unwrap();
+    scenefile::save(&rs, root.join("b.scene")).unwrap();
+    let written = fs::read_to_string(root.join("b.scene")).unwrap();
+    assert!(!written.contains("polygon"));
+    assert!(written.matches("  file models/tri.obj\n").count() == 3, "{}", written);
+
+    let saved = scenefile::load(root.join("b.scene")).unwrap();
+    let (a, b) = (&rs.scene.meshes()[0], &saved.scene.meshes()[0]);
+    assert!(saved.scene.meshes().len() == 1);
+    assert!(a.positions() == b.positions() && a.normals() == b.normals() && a.uvs() == b.uvs() && a.indices() == b.indices());
+    assert!(b.normals().len() == 3 && b.uvs().len() == 3);
+
+    let instances = |rs : &RenderSetting| rs.scene.shapes().iter().map(|s| {
+        let i = (&**s as &dyn std::any::Any).downcast_ref::<Instance>().unwrap();
+        let mesh = (&*i.object as &dyn std::any::Any).downcast_ref::<TriangleMesh>().unwrap();
+        (i.transform.matrix, mesh.normals().to_vec(), mesh.uvs().to_vec(), i.material.is_some())
+    }).collect::<Vec<_>>();
+    let (a, b) = (instances(&rs), instances(&saved));
+    assert!(a.len() == 2 && b.len() == 2);
+    for (s, t) in a.iter().zip(&b) {
+        assert!(s.0 == t.0 && s.1 == t.1 && s.2 == t.2 && s.3 == t.3);
+    }
+    assert!(a[0].3 && !a[1].3);
+
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn asset_paths_are_relative_to_the_saved_file() {
+    let root = temp_dir("paths");
+    fs::create_dir_all(root.join("assets")).unwrap();
+    fs::create_dir_all(root.join("scenes")).unwrap();
+    fs::create_dir_all(root.join("out/deeper")).unwrap();
+    io::write_image((2, 2), vec![(255u8, 0u8, 0u8); 4], root.join("assets/tex.png")).unwrap();
+    io::write_hdr_image((4, 2), &[Vec3::new(1.0); 8], root.join("assets/sky.pfm")).unwrap();
+
+    let source = "environment\n  file ../assets/sky.pfm\nend\n\n\
+        texture tex\n  type Image\n  file ../assets/tex.png\nend\n\n\
+        material m\n  type Diffuse\n  reflectance tex\n  emission 0 0 0\nend\n\n\
+        sphere\n  center 0 0 0\n  radius 1\n  material m\nend\n";
+    fs::write(root.join("scenes/a.scene"), source).unwrap();
+
+    // 読み込んだファイルの正規化した絶対パス
+    let assets = |path : &Path| {
+        let rs = scenefile::load(path).unwrap();
+        let texture = match (&*rs.scene.spheres()[0].material as &dyn std::any::Any).downcast_ref::<Diffuse>() {
+            Some(Diffuse{reflectance : Texture::Image(image, _), ..}) => image.path.clone().unwrap(),
+            _ => panic!("not an image texture"),
+        };
+        let sky = match rs.scene.environment() {
+            Some(Environment::Map(m)) => m.path.clone().unwrap(),
+            _ => panic!("not an environment map"),
+        };
+        (fs::canonicalize(texture).unwrap(), fs::canonicalize(sky).unwrap())
+    };
+    let expected = (fs::canonicalize(root.join("assets/tex.png")).unwrap(), fs::canonicalize(root.join("assets/sky.pfm")).unwrap());
+    assert!(assets(&root.join("scenes/a.scene")) == expected);
+
+    // 同じディレクトリにも、別のディレクトリにも保存して読み直せる
+    let rs = scenefile::load(root.join("scenes/a.scene")).unwrap();
+    for saved in &["scenes/b.scene", "out/deeper/c.scene"] {
+        scenefile::save(&rs, root.join(saved)).unwrap();
+        assert!(assets(&root.join(saved)) == expected, "{}", saved);
+    }
+    let written = fs::read_to_string(root.join("scenes/b.scene")).unwrap();
+    assert!(written.contains("  file ../assets/tex.png\n") && written.contains("  file ../assets/sky.pfm\n"));
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,29 @@
+extern crate raytrace;
+extern crate rayon;
+
+use raytrace::render::*;
+
+fn render_with_threads(seed : u64, threads : usize) -> Vec<(f64, f64, f64)> {
+    let rs = RenderSetting {
+        window_size : (24, 16),
+        spp : 4,
+        seed,
+        ..Default::default()
+    };
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+    pool.install(|| render(&rs)).pixels.iter().map(|v| (v.x, v.y, v.z)).collect()
+}
+
+#[test]
+fn same_seed_is_bit_identical_across_thread_counts() {
+    let one = render_with_threads(7, 1);
+    assert!(one.iter().any(|&(r, g, b)| r + g + b > 0.0));
+    for &threads in &[2, 5] {
+        assert!(one == render_with_threads(7, threads), "{} threads differ from 1 thread", threads);
+    }
+}
+
+#[test]
+fn different_seeds_give_different_noise() {
+    assert!(render_with_threads(1, 2) != render_with_threads(2, 2));
+}
@@ -0,0 +1,110 @@
+extern crate raytrace;
+
+use raytrace::env::*;
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::material::*;
+use raytrace::render::*;
+
+use std::sync::Arc;
+
+// クレートの外で定義した形状: 軸に平行な直方体
+struct Cuboid {
+    min : Vec3,
+    max : Vec3,
+    material : Arc<dyn Bsdf>,
+}
+
+impl Hit for Cuboid {
+    fn hit(&self, ray : &Ray, (tmin, tmax) : (f64, f64)) -> Option<HitRecord<'_>> {
+        let axes = |v : &Vec3| [v.x, v.y, v.z];
+        let (o, d) = (axes(&ray.origin), axes(&ray.direction));
+        let (lo, hi) = (axes(&self.min), axes(&self.max));
+
+        // slab ごとに入る t と出る t を求め、それぞれの法線の軸を覚えておく
+        let mut enter = (f64::NEG_INFINITY, 0, 0.0);
+        let mut exit = (f64::INFINITY, 0, 0.0);
+        for a in 0..3 {
+            let (t0, t1) = ((lo[a] - o[a]) / d[a], (hi[a] - o[a]) / d[a]);
+            let (near, far, sign) = if t0 < t1 {(t0, t1, -1.0)} else {(t1, t0, 1.0)};
+            if near > enter.0 {
+                enter = (near, a, sign);
+            }
+            if far < exit.0 {
+                exit = (far, a, -sign);
+            }
+        }
+        if enter.0 > exit.0 {
+            return None;
+        }
+
+        let (t, axis, sign) = if tmin < enter.0 && enter.0 < tmax {
+            enter
+        } else if tmin < exit.0 && exit.0 < tmax {
+            exit
+        } else {
+            return None;
+        };
+
+        let mut n = [0.0; 3];
+        n[axis] = sign;
+        let normal = Vec3{x : n[0], y : n[1], z : n[2]};
+        Some(HitRecord::new(t, ray.direction * t + ray.origin, normal, &*self.material))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb{min : self.min, max : self.max})
+    }
+}
+
+fn setting(scene : Scene, mode : RenderMode) -> RenderSetting {
+    RenderSetting {
+        window_size : (9, 9),
+        spp : 16,
+        camera : Camera::new((0.0, 0.0, 10.0), (0.0, 0.0, 0.0), (0.0, 1.0, 0.0), 30.0, (0.1f64.powi(4), 10.0f64.powi(10))),
+        scene,
+        mode,
+        .. Default::default()
+    }
+}
+
+fn cuboid(le : f64) -> Box<dyn Hit> {
+    Box::new(Cuboid{
+        min : Vec3::new(-1.0),
+        max : Vec3::new(1.0),
+        material : Arc::new(Diffuse{reflectance : Vec3::new(0.5).into(), le : Vec3::new(le).into()}),
+    })
+}
+
+#[test]
+fn shape_is_hit_through_the_bvh() {
+    let mut scene = Scene::new(Vec::new(), Vec::new(), Vec::new());
+    scene.shapes_mut().push(cuboid(0.0));
+    scene.rebuild();
+
+    let b = scene.bounds().unwrap();
+    assert!(b.min == Vec3::new(-1.0) && b.max == Vec3::new(1.0));
+
+    let ray = Ray{origin : Vec3::new((0.0, 0.0, 10.0)), direction : Vec3::new((0.0, 0.0, -1.0))};
+    let hr = scene.hit(&ray, (0.0, f64::INFINITY)).unwrap();
+    assert!((hr.t - 9.0).abs() < 1e-9);
+    assert!(hr.normal == Vec3::new((0.0, 0.0, 1.0)));
+
+    // 画面の中央は正面の面、端は何もない
+    let fb = render(&setting(scene, RenderMode::Depth(10.0)));
+    let center = fb.pixels[4 * 9 + 4];
+    assert!((center.x - 0.1).abs() < 0.01, "{}", center.x);
+    assert!(fb.pixels[0] == Vec3::new(0.0));
+}
+
+#[test]
+fn emissive_shape_is_shaded() {
+    let mut scene = Scene::new(Vec::new(), Vec::new(), Vec::new());
+    scene.shapes_mut().push(cuboid(2.0));
+    scene.rebuild();
+
+    // 光源としてサンプリングされないので、見えている面の放射だけが写る
+    let fb = render(&setting(scene, RenderMode::Shade));
+    let center = fb.pixels[4 * 9 + 4];
+    assert!((center.x - 2.0).abs() < 1e-9, "{}", center.x);
+}
@@ -0,0 +1,126 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::obj::*;
+use raytrace::io;
+use raytrace::material::*;
+use raytrace::texture::*;
+use raytrace::noise::*;
+use raytrace::tonemap::srgb_eotf;
+
+use std::sync::Arc;
+
+#[test]
+fn png_round_trip() {
+    // フィルタの種類が行ごとに変わるように、なめらかな部分と乱れた部分を混ぜる
+    let (w, h) = (23, 17);
+    let colors : Vec<(u16, u16, u16)> = (0..w * h).map(|i| {
+        let (x, y) = (i % w, i / w);
+        ((x * 2000) as u16, (y * 3000) as u16, ((i * i * 7919) % 65536) as u16)
+    }).collect();
+
+    let path = std::env::temp_dir().join(format!("raytrace-texture-{}.png", std::process::id()));
+    io::write_image((w, h), colors.clone(), &path).unwrap();
+    let image = Image::load(&path);
+    let _ = std::fs::remove_file(&path);
+    let image = image.unwrap();
+
+    assert_eq!(image.size, (w, h));
+    for (p, c) in image.pixels.iter().zip(&colors) {
+        let expected = |v : u16| srgb_eotf(v as f64 / 65535.0);
+        assert!((p.x - expected(c.0)).abs() < 1e-12);
+        assert!((p.y - expected(c.1)).abs() < 1e-12);
+        assert!((p.z - expected(c.2)).abs() < 1e-12);
+    }
+}
+
+#[test]
+fn checker_follows_uv() {
+    let white = Vec3::new(1.0);
+    let black = Vec3::new(0.0);
+    let checker = Texture::Transform {
+        texture : Box::new(Texture::Checker(Box::new(white.into()), Box::new(black.into()))),
+        scale : (0.5, 0.5),
+        offset : (0.0, 0.0),
+    };
+    let plane = Plane {
+        normal : Vec3::new((0.0, 1.0, 0.0)),
+        point : Vec3::new(0.0),
+        material : Arc::new(Diffuse{reflectance : checker, le : black.into()}),
+    };
+
+    // 床を真上から見ると、2 ごとに色が入れ替わる
+    let color = |x : f64, z : f64| {
+        let ray = Ray{origin : Vec3::new((x, 1.0, z)), direction : Vec3::new((0.0, -1.0, 0.0))};
+        let hr = plane.hit(&ray, (0.0, f64::INFINITY)).unwrap();
+        hr.material.reflectance(&hr)
+    };
+    assert!(color(0.5, -0.5) == white);
+    assert!(color(2.5, -0.5) == black);
+    assert!(color(2.5, -2.5) == white);
+    assert!(color(-0.5, -0.5) == black);
+
+    // 球の uv は +y の極で v = 1, -y の極で v = 0
+    let sphere = Sphere{point : Vec3::new(0.0), radius : 2.0, material : plane.material.clone()};
+    let v = |d : f64| {
+        let ray = Ray{origin : Vec3::new((0.0, 5.0 * d, 0.0)), direction : Vec3::new((0.0, -d, 0.0))};
+        sphere.hit(&ray, (0.0, f64::INFINITY)).unwrap().uv.1
+    };
+    assert!((v(1.0) - 1.0).abs() < 1e-12);
+    assert!(v(-1.0).abs() < 1e-12);
+}
+
+#[test]
+fn noise_depends_only_on_seed() {
+    let points : Vec<Vec3> = (0..200).map(|i| {
+        let i = i as f64;
+        Vec3::new(((i * 0.618).fract() * 40.0 - 20.0, (i * 0.755).fract() * 40.0 - 20.0, (i * 0.570).fract() * 40.0 - 20.0))
+    }).collect();
+
+    let patterns = [Pattern::Noise, Pattern::Fbm, Pattern::Turbulence, Pattern::Worley, Pattern::Marble, Pattern::Wood];
+    for &pattern in &patterns {
+        for &basis in &[Basis::Perlin, Basis::Simplex] {
+            let solid = |seed| Solid{basis, frequency : 0.7, .. Solid::new(pattern, seed)};
+            let values = |seed| points.iter().map(|p| solid(seed).value(p)).collect::<Vec<_>>();
+            let a = values(5);
+            assert!(a.iter().all(|v| (0.0..=1.0).contains(v)), "{} out of range", pattern);
+            assert!(a == values(5), "{} is not reproducible", pattern);
+            assert!(a != values(6), "{} ignores the seed", pattern);
+        }
+    }
+}
+
+#[test]
+fn noise_octaves_stay_in_range() {
+    // 格子に揃わない点を広く取る
+    let points : Vec<Vec3> = (0..20000).map(|i| {
+        let i = i as f64;
+        Vec3::new(((i * 0.618_034).fract() * 64.0 - 32.0, (i * 0.754_878).fract() * 64.0 - 32.0, (i * 0.569_840).fract() * 64.0 - 32.0))
+    }).collect();
+
+    for &basis in &[Basis::Perlin, Basis::Simplex] {
+        let (a, b) = (Noise::new(11), Noise::new(12));
+        let mut differs = 0;
+        let (mut lo, mut hi) = (f64::INFINITY, f64::NEG_INFINITY);
+        for p in &points {
+            let g = a.gradient(basis, p);
+            // 同じ seed なら別に作っても同じ値
+            assert!(g == Noise::new(11).gradient(basis, p));
+            if g != b.gradient(basis, p) {
+                differs += 1;
+            }
+
+            for octaves in 1..=6 {
+                let f = a.fbm(basis, p, octaves);
+                let t = a.turbulence(basis, p, octaves);
+                assert!((-1.0..=1.0).contains(&f), "{} fbm {} at {} octaves", basis, f, octaves);
+                assert!((0.0..=1.0).contains(&t), "{} turbulence {} at {} octaves", basis, t, octaves);
+                lo = lo.min(f);
+                hi = hi.max(f);
+            }
+        }
+        // 別の seed ではほとんどの点で値が変わり、範囲の大部分を使う
+        assert!(differs > points.len() * 9 / 10, "{}: {} of {} differ", basis, differs, points.len());
+        assert!(lo < -0.5 && hi > 0.5, "{}: fbm in [{}, {}]", basis, lo, hi);
+    }
+}
@@ -0,0 +1,67 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::tonemap::*;
+
+#[test]
+fn dither_leaves_black_and_clipped_white_alone() {
+    let tonemap = Tonemap::default();
+    assert!(tonemap.dither);
+    for index in 0..10000 {
+        assert!(tonemap.to_rgb8(Vec3::new(0.0), index) == (0, 0, 0));
+        assert!(tonemap.to_rgb8(Vec3::new(-1.0), index) == (0, 0, 0));
+        assert!(tonemap.to_rgb8(Vec3::new(1.0), index) == (255, 255, 255));
+        assert!(tonemap.to_rgb8(Vec3::new(7.5), index) == (255, 255, 255));
+        assert!(tonemap.to_rgb16(Vec3::new(0.0), index) == (0, 0, 0));
+        assert!(tonemap.to_rgb16(Vec3::new(2.0), index) == (65535, 65535, 65535));
+    }
+
+    // 中間の値はディザで平均が保たれる
+    let v = 0.3;
+    let expected = srgb_oetf(v) * 255.0;
+    let mean = (0..10000).map(|i| tonemap.to_rgb8(Vec3::new(v), i).0 as f64).sum::<f64>() / 10000.0;
+    assert!((mean - expected).abs() < 0.05, "{} != {}", mean, expected);
+}
+
+#[test]
+fn srgb_curve_is_continuous_at_the_breakpoint() {
+    let x = 0.003_130_8;
+    let (linear, power) = (12.92 * x, 1.055 * f64::powf(x, 1.0 / 2.4) - 0.055);
+    assert!((linear - power).abs() < 1e-7, "{} != {}", linear, power);
+    assert!((srgb_oetf(x) - srgb_oetf(x * (1.0 + 1e-9))).abs() < 1e-7);
+    assert!(srgb_oetf(0.0) == 0.0 && (srgb_oetf(1.0) - 1.0).abs() < 1e-15);
+
+    // 単調に増え、中間の灰色は既知の値になる
+    for i in 0..1000 {
+        let x = i as f64 / 1000.0;
+        assert!(srgb_oetf(x) < srgb_oetf(x + 0.001), "{}", x);
+    }
+    assert!((srgb_oetf(0.5) - 0.735_356_983).abs() < 1e-9, "{}", srgb_oetf(0.5));
+
+    // EOTF は逆関数
+    for i in 0..=1000 {
+        let x = i as f64 / 1000.0;
+        assert!((srgb_eotf(srgb_oetf(x)) - x).abs() < 1e-12, "{}", x);
+    }
+}
+
+#[test]
+fn operators_fix_black_and_white_points() {
+    let operators = [Operator::Linear, Operator::Reinhard, Operator::ReinhardExtended(4.0), Operator::Aces, Operator::Uncharted2];
+    for op in &operators {
+        assert!(op.apply(Vec3::new(0.0)) == Vec3::new(0.0), "{}", op);
+    }
+    assert!(Operator::Linear.apply(Vec3::new((0.2, 0.5, 3.0))) == Vec3::new((0.2, 0.5, 3.0)));
+    // 輝度 1 の灰色は Reinhard で 1/2, ReinhardExtended では white で 1
+    assert!((Operator::Reinhard.apply(Vec3::new(1.0)).x - 0.5).abs() < 1e-12);
+    for &white in &[1.0, 4.0, 11.0] {
+        let v = Operator::ReinhardExtended(white).apply(Vec3::new(white));
+        assert!((v.x - 1.0).abs() < 1e-12 && (v.z - 1.0).abs() < 1e-12, "white {}: {}", white, v.x);
+    }
+    // Uncharted2 は露出 2 倍のあとの白色点 11.2 で 1
+    assert!((Operator::Uncharted2.apply(Vec3::new(5.6)).y - 1.0).abs() < 1e-12);
+    // Reinhard は色相を変えない
+    let v = Vec3::new((0.2, 0.5, 3.0));
+    let m = Operator::Reinhard.apply(v);
+    assert!((m.x / m.z - v.x / v.z).abs() < 1e-12 && (m.y / m.z - v.y / v.z).abs() < 1e-12);
+}
@@ -0,0 +1,91 @@
+extern crate raytrace;
+
+use raytrace::geo::*;
+use raytrace::io;
+use raytrace::material::*;
+use raytrace::texture::Texture;
+use raytrace::wavefront;
+
+use std::any::Any;
+use std::fs;
+use std::path::PathBuf;
+
+// テストごとに別の一時ディレクトリ
+fn temp_dir(name : &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("raytrace-wavefront-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn faces_are_fanned_and_indices_resolved() {
+    let dir = temp_dir("faces");
+    // 五角形と、負の添字で直前の 3 頂点を指す三角形
+    let source = "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\n\
+        vt 0 0\nvt 1 0\nvt 1 1\n\
+        f 1 2 3 4 5\n\
+        v 5 0 0\nv 6 0 0\nv 5 1 0\n\
+        f -3/-3 -2/-2 -1/-1\n";
+    fs::write(dir.join("faces.obj"), source).unwrap();
+
+    let meshes = wavefront::load_meshes(dir.join("faces.obj")).unwrap();
+    // テクスチャ座標の有無が違うので 2 つのメッシュになる
+    assert!(meshes.len() == 2);
+    let (fan, tri) = (&meshes[0], &meshes[1]);
+    assert!(fan.indices() == [[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+    assert!(fan.positions()[2] == Vec3::new((2.0, 1.0, 0.0)) && fan.uvs().is_empty());
+    assert!(tri.triangle(0) == [Vec3::new((5.0, 0.0, 0.0)), Vec3::new((6.0, 0.0, 0.0)), Vec3::new((5.0, 1.0, 0.0))]);
+    assert!(tri.uvs() == [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)]);
+
+    // 範囲外の添字と不正な行は "ファイル:行" の位置つきのエラーになる
+    for (source, line) in &[("v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 4\n", 5), ("v 0 0 0\nv 1 0 x\n", 2), ("v 0 0 0\nf 1 -2 1\n", 2)] {
+        let path = dir.join("broken.obj");
+        fs::write(&path, source).unwrap();
+        let message = match wavefront::load_meshes(&path) {
+            Err(e @ wavefront::LoadObjError::Parse{..}) => e.to_string(),
+            _ => panic!("expected a parse error for {:?}", source),
+        };
+        assert!(message.starts_with(&format!("{}:{}: ", path.display(), line)), "{}", message);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn usemtl_selects_materials() {
+    let dir = temp_dir("usemtl");
+    io::write_image((2, 2), vec![(255u8, 255u8, 255u8); 4], dir.join("white.png")).unwrap();
+    fs::write(dir.join("m.mtl"), "newmtl red\nKd 0.8 0.1 0.1\n\n\
+        newmtl tinted\nKd 0.5 0.25 1\nmap_Kd white.png\n\n\
+        newmtl plain\nmap_Kd white.png\n").unwrap();
+    let source = "mtllib m.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\n\
+        f 1 2 3\nusemtl red\nf 1 2 3\nusemtl tinted\nf 1 2 3\nusemtl plain\nf 1 2 3\n\
+        usemtl red\nf 3 2 1\n";
+    fs::write(dir.join("m.obj"), source).unwrap();
+
+    let meshes = wavefront::load_meshes(dir.join("m.obj")).unwrap();
+    let reflectance = |i : usize| match (&*meshes[i].material as &dyn Any).downcast_ref::<Diffuse>() {
+        Some(d) => d.reflectance.clone(),
+        None => panic!("not a diffuse material"),
+    };
+    // usemtl の前, red, tinted, plain の順 (red の 2 つ目の面は red のメッシュに入る)
+    assert!(meshes.len() == 4);
+    assert!(meshes[1].indices().len() == 2);
+    assert!(matches!(reflectance(0), Texture::Constant(c) if c == Vec3::new(0.75)));
+    assert!(matches!(reflectance(1), Texture::Constant(c) if c == Vec3::new((0.8, 0.1, 0.1))));
+    // map_Kd には Kd が掛かる
+    assert!(matches!(reflectance(2), Texture::Scale{ref texture, factor} if factor == Vec3::new((0.5, 0.25, 1.0)) && matches!(**texture, Texture::Image(..))));
+    assert!(matches!(reflectance(3), Texture::Image(..)));
+
+    // 定義されていない材質と、鏡やガラスの Ke は "ファイル:行" の位置つきのエラーになる
+    fs::write(dir.join("glow.mtl"), "newmtl mirror\nillum 3\nKe 0 0 0\n\nnewmtl glass\nillum 7\nKe 1 1 1\n").unwrap();
+    for (source, file, line) in &[("mtllib m.mtl\nv 0 0 0\nusemtl missing\n", "m.obj", 3), ("mtllib glow.mtl\n", "glow.mtl", 7)] {
+        fs::write(dir.join("m.obj"), source).unwrap();
+        let message = match wavefront::load_meshes(dir.join("m.obj")) {
+            Err(e @ wavefront::LoadObjError::Parse{..}) => e.to_string(),
+            _ => panic!("expected a parse error for {:?}", source),
+        };
+        assert!(message.starts_with(&format!("{}:{}: ", dir.join(file).display(), line)), "{}", message);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}